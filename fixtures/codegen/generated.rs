@@ -0,0 +1,301 @@
+// Generated by `fhir codegen` from FHIR StructureDefinitions; do not edit.
+#![allow(dead_code, unused_imports)]
+
+use element::{Element, Value, check_choice};
+use extension::{Extension, check_understood};
+use primitive::{Dec, UnsignedInt, PositiveInt};
+use resource::Resource;
+use error::FhirError;
+use typed::{Composite, TypedResource, ToValue, FromValue, elts_of, check_known, refuse_extensions, put, put_opt, put_vec, get, get_opt, get_vec};
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct HumanName {
+	pub id: Option<String>,
+	pub extension: Vec<Extension>,
+	pub use_: Option<String>,
+	pub given: Vec<String>,
+}
+
+impl Composite for HumanName {
+	fn to_elts(&self) -> Vec<Element> {
+		let mut elts = Vec::new();
+		put_opt(&mut elts, "use", &self.use_);
+		put_vec(&mut elts, "given", &self.given);
+		elts
+	}
+
+	fn from_elts(elts: &[Element]) -> Result<Self, FhirError> {
+		check_known(elts, &["use", "given"])?;
+		Ok(HumanName {
+			id: None,
+			extension: Vec::new(),
+			use_: get_opt(elts, "use")?,
+			given: get_vec(elts, "given")?,
+		})
+	}
+}
+
+impl ToValue for HumanName {
+	fn to_value(&self) -> Value {
+		let mut v = Value::from(self.to_elts());
+		v.id = self.id.clone();
+		v.extension = self.extension.clone();
+		v
+	}
+}
+
+impl FromValue for HumanName {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		check_understood(&v.modifier_extension, &[])?;
+		let mut x = Self::from_elts(elts_of(v)?)?;
+		x.id = v.id.clone();
+		x.extension = v.extension.clone();
+		Ok(x)
+	}
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Identifier {
+	pub id: Option<String>,
+	pub extension: Vec<Extension>,
+	pub system: Option<String>,
+	pub value: Option<String>,
+	pub assigner: Option<Reference>,
+}
+
+impl Composite for Identifier {
+	fn to_elts(&self) -> Vec<Element> {
+		let mut elts = Vec::new();
+		put_opt(&mut elts, "system", &self.system);
+		put_opt(&mut elts, "value", &self.value);
+		put_opt(&mut elts, "assigner", &self.assigner);
+		elts
+	}
+
+	fn from_elts(elts: &[Element]) -> Result<Self, FhirError> {
+		check_known(elts, &["system", "value", "assigner"])?;
+		Ok(Identifier {
+			id: None,
+			extension: Vec::new(),
+			system: get_opt(elts, "system")?,
+			value: get_opt(elts, "value")?,
+			assigner: get_opt(elts, "assigner")?,
+		})
+	}
+}
+
+impl ToValue for Identifier {
+	fn to_value(&self) -> Value {
+		let mut v = Value::from(self.to_elts());
+		v.id = self.id.clone();
+		v.extension = self.extension.clone();
+		v
+	}
+}
+
+impl FromValue for Identifier {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		check_understood(&v.modifier_extension, &[])?;
+		let mut x = Self::from_elts(elts_of(v)?)?;
+		x.id = v.id.clone();
+		x.extension = v.extension.clone();
+		Ok(x)
+	}
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Reference {
+	pub id: Option<String>,
+	pub extension: Vec<Extension>,
+	pub reference: Option<String>,
+	pub identifier: Option<Box<Identifier>>,
+	pub display: Option<String>,
+}
+
+impl Composite for Reference {
+	fn to_elts(&self) -> Vec<Element> {
+		let mut elts = Vec::new();
+		put_opt(&mut elts, "reference", &self.reference);
+		put_opt(&mut elts, "identifier", &self.identifier);
+		put_opt(&mut elts, "display", &self.display);
+		elts
+	}
+
+	fn from_elts(elts: &[Element]) -> Result<Self, FhirError> {
+		check_known(elts, &["reference", "identifier", "display"])?;
+		Ok(Reference {
+			id: None,
+			extension: Vec::new(),
+			reference: get_opt(elts, "reference")?,
+			identifier: get_opt(elts, "identifier")?,
+			display: get_opt(elts, "display")?,
+		})
+	}
+}
+
+impl ToValue for Reference {
+	fn to_value(&self) -> Value {
+		let mut v = Value::from(self.to_elts());
+		v.id = self.id.clone();
+		v.extension = self.extension.clone();
+		v
+	}
+}
+
+impl FromValue for Reference {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		check_understood(&v.modifier_extension, &[])?;
+		let mut x = Self::from_elts(elts_of(v)?)?;
+		x.id = v.id.clone();
+		x.extension = v.extension.clone();
+		Ok(x)
+	}
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct PatientContact {
+	pub id: Option<String>,
+	pub modifier_extension: Vec<Extension>,
+	pub gender: String,
+	pub contact: Option<Box<PatientContact>>,
+}
+
+impl Composite for PatientContact {
+	fn to_elts(&self) -> Vec<Element> {
+		let mut elts = Vec::new();
+		put(&mut elts, "gender", &self.gender);
+		put_opt(&mut elts, "contact", &self.contact);
+		elts
+	}
+
+	fn from_elts(elts: &[Element]) -> Result<Self, FhirError> {
+		check_known(elts, &["gender", "contact"])?;
+		Ok(PatientContact {
+			id: None,
+			modifier_extension: Vec::new(),
+			gender: get(elts, "gender")?,
+			contact: get_opt(elts, "contact")?,
+		})
+	}
+}
+
+impl ToValue for PatientContact {
+	fn to_value(&self) -> Value {
+		let mut v = Value::from(self.to_elts());
+		v.id = self.id.clone();
+		v.modifier_extension = self.modifier_extension.clone();
+		v
+	}
+}
+
+impl FromValue for PatientContact {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		refuse_extensions(&v.extension)?;
+		let mut x = Self::from_elts(elts_of(v)?)?;
+		x.id = v.id.clone();
+		x.modifier_extension = v.modifier_extension.clone();
+		Ok(x)
+	}
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum PatientDeceased {
+	Boolean(bool),
+	DateTime(String),
+}
+
+impl PatientDeceased {
+	pub fn put(&self, elts: &mut Vec<Element>) {
+		match *self {
+			PatientDeceased::Boolean(ref v) => put(elts, "deceasedBoolean", v),
+			PatientDeceased::DateTime(ref v) => put(elts, "deceasedDateTime", v),
+		}
+	}
+
+	pub fn get(elts: &[Element]) -> Result<Option<Self>, FhirError> {
+		check_choice(elts, "deceased")?;
+		if let Some(v) = get_opt(elts, "deceasedBoolean")? {
+			return Ok(Some(PatientDeceased::Boolean(v)));
+		}
+		if let Some(v) = get_opt(elts, "deceasedDateTime")? {
+			return Ok(Some(PatientDeceased::DateTime(v)));
+		}
+		Ok(None)
+	}
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Patient {
+	pub id: Option<String>,
+	pub contained: Vec<Resource>,
+	pub extension: Vec<Extension>,
+	pub modifier_extension: Vec<Extension>,
+	pub identifier: Vec<Identifier>,
+	pub active: Option<bool>,
+	pub name: Vec<HumanName>,
+	pub birth_date: String,
+	pub deceased: Option<PatientDeceased>,
+	pub contact: Vec<PatientContact>,
+	pub managing_organization: Option<Reference>,
+}
+
+impl Composite for Patient {
+	fn to_elts(&self) -> Vec<Element> {
+		let mut elts = Vec::new();
+		put_opt(&mut elts, "id", &self.id);
+		put_vec(&mut elts, "contained", &self.contained);
+		put_vec(&mut elts, "identifier", &self.identifier);
+		put_opt(&mut elts, "active", &self.active);
+		put_vec(&mut elts, "name", &self.name);
+		put(&mut elts, "birthDate", &self.birth_date);
+		if let Some(ref c) = self.deceased { c.put(&mut elts); }
+		put_vec(&mut elts, "contact", &self.contact);
+		put_opt(&mut elts, "managingOrganization", &self.managing_organization);
+		elts
+	}
+
+	fn from_elts(elts: &[Element]) -> Result<Self, FhirError> {
+		check_known(elts, &["id", "contained", "identifier", "active", "name", "birthDate", "deceasedBoolean", "deceasedDateTime", "contact", "managingOrganization"])?;
+		Ok(Patient {
+			id: get_opt(elts, "id")?,
+			contained: get_vec(elts, "contained")?,
+			extension: Vec::new(),
+			modifier_extension: Vec::new(),
+			identifier: get_vec(elts, "identifier")?,
+			active: get_opt(elts, "active")?,
+			name: get_vec(elts, "name")?,
+			birth_date: get(elts, "birthDate")?,
+			deceased: PatientDeceased::get(elts)?,
+			contact: get_vec(elts, "contact")?,
+			managing_organization: get_opt(elts, "managingOrganization")?,
+		})
+	}
+}
+
+impl ToValue for Patient {
+	fn to_value(&self) -> Value {
+		self.to_resource().to_value()
+	}
+}
+
+impl FromValue for Patient {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		Self::from_resource(&Resource::from_value(v)?)
+	}
+}
+
+impl TypedResource for Patient {
+	fn resource_type() -> &'static str {
+		"Patient"
+	}
+
+	fn extensions(&self) -> (Vec<Extension>, Vec<Extension>) {
+		(self.extension.clone(), self.modifier_extension.clone())
+	}
+
+	fn set_extensions(&mut self, exts: &[Extension], mods: &[Extension]) -> Result<(), FhirError> {
+		self.extension = exts.to_vec();
+		self.modifier_extension = mods.to_vec();
+		Ok(())
+	}
+}
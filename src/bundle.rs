@@ -0,0 +1,421 @@
+use std::collections::btree_map::BTreeMap;
+
+use rustc_serialize::json::{ToJson, Json};
+use url::Url;
+use chrono::{DateTime, FixedOffset};
+
+use element::{Element, Value};
+use extension::{Extension, check_understood};
+use primitive::{Code, Dec, UnsignedInt, Uuid};
+use resource::Resource;
+use datatypes::{Identifier, Reference, ReferenceTarget};
+use typed::{Composite, ToValue, FromValue, elts_of, put, put_opt, put_vec, get, get_opt, get_vec, split_extras, graft_extras};
+use json::FromJson;
+use error::FhirError;
+#[cfg(test)]
+use error::issues;
+
+// A Bundle as a typed view of a Resource: the members modelled here are
+// lifted out of it, and its id, meta, extensions and the members not
+// modelled (signature, issues) stay in `base`. The entries' backbone
+// elements are read like datatypes.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum BundleType {
+	Document,
+	Message,
+	Transaction,
+	TransactionResponse,
+	Batch,
+	BatchResponse,
+	History,
+	Searchset,
+	Collection,
+	SubscriptionNotification
+}
+
+const BUNDLE_TYPES: &'static [(BundleType, &'static str)] = &[
+	(BundleType::Document, "document"),
+	(BundleType::Message, "message"),
+	(BundleType::Transaction, "transaction"),
+	(BundleType::TransactionResponse, "transaction-response"),
+	(BundleType::Batch, "batch"),
+	(BundleType::BatchResponse, "batch-response"),
+	(BundleType::History, "history"),
+	(BundleType::Searchset, "searchset"),
+	(BundleType::Collection, "collection"),
+	(BundleType::SubscriptionNotification, "subscription-notification")];
+
+impl BundleType {
+	pub fn code(&self) -> &'static str {
+		BUNDLE_TYPES.iter().find(|&&(t, _)| t == *self).map(|&(_, c)| c).unwrap_or("")
+	}
+
+	pub fn parse(s: &str) -> Result<Self, FhirError> {
+		BUNDLE_TYPES.iter().find(|&&(_, c)| c == s).map(|&(t, _)| t)
+			.ok_or_else(|| FhirError::invalid(s, "Unknown bundle type"))
+	}
+
+	fn has_requests(&self) -> bool {
+		[BundleType::Batch, BundleType::Transaction, BundleType::History].contains(self)
+	}
+
+	fn has_responses(&self) -> bool {
+		[BundleType::BatchResponse, BundleType::TransactionResponse, BundleType::History].contains(self)
+	}
+}
+
+impl ToValue for BundleType {
+	fn to_value(&self) -> Value {
+		Value::from(self.code())
+	}
+}
+
+impl FromValue for BundleType {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		BundleType::parse(&String::from_value(v)?)
+	}
+}
+
+datatype!(BundleLink {
+	relation: opt String = "relation",
+	url: opt Url = "url"
+});
+
+datatype!(EntrySearch {
+	mode: opt Code = "mode",
+	score: opt Dec = "score"
+});
+
+datatype!(EntryRequest {
+	method: opt Code = "method",
+	url: opt String = "url",
+	if_none_match: opt String = "ifNoneMatch",
+	if_modified_since: opt DateTime<FixedOffset> = "ifModifiedSince",
+	if_match: opt String = "ifMatch",
+	if_none_exist: opt String = "ifNoneExist"
+});
+
+datatype!(EntryResponse {
+	status: opt String = "status",
+	location: opt String = "location",
+	etag: opt String = "etag",
+	last_modified: opt DateTime<FixedOffset> = "lastModified",
+	outcome: opt Resource = "outcome"
+});
+
+datatype!(BundleEntry {
+	link: vec BundleLink = "link",
+	full_url: opt Url = "fullUrl",
+	resource: opt Resource = "resource",
+	search: opt EntrySearch = "search",
+	request: opt EntryRequest = "request",
+	response: opt EntryResponse = "response"
+});
+
+impl BundleEntry {
+	pub fn new(resource: Resource) -> Self {
+		BundleEntry {resource: Some(resource), ..BundleEntry::default()}
+	}
+
+	pub fn full_url(mut self, u: Url) -> Self {
+		self.full_url = Some(u);
+		self
+	}
+
+	// A request in a batch or transaction; `url` is relative to the server base
+	pub fn request(mut self, method: Code, url: &str) -> Self {
+		self.request = Some(EntryRequest {method: Some(method), url: Some(url.to_string()), ..EntryRequest::default()});
+		self
+	}
+
+	pub fn search(mut self, mode: Code) -> Self {
+		self.search = Some(EntrySearch {mode: Some(mode), ..EntrySearch::default()});
+		self
+	}
+
+	fn version_id(&self) -> Option<&str> {
+		self.resource.as_ref().and_then(|r| r.meta.as_ref()).and_then(|m| m.version_id.as_ref()).map(|v| v.as_str())
+	}
+}
+
+const MODELLED: &'static [&'static str] = &["identifier", "type", "timestamp", "total", "link", "entry"];
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Bundle {
+	pub base: Resource,
+	pub type_: BundleType,
+	pub identifier: Option<Identifier>,
+	pub timestamp: Option<DateTime<FixedOffset>>,
+	pub total: Option<UnsignedInt>,
+	pub link: Vec<BundleLink>,
+	pub entry: Vec<BundleEntry>
+}
+
+impl Bundle {
+	pub fn new(t: BundleType) -> Self {
+		Bundle {base: Resource::new("Bundle"), type_: t, identifier: None, timestamp: None, total: None, link: Vec::new(), entry: Vec::new()}
+	}
+
+	pub fn from_resource(r: &Resource) -> Result<Self, FhirError> {
+		if r.name != "Bundle" {
+			return Err(FhirError::unexpected(&r.name, "Wrong resource type"));
+		}
+		let (elts, rest): (Vec<Element>, Vec<Element>) = r.elts.iter().cloned().partition(|e| MODELLED.contains(&e.name.as_str()));
+		let at = |e: FhirError| e.at("Bundle");
+		let mut base = r.clone();
+		base.elts = rest;
+		Ok(Bundle {
+			base: base,
+			type_: get(&elts, "type").map_err(at)?,
+			identifier: get_opt(&elts, "identifier").map_err(at)?,
+			timestamp: get_opt(&elts, "timestamp").map_err(at)?,
+			total: get_opt(&elts, "total").map_err(at)?,
+			link: get_vec(&elts, "link").map_err(at)?,
+			entry: get_vec(&elts, "entry").map_err(at)?
+		})
+	}
+
+	pub fn to_resource(&self) -> Resource {
+		let mut elts = Vec::new();
+		put_opt(&mut elts, "identifier", &self.identifier);
+		put(&mut elts, "type", &self.type_);
+		put_opt(&mut elts, "timestamp", &self.timestamp);
+		put_opt(&mut elts, "total", &self.total);
+		put_vec(&mut elts, "link", &self.link);
+		put_vec(&mut elts, "entry", &self.entry);
+		let mut r = self.base.clone();
+		elts.extend(r.elts.drain(..));
+		r.elts = elts;
+		r
+	}
+
+	pub fn add(&mut self, entry: BundleEntry) {
+		self.entry.push(entry);
+	}
+
+	pub fn resources(&self) -> Vec<&Resource> {
+		self.entry.iter().filter_map(|e| e.resource.as_ref()).collect()
+	}
+
+	pub fn resources_of_type(&self, name: &str) -> Vec<&Resource> {
+		self.resources().into_iter().filter(|r| r.name == name).collect()
+	}
+
+	// The url of a paging link: self, first, previous, next or last
+	pub fn link(&self, relation: &str) -> Option<&Url> {
+		self.link.iter().find(|l| l.relation.as_ref().map(|r| r.as_str()) == Some(relation)).and_then(|l| l.url.as_ref())
+	}
+
+	pub fn next(&self) -> Option<&Url> {
+		self.link("next")
+	}
+
+	// Earlier versions of FHIR called this link "prev"
+	pub fn previous(&self) -> Option<&Url> {
+		self.link("previous").or_else(|| self.link("prev"))
+	}
+
+	// Replaces any link with the same relation
+	pub fn set_link(&mut self, relation: &str, u: Url) {
+		self.link.retain(|l| l.relation.as_ref().map(|r| r.as_str()) != Some(relation));
+		self.link.push(BundleLink {relation: Some(relation.to_string()), url: Some(u), ..BundleLink::default()});
+	}
+
+	// The entry a reference made in this bundle points to. `from` is the
+	// fullUrl of the entry holding the reference: a relative reference is
+	// taken against its RESTful base, and failing one (a urn:uuid, or no
+	// fullUrl) matches an entry by resource type and id. A version in the
+	// reference must match the resource's meta.versionId. Contained and
+	// logical references aren't to entries.
+	pub fn resolve(&self, target: &ReferenceTarget, from: Option<&Url>) -> Option<&BundleEntry> {
+		let (base, t, id, version) = match *target {
+			ReferenceTarget::Absolute {resource_type: Some(ref t), id: Some(ref id), ref version, ..} =>
+				(target.base().map(String::from), t, id, version),
+			ReferenceTarget::Absolute {ref url, ..} => return self.entry.iter().find(|e| e.full_url.as_ref() == Some(url)),
+			ReferenceTarget::Relative {ref resource_type, ref id, ref version} =>
+				(from.and_then(rest_target).and_then(|f| f.base().map(String::from)), resource_type, id, version),
+			ReferenceTarget::Contained(_) | ReferenceTarget::Logical {..} => return None
+		};
+		self.entry.iter()
+			.filter(|e| version.as_ref().map_or(true, |v| e.version_id() == Some(v)))
+			.find(|e| match base {
+				Some(ref b) => e.full_url.as_ref().and_then(rest_target)
+					.map_or(false, |f| f.base() == Some(b) && f.resource_type() == Some(t) && f.id() == Some(id)),
+				None => e.resource.as_ref().map_or(false, |r| r.name == *t && r.id.as_ref().map(|i| i.as_str()) == Some(id))
+			})
+	}
+
+	// Resolve a reference made by the resource in `entry`; one that isn't a
+	// valid reference resolves to nothing
+	pub fn resolve_from(&self, entry: &BundleEntry, reference: &str) -> Option<&Resource> {
+		Reference::new(reference).target().ok().and_then(|t| t)
+			.and_then(|t| self.resolve(&t, entry.full_url.as_ref()))
+			.and_then(|e| e.resource.as_ref())
+	}
+
+	// The rules of bundles that the element types alone don't enforce
+	pub fn check(&self) -> Vec<FhirError> {
+		let mut issues = Vec::new();
+		let t = self.type_;
+		if self.total.is_some() && t != BundleType::Searchset && t != BundleType::History {
+			issues.push(FhirError::unexpected("total", "Total only in a searchset or history").at("total"));
+		}
+		if t == BundleType::Document {
+			if self.identifier.is_none() {
+				issues.push(FhirError::missing("A document needs an identifier").at("identifier"));
+			}
+			if self.timestamp.is_none() {
+				issues.push(FhirError::missing("A document needs a timestamp").at("timestamp"));
+			}
+		}
+		let first = |name: &str| self.entry.first().and_then(|e| e.resource.as_ref()).map_or(false, |r| r.name == name);
+		if t == BundleType::Document && !first("Composition") {
+			issues.push(FhirError::missing("A document starts with a Composition").at("entry"));
+		}
+		if t == BundleType::Message && !first("MessageHeader") {
+			issues.push(FhirError::missing("A message starts with a MessageHeader").at("entry"));
+		}
+		let mut seen: BTreeMap<&str, Vec<Option<&str>>> = BTreeMap::new();
+		for (i, e) in self.entry.iter().enumerate() {
+			let mut issue = |err: FhirError| issues.push(err.at(&format!("[{}]", i)).at("entry"));
+			if e.resource.is_none() && e.request.is_none() && e.response.is_none() {
+				issue(FhirError::missing("An entry needs a resource, request or response"));
+			}
+			if e.search.is_some() && t != BundleType::Searchset {
+				issue(FhirError::unexpected("search", "Search only in a searchset").at("search"));
+			}
+			if e.request.is_some() != t.has_requests() {
+				issue(match e.request {
+					Some(_) => FhirError::unexpected("request", "Request only in a batch, transaction or history").at("request"),
+					None => FhirError::missing("Missing required element").at("request")
+				});
+			}
+			if e.response.is_some() != t.has_responses() {
+				issue(match e.response {
+					Some(_) => FhirError::unexpected("response", "Response only in a batch or transaction response or history").at("response"),
+					None => FhirError::missing("Missing required element").at("response")
+				});
+			}
+			let u = match e.full_url {
+				Some(ref u) => u.as_str(),
+				None => continue
+			};
+			if u.contains("/_history/") {
+				issue(FhirError::invalid(u, "A fullUrl can't name a version").at("fullUrl"));
+			}
+			if u.starts_with("urn:uuid:") && Uuid::parse(u).is_err() {
+				issue(FhirError::invalid(u, "Invalid uuid").at("fullUrl"));
+			}
+			let named = e.full_url.as_ref().and_then(rest_target);
+			if let (Some(id), Some(r)) = (named.as_ref().and_then(|f| f.id()), e.resource.as_ref()) {
+				if r.id.as_ref().map_or(false, |rid| rid.as_str() != id) {
+					issue(FhirError::invalid(u, "The fullUrl doesn't match the resource id").at("fullUrl"));
+				}
+			}
+			let versions = seen.entry(u).or_insert_with(Vec::new);
+			if t != BundleType::History && versions.iter().any(|v| v.is_none() || *v == e.version_id()) {
+				issue(FhirError::invalid(u, "Duplicate fullUrl").at("fullUrl"));
+			}
+			versions.push(e.version_id());
+		}
+		issues.into_iter().map(|e| e.at("Bundle")).collect()
+	}
+}
+
+// What a fullUrl names, read as an absolute reference
+fn rest_target(u: &Url) -> Option<ReferenceTarget> {
+	Reference::new(u.as_str()).target().ok().and_then(|t| t)
+}
+
+impl FromJson for Bundle {
+	fn from_json(j: &Json) -> Result<Self, FhirError> {
+		Bundle::from_resource(&Resource::from_json(j)?)
+	}
+}
+
+impl ToJson for Bundle {
+	fn to_json(&self) -> Json {
+		self.to_resource().to_json()
+	}
+}
+
+
+#[cfg(test)]
+const TEST_SEARCHSET: &'static str = r#"{"resourceType": "Bundle", "id": "b1", "type": "searchset", "total": 2,
+	"link": [{"relation": "self", "url": "http://example.org/fhir/Patient?name=smith"},
+		{"relation": "next", "url": "http://example.org/fhir/Patient?name=smith&page=2"}],
+	"entry": [
+		{"fullUrl": "http://example.org/fhir/Patient/1", "resource": {"resourceType": "Patient", "id": "1",
+			"meta": {"versionId": "3"}, "managingOrganization": {"reference": "Organization/o1"}}, "search": {"mode": "match", "score": 0.5}},
+		{"fullUrl": "http://example.org/fhir/Organization/o1", "resource": {"resourceType": "Organization", "id": "o1"},
+			"search": {"mode": "include"}}],
+	"signature": {"who": {"display": "Example"}}}"#;
+
+#[test]
+fn test_bundle_round_trip() {
+	let j = ::json::parse(TEST_SEARCHSET).unwrap();
+	let b = Bundle::from_json(&j).unwrap();
+	assert_eq!(BundleType::Searchset, b.type_);
+	assert_eq!(2, b.entry.len());
+	assert_eq!("page=2", b.next().unwrap().query().unwrap().split('&').nth(1).unwrap());
+	assert_eq!(None, b.previous());
+	assert_eq!(vec!["Organization"], b.resources_of_type("Organization").iter().map(|r| r.name.as_str()).collect::<Vec<_>>());
+	assert_eq!("signature", b.base.elts[0].name);
+	assert_eq!(j, b.to_json());
+	assert!(b.check().is_empty());
+	let bad = Json::from_str(r#"{"resourceType": "Bundle", "type": "pile"}"#).unwrap();
+	assert_eq!("Bundle.type", Bundle::from_json(&bad).err().unwrap().path());
+}
+
+#[test]
+fn test_bundle_xml_entries() {
+	use xml::{ToXml, FromXml};
+	let b = Bundle::from_json(&Json::from_str(TEST_SEARCHSET).unwrap()).unwrap();
+	let xml = b.to_resource().to_xml();
+	assert!(xml.contains(r#"<resource><Patient xmlns="http://hl7.org/fhir"><id value="1"/><meta>"#));
+	let back = Bundle::from_resource(&Resource::from_xml(&xml).unwrap()).unwrap();
+	assert_eq!(b.to_json(), back.to_json());
+}
+
+#[test]
+fn test_bundle_resolve() {
+	let b = Bundle::from_json(&Json::from_str(TEST_SEARCHSET).unwrap()).unwrap();
+	let patient = &b.entry[0];
+	assert_eq!("Organization", b.resolve_from(patient, "Organization/o1").unwrap().name);
+	assert!(b.resolve_from(patient, "Organization/o2").is_none());
+	assert!(b.resolve_from(patient, "Organization/o 1").is_none());
+	let target = |r: &str| Reference::new(r).target().unwrap().unwrap();
+	assert!(b.resolve(&target("http://example.org/fhir/Patient/1"), None).is_some());
+	assert!(b.resolve(&target("http://example.org/fhir/Patient/1/_history/3"), None).is_some());
+	assert!(b.resolve(&target("http://example.org/other/Patient/1"), None).is_none());
+	assert!(b.resolve(&target("Patient/1/_history/3"), None).is_some());
+	assert!(b.resolve(&target("Patient/1/_history/2"), None).is_none());
+	assert!(b.resolve(&target("#p1"), None).is_none());
+
+	let mut tx = Bundle::new(BundleType::Transaction);
+	let post = Code::parse("POST").unwrap();
+	let obs = Resource::from_json(&Json::from_str(r#"{"resourceType": "Observation",
+		"subject": {"reference": "urn:uuid:c757873d-ec9a-4326-a141-556f43239520"}}"#).unwrap()).unwrap();
+	tx.add(BundleEntry::new(Resource::new("Patient")).full_url(Url::parse("urn:uuid:c757873d-ec9a-4326-a141-556f43239520").unwrap())
+		.request(post.clone(), "Patient"));
+	tx.add(BundleEntry::new(obs).full_url(Url::parse("urn:uuid:2d2c1a5b-4f0e-4a6c-9f39-8d0c3f4e2b11").unwrap()).request(post, "Observation"));
+	assert_eq!("Patient", tx.resolve_from(&tx.entry[1], "urn:uuid:c757873d-ec9a-4326-a141-556f43239520").unwrap().name);
+	assert!(tx.check().is_empty());
+}
+
+#[test]
+fn test_bundle_check() {
+	let j = Json::from_str(r#"{"resourceType": "Bundle", "type": "collection", "total": 1, "entry": [
+		{"fullUrl": "http://example.org/fhir/Patient/1", "resource": {"resourceType": "Patient", "id": "2"}, "search": {"mode": "match"}},
+		{"fullUrl": "http://example.org/fhir/Patient/1", "request": {"method": "GET", "url": "Patient/1"}},
+		{"fullUrl": "urn:uuid:nope"}]}"#).unwrap();
+	assert_eq!(vec![
+		("Bundle.total".to_string(), "Total only in a searchset or history"),
+		("Bundle.entry[0].search".to_string(), "Search only in a searchset"),
+		("Bundle.entry[0].fullUrl".to_string(), "The fullUrl doesn't match the resource id"),
+		("Bundle.entry[1].request".to_string(), "Request only in a batch, transaction or history"),
+		("Bundle.entry[1].fullUrl".to_string(), "Duplicate fullUrl"),
+		("Bundle.entry[2]".to_string(), "An entry needs a resource, request or response"),
+		("Bundle.entry[2].fullUrl".to_string(), "Invalid uuid")], issues(&Bundle::from_json(&j).unwrap().check()));
+}
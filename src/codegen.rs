@@ -0,0 +1,535 @@
+use rustc_serialize::json::Json;
+
+use error::FhirError;
+#[cfg(test)]
+use error::issue;
+use json::{as_string, resources_of_type};
+
+// Generates Rust structs for the resources and complex datatypes in FHIR
+// StructureDefinition bundles (profiles-resources.json, profiles-types.json).
+// The output is a module to be compiled into this crate; see typed.rs for the
+// conversions it relies on. A struct keeps its ids and extensions, and refuses
+// whatever it has no field for, so nothing is lost on the way through.
+
+const HEADER: &'static str = "// Generated by `fhir codegen` from FHIR StructureDefinitions; do not edit.
+#![allow(dead_code, unused_imports)]
+
+use element::{Element, Value, check_choice};
+use extension::{Extension, check_understood};
+use primitive::{Dec, UnsignedInt, PositiveInt};
+use resource::Resource;
+use error::FhirError;
+use typed::{Composite, TypedResource, ToValue, FromValue, elts_of, check_known, refuse_extensions, put, put_opt, put_vec, get, get_opt, get_vec};
+";
+
+const KEYWORDS: &'static [&'static str] = &["abstract", "as", "async", "await", "become", "box", "break", "const",
+	"continue", "crate", "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in",
+	"let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "self",
+	"static", "struct", "super", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual",
+	"where", "while", "yield"];
+
+const SYSTEM_PREFIX: &'static str = "http://hl7.org/fhirpath/System.";
+
+enum FieldType {
+	Plain(String),
+	Boxed(String),
+	// an element id, extension or modifierExtension, held on the Value (or
+	// the Resource) rather than among the elements
+	Attr,
+	// enum name and (type suffix, Rust type) per variant
+	Choice(String, Vec<(String, String)>)
+}
+
+struct Field {
+	name: String,
+	ty: FieldType,
+	required: bool,
+	many: bool
+}
+
+struct Struct {
+	name: String,
+	resource: bool,
+	fields: Vec<Field>
+}
+
+pub fn generate(docs: &[Json]) -> Result<String, FhirError> {
+	let mut structs = Vec::new();
+	for d in docs.iter() {
+		for sd in resources_of_type(d, "StructureDefinition") {
+			collect(sd, &mut structs)?;
+		}
+	}
+	break_cycles(&mut structs);
+	let mut out = String::from(HEADER);
+	for s in structs.iter() {
+		write_struct(&mut out, s);
+	}
+	Ok(out)
+}
+
+fn str_at<'a>(j: &'a Json, key: &str) -> Option<&'a str> {
+	j.find(key).and_then(|v| v.as_string())
+}
+
+fn collect(sd: &Json, out: &mut Vec<Struct>) -> Result<(), FhirError> {
+	let kind = str_at(sd, "kind").unwrap_or("");
+	let is_abstract = sd.find("abstract").and_then(|a| a.as_boolean()).unwrap_or(false);
+	if (kind != "resource" && kind != "complex-type") || is_abstract || str_at(sd, "derivation") == Some("constraint") {
+		return Ok(());
+	}
+	let name = match str_at(sd, "type").or(str_at(sd, "name")) {
+		Some(n) => n,
+		None => return Err(FhirError::missing("StructureDefinition without type"))
+	};
+	if ["Extension", "Resource", "DomainResource", "Element", "BackboneElement"].contains(&name) {
+		return Ok(());
+	}
+	let elements = sd.find_path(&["snapshot", "element"]).and_then(|e| e.as_array())
+		.ok_or_else(|| FhirError::missing("Missing snapshot").at(name))?;
+	build_struct(name, name, elements, kind == "resource", out).map_err(|e| e.at(name))
+}
+
+fn build_struct(struct_name: &str, path: &str, elements: &[Json], resource: bool, out: &mut Vec<Struct>) -> Result<(), FhirError> {
+	let prefix = format!("{}.", path);
+	let mut fields = Vec::new();
+	for e in elements.iter() {
+		let p = match e.find("path") {
+			Some(p) => as_string(p)?,
+			None => return Err(FhirError::missing("Element without path"))
+		};
+		if !p.starts_with(&prefix) || p[prefix.len()..].contains('.') {
+			continue;
+		}
+		let seg = &p[prefix.len()..];
+		let max = str_at(e, "max");
+		if max == Some("0") {
+			continue;
+		}
+		let required = e.find("min").and_then(|m| m.as_u64()).unwrap_or(0) > 0;
+		let many = match max {
+			Some("*") => true,
+			Some(m) => m.parse::<u32>().unwrap_or(1) > 1,
+			None => false
+		};
+		let codes: Vec<&str> = e.find("type").and_then(|t| t.as_array())
+			.map(|ts| ts.iter().filter_map(|t| str_at(t, "code")).collect())
+			.unwrap_or_else(Vec::new);
+		let (name, ty) = if seg == "extension" || seg == "modifierExtension" || (seg == "id" && !resource) {
+			(seg, FieldType::Attr)
+		} else if seg == "contained" && resource {
+			(seg, FieldType::Plain("Resource".to_string()))
+		} else if seg.ends_with("[x]") {
+			let base = &seg[..seg.len() - 3];
+			let variants = codes.iter()
+				.map(|c| rust_type(c).map(|t| (capitalize(c), t)).ok_or_else(|| untyped(c, seg)))
+				.collect::<Result<Vec<(String, String)>, FhirError>>()?;
+			if variants.is_empty() {
+				return Err(untyped("", seg));
+			}
+			(base, FieldType::Choice(format!("{}{}", struct_name, capitalize(base)), variants))
+		} else if let Some(r) = str_at(e, "contentReference") {
+			let target = r.rsplit('#').next().unwrap_or(r);
+			let t = target.split('.').map(capitalize).collect::<Vec<_>>().concat();
+			(seg, if many { FieldType::Plain(t) } else { FieldType::Boxed(t) })
+		} else if codes == ["BackboneElement"] || codes == ["Element"] {
+			let nested = format!("{}{}", struct_name, capitalize(seg));
+			build_struct(&nested, p, elements, false, out)?;
+			(seg, FieldType::Plain(nested))
+		} else {
+			match codes.first().and_then(|c| rust_type(c)) {
+				Some(t) if codes.len() == 1 => (seg, FieldType::Plain(t)),
+				_ => return Err(untyped(&codes.join("|"), seg))
+			}
+		};
+		fields.push(Field {name: name.to_string(), ty: ty, required: required, many: many});
+	}
+	out.push(Struct {name: struct_name.to_string(), resource: resource, fields: fields});
+	Ok(())
+}
+
+// An element the generator has no field type for is an error rather than
+// a field left out, which would make the struct refuse every instance that has it
+fn untyped(code: &str, seg: &str) -> FhirError {
+	FhirError::unexpected(code, "Element type can't be generated").at(seg)
+}
+
+// None for types that have no typed representation of their own (extensions,
+// and the abstract bases of elements and resources). A nested resource is
+// held as a Resource.
+fn rust_type(code: &str) -> Option<String> {
+	if code.starts_with(SYSTEM_PREFIX) {
+		let t = match &code[SYSTEM_PREFIX.len()..] {
+			"Boolean" => "bool",
+			"Integer" => "i32",
+			"Decimal" => "Dec",
+			_ => "String"
+		};
+		return Some(t.to_string());
+	}
+	let t = match code {
+		"boolean" => "bool",
+		"integer" => "i32",
+		"integer64" => "i64",
+		"unsignedInt" => "UnsignedInt",
+		"positiveInt" => "PositiveInt",
+		"decimal" => "Dec",
+		"Extension" | "DomainResource" | "Element" | "BackboneElement" => return None,
+		c if c.starts_with(char::is_lowercase) => "String",
+		c => c
+	};
+	Some(t.to_string())
+}
+
+pub fn capitalize(s: &str) -> String {
+	let mut c = s.chars();
+	match c.next() {
+		Some(f) => f.to_uppercase().chain(c).collect(),
+		None => String::new()
+	}
+}
+
+fn field_name(json: &str) -> String {
+	let mut s = String::new();
+	for c in json.chars() {
+		if c.is_uppercase() {
+			s.push('_');
+			s.extend(c.to_lowercase());
+		} else {
+			s.push(c);
+		}
+	}
+	if KEYWORDS.contains(&s.as_ref()) {
+		s.push('_');
+	}
+	s
+}
+
+fn field_type(f: &Field) -> String {
+	let t = match f.ty {
+		FieldType::Attr if f.name == "id" => return "Option<String>".to_string(),
+		FieldType::Attr => return "Vec<Extension>".to_string(),
+		FieldType::Plain(ref t) if f.many => return format!("Vec<{}>", t),
+		FieldType::Plain(ref t) => t.clone(),
+		FieldType::Boxed(ref t) => format!("Box<{}>", t),
+		FieldType::Choice(ref e, _) => e.clone()
+	};
+	if f.required { t } else { format!("Option<{}>", t) }
+}
+
+// The struct types a field holds inline, with the choice variant they are in
+fn inline_types(f: &Field) -> Vec<(Option<usize>, &str)> {
+	match f.ty {
+		FieldType::Plain(ref t) if !f.many => vec![(None, t.as_ref())],
+		FieldType::Choice(_, ref variants) => variants.iter().enumerate().map(|(i, v)| (Some(i), v.1.as_ref())).collect(),
+		_ => Vec::new()
+	}
+}
+
+// A struct that holds itself inline, directly or through others (as
+// Identifier.assigner holds a Reference, whose identifier holds an
+// Identifier), has no size; the field that closes each cycle is boxed
+fn break_cycles(structs: &mut Vec<Struct>) {
+	let mut state = vec![Visit::New; structs.len()];
+	let mut back = Vec::new();
+	for i in 0..structs.len() {
+		visit(structs, i, &mut state, &mut back);
+	}
+	for (i, f, variant) in back {
+		let field = &mut structs[i].fields[f];
+		match (variant, &mut field.ty) {
+			(Some(v), &mut FieldType::Choice(_, ref mut variants)) => variants[v].1 = format!("Box<{}>", variants[v].1),
+			(None, ty) => {
+				if let FieldType::Plain(t) = ::std::mem::replace(ty, FieldType::Attr) {
+					*ty = FieldType::Boxed(t);
+				}
+			},
+			_ => ()
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+	New,
+	Open,
+	Done
+}
+
+fn visit(structs: &[Struct], i: usize, state: &mut Vec<Visit>, back: &mut Vec<(usize, usize, Option<usize>)>) {
+	if state[i] != Visit::New {
+		return;
+	}
+	state[i] = Visit::Open;
+	for (f, field) in structs[i].fields.iter().enumerate() {
+		for (variant, t) in inline_types(field) {
+			match structs.iter().position(|s| s.name == t) {
+				Some(j) if state[j] == Visit::Open => back.push((i, f, variant)),
+				Some(j) => visit(structs, j, state, back),
+				None => ()
+			}
+		}
+	}
+	state[i] = Visit::Done;
+}
+
+fn write_choice(out: &mut String, name: &str, base: &str, variants: &[(String, String)]) {
+	out.push_str(&format!("\n#[derive(Debug,Clone,PartialEq)]\npub enum {} {{\n", name));
+	for &(ref v, ref t) in variants.iter() {
+		out.push_str(&format!("\t{}({}),\n", v, t));
+	}
+	out.push_str(&format!("}}\n\nimpl {} {{\n\tpub fn put(&self, elts: &mut Vec<Element>) {{\n\t\tmatch *self {{\n", name));
+	for &(ref v, _) in variants.iter() {
+		out.push_str(&format!("\t\t\t{}::{}(ref v) => put(elts, \"{}{}\", v),\n", name, v, base, v));
+	}
+	out.push_str(&format!("\t\t}}\n\t}}\n\n\tpub fn get(elts: &[Element]) -> Result<Option<Self>, FhirError> {{\n\t\tcheck_choice(elts, \"{}\")?;\n", base));
+	for &(ref v, _) in variants.iter() {
+		out.push_str(&format!("\t\tif let Some(v) = get_opt(elts, \"{}{}\")? {{\n\t\t\treturn Ok(Some({}::{}(v)));\n\t\t}}\n", base, v, name, v));
+	}
+	out.push_str("\t\tOk(None)\n\t}\n}\n");
+}
+
+// The element names a struct keeps, choices under each of their variants
+fn known_names(s: &Struct) -> Vec<String> {
+	let mut names = Vec::new();
+	for f in s.fields.iter() {
+		match f.ty {
+			FieldType::Attr => (),
+			FieldType::Choice(_, ref variants) => names.extend(variants.iter().map(|v| format!("{}{}", f.name, v.0))),
+			_ => names.push(f.name.clone())
+		}
+	}
+	names
+}
+
+fn has_attr(s: &Struct, name: &str) -> bool {
+	s.fields.iter().any(|f| f.name == name && match f.ty { FieldType::Attr => true, _ => false })
+}
+
+fn write_struct(out: &mut String, s: &Struct) {
+	for f in s.fields.iter() {
+		if let FieldType::Choice(ref name, ref variants) = f.ty {
+			write_choice(out, name, &f.name, variants);
+		}
+	}
+	out.push_str(&format!("\n#[derive(Debug,Clone,PartialEq)]\npub struct {} {{\n", s.name));
+	for f in s.fields.iter() {
+		out.push_str(&format!("\tpub {}: {},\n", field_name(&f.name), field_type(f)));
+	}
+	out.push_str(&format!("}}\n\nimpl Composite for {} {{\n\tfn to_elts(&self) -> Vec<Element> {{\n\t\tlet mut elts = Vec::new();\n", s.name));
+	for f in s.fields.iter() {
+		let rust = field_name(&f.name);
+		let line = match f.ty {
+			FieldType::Attr => continue,
+			FieldType::Choice(..) if f.required => format!("self.{}.put(&mut elts);", rust),
+			FieldType::Choice(..) => format!("if let Some(ref c) = self.{} {{ c.put(&mut elts); }}", rust),
+			_ if f.many => format!("put_vec(&mut elts, \"{}\", &self.{});", f.name, rust),
+			_ if f.required => format!("put(&mut elts, \"{}\", &self.{});", f.name, rust),
+			_ => format!("put_opt(&mut elts, \"{}\", &self.{});", f.name, rust)
+		};
+		out.push_str(&format!("\t\t{}\n", line));
+	}
+	let known: Vec<String> = known_names(s).iter().map(|n| format!("\"{}\"", n)).collect();
+	out.push_str(&format!("\t\telts\n\t}}\n\n\tfn from_elts(elts: &[Element]) -> Result<Self, FhirError> {{\n\t\tcheck_known(elts, &[{}])?;\n\t\tOk({} {{\n",
+		known.join(", "), s.name));
+	for f in s.fields.iter() {
+		let expr = match f.ty {
+			FieldType::Attr if f.name == "id" => "None".to_string(),
+			FieldType::Attr => "Vec::new()".to_string(),
+			FieldType::Choice(ref e, _) if f.required =>
+				format!("{}::get(elts)?.ok_or_else(|| FhirError::missing(\"Missing required element\").at(\"{}[x]\"))?", e, f.name),
+			FieldType::Choice(ref e, _) => format!("{}::get(elts)?", e),
+			_ if f.many => format!("get_vec(elts, \"{}\")?", f.name),
+			_ if f.required => format!("get(elts, \"{}\")?", f.name),
+			_ => format!("get_opt(elts, \"{}\")?", f.name)
+		};
+		out.push_str(&format!("\t\t\t{}: {},\n", field_name(&f.name), expr));
+	}
+	out.push_str("\t\t})\n\t}\n}\n");
+	if s.resource {
+		write_resource(out, s);
+	} else {
+		write_element(out, s);
+	}
+}
+
+// An element's id and extensions are held on its Value
+fn write_element(out: &mut String, s: &Struct) {
+	let attrs: Vec<String> = ["id", "extension", "modifierExtension"].iter()
+		.filter(|a| has_attr(s, a))
+		.map(|a| field_name(a))
+		.collect();
+	out.push_str(&format!("\nimpl ToValue for {} {{\n\tfn to_value(&self) -> Value {{\n\t\tlet mut v = Value::from(self.to_elts());\n", s.name));
+	for rust in attrs.iter() {
+		out.push_str(&format!("\t\tv.{0} = self.{0}.clone();\n", rust));
+	}
+	out.push_str(&format!("\t\tv\n\t}}\n}}\n\nimpl FromValue for {} {{\n\tfn from_value(v: &Value) -> Result<Self, FhirError> {{\n", s.name));
+	if !has_attr(s, "modifierExtension") {
+		out.push_str("\t\tcheck_understood(&v.modifier_extension, &[])?;\n");
+	}
+	if !has_attr(s, "extension") {
+		out.push_str("\t\trefuse_extensions(&v.extension)?;\n");
+	}
+	out.push_str("\t\tlet mut x = Self::from_elts(elts_of(v)?)?;\n");
+	for rust in attrs.iter() {
+		out.push_str(&format!("\t\tx.{0} = v.{0}.clone();\n", rust));
+	}
+	out.push_str("\t\tOk(x)\n\t}\n}\n");
+}
+
+// A resource's extensions are held on the Resource; one without extension
+// elements leaves TypedResource to refuse them
+fn write_resource(out: &mut String, s: &Struct) {
+	out.push_str(&format!("\nimpl ToValue for {0} {{\n\tfn to_value(&self) -> Value {{\n\t\tself.to_resource().to_value()\n\t}}\n}}\n\n\
+		impl FromValue for {0} {{\n\tfn from_value(v: &Value) -> Result<Self, FhirError> {{\n\t\tSelf::from_resource(&Resource::from_value(v)?)\n\t}}\n}}\n", s.name));
+	out.push_str(&format!("\nimpl TypedResource for {0} {{\n\tfn resource_type() -> &'static str {{\n\t\t\"{0}\"\n\t}}\n", s.name));
+	if has_attr(s, "extension") && has_attr(s, "modifierExtension") {
+		out.push_str("\n\tfn extensions(&self) -> (Vec<Extension>, Vec<Extension>) {\n\t\t(self.extension.clone(), self.modifier_extension.clone())\n\t}\n");
+		out.push_str("\n\tfn set_extensions(&mut self, exts: &[Extension], mods: &[Extension]) -> Result<(), FhirError> {\n");
+		out.push_str("\t\tself.extension = exts.to_vec();\n\t\tself.modifier_extension = mods.to_vec();\n\t\tOk(())\n\t}\n");
+	}
+	out.push_str("}\n");
+}
+
+#[cfg(test)]
+const TEST_BUNDLE: &'static str = r##"{"resourceType": "Bundle", "entry": [
+	{"resource": {"resourceType": "StructureDefinition", "kind": "complex-type", "type": "HumanName", "derivation": "specialization",
+		"snapshot": {"element": [
+			{"path": "HumanName", "min": 0, "max": "*"},
+			{"path": "HumanName.id", "min": 0, "max": "1", "type": [{"code": "http://hl7.org/fhirpath/System.String"}]},
+			{"path": "HumanName.extension", "min": 0, "max": "*", "type": [{"code": "Extension"}]},
+			{"path": "HumanName.use", "min": 0, "max": "1", "type": [{"code": "code"}]},
+			{"path": "HumanName.given", "min": 0, "max": "*", "type": [{"code": "string"}]}]}}},
+	{"resource": {"resourceType": "StructureDefinition", "kind": "complex-type", "type": "Identifier", "derivation": "specialization",
+		"snapshot": {"element": [
+			{"path": "Identifier", "min": 0, "max": "*"},
+			{"path": "Identifier.id", "min": 0, "max": "1", "type": [{"code": "http://hl7.org/fhirpath/System.String"}]},
+			{"path": "Identifier.extension", "min": 0, "max": "*", "type": [{"code": "Extension"}]},
+			{"path": "Identifier.system", "min": 0, "max": "1", "type": [{"code": "uri"}]},
+			{"path": "Identifier.value", "min": 0, "max": "1", "type": [{"code": "string"}]},
+			{"path": "Identifier.assigner", "min": 0, "max": "1", "type": [{"code": "Reference"}]}]}}},
+	{"resource": {"resourceType": "StructureDefinition", "kind": "complex-type", "type": "Reference", "derivation": "specialization",
+		"snapshot": {"element": [
+			{"path": "Reference", "min": 0, "max": "*"},
+			{"path": "Reference.id", "min": 0, "max": "1", "type": [{"code": "http://hl7.org/fhirpath/System.String"}]},
+			{"path": "Reference.extension", "min": 0, "max": "*", "type": [{"code": "Extension"}]},
+			{"path": "Reference.reference", "min": 0, "max": "1", "type": [{"code": "string"}]},
+			{"path": "Reference.identifier", "min": 0, "max": "1", "type": [{"code": "Identifier"}]},
+			{"path": "Reference.display", "min": 0, "max": "1", "type": [{"code": "string"}]}]}}},
+	{"resource": {"resourceType": "StructureDefinition", "kind": "resource", "type": "Patient", "derivation": "specialization",
+		"snapshot": {"element": [
+			{"path": "Patient", "min": 0, "max": "*"},
+			{"path": "Patient.id", "min": 0, "max": "1", "type": [{"code": "http://hl7.org/fhirpath/System.String"}]},
+			{"path": "Patient.contained", "min": 0, "max": "*", "type": [{"code": "Resource"}]},
+			{"path": "Patient.extension", "min": 0, "max": "*", "type": [{"code": "Extension"}]},
+			{"path": "Patient.modifierExtension", "min": 0, "max": "*", "type": [{"code": "Extension"}]},
+			{"path": "Patient.identifier", "min": 0, "max": "*", "type": [{"code": "Identifier"}]},
+			{"path": "Patient.active", "min": 0, "max": "1", "type": [{"code": "boolean"}]},
+			{"path": "Patient.name", "min": 0, "max": "*", "type": [{"code": "HumanName"}]},
+			{"path": "Patient.birthDate", "min": 1, "max": "1", "type": [{"code": "date"}]},
+			{"path": "Patient.deceased[x]", "min": 0, "max": "1", "type": [{"code": "boolean"}, {"code": "dateTime"}]},
+			{"path": "Patient.contact", "min": 0, "max": "*", "type": [{"code": "BackboneElement"}]},
+			{"path": "Patient.contact.id", "min": 0, "max": "1", "type": [{"code": "http://hl7.org/fhirpath/System.String"}]},
+			{"path": "Patient.contact.modifierExtension", "min": 0, "max": "*", "type": [{"code": "Extension"}]},
+			{"path": "Patient.contact.gender", "min": 1, "max": "1", "type": [{"code": "code"}]},
+			{"path": "Patient.contact.contact", "min": 0, "max": "1", "contentReference": "#Patient.contact"},
+			{"path": "Patient.managingOrganization", "min": 0, "max": "1", "type": [{"code": "Reference"}]}]}}},
+	{"resource": {"resourceType": "StructureDefinition", "kind": "resource", "type": "Patient", "derivation": "constraint",
+		"snapshot": {"element": []}}},
+	{"resource": {"resourceType": "ValueSet"}}]}"##;
+
+// The output for TEST_BUNDLE, kept as a file so that it is compiled with the tests
+#[cfg(test)]
+#[path = "../fixtures/codegen/generated.rs"]
+mod generated;
+
+#[test]
+fn test_generate_structs() {
+	let src = generate(&[Json::from_str(TEST_BUNDLE).unwrap()]).unwrap();
+	assert!(src.contains("pub struct HumanName {\n\tpub id: Option<String>,\n\tpub extension: Vec<Extension>,\n\tpub use_: Option<String>,\n\tpub given: Vec<String>,\n}\n"));
+	assert!(src.contains(concat!("pub struct PatientContact {\n\tpub id: Option<String>,\n\tpub modifier_extension: Vec<Extension>,\n",
+		"\tpub gender: String,\n\tpub contact: Option<Box<PatientContact>>,\n}\n")));
+	assert!(src.contains(concat!("pub struct Patient {\n\tpub id: Option<String>,\n\tpub contained: Vec<Resource>,\n",
+		"\tpub extension: Vec<Extension>,\n\tpub modifier_extension: Vec<Extension>,\n\tpub identifier: Vec<Identifier>,\n",
+		"\tpub active: Option<bool>,\n\tpub name: Vec<HumanName>,\n\tpub birth_date: String,\n\tpub deceased: Option<PatientDeceased>,\n",
+		"\tpub contact: Vec<PatientContact>,\n\tpub managing_organization: Option<Reference>,\n}\n")));
+	assert!(src.contains("pub enum PatientDeceased {\n\tBoolean(bool),\n\tDateTime(String),\n}\n"));
+	assert!(src.contains("impl TypedResource for Patient {"));
+	assert!(!src.contains("impl TypedResource for HumanName {"));
+	assert_eq!(1, src.matches("pub struct Patient {").count());
+}
+
+#[test]
+fn test_generate_breaks_cycles() {
+	let src = generate(&[Json::from_str(TEST_BUNDLE).unwrap()]).unwrap();
+	assert!(src.contains("\tpub assigner: Option<Reference>,\n"));
+	assert!(src.contains("\tpub identifier: Option<Box<Identifier>>,\n"));
+}
+
+#[test]
+fn test_generate_conversions() {
+	let src = generate(&[Json::from_str(TEST_BUNDLE).unwrap()]).unwrap();
+	assert!(src.contains("\t\tput_vec(&mut elts, \"given\", &self.given);\n"));
+	assert!(src.contains("\t\t\tbirth_date: get(elts, \"birthDate\")?,\n"));
+	assert!(src.contains("\t\t\tPatientDeceased::DateTime(ref v) => put(elts, \"deceasedDateTime\", v),\n"));
+	assert!(src.contains("\t\tcheck_choice(elts, \"deceased\")?;\n"));
+	assert!(src.contains("\t\tif let Some(ref c) = self.deceased { c.put(&mut elts); }\n"));
+	assert!(src.contains("\t\tcheck_known(elts, &[\"use\", \"given\"])?;\n"));
+}
+
+#[test]
+fn test_generated_output_compiles() {
+	assert_eq!(include_str!("../fixtures/codegen/generated.rs"), generate(&[Json::from_str(TEST_BUNDLE).unwrap()]).unwrap());
+}
+
+#[test]
+fn test_generated_conversions_keep_everything() {
+	use rustc_serialize::json::ToJson;
+	use json::FromJson;
+	use typed::TypedResource;
+	use resource::Resource;
+	let j = Json::from_str(r##"{"resourceType": "Patient", "id": "p1",
+		"contained": [{"resourceType": "Organization", "id": "o1"}],
+		"extension": [{"url": "http://example.org/a", "valueString": "x"}],
+		"modifierExtension": [{"url": "http://example.org/m", "valueBoolean": true}],
+		"identifier": [{"system": "http://example.org/mrn", "value": "1", "assigner": {"reference": "#o1", "identifier": {"value": "o"}}}],
+		"name": [{"id": "n1", "extension": [{"url": "http://example.org/b", "valueCode": "y"}], "given": ["Jo"]}],
+		"birthDate": "1974-12-25", "deceasedBoolean": false,
+		"contact": [{"id": "c1", "modifierExtension": [{"url": "http://example.org/m", "valueBoolean": true}], "gender": "female",
+			"contact": {"gender": "male"}}],
+		"managingOrganization": {"reference": "#o1"}}"##).unwrap();
+	let p = generated::Patient::from_resource(&Resource::from_json(&j).unwrap()).unwrap();
+	assert_eq!(Some("n1".to_string()), p.name[0].id);
+	assert_eq!(1, p.contained.len());
+	assert_eq!(j, p.to_resource().to_json());
+	let refused = |s: &str| generated::Patient::from_resource(&Resource::from_json(&Json::from_str(s).unwrap()).unwrap())
+		.map(|_| ()).map_err(|e| e.path().to_string());
+	assert_eq!(Err("Patient.birthDate.extension".to_string()), refused(r#"{"resourceType": "Patient", "birthDate": "1974",
+		"_birthDate": {"extension": [{"url": "http://example.org/t", "valueTime": "14:35:45"}]}}"#));
+	assert_eq!(Err("Patient".to_string()), refused(r#"{"resourceType": "Patient", "birthDate": "1974", "gender": "male"}"#));
+	assert_eq!(Err("Patient.name[0].modifierExtension[0]".to_string()), refused(r#"{"resourceType": "Patient", "birthDate": "1974",
+		"name": [{"modifierExtension": [{"url": "http://example.org/m", "valueBoolean": true}]}]}"#));
+}
+
+#[test]
+fn test_generate_nested_resources() {
+	let sd = Json::from_str(r#"{"resourceType": "StructureDefinition", "kind": "resource", "type": "Bundle", "snapshot": {"element": [
+		{"path": "Bundle", "min": 0, "max": "*"},
+		{"path": "Bundle.entry", "min": 0, "max": "*", "type": [{"code": "BackboneElement"}]},
+		{"path": "Bundle.entry.fullUrl", "min": 0, "max": "1", "type": [{"code": "uri"}]},
+		{"path": "Bundle.entry.resource", "min": 0, "max": "1", "type": [{"code": "Resource"}]}]}}"#).unwrap();
+	let src = generate(&[sd]).unwrap();
+	assert!(src.contains("\tpub full_url: Option<String>,\n\tpub resource: Option<Resource>,\n}\n"));
+	let sd = Json::from_str(r#"{"resourceType": "StructureDefinition", "kind": "resource", "type": "Basic", "snapshot": {"element": [
+		{"path": "Basic", "min": 0, "max": "*"},
+		{"path": "Basic.note", "min": 0, "max": "1"}]}}"#).unwrap();
+	let e = generate(&[sd]).err().unwrap();
+	assert_eq!(("Basic.note".to_string(), "Element type can't be generated"), issue(&e));
+}
+
+#[test]
+fn test_generate_requires_snapshot() {
+	let sd = Json::from_str(r#"{"resourceType": "StructureDefinition", "kind": "resource", "type": "Basic"}"#).unwrap();
+	let e = generate(&[sd]).err().unwrap();
+	assert_eq!("Basic", e.path());
+}
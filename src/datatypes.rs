@@ -0,0 +1,1102 @@
+use std::cmp::Ordering;
+
+use url::Url;
+use chrono::{DateTime, FixedOffset};
+
+use element::{Element, Value};
+use extension::{Extension, check_understood};
+use primitive::{Id, Code, Canonical, Markdown, Dec, UnsignedInt, PositiveInt, Time, VarDate};
+use primitive::text::parse_uri;
+use typed::{Composite, ToValue, FromValue, elts_of, put_opt, put_vec, get_opt, get_vec, split_extras, graft_extras};
+use error::FhirError;
+#[cfg(test)]
+use error::issue;
+
+// Typed forms of the general-purpose and metadata datatypes, as of R5. Each
+// converts to and from the Element model the way generated code does,
+// keeping its own id and extensions, and any members it doesn't model in
+// `other`, so that nothing is lost on the way through. A primitive member is
+// a plain Rust value, so its id and extensions are kept in
+// `primitive_extras`. A modifier extension, which would change the meaning
+// of the data, is refused.
+
+macro_rules! slot_type {
+	(opt $t:ty) => { Option<$t> };
+	(vec $t:ty) => { Vec<$t> };
+}
+
+macro_rules! put_slot {
+	(opt, $elts:expr, $name:expr, $v:expr) => { put_opt($elts, $name, $v) };
+	(vec, $elts:expr, $name:expr, $v:expr) => { put_vec($elts, $name, $v) };
+}
+
+macro_rules! get_slot {
+	(opt, $elts:expr, $name:expr) => { get_opt($elts, $name) };
+	(vec, $elts:expr, $name:expr) => { get_vec($elts, $name) };
+}
+
+macro_rules! datatype {
+	($name:ident { $($field:ident: $mode:ident $t:ty = $json:expr),* }) => {
+		#[derive(Debug,Clone,PartialEq,Default)]
+		pub struct $name {
+			pub id: Option<String>,
+			pub extension: Vec<Extension>,
+			$(pub $field: slot_type!($mode $t),)*
+			pub primitive_extras: Vec<Element>,
+			pub other: Vec<Element>
+		}
+
+		impl Composite for $name {
+			fn to_elts(&self) -> Vec<Element> {
+				let mut elts = Vec::new();
+				$(put_slot!($mode, &mut elts, $json, &self.$field);)*
+				graft_extras(&mut elts, &self.primitive_extras);
+				elts.extend(self.other.iter().cloned());
+				elts
+			}
+
+			fn from_elts(elts: &[Element]) -> Result<Self, FhirError> {
+				let known: &[&str] = &[$($json),*];
+				let (values, extras) = split_extras(elts, known);
+				Ok($name {
+					id: None,
+					extension: Vec::new(),
+					$($field: get_slot!($mode, &values, $json)?,)*
+					primitive_extras: extras,
+					other: elts.iter().filter(|e| !known.contains(&e.name.as_str())).cloned().collect()
+				})
+			}
+		}
+
+		impl ToValue for $name {
+			fn to_value(&self) -> Value {
+				let mut v = Value::from(self.to_elts());
+				v.id = self.id.clone();
+				v.extension = self.extension.clone();
+				v
+			}
+		}
+
+		impl FromValue for $name {
+			fn from_value(v: &Value) -> Result<Self, FhirError> {
+				check_understood(&v.modifier_extension, &[])?;
+				let mut x = Self::from_elts(elts_of(v)?)?;
+				x.id = v.id.clone();
+				x.extension = v.extension.clone();
+				Ok(x)
+			}
+		}
+	}
+}
+
+// The invariants of a datatype that its field types alone don't enforce
+pub trait Validate {
+	fn validate(&self) -> Result<(), FhirError> {
+		Ok(())
+	}
+}
+
+// A choice element (author[x], value[x], ...) takes one of its types at most
+fn check_choice(choices: &[(&str, bool)]) -> Result<(), FhirError> {
+	match choices.iter().filter(|c| c.1).nth(1) {
+		Some(&(name, _)) => Err(FhirError::unexpected(name, "A choice element has only one type")),
+		None => Ok(())
+	}
+}
+
+fn check_non_negative(d: &Option<Dec>, name: &str) -> Result<(), FhirError> {
+	match *d {
+		Some(ref d) if *d < Dec::from_i64(0) => Err(FhirError::invalid(&d.to_string(), "Must not be negative").at(name)),
+		_ => Ok(())
+	}
+}
+
+datatype!(Coding {
+	system: opt Url = "system",
+	version: opt String = "version",
+	code: opt Code = "code",
+	display: opt String = "display",
+	user_selected: opt bool = "userSelected"
+});
+
+impl Coding {
+	pub fn new(system: Url, code: Code) -> Self {
+		Coding {system: Some(system), code: Some(code), ..Coding::default()}
+	}
+
+	pub fn display(mut self, display: &str) -> Self {
+		self.display = Some(display.to_string());
+		self
+	}
+
+	pub fn is(&self, system: &str, code: &str) -> bool {
+		self.system.as_ref().map(|s| s.as_str()) == Some(system) && self.code.as_ref().map(|c| c.as_str()) == Some(code)
+	}
+}
+
+impl Validate for Coding {}
+
+datatype!(CodeableConcept {
+	coding: vec Coding = "coding",
+	text: opt String = "text"
+});
+
+impl CodeableConcept {
+	pub fn from_coding(c: Coding) -> Self {
+		CodeableConcept {coding: vec![c], ..CodeableConcept::default()}
+	}
+
+	pub fn from_text(text: &str) -> Self {
+		CodeableConcept {text: Some(text.to_string()), ..CodeableConcept::default()}
+	}
+
+	pub fn has_code(&self, system: &str, code: &str) -> bool {
+		self.coding.iter().any(|c| c.is(system, code))
+	}
+}
+
+impl Validate for CodeableConcept {}
+
+datatype!(Quantity {
+	value: opt Dec = "value",
+	comparator: opt Code = "comparator",
+	unit: opt String = "unit",
+	system: opt Url = "system",
+	code: opt Code = "code"
+});
+
+const UCUM: &'static str = "http://unitsofmeasure.org";
+
+// Age, Count, Distance, Duration and SimpleQuantity constrain Quantity
+// without adding to it; each holds a Quantity and checks its own rules
+macro_rules! quantity_profile {
+	($name:ident) => {
+		#[derive(Debug,Clone,PartialEq,Default)]
+		pub struct $name(pub Quantity);
+
+		impl ToValue for $name {
+			fn to_value(&self) -> Value {
+				self.0.to_value()
+			}
+		}
+
+		impl FromValue for $name {
+			fn from_value(v: &Value) -> Result<Self, FhirError> {
+				Quantity::from_value(v).map($name)
+			}
+		}
+	}
+}
+
+quantity_profile!(Age);
+quantity_profile!(Count);
+quantity_profile!(Distance);
+quantity_profile!(Duration);
+quantity_profile!(SimpleQuantity);
+
+fn is_ucum(system: &Option<Url>) -> bool {
+	system.as_ref().map_or(false, |s| Url::parse(UCUM).ok().as_ref() == Some(s))
+}
+
+// A value needs a coded unit, and any system is UCUM
+fn check_ucum(q: &Quantity) -> Result<(), FhirError> {
+	if q.value.is_some() && q.code.is_none() {
+		return Err(FhirError::missing("A value needs a coded unit").at("code"));
+	}
+	match q.system {
+		Some(ref s) if !is_ucum(&q.system) => Err(FhirError::invalid(s.as_str(), "The unit must be UCUM").at("system")),
+		_ => Ok(())
+	}
+}
+
+// age-1: a positive UCUM quantity
+impl Validate for Age {
+	fn validate(&self) -> Result<(), FhirError> {
+		self.0.validate()?;
+		check_ucum(&self.0)?;
+		match self.0.value {
+			Some(ref v) if *v <= Dec::from_i64(0) => Err(FhirError::invalid(&v.to_string(), "An age must be positive").at("value")),
+			_ => Ok(())
+		}
+	}
+}
+
+// cnt-3: a whole number of the UCUM unit 1
+impl Validate for Count {
+	fn validate(&self) -> Result<(), FhirError> {
+		self.0.validate()?;
+		check_ucum(&self.0)?;
+		if let Some(ref c) = self.0.code {
+			if c.as_str() != "1" {
+				return Err(FhirError::invalid(c.as_str(), "A count's unit must be 1").at("code"));
+			}
+		}
+		match self.0.value {
+			Some(ref v) if v.to_string().contains('.') => Err(FhirError::invalid(&v.to_string(), "A count must be a whole number").at("value")),
+			_ => Ok(())
+		}
+	}
+}
+
+// dis-1
+impl Validate for Distance {
+	fn validate(&self) -> Result<(), FhirError> {
+		self.0.validate()?;
+		check_ucum(&self.0)
+	}
+}
+
+// drt-1: a coded unit is UCUM and goes with a value
+impl Validate for Duration {
+	fn validate(&self) -> Result<(), FhirError> {
+		self.0.validate()?;
+		if self.0.code.is_some() {
+			if !is_ucum(&self.0.system) {
+				return Err(FhirError::invalid("system", "A coded duration must be UCUM").at("system"));
+			}
+			if self.0.value.is_none() {
+				return Err(FhirError::missing("A coded duration needs a value").at("value"));
+			}
+		}
+		Ok(())
+	}
+}
+
+// sqty-1: no comparator
+impl Validate for SimpleQuantity {
+	fn validate(&self) -> Result<(), FhirError> {
+		self.0.validate()?;
+		match self.0.comparator {
+			Some(ref c) => Err(FhirError::unexpected(c.as_str(), "A simple quantity has no comparator").at("comparator")),
+			None => Ok(())
+		}
+	}
+}
+
+impl Quantity {
+	pub fn new(value: Dec, unit: &str) -> Self {
+		Quantity {value: Some(value), unit: Some(unit.to_string()), ..Quantity::default()}
+	}
+
+	// A quantity in a UCUM unit, which is both its code and display unit
+	pub fn ucum(value: Dec, code: &str) -> Result<Self, FhirError> {
+		Ok(Quantity {
+			system: Some(Url::parse(UCUM).map_err(|_| FhirError::invalid(UCUM, "Invalid URI"))?),
+			code: Some(Code::parse(code)?),
+			..Quantity::new(value, code)
+		})
+	}
+
+	// Units compare by system and code where coded, else by the unit text
+	fn same_units(&self, other: &Quantity) -> bool {
+		match (&self.code, &other.code) {
+			(&Some(_), &Some(_)) => self.system == other.system && self.code == other.code,
+			_ => self.unit == other.unit
+		}
+	}
+}
+
+impl Validate for Quantity {
+	fn validate(&self) -> Result<(), FhirError> {
+		if let Some(ref c) = self.comparator {
+			if !["<", "<=", ">=", ">", "ad"].contains(&c.as_str()) {
+				return Err(FhirError::invalid(c.as_str(), "Invalid comparator").at("comparator"));
+			}
+		}
+		if self.code.is_some() && self.system.is_none() {
+			return Err(FhirError::missing("A coded unit needs a system").at("system"));
+		}
+		Ok(())
+	}
+}
+
+datatype!(Money {
+	value: opt Dec = "value",
+	currency: opt Code = "currency"
+});
+
+impl Validate for Money {}
+
+datatype!(Range {
+	low: opt Quantity = "low",
+	high: opt Quantity = "high"
+});
+
+impl Validate for Range {
+	fn validate(&self) -> Result<(), FhirError> {
+		for (q, name) in [(&self.low, "low"), (&self.high, "high")].iter() {
+			if let Some(ref q) = **q {
+				q.validate().map_err(|e| e.at(name))?;
+				if q.comparator.is_some() {
+					return Err(FhirError::unexpected("comparator", "A range bound has no comparator").at(name));
+				}
+			}
+		}
+		if let (&Some(ref l), &Some(ref h)) = (&self.low, &self.high) {
+			if let (true, &Some(ref lv), &Some(ref hv)) = (l.same_units(h), &l.value, &h.value) {
+				if lv > hv {
+					return Err(FhirError::invalid(&format!("{} > {}", lv, hv), "Range low is above high"));
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+datatype!(Ratio {
+	numerator: opt Quantity = "numerator",
+	denominator: opt Quantity = "denominator"
+});
+
+impl Ratio {
+	pub fn new(numerator: Quantity, denominator: Quantity) -> Self {
+		Ratio {numerator: Some(numerator), denominator: Some(denominator), ..Ratio::default()}
+	}
+}
+
+impl Validate for Ratio {
+	fn validate(&self) -> Result<(), FhirError> {
+		if self.numerator.is_some() != self.denominator.is_some() && self.extension.is_empty() {
+			return Err(FhirError::missing("A ratio needs both a numerator and a denominator"));
+		}
+		Ok(())
+	}
+}
+
+datatype!(RatioRange {
+	low_numerator: opt Quantity = "lowNumerator",
+	high_numerator: opt Quantity = "highNumerator",
+	denominator: opt Quantity = "denominator"
+});
+
+impl Validate for RatioRange {}
+
+datatype!(Period {
+	start: opt VarDate = "start",
+	end: opt VarDate = "end"
+});
+
+impl Period {
+	pub fn new(start: Option<VarDate>, end: Option<VarDate>) -> Self {
+		Period {start: start, end: end, ..Period::default()}
+	}
+}
+
+// Dates of different precision that overlap, like 2015 and 2015-03, can't be ordered
+impl Validate for Period {
+	fn validate(&self) -> Result<(), FhirError> {
+		if let (&Some(ref s), &Some(ref e)) = (&self.start, &self.end) {
+			if s.partial_cmp(e) == Some(Ordering::Greater) {
+				return Err(FhirError::invalid(&format!("{} > {}", s, e), "Period starts after it ends"));
+			}
+		}
+		Ok(())
+	}
+}
+
+datatype!(Identifier {
+	use_: opt Code = "use",
+	type_: opt CodeableConcept = "type",
+	system: opt Url = "system",
+	value: opt String = "value",
+	period: opt Period = "period",
+	assigner: opt Box<Reference> = "assigner"
+});
+
+impl Identifier {
+	pub fn new(system: Url, value: &str) -> Self {
+		Identifier {system: Some(system), value: Some(value.to_string()), ..Identifier::default()}
+	}
+
+	// The same system and value; an identifier without a value matches nothing
+	pub fn matches(&self, other: &Identifier) -> bool {
+		self.value.is_some() && self.system == other.system && self.value == other.value
+	}
+}
+
+impl Validate for Identifier {}
+
+datatype!(Reference {
+	reference: opt String = "reference",
+	type_: opt String = "type",
+	identifier: opt Identifier = "identifier",
+	display: opt String = "display"
+});
+
+// What a reference points to
+#[derive(Debug,Clone,PartialEq)]
+pub enum ReferenceTarget {
+	// #id, or "#" for the resource holding the reference
+	Contained(String),
+	// Type/id, or Type/id/_history/version, against the server base
+	Relative {resource_type: String, id: String, version: Option<String>},
+	// A url; only RESTful ones name a type and id
+	Absolute {url: Url, resource_type: Option<String>, id: Option<String>, version: Option<String>},
+	// No reference, only a business identifier and perhaps a type
+	Logical {resource_type: Option<String>, identifier: Identifier}
+}
+
+impl ReferenceTarget {
+	pub fn resource_type(&self) -> Option<&str> {
+		match *self {
+			ReferenceTarget::Contained(_) => None,
+			ReferenceTarget::Relative {ref resource_type, ..} => Some(resource_type),
+			ReferenceTarget::Absolute {ref resource_type, ..} | ReferenceTarget::Logical {ref resource_type, ..} =>
+				resource_type.as_ref().map(|t| t.as_str())
+		}
+	}
+
+	pub fn id(&self) -> Option<&str> {
+		match *self {
+			ReferenceTarget::Contained(ref id) | ReferenceTarget::Relative {ref id, ..} => Some(id),
+			ReferenceTarget::Absolute {ref id, ..} => id.as_ref().map(|i| i.as_str()),
+			ReferenceTarget::Logical {..} => None
+		}
+	}
+
+	// The server base of a RESTful url: http://example.org/fhir/ for
+	// http://example.org/fhir/Patient/1/_history/2
+	pub fn base(&self) -> Option<&str> {
+		match *self {
+			ReferenceTarget::Absolute {ref url, resource_type: Some(ref t), id: Some(ref id), ref version} => {
+				let s = url.as_str();
+				let tail = t.len() + 1 + id.len() + version.as_ref().map_or(0, |v| "/_history/".len() + v.len());
+				Some(&s[..s.len() - tail])
+			},
+			_ => None
+		}
+	}
+
+	// The reference as written, which a logical reference doesn't have
+	pub fn reference(&self) -> Option<String> {
+		match *self {
+			ReferenceTarget::Contained(ref id) => Some(format!("#{}", id)),
+			ReferenceTarget::Relative {ref resource_type, ref id, version: None} => Some(format!("{}/{}", resource_type, id)),
+			ReferenceTarget::Relative {ref resource_type, ref id, version: Some(ref v)} => Some(format!("{}/{}/_history/{}", resource_type, id, v)),
+			ReferenceTarget::Absolute {ref url, ..} => Some(url.as_str().to_string()),
+			ReferenceTarget::Logical {..} => None
+		}
+	}
+}
+
+// Type, id and version at the end of a RESTful path: Patient/1/_history/2
+fn rest_tail(s: &str) -> Option<(&str, &str, Option<&str>)> {
+	let (path, version) = match s.find("/_history/") {
+		Some(i) => (&s[..i], Some(&s[i + "/_history/".len()..])),
+		None => (s, None)
+	};
+	let mut parts = path.rsplitn(3, '/');
+	let (id, t) = (parts.next()?, parts.next()?);
+	let is_type = t.starts_with(char::is_uppercase) && t.chars().all(|c| c.is_ascii_alphanumeric());
+	match (is_type, Id::parse(id).is_ok(), version.map_or(true, |v| Id::parse(v).is_ok())) {
+		(true, true, true) => Some((t, id, version)),
+		_ => None
+	}
+}
+
+impl Reference {
+	pub fn new(reference: &str) -> Self {
+		Reference {reference: Some(reference.to_string()), ..Reference::default()}
+	}
+
+	pub fn logical(resource_type: &str, identifier: Identifier) -> Self {
+		Reference {type_: Some(resource_type.to_string()), identifier: Some(identifier), ..Reference::default()}
+	}
+
+	// None for a reference that only has a display
+	pub fn target(&self) -> Result<Option<ReferenceTarget>, FhirError> {
+		let r = match (&self.reference, &self.identifier) {
+			(&Some(ref r), _) => r,
+			(&None, &Some(ref i)) => return Ok(Some(ReferenceTarget::Logical {resource_type: self.type_.clone(), identifier: i.clone()})),
+			(&None, &None) => return Ok(None)
+		};
+		let invalid = || FhirError::invalid(r, "Invalid reference").at("reference");
+		if r.starts_with('#') {
+			return match Id::parse(&r[1..]) {
+				Ok(_) => Ok(Some(ReferenceTarget::Contained(r[1..].to_string()))),
+				Err(_) if r.len() == 1 => Ok(Some(ReferenceTarget::Contained(String::new()))),
+				Err(_) => Err(invalid())
+			};
+		}
+		let tail = rest_tail(r).map(|(t, id, v)| (t.to_string(), id.to_string(), v.map(String::from)));
+		if r.starts_with("urn:") || r.contains("://") {
+			let url = parse_uri(r).map_err(|_| invalid())?;
+			let (t, id, v) = match tail {
+				Some((t, id, v)) => (Some(t), Some(id), v),
+				None => (None, None, None)
+			};
+			return Ok(Some(ReferenceTarget::Absolute {url: url, resource_type: t, id: id, version: v}));
+		}
+		match tail {
+			Some((t, id, v)) if r.matches('/').count() == if v.is_some() { 3 } else { 1 } =>
+				Ok(Some(ReferenceTarget::Relative {resource_type: t, id: id, version: v})),
+			_ => Err(invalid())
+		}
+	}
+}
+
+// A type given alongside the reference must be the type it names
+impl Validate for Reference {
+	fn validate(&self) -> Result<(), FhirError> {
+		let target = self.target()?;
+		let named = target.as_ref().and_then(|t| t.resource_type());
+		if let (Some(t), Some(n)) = (self.type_.as_ref(), named) {
+			if t != n && !t.ends_with(&format!("/{}", n)) {
+				return Err(FhirError::invalid(t, "Reference type doesn't match its target").at("type"));
+			}
+		}
+		Ok(())
+	}
+}
+
+datatype!(HumanName {
+	use_: opt Code = "use",
+	text: opt String = "text",
+	family: opt String = "family",
+	given: vec String = "given",
+	prefix: vec String = "prefix",
+	suffix: vec String = "suffix",
+	period: opt Period = "period"
+});
+
+impl HumanName {
+	pub fn new(family: &str, given: &[&str]) -> Self {
+		HumanName {family: Some(family.to_string()), given: given.iter().map(|g| g.to_string()).collect(), ..HumanName::default()}
+	}
+
+	// The text if given, else the parts in the usual order
+	pub fn display(&self) -> String {
+		if let Some(ref t) = self.text {
+			return t.clone();
+		}
+		self.prefix.iter().chain(self.given.iter()).chain(self.family.iter()).chain(self.suffix.iter())
+			.map(|s| s.as_str()).collect::<Vec<&str>>().join(" ")
+	}
+}
+
+impl Validate for HumanName {}
+
+datatype!(Address {
+	use_: opt Code = "use",
+	type_: opt Code = "type",
+	text: opt String = "text",
+	line: vec String = "line",
+	city: opt String = "city",
+	district: opt String = "district",
+	state: opt String = "state",
+	postal_code: opt String = "postalCode",
+	country: opt String = "country",
+	period: opt Period = "period"
+});
+
+impl Validate for Address {}
+
+datatype!(ContactPoint {
+	system: opt Code = "system",
+	value: opt String = "value",
+	use_: opt Code = "use",
+	rank: opt PositiveInt = "rank",
+	period: opt Period = "period"
+});
+
+impl ContactPoint {
+	pub fn new(system: Code, value: &str) -> Self {
+		ContactPoint {system: Some(system), value: Some(value.to_string()), ..ContactPoint::default()}
+	}
+}
+
+impl Validate for ContactPoint {
+	fn validate(&self) -> Result<(), FhirError> {
+		if self.value.is_some() && self.system.is_none() {
+			return Err(FhirError::missing("A contact point with a value needs a system").at("system"));
+		}
+		Ok(())
+	}
+}
+
+datatype!(Attachment {
+	content_type: opt Code = "contentType",
+	language: opt Code = "language",
+	data: opt Vec<u8> = "data",
+	url: opt Url = "url",
+	// integer64 since R5
+	size: opt i64 = "size",
+	hash: opt Vec<u8> = "hash",
+	title: opt String = "title",
+	creation: opt VarDate = "creation"
+});
+
+impl Validate for Attachment {
+	fn validate(&self) -> Result<(), FhirError> {
+		if self.data.is_some() && self.content_type.is_none() {
+			return Err(FhirError::missing("Attached data needs a content type").at("contentType"));
+		}
+		Ok(())
+	}
+}
+
+datatype!(Annotation {
+	author_reference: opt Reference = "authorReference",
+	author_string: opt String = "authorString",
+	time: opt VarDate = "time",
+	text: opt Markdown = "text"
+});
+
+impl Validate for Annotation {
+	fn validate(&self) -> Result<(), FhirError> {
+		check_choice(&[("authorReference", self.author_reference.is_some()), ("authorString", self.author_string.is_some())])?;
+		match self.text {
+			Some(_) => Ok(()),
+			None => Err(FhirError::missing("Missing required element").at("text"))
+		}
+	}
+}
+
+datatype!(CodeableReference {
+	concept: opt CodeableConcept = "concept",
+	reference: opt Reference = "reference"
+});
+
+impl Validate for CodeableReference {}
+
+datatype!(TimingRepeat {
+	bounds_duration: opt Duration = "boundsDuration",
+	bounds_range: opt Range = "boundsRange",
+	bounds_period: opt Period = "boundsPeriod",
+	count: opt PositiveInt = "count",
+	count_max: opt PositiveInt = "countMax",
+	duration: opt Dec = "duration",
+	duration_max: opt Dec = "durationMax",
+	duration_unit: opt Code = "durationUnit",
+	frequency: opt PositiveInt = "frequency",
+	frequency_max: opt PositiveInt = "frequencyMax",
+	period: opt Dec = "period",
+	period_max: opt Dec = "periodMax",
+	period_unit: opt Code = "periodUnit",
+	day_of_week: vec Code = "dayOfWeek",
+	time_of_day: vec Time = "timeOfDay",
+	when: vec Code = "when",
+	offset: opt UnsignedInt = "offset"
+});
+
+// The tim-* invariants; an offset from a meal (C, CM, CD, CV) is meaningless
+impl Validate for TimingRepeat {
+	fn validate(&self) -> Result<(), FhirError> {
+		check_choice(&[("boundsDuration", self.bounds_duration.is_some()), ("boundsRange", self.bounds_range.is_some()),
+			("boundsPeriod", self.bounds_period.is_some())])?;
+		if self.duration.is_some() && self.duration_unit.is_none() {
+			return Err(FhirError::missing("A duration needs a unit").at("durationUnit"));
+		}
+		if self.period.is_some() && self.period_unit.is_none() {
+			return Err(FhirError::missing("A period needs a unit").at("periodUnit"));
+		}
+		check_non_negative(&self.duration, "duration")?;
+		check_non_negative(&self.period, "period")?;
+		for &(max, set, base) in [("durationMax", self.duration_max.is_some(), self.duration.is_some()),
+			("periodMax", self.period_max.is_some(), self.period.is_some()),
+			("countMax", self.count_max.is_some(), self.count.is_some())].iter() {
+			if set && !base {
+				return Err(FhirError::unexpected(max, "A maximum needs the value it bounds").at(max));
+			}
+		}
+		let meal = self.when.iter().any(|w| ["C", "CM", "CD", "CV"].contains(&w.as_str()));
+		if self.offset.is_some() && (self.when.is_empty() || meal) {
+			return Err(FhirError::unexpected("offset", "An offset needs a when that isn't a meal").at("offset"));
+		}
+		if !self.time_of_day.is_empty() && !self.when.is_empty() {
+			return Err(FhirError::unexpected("timeOfDay", "A time of day can't be given with a when").at("timeOfDay"));
+		}
+		if let Some(ref d) = self.bounds_duration {
+			d.validate().map_err(|e| e.at("boundsDuration"))?;
+		}
+		if let Some(ref r) = self.bounds_range {
+			r.validate().map_err(|e| e.at("boundsRange"))?;
+		}
+		if let Some(ref p) = self.bounds_period {
+			p.validate().map_err(|e| e.at("boundsPeriod"))?;
+		}
+		Ok(())
+	}
+}
+
+datatype!(Timing {
+	event: vec VarDate = "event",
+	repeat: opt TimingRepeat = "repeat",
+	code: opt CodeableConcept = "code"
+});
+
+impl Validate for Timing {
+	fn validate(&self) -> Result<(), FhirError> {
+		match self.repeat {
+			Some(ref r) => r.validate().map_err(|e| e.at("repeat")),
+			None => Ok(())
+		}
+	}
+}
+
+datatype!(SampledData {
+	origin: opt SimpleQuantity = "origin",
+	interval: opt Dec = "interval",
+	interval_unit: opt Code = "intervalUnit",
+	factor: opt Dec = "factor",
+	lower_limit: opt Dec = "lowerLimit",
+	upper_limit: opt Dec = "upperLimit",
+	dimensions: opt PositiveInt = "dimensions",
+	code_map: opt Canonical = "codeMap",
+	offsets: opt String = "offsets",
+	data: opt String = "data"
+});
+
+// The points are spaced by an interval or placed by offsets, not both
+impl Validate for SampledData {
+	fn validate(&self) -> Result<(), FhirError> {
+		for &(set, name) in [(self.origin.is_some(), "origin"), (self.interval_unit.is_some(), "intervalUnit"),
+			(self.dimensions.is_some(), "dimensions")].iter() {
+			if !set {
+				return Err(FhirError::missing("Missing required element").at(name));
+			}
+		}
+		match (&self.interval, &self.offsets) {
+			(&Some(_), &Some(_)) => return Err(FhirError::unexpected("offsets", "Sampled data has an interval or offsets, not both").at("offsets")),
+			(&None, &None) => return Err(FhirError::missing("Sampled data needs an interval or offsets").at("interval")),
+			_ => ()
+		}
+		Ok(())
+	}
+}
+
+datatype!(Signature {
+	type_: vec Coding = "type",
+	when: opt DateTime<FixedOffset> = "when",
+	who: opt Reference = "who",
+	on_behalf_of: opt Reference = "onBehalfOf",
+	target_format: opt Code = "targetFormat",
+	sig_format: opt Code = "sigFormat",
+	data: opt Vec<u8> = "data"
+});
+
+impl Validate for Signature {}
+
+datatype!(DosageDoseAndRate {
+	type_: opt CodeableConcept = "type",
+	dose_range: opt Range = "doseRange",
+	dose_quantity: opt SimpleQuantity = "doseQuantity",
+	rate_ratio: opt Ratio = "rateRatio",
+	rate_range: opt Range = "rateRange",
+	rate_quantity: opt SimpleQuantity = "rateQuantity"
+});
+
+impl Validate for DosageDoseAndRate {
+	fn validate(&self) -> Result<(), FhirError> {
+		check_choice(&[("doseRange", self.dose_range.is_some()), ("doseQuantity", self.dose_quantity.is_some())])?;
+		check_choice(&[("rateRatio", self.rate_ratio.is_some()), ("rateRange", self.rate_range.is_some()),
+			("rateQuantity", self.rate_quantity.is_some())])?;
+		for &(q, name) in [(&self.dose_quantity, "doseQuantity"), (&self.rate_quantity, "rateQuantity")].iter() {
+			if let Some(ref q) = *q {
+				q.validate().map_err(|e| e.at(name))?;
+			}
+		}
+		Ok(())
+	}
+}
+
+datatype!(Dosage {
+	sequence: opt i32 = "sequence",
+	text: opt String = "text",
+	additional_instruction: vec CodeableConcept = "additionalInstruction",
+	patient_instruction: opt String = "patientInstruction",
+	timing: opt Timing = "timing",
+	as_needed: opt bool = "asNeeded",
+	as_needed_for: vec CodeableConcept = "asNeededFor",
+	site: opt CodeableConcept = "site",
+	route: opt CodeableConcept = "route",
+	method: opt CodeableConcept = "method",
+	dose_and_rate: vec DosageDoseAndRate = "doseAndRate",
+	max_dose_per_period: vec Ratio = "maxDosePerPeriod",
+	max_dose_per_administration: opt SimpleQuantity = "maxDosePerAdministration",
+	max_dose_per_lifetime: opt SimpleQuantity = "maxDosePerLifetime"
+});
+
+// dos-1: what a dose is needed for only goes with asNeeded unset or true
+impl Validate for Dosage {
+	fn validate(&self) -> Result<(), FhirError> {
+		if !self.as_needed_for.is_empty() && self.as_needed == Some(false) {
+			return Err(FhirError::unexpected("asNeededFor", "asNeededFor needs asNeeded unset or true").at("asNeededFor"));
+		}
+		if let Some(ref t) = self.timing {
+			t.validate().map_err(|e| e.at("timing"))?;
+		}
+		for (i, d) in self.dose_and_rate.iter().enumerate() {
+			d.validate().map_err(|e| e.at(&format!("[{}]", i)).at("doseAndRate"))?;
+		}
+		Ok(())
+	}
+}
+
+datatype!(ContactDetail {
+	name: opt String = "name",
+	telecom: vec ContactPoint = "telecom"
+});
+
+impl Validate for ContactDetail {
+	fn validate(&self) -> Result<(), FhirError> {
+		for (i, t) in self.telecom.iter().enumerate() {
+			t.validate().map_err(|e| e.at(&format!("[{}]", i)).at("telecom"))?;
+		}
+		Ok(())
+	}
+}
+
+datatype!(Expression {
+	description: opt String = "description",
+	name: opt Code = "name",
+	language: opt Code = "language",
+	expression: opt String = "expression",
+	reference: opt Url = "reference"
+});
+
+// exp-1: an expression is given inline or by reference
+impl Validate for Expression {
+	fn validate(&self) -> Result<(), FhirError> {
+		if self.expression.is_none() && self.reference.is_none() {
+			return Err(FhirError::missing("An expression needs its text or a reference to it").at("expression"));
+		}
+		Ok(())
+	}
+}
+
+datatype!(UsageContext {
+	code: opt Coding = "code",
+	value_codeable_concept: opt CodeableConcept = "valueCodeableConcept",
+	value_quantity: opt Quantity = "valueQuantity",
+	value_range: opt Range = "valueRange",
+	value_reference: opt Reference = "valueReference"
+});
+
+impl Validate for UsageContext {
+	fn validate(&self) -> Result<(), FhirError> {
+		if self.code.is_none() {
+			return Err(FhirError::missing("Missing required element").at("code"));
+		}
+		let values = [("valueCodeableConcept", self.value_codeable_concept.is_some()), ("valueQuantity", self.value_quantity.is_some()),
+			("valueRange", self.value_range.is_some()), ("valueReference", self.value_reference.is_some())];
+		check_choice(&values)?;
+		if !values.iter().any(|v| v.1) {
+			return Err(FhirError::missing("Missing required element").at("value[x]"));
+		}
+		Ok(())
+	}
+}
+
+datatype!(RelatedArtifact {
+	type_: opt Code = "type",
+	classifier: vec CodeableConcept = "classifier",
+	label: opt String = "label",
+	display: opt String = "display",
+	citation: opt Markdown = "citation",
+	document: opt Attachment = "document",
+	resource: opt Canonical = "resource",
+	resource_reference: opt Reference = "resourceReference",
+	publication_status: opt Code = "publicationStatus",
+	publication_date: opt VarDate = "publicationDate"
+});
+
+impl Validate for RelatedArtifact {
+	fn validate(&self) -> Result<(), FhirError> {
+		if self.type_.is_none() {
+			return Err(FhirError::missing("Missing required element").at("type"));
+		}
+		match self.document {
+			Some(ref d) => d.validate().map_err(|e| e.at("document")),
+			None => Ok(())
+		}
+	}
+}
+
+datatype!(Meta {
+	version_id: opt Id = "versionId",
+	last_updated: opt DateTime<FixedOffset> = "lastUpdated",
+	source: opt Url = "source",
+	profile: vec Canonical = "profile",
+	security: vec Coding = "security",
+	tag: vec Coding = "tag"
+});
+
+impl Meta {
+	pub fn new() -> Self {
+		Meta::default()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		*self == Meta::default()
+	}
+}
+
+impl Validate for Meta {}
+
+
+#[test]
+fn test_meta_round_trip() {
+	use rustc_serialize::json::{Json, ToJson};
+	use json::FromJson;
+	let j = Json::from_str(r#"{"versionId": "2", "lastUpdated": "2015-05-02T05:34:00-07:00",
+		"profile": ["http://example.org/StructureDefinition/p"],
+		"tag": [{"system": "http://example.org/tags", "code": "urgent", "display": "Urgent"}]}"#).unwrap();
+	let m = Meta::from_value(&Value::from_json(&j).unwrap()).unwrap();
+	assert_eq!("2", m.version_id.as_ref().unwrap().as_str());
+	assert_eq!(Some("urgent"), m.tag[0].code.as_ref().map(|c| c.as_str()));
+	assert_eq!(j, m.to_value().to_json());
+	let bad = Json::from_str(r#"{"versionId": "two words"}"#).unwrap();
+	assert!(Meta::from_value(&Value::from_json(&bad).unwrap()).is_err());
+}
+
+#[test]
+fn test_datatype_lossless() {
+	use rustc_serialize::json::{Json, ToJson};
+	use json::FromJson;
+	use extension::Extensible;
+	let j = Json::from_str(r#"{"id": "i1", "extension": [{"url": "http://example.org/checked", "valueBoolean": true}],
+		"use": "official", "type": {"coding": [{"system": "http://example.org/ids", "code": "MR"}], "text": "Record"},
+		"system": "http://example.org/mrn", "value": "12345", "assigner": {"display": "Example Hospital"}, "note": "kept"}"#).unwrap();
+	let id = Identifier::from_value(&Value::from_json(&j).unwrap()).unwrap();
+	assert_eq!(Some("official"), id.use_.as_ref().map(|c| c.as_str()));
+	assert!(id.type_.as_ref().unwrap().has_code("http://example.org/ids", "MR"));
+	assert_eq!(Some("Example Hospital"), id.assigner.as_ref().and_then(|a| a.display.as_ref()).map(|d| d.as_str()));
+	assert_eq!("note", id.other[0].name);
+	assert_eq!(Some(true), id.to_value().extension_value::<bool>("http://example.org/checked").unwrap());
+	assert_eq!(j, id.to_value().to_json());
+}
+
+#[test]
+fn test_datatype_elements() {
+	use element::NamedFrom;
+	let name = HumanName::new("Smith", &["Jo", "Al"]);
+	let e = Element::with("name", name.to_elts());
+	assert_eq!(name, HumanName::from_value(&e.value).unwrap());
+	assert_eq!("Jo Al Smith", name.display());
+	let q = Quantity::ucum(Dec::from_str("5.0").unwrap(), "mg").unwrap();
+	let back = Quantity::from_value(&q.to_value()).unwrap();
+	assert_eq!(Some("5.0".to_string()), back.value.map(|v| v.to_string()));
+	assert_eq!(Some("mg"), back.code.as_ref().map(|c| c.as_str()));
+}
+
+#[test]
+fn test_datatype_validation() {
+	let d = |s: &str| Some(VarDate::parse(s).unwrap());
+	assert!(Period::new(d("2015-01-01"), d("2015-02")).validate().is_ok());
+	assert!(Period::new(d("2015-03-01"), d("2015-02")).validate().is_err());
+	assert!(Period::new(d("2015"), d("2015-02")).validate().is_ok());
+	let mg = |v: &str| Quantity::ucum(Dec::from_str(v).unwrap(), "mg").unwrap();
+	assert!(Range {low: Some(mg("5")), high: Some(mg("2")), ..Range::default()}.validate().is_err());
+	assert!(Range {low: Some(mg("2")), high: Some(mg("5")), ..Range::default()}.validate().is_ok());
+	assert!(Ratio {numerator: Some(mg("2")), ..Ratio::default()}.validate().is_err());
+	let mut q = mg("1");
+	q.comparator = Some(Code::parse("~").unwrap());
+	assert_eq!("comparator", q.validate().err().unwrap().path());
+	assert!(Attachment {data: Some(vec![1, 2]), ..Attachment::default()}.validate().is_err());
+	assert!(ContactPoint {value: Some("555".to_string()), ..ContactPoint::default()}.validate().is_err());
+	assert!(Annotation::default().validate().is_err());
+}
+
+#[test]
+fn test_reference_target() {
+	let target = |r: &str| Reference::new(r).target();
+	assert_eq!(Some(ReferenceTarget::Contained("p1".to_string())), target("#p1").unwrap());
+	assert_eq!(Some(ReferenceTarget::Contained(String::new())), target("#").unwrap());
+	assert_eq!(Some(ReferenceTarget::Relative {resource_type: "Patient".to_string(), id: "1".to_string(), version: Some("2".to_string())}),
+		target("Patient/1/_history/2").unwrap());
+	match target("http://example.org/fhir/Patient/1").unwrap() {
+		Some(ReferenceTarget::Absolute {resource_type: Some(ref t), id: Some(ref id), version: None, ..}) => assert_eq!(("Patient", "1"), (t.as_str(), id.as_str())),
+		t => panic!("{:?}", t)
+	}
+	match target("urn:uuid:c757873d-ec9a-4326-a141-556f43239520").unwrap() {
+		Some(ReferenceTarget::Absolute {resource_type: None, ..}) => (),
+		t => panic!("{:?}", t)
+	}
+	assert_eq!(Some("Patient/1/_history/2".to_string()), target("Patient/1/_history/2").unwrap().unwrap().reference());
+	assert_eq!(Some("http://example.org/fhir/"), target("http://example.org/fhir/Patient/1/_history/2").unwrap().unwrap().base());
+	assert_eq!(None, target("Patient/1").unwrap().unwrap().base());
+	for bad in ["Patient", "patient/1", "Patient/1 2", "a/Patient/1", "#two words"].iter() {
+		assert_eq!("reference", target(bad).err().unwrap().path(), "{}", bad);
+	}
+	let mrn = Identifier::new(Url::parse("http://example.org/mrn").unwrap(), "12345");
+	let logical = Reference::logical("Patient", mrn.clone());
+	assert_eq!(Some("Patient"), logical.target().unwrap().unwrap().resource_type());
+	assert_eq!(None, Reference {display: Some("Jo".to_string()), ..Reference::default()}.target().unwrap());
+	assert!(Reference {type_: Some("Group".to_string()), ..Reference::new("Patient/1")}.validate().is_err());
+	assert!(Reference {type_: Some("http://hl7.org/fhir/StructureDefinition/Patient".to_string()), ..Reference::new("Patient/1")}.validate().is_ok());
+}
+
+#[test]
+fn test_primitive_member_extensions_kept() {
+	use rustc_serialize::json::{Json, ToJson};
+	use json::FromJson;
+	let j = Json::from_str(r#"{"family": "Smith", "_family": {"id": "f1"}, "given": ["Jo", "Al"],
+		"_given": [null, {"extension": [{"url": "http://example.org/nickname", "valueBoolean": true}]}],
+		"_text": {"extension": [{"url": "http://example.org/absent", "valueCode": "masked"}]}}"#).unwrap();
+	let n = HumanName::from_value(&Value::from_json(&j).unwrap()).unwrap();
+	assert_eq!(vec!["Jo", "Al"], n.given);
+	assert_eq!(None, n.text);
+	assert_eq!(j, n.to_value().to_json());
+	// A list item with extensions but no value can't keep its place
+	let j = Json::from_str(r#"{"given": ["Jo", null], "_given": [null, {"id": "g2"}]}"#).unwrap();
+	assert_eq!("given[1]", HumanName::from_value(&Value::from_json(&j).unwrap()).err().unwrap().path());
+}
+
+#[test]
+fn test_quantity_profiles() {
+	let q = |v: &str, code: &str| Quantity::ucum(Dec::from_str(v).unwrap(), code).unwrap();
+	assert!(Age(q("40", "a")).validate().is_ok());
+	assert_eq!(("value".to_string(), "An age must be positive"), issue(&Age(q("0", "a")).validate().err().unwrap()));
+	assert!(Age(Quantity::new(Dec::from_i64(40), "years")).validate().is_err());
+	assert!(Count(q("3", "1")).validate().is_ok());
+	assert!(Count(q("3.5", "1")).validate().is_err());
+	assert_eq!("code", Count(q("3", "mg")).validate().err().unwrap().path());
+	assert!(Distance(q("5", "km")).validate().is_ok());
+	assert!(Duration(q("2", "h")).validate().is_ok());
+	assert!(Duration(Quantity {value: None, ..q("2", "h")}).validate().is_err());
+	let mut sq = SimpleQuantity(q("1", "mg"));
+	assert!(sq.validate().is_ok());
+	sq.0.comparator = Some(Code::parse("<").unwrap());
+	assert_eq!("comparator", sq.validate().err().unwrap().path());
+	assert_eq!(q("1", "mg").to_value(), SimpleQuantity::from_value(&q("1", "mg").to_value()).unwrap().to_value());
+}
+
+#[test]
+fn test_more_datatypes() {
+	use rustc_serialize::json::{Json, ToJson};
+	use json::FromJson;
+	let j = Json::from_str(r#"{"sequence": 1, "text": "1 tablet twice daily", "asNeededFor": [{"text": "pain"}],
+		"timing": {"event": ["2015-02-07"], "repeat": {"boundsPeriod": {"start": "2015-02-07"}, "frequency": 2, "period": 1, "periodUnit": "d"}},
+		"doseAndRate": [{"doseQuantity": {"value": 1, "unit": "tablet"}}]}"#).unwrap();
+	let d = Dosage::from_value(&Value::from_json(&j).unwrap()).unwrap();
+	assert_eq!(Some("d"), d.timing.as_ref().and_then(|t| t.repeat.as_ref()).and_then(|r| r.period_unit.as_ref()).map(|u| u.as_str()));
+	assert!(d.validate().is_ok());
+	assert_eq!(j.to_string(), d.to_value().to_json().to_string());
+	let mut bad = d.clone();
+	bad.as_needed = Some(false);
+	assert_eq!("asNeededFor", bad.validate().err().unwrap().path());
+	let mut bad = d.clone();
+	bad.timing.as_mut().unwrap().repeat.as_mut().unwrap().period_unit = None;
+	assert_eq!("timing.repeat.periodUnit", bad.validate().err().unwrap().path());
+	let mut bad = d.clone();
+	bad.dose_and_rate[0].dose_range = Some(Range::default());
+	assert_eq!("doseAndRate[0]", bad.validate().err().unwrap().path());
+	let j = Json::from_str(r#"{"origin": {"value": 0, "unit": "mV"}, "interval": 10, "intervalUnit": "ms", "dimensions": 1, "data": "1 2 3"}"#).unwrap();
+	let mut s = SampledData::from_value(&Value::from_json(&j).unwrap()).unwrap();
+	assert!(s.validate().is_ok());
+	assert_eq!(j, s.to_value().to_json());
+	s.offsets = Some("0 5 10".to_string());
+	assert_eq!("offsets", s.validate().err().unwrap().path());
+	let j = Json::from_str(r#"{"type": [{"system": "urn:iso-astm:E1762-95:2013", "code": "1.2.840.10065.1.12.1.1"}],
+		"when": "2015-02-07T13:28:17+02:00", "who": {"reference": "Practitioner/1"}, "data": "AQID"}"#).unwrap();
+	let sig = Signature::from_value(&Value::from_json(&j).unwrap()).unwrap();
+	assert_eq!(Some(vec![1, 2, 3]), sig.data);
+	assert_eq!(j, sig.to_value().to_json());
+	assert!(Expression {language: Some(Code::parse("text/fhirpath").unwrap()), ..Expression::default()}.validate().is_err());
+	let focus = Coding::new(Url::parse("http://terminology.hl7.org/CodeSystem/usage-context-type").unwrap(), Code::parse("focus").unwrap());
+	assert_eq!("value[x]", UsageContext {code: Some(focus.clone()), ..UsageContext::default()}.validate().err().unwrap().path());
+	assert!(UsageContext {code: Some(focus), value_codeable_concept: Some(CodeableConcept::from_text("flu")), ..UsageContext::default()}.validate().is_ok());
+	assert!(ContactDetail {telecom: vec![ContactPoint {value: Some("555".to_string()), ..ContactPoint::default()}], ..ContactDetail::default()}.validate().is_err());
+	assert!(RelatedArtifact::default().validate().is_err());
+}
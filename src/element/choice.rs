@@ -0,0 +1,136 @@
+use primitive::Primitive;
+use element::{Element, Value, ValueType};
+#[cfg(test)]
+use element::NamedFrom;
+use codegen::capitalize;
+use error::FhirError;
+
+// Choice elements (value[x]) are named by their base and the FHIR type of
+// the value, e.g. valueQuantity or deceasedBoolean. The caller names the
+// base; any name that is the base followed by a FHIR type is one of its variants.
+
+const PRIMITIVE_TYPES: &'static [&'static str] = &["Base64Binary", "Boolean", "Canonical", "Code", "Date", "DateTime",
+	"Decimal", "Id", "Instant", "Integer", "Integer64", "Markdown", "Oid", "PositiveInt", "String", "Time",
+	"UnsignedInt", "Uri", "Url", "Uuid"];
+
+const COMPLEX_TYPES: &'static [&'static str] = &["Address", "Age", "Annotation", "Attachment", "Availability",
+	"CodeableConcept", "CodeableReference", "Coding", "ContactDetail", "ContactPoint", "Contributor", "Count",
+	"DataRequirement", "Distance", "Dosage", "Duration", "Expression", "ExtendedContactDetail", "HumanName",
+	"Identifier", "Meta", "Money", "ParameterDefinition", "Period", "Quantity", "Range", "Ratio", "RatioRange",
+	"Reference", "RelatedArtifact", "SampledData", "Signature", "Timing", "TriggerDefinition", "UsageContext"];
+
+pub fn is_primitive_type(name: &str) -> bool {
+	PRIMITIVE_TYPES.contains(&name)
+}
+
+pub fn is_complex_type(name: &str) -> bool {
+	COMPLEX_TYPES.contains(&name)
+}
+
+fn is_type(name: &str) -> bool {
+	is_primitive_type(name) || COMPLEX_TYPES.contains(&name)
+}
+
+// Split a name into its base and type, taking the longest type that fits so
+// that valueDateTime is value + DateTime rather than valueDate + Time
+pub fn split_choice(name: &str) -> Option<(&str, &str)> {
+	(1..name.len())
+		.filter(|&i| name.is_char_boundary(i))
+		.map(|i| (&name[..i], &name[i..]))
+		.find(|&(_, t)| is_type(t))
+}
+
+impl Element {
+	// A variant of the choice `base`, named for the type of the primitive
+	pub fn choice<T: Into<Primitive>>(base: &str, v: T) -> Self {
+		let p = v.into();
+		Element {
+			name: format!("{}{}", base, p.type_suffix()),
+			value: Value {value: ValueType::Atom(Some(p)), id: None, extension: Vec::new(), modifier_extension: Vec::new()}
+		}
+	}
+
+	// A variant of the choice `base` holding a complex datatype, e.g. ("value", "Quantity", ...)
+	pub fn choice_of(base: &str, type_name: &str, elts: Vec<Element>) -> Self {
+		Element {
+			name: format!("{}{}", base, capitalize(type_name)),
+			value: Value::from(elts)
+		}
+	}
+
+	// The type of this element if it is a variant of the choice `base`
+	pub fn choice_type(&self, base: &str) -> Option<&str> {
+		if !self.name.starts_with(base) {
+			return None;
+		}
+		let t = &self.name[base.len()..];
+		if is_type(t) { Some(t) } else { None }
+	}
+}
+
+pub fn get_choice<'a>(elts: &'a [Element], base: &str) -> Option<&'a Element> {
+	elts.iter().find(|e| e.choice_type(base).is_some())
+}
+
+// Set the choice `base`, replacing whichever variant it held before
+pub fn set_choice(elts: &mut Vec<Element>, base: &str, e: Element) -> Result<(), FhirError> {
+	if e.choice_type(base).is_none() {
+		return Err(FhirError::unexpected(&e.name, "Not a variant of the choice").at(&format!("{}[x]", base)));
+	}
+	let at = elts.iter().position(|x| x.choice_type(base).is_some());
+	elts.retain(|x| x.choice_type(base).is_none());
+	match at {
+		Some(i) => elts.insert(i, e),
+		None => elts.push(e)
+	}
+	Ok(())
+}
+
+// A choice holds a single value, so two variants of the same base are an error
+pub fn check_choice(elts: &[Element], base: &str) -> Result<(), FhirError> {
+	let mut variants = elts.iter().filter(|e| e.choice_type(base).is_some());
+	match (variants.next(), variants.next()) {
+		(Some(a), Some(b)) => Err(FhirError::unexpected(&format!("{} and {}", a.name, b.name), "Two values for one choice").at(&format!("{}[x]", base))),
+		_ => Ok(())
+	}
+}
+
+
+#[test]
+fn test_choice_names() {
+	assert_eq!("deceasedBoolean", Element::choice("deceased", false).name);
+	let q = Element::choice_of("value", "Quantity", vec![Element::with("unit", "mg")]);
+	assert_eq!("valueQuantity", q.name);
+	assert_eq!(Some("Quantity"), q.choice_type("value"));
+	assert_eq!(None, q.choice_type("val"));
+	assert_eq!(Some(("value", "DateTime")), split_choice("valueDateTime"));
+	assert_eq!(None, split_choice("status"));
+}
+
+#[test]
+fn test_set_choice() {
+	let mut elts = vec![Element::with("status", "final"), Element::choice("effective", false), Element::with("issued", "x")];
+	set_choice(&mut elts, "effective", Element::choice_of("effective", "period", vec![])).unwrap();
+	assert_eq!(vec!["status", "effectivePeriod", "issued"], elts.iter().map(|e| e.name.as_ref()).collect::<Vec<&str>>());
+	assert!(set_choice(&mut elts, "effective", Element::with("issued", "y")).is_err());
+	assert_eq!("effectivePeriod", get_choice(&elts, "effective").unwrap().name);
+}
+
+#[test]
+fn test_check_choice() {
+	use rustc_serialize::json::Json;
+	use element::{elts_from_json, elts_from_xml};
+	use xml::reader;
+	// Only the bases named are taken as choices: birthDate is not a variant of birth
+	let j = Json::from_str(r#"{"birthDate": "2015-02-07", "deceasedBoolean": true, "deceasedDateTime": "2015-02-07"}"#).unwrap();
+	let elts = elts_from_json(j.as_object().unwrap()).unwrap();
+	assert!(check_choice(&elts, "value").is_ok());
+	assert_eq!(("deceased[x]".to_string(), "Two values for one choice"), ::error::issue(&check_choice(&elts, "deceased").unwrap_err()));
+	match elts[0].value.value {
+		ValueType::Atom(Some(Primitive::String(ref s))) => assert_eq!("2015-02-07", s),
+		ref v => panic!("{:?}", v)
+	}
+	let src = r#"<Patient><deceasedBoolean value="true"/><deceasedDateTime value="2015"/></Patient>"#;
+	let (_, elts) = elts_from_xml(src, &reader::parse(src).unwrap().children).unwrap();
+	assert!(check_choice(&elts, "deceased").is_err());
+}
@@ -1,15 +1,25 @@
 use std::convert::{From};
 use std::collections::btree_map::BTreeMap;
+use std::io::{self, Write};
 
 use rustc_serialize::json::{ToJson, Json};
 use url::{Url};
 use chrono::{DateTime,FixedOffset};
 
-use primitive::{Dec, Time};
+use primitive::{Primitive, Dec, Time, UnsignedInt, PositiveInt, Id, Code, Oid, Canonical, Uuid, Markdown};
+use json::{FromJson, JsonWriter, ElementOrder};
+use json::writer::sort_key;
+use xml::XmlNode;
+use extension::{Extension, write_extensions};
+use narrative::read_div;
+use error::FhirError;
 
 pub mod value;
 pub use element::value::{Value,ValueType};
+pub mod choice;
+pub use element::choice::{get_choice, set_choice, check_choice};
 
+#[derive(Debug,Clone,PartialEq)]
 pub struct Element {
 	pub name: String,
 	pub value: Value
@@ -22,29 +32,54 @@ impl Element {
 		self
 	}
 
+	pub fn add_modifier_ext(mut self, e: Extension) -> Self {
+		let v = self.value;
+		self.value = v.add_modifier_ext(e);
+		self
+	}
+
+	// An extension may hold any of the datatypes
 	pub fn valid_extension(&self) -> bool {
-		match self.name.as_ref() {
-			"Coding" => true,
-			"CodeableConcept" => true,
-			"Attachment" => true,
-			"Identifier" => true,
-			"Quantity" => true,
-			"Range" => true,
-			"Period" => true,
-			"Ratio" => true,
-			"HumanName" => true,
-			"Address" => true,
-			"ContactPoint" => true,
-			"Timing" => true,
-			"Signature" => true,
-			"Reference" => true,
-			_ => false
-		}
+		choice::is_complex_type(&self.name)
 	}
 
 	pub fn extension_name(&self) -> String {
 		format!("value{}",self.name)
 	}
+
+	// Add to a repeating element in place; a single value becomes a list of
+	// two, and the items of a list are added one by one
+	pub fn push(&mut self, v: Value) {
+		if let ValueType::List(items) = v.value {
+			for item in items {
+				self.push(item);
+			}
+			return;
+		}
+		if let ValueType::List(ref mut l) = self.value.value {
+			l.push(v);
+			return;
+		}
+		let first = ::std::mem::replace(&mut self.value, Value::from(Vec::<Element>::new()));
+		self.value = Value {value: ValueType::List(vec![first, v]), id: None, extension: Vec::new(), modifier_extension: Vec::new()};
+	}
+}
+
+// Add an element to a composite, folding it into an existing element of the
+// same name rather than repeating the name, which JSON can't represent
+pub fn append(elts: &mut Vec<Element>, e: Element) {
+	match elts.iter_mut().find(|x| x.name == e.name) {
+		Some(x) => x.push(e.value),
+		None => elts.push(e)
+	}
+}
+
+pub fn fold(elts: Vec<Element>) -> Vec<Element> {
+	let mut out = Vec::with_capacity(elts.len());
+	for e in elts {
+		append(&mut out, e);
+	}
+	out
 }
 
 trait InternalToJson {
@@ -70,6 +105,110 @@ impl InternalToJson for Element {
 	}
 }
 
+// The type of a resource held in an element, as a Bundle entry or a
+// contained resource is
+pub fn resource_type(elts: &[Element]) -> Option<&str> {
+	elts.iter().filter(|e| e.name == "resourceType")
+		.filter_map(|e| match e.value.value {
+			ValueType::Atom(Some(Primitive::String(ref t))) => Some(t.as_ref()),
+			_ => None
+		})
+		.next()
+}
+
+enum Member<'a> {
+	Elt(&'a Element),
+	Exts(&'static str, &'a [Extension])
+}
+
+// Write the members of a composite in output order. `exts` and `mods` are
+// extensions held on the composite itself rather than as an "extension" or
+// "modifierExtension" element; a contained resource is ordered by its own
+// resourceType.
+pub fn write_members<W: Write, O: ElementOrder>(elts: &[Element], exts: &[Extension], mods: &[Extension], path: &str, order: &O, w: &mut JsonWriter<W>) -> io::Result<()> {
+	let path = resource_type(elts).unwrap_or(path);
+	let mut members: Vec<(((usize, usize), String), Member)> = elts.iter()
+		.map(|e| (sort_key(order, path, &e.name), Member::Elt(e)))
+		.collect();
+	for &(name, list) in [("extension", exts), ("modifierExtension", mods)].iter() {
+		if !list.is_empty() {
+			members.push((sort_key(order, path, name), Member::Exts(name, list)));
+		}
+	}
+	members.sort_by_key(|m| (m.0).0);
+	for ((_, child), m) in members {
+		match m {
+			Member::Elt(e) => e.value.write_json(&e.name, &child, order, w)?,
+			Member::Exts(name, list) => {
+				w.key(name)?;
+				write_extensions(list, order, w)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+// Decode the members of a composite, merging "_name" shadows into their elements
+pub fn elts_from_json(o: &BTreeMap<String,Json>) -> Result<Vec<Element>, FhirError> {
+	let mut elts = Vec::new();
+	for (name, j) in o.iter() {
+		if name.starts_with('_') {
+			if !o.contains_key(&name[1..]) {
+				let mut value = match *j {
+					Json::Array(ref shadows) => Value::from(ValueType::List(shadows.iter().map(|_| Value::from(ValueType::Atom(None))).collect())),
+					_ => Value::from(ValueType::Atom(None))
+				};
+				value.merge_idext(j).map_err(|e| e.at(name))?;
+				value.check_valueless().map_err(|e| e.at(name))?;
+				elts.push(Element {name: name[1..].to_string(), value: value});
+			}
+			continue;
+		}
+		let mut value = Value::from_json(j).map_err(|e| e.at(name))?;
+		// Only the narrative's div is XHTML; a div anywhere else is an ordinary element
+		if let (true, &mut ValueType::Elt(ref mut text)) = (name == "text", &mut value.value) {
+			for e in text.iter_mut().filter(|e| e.name == "div") {
+				let div = match e.value.value {
+					ValueType::Atom(Some(Primitive::String(ref s))) => read_div(s).map_err(|e| e.at("div").at(name))?,
+					_ => continue
+				};
+				e.value.value = ValueType::Atom(Some(Primitive::Xhtml(div)));
+			}
+		}
+		if let Some(shadow) = o.get(&format!("_{}", name)) {
+			value.merge_idext(shadow).map_err(|e| e.at(&format!("_{}", name)))?;
+		}
+		value.check_valueless().map_err(|e| e.at(name))?;
+		elts.push(Element {name: name.clone(), value: value});
+	}
+	Ok(elts)
+}
+
+// Decode the children of an XML element. Repeated siblings fold into a list;
+// a repeating element that occurs once can't be told apart from a single one.
+pub fn elts_from_xml(src: &str, nodes: &[XmlNode]) -> Result<(Vec<Extension>, Vec<Element>), FhirError> {
+	let mut exts = Vec::new();
+	let mut elts: Vec<Element> = Vec::new();
+	for n in nodes.iter() {
+		if n.name == "extension" {
+			exts.push(Extension::from_xml_node(src, n).map_err(|e| e.at("extension"))?);
+			continue;
+		}
+		if n.name == "modifierExtension" {
+			continue;
+		}
+		let v = Value::from_xml_node(src, n).map_err(|e| e.at(&n.name))?;
+		append(&mut elts, Element {name: n.name.clone(), value: v});
+	}
+	Ok((exts, elts))
+}
+
+// The modifierExtension children of an XML element, which elts_from_xml passes over
+pub fn modifiers_from_xml(src: &str, nodes: &[XmlNode]) -> Result<Vec<Extension>, FhirError> {
+	nodes.iter().filter(|n| n.name == "modifierExtension")
+		.map(|n| Extension::from_xml_node(src, n).map_err(|e| e.at("modifierExtension")))
+		.collect()
+}
 
 pub trait NamedFrom<T> {
 	fn with(name: &str, val: T) -> Self;
@@ -90,9 +229,17 @@ macro_rules! gen_named {
 
 gen_named!(bool);
 gen_named!(i32);
-gen_named!(u32);
+gen_named!(i64);
+gen_named!(UnsignedInt);
+gen_named!(PositiveInt);
 gen_named!(Dec);
 gen_named!(String);
+gen_named!(Id);
+gen_named!(Code);
+gen_named!(Oid);
+gen_named!(Canonical);
+gen_named!(Uuid);
+gen_named!(Markdown);
 gen_named!(Time);
 gen_named!(Url);
 gen_named!(DateTime<FixedOffset>);
@@ -106,11 +253,12 @@ impl<'a> NamedFrom<&'a str> for Element {
 	}
 }
 
+// Children that share a name are folded into one repeating element
 impl NamedFrom<Vec<Element>> for Element {
 	fn with(name: &str, val: Vec<Element>) -> Self {
 		Element {
 			name: name.to_string(),
-			value: Value { value: ValueType::Elt(val), id: None, extension: Vec::new()}
+			value: Value { value: ValueType::Elt(fold(val)), id: None, extension: Vec::new(), modifier_extension: Vec::new()}
 		}
 	}
 }
@@ -119,7 +267,7 @@ impl NamedFrom<Vec<Value>> for Element {
 	fn with(name: &str, val: Vec<Value>) -> Self {
 		Element {
 			name: name.to_string(),
-			value: Value { value: ValueType::List(val), id: None, extension: Vec::new()}
+			value: Value { value: ValueType::List(val), id: None, extension: Vec::new(), modifier_extension: Vec::new()}
 		}
 	}
 }
@@ -129,7 +277,7 @@ fn make_test_elt() -> Element {
 	let e1 = Element::with("foo",false)
 				.id("quux");
 	let e2 = Element::with("bar",false);
-	let e3 = Element::with("baz",23u32);
+	let e3 = Element::with("baz",UnsignedInt::new(23).unwrap());
 	let e_second = Element::with("second", vec![e3]);
 	let e_list = Element::with("list", vec![
 		Value::from(true),
@@ -142,4 +290,73 @@ fn make_test_elt() -> Element {
 fn test_compound_elt() {
 	let expected = Json::from_str(r#"{"foo": false, "_foo": {"id": "quux"}, "bar": false, "second": { "baz": 23 }, "list": [true,true], "_list": [null, {"id":"abc123"}]}"#).unwrap();
   	assert_eq!(expected, make_test_elt()._to_json());
-}
\ No newline at end of file
+}
+#[test]
+fn test_compound_elt_from_json() {
+	let expected = make_test_elt()._to_json();
+	let elts = elts_from_json(expected.as_object().unwrap()).unwrap();
+	assert_eq!(expected, elts._to_json());
+}
+
+#[test]
+fn test_orphan_shadow_from_json() {
+	let j = Json::from_str(r#"{"_foo": {"id": "quux"}, "list": [true, null], "_list": [null, {"id": "abc123"}], "_only": [{"id": "o1"}, {"id": "o2"}]}"#).unwrap();
+	let elts = elts_from_json(j.as_object().unwrap()).unwrap();
+	assert_eq!(Some(&Element {name: "foo".to_string(), value: Value::from(ValueType::Atom(None)).id("quux")}), elts.iter().find(|e| e.name == "foo"));
+	assert_eq!(j, elts._to_json());
+	let mut out = Vec::new();
+	{
+		let mut w = JsonWriter::new(&mut out);
+		w.begin_object().unwrap();
+		write_members(&elts, &[], &[], "", &::json::writer::ModelOrder, &mut w).unwrap();
+		w.end_object().unwrap();
+	}
+	assert_eq!(j, Json::from_str(&String::from_utf8(out).unwrap()).unwrap());
+	for bad in [r#"{"foo": null}"#, r#"{"list": [true, null]}"#, r#"{"_list": [null]}"#].iter() {
+		assert!(elts_from_json(Json::from_str(bad).unwrap().as_object().unwrap()).is_err());
+	}
+}
+
+#[test]
+fn test_only_narrative_div_is_xhtml() {
+	let j = Json::from_str(r#"{"text": {"status": "generated", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">Jo</div>"},
+		"item": {"div": "<b>not markup</b>"}}"#).unwrap();
+	let elts = elts_from_json(j.as_object().unwrap()).unwrap();
+	let child = |name: &str| match elts.iter().find(|e| e.name == name).unwrap().value.value {
+		ValueType::Elt(ref c) => c.iter().find(|e| e.name == "div").unwrap().value.value.clone(),
+		_ => panic!("{} isn't composite", name)
+	};
+	assert_eq!(ValueType::Atom(Some(Primitive::Xhtml("<div xmlns=\"http://www.w3.org/1999/xhtml\">Jo</div>".to_string()))), child("text"));
+	assert_eq!(ValueType::Atom(Some(Primitive::String("<b>not markup</b>".to_string()))), child("item"));
+	let bad = Json::from_str(r#"{"text": {"div": "<b>no div</b>"}}"#).unwrap();
+	assert!(elts_from_json(bad.as_object().unwrap()).is_err());
+}
+
+#[test]
+fn test_repeated_names_fold() {
+	let e = Element::with("name", vec![
+		Element::with("given", "Jo"),
+		Element::with("family", "Smith"),
+		Element::with("given", vec![Value::from("Al"), Value::from("Bo")])]);
+	let expected = Json::from_str(r#"{"given": ["Jo", "Al", "Bo"], "family": "Smith"}"#).unwrap();
+	assert_eq!(expected, e.value.to_json());
+	let v = Value::from(vec![Element::with("given", "Jo"), Element::with("family", "Smith"), Element::with("given", "Al")]);
+	assert_eq!(Json::from_str(r#"{"given": ["Jo", "Al"], "family": "Smith"}"#).unwrap(), v.to_json());
+	let mut out = Vec::new();
+	{
+		let mut w = JsonWriter::new(&mut out);
+		v.write_json("name", "HumanName", &::json::writer::ModelOrder, &mut w).unwrap();
+	}
+	assert_eq!(r#""name":{"given":["Jo","Al"],"family":"Smith"}"#, String::from_utf8(out).unwrap());
+}
+
+#[test]
+fn test_push_in_place() {
+	let mut elts = vec![Element::with("given", "Jo")];
+	append(&mut elts, Element::with("family", "Smith"));
+	append(&mut elts, Element::with("given", "Al"));
+	elts[0].push(Value::from("Bo").id("b1"));
+	assert_eq!(2, elts.len());
+	let expected = Json::from_str(r#"{"given": ["Jo", "Al", "Bo"], "_given": [null, null, {"id": "b1"}], "family": "Smith"}"#).unwrap();
+	assert_eq!(expected, elts._to_json());
+}
@@ -1,17 +1,25 @@
 use std::collections::btree_map::BTreeMap;
+use std::io::{self, Write};
 
 use rustc_serialize::json::{ToJson, Json};
 use url::{Url};
 use chrono::{DateTime,FixedOffset};
 
-use element::{Element,InternalToJson};
-use primitive::{Primitive, Dec, Time};
-use extension::Extension;
+use element::{Element,InternalToJson,fold,elts_from_json,elts_from_xml,modifiers_from_xml,write_members,resource_type};
+use primitive::{Primitive, Dec, Time, UnsignedInt, PositiveInt, Id, Code, Oid, Canonical, Uuid, Markdown};
+use extension::{Extension, Extensible, write_extensions, check_understood};
+use json::{FromJson, JsonWriter, ElementOrder, as_object, as_string};
+use xml::{XmlWriter, XmlNode, FHIR_NS};
+use resource::LEADING;
+use narrative::{read_div, write_div};
+use error::FhirError;
 
 
 
+#[derive(Debug,Clone,PartialEq)]
 pub enum ValueType {
-	Atom(Primitive),
+	// A primitive without a value has an id or extensions instead
+	Atom(Option<Primitive>),
 	List(Vec<Value>),
 	Elt(Vec<Element>)
 }
@@ -19,7 +27,8 @@ pub enum ValueType {
 impl ToJson for ValueType {
 	fn to_json(&self) -> Json {
 		match *self {
-			ValueType::Atom(ref v) => v.to_json(),
+			ValueType::Atom(Some(ref v)) => v.to_json(),
+			ValueType::Atom(None) => Json::Null,
 			ValueType::List(ref v) => v.to_json(),
 			ValueType::Elt(ref v) => v._to_json()
 		}
@@ -27,17 +36,22 @@ impl ToJson for ValueType {
 }
 
 
+#[derive(Debug,Clone,PartialEq)]
 pub struct Value {
 	pub value: ValueType,
 	pub id: Option<String>,
-	pub extension: Vec<Extension>
+	pub extension: Vec<Extension>,
+	// Extensions that change the meaning of a backbone element; only composites carry them
+	pub modifier_extension: Vec<Extension>
 }
 
 impl Value {
 
 	pub fn keys(&self, name: &str) -> Vec<(String,Json)>{
 		let mut v = Vec::new();
-		v.push((String::from(name),self.to_json()));
+		if self.has_value() {
+			v.push((String::from(name),self.to_json()));
+		}
 		self.id_ext_to_json()
 			.map(|j| v.push((format!("_{}",name),j)));
 		v
@@ -70,7 +84,7 @@ impl Value {
 		let mut found = false;
 		let mut retlist: Vec<Json> = Vec::new();
 		for v in list {
-			if v.has_idext()  {
+			if v.is_atom() && v.has_idext()  {
 				found = true;
 				retlist.push(v.make_idext_ojb());				
 			} else {
@@ -81,7 +95,7 @@ impl Value {
 	}
 
 	fn simple_idext_to_json(&self) -> Option<Json> {
-		if self.id.is_some() || self.has_extension() {
+		if self.is_atom() && self.has_idext() {
 			Some(self.make_idext_ojb())
 		} else {
 			None
@@ -102,6 +116,269 @@ impl Value {
 		self
 	}
 
+	pub fn add_modifier_ext(mut self, e: Extension) -> Self {
+		self.modifier_extension.push(e);
+		self
+	}
+
+	fn is_atom(&self) -> bool {
+		match self.value {
+			ValueType::Atom(_) => true,
+			_ => false
+		}
+	}
+
+	// Whether there is anything to write under the element's own name; a
+	// primitive without a value, or a list of them, has only its "_name"
+	fn has_value(&self) -> bool {
+		match self.value {
+			ValueType::Atom(ref p) => p.is_some(),
+			ValueType::List(ref list) => list.iter().any(|v| v.has_value()),
+			ValueType::Elt(_) => true
+		}
+	}
+
+	// A primitive without a value must have an id or extensions in its place
+	pub fn check_valueless(&self) -> Result<(), FhirError> {
+		match self.value {
+			ValueType::Atom(None) if !self.has_idext() => Err(FhirError::missing("Primitive with neither value nor extension")),
+			ValueType::List(ref list) => list.iter().enumerate()
+				.map(|(i, v)| v.check_valueless().map_err(|e| e.at(&format!("[{}]", i))))
+				.collect(),
+			_ => Ok(())
+		}
+	}
+
+	// Composites carry their id and extensions inline; primitives carry them
+	// in a "_name" shadow after the value, with nulls for a list's plain items
+	pub fn write_json<W: Write, O: ElementOrder>(&self, name: &str, path: &str, order: &O, w: &mut JsonWriter<W>) -> io::Result<()> {
+		if self.has_value() {
+			w.key(name)?;
+			self.write_json_value(path, order, w)?;
+		}
+		let list = match self.value {
+			ValueType::List(ref list) => list,
+			_ if self.is_atom() && self.has_idext() => {
+				w.key(&format!("_{}", name))?;
+				return self.write_idext(order, w);
+			},
+			_ => return Ok(())
+		};
+		if list.iter().any(|v| v.is_atom() && v.has_idext()) {
+			w.key(&format!("_{}", name))?;
+			w.begin_array()?;
+			for v in list.iter() {
+				if v.is_atom() && v.has_idext() {
+					v.write_idext(order, w)?;
+				} else {
+					w.raw("null")?;
+				}
+			}
+			w.end_array()?;
+		}
+		Ok(())
+	}
+
+	fn write_json_value<W: Write, O: ElementOrder>(&self, path: &str, order: &O, w: &mut JsonWriter<W>) -> io::Result<()> {
+		match self.value {
+			ValueType::Atom(Some(ref p)) => p.write_json(w),
+			ValueType::Atom(None) => w.raw("null"),
+			ValueType::List(ref list) => {
+				w.begin_array()?;
+				for v in list.iter() {
+					v.write_json_value(path, order, w)?;
+				}
+				w.end_array()
+			},
+			ValueType::Elt(ref elts) => {
+				w.begin_object()?;
+				if let Some(ref id) = self.id {
+					w.key("id")?;
+					w.string(id)?;
+				}
+				write_members(elts, &self.extension, &self.modifier_extension, path, order, w)?;
+				w.end_object()
+			}
+		}
+	}
+
+	// Every extension here and below, with the sub-extensions of complex ones
+	pub fn all_extensions(&self) -> Vec<&Extension> {
+		let mut out: Vec<&Extension> = self.extension.iter().chain(self.modifier_extension.iter())
+			.flat_map(|e| e.all_extensions())
+			.collect();
+		match self.value {
+			ValueType::List(ref list) => for v in list.iter() { out.extend(v.all_extensions()) },
+			ValueType::Elt(ref elts) => for e in elts.iter() { out.extend(e.value.all_extensions()) },
+			ValueType::Atom(_) => ()
+		}
+		out
+	}
+
+	// Every modifier extension here or in a nested backbone element must be understood
+	pub fn check_modifiers(&self, understood: &[&str]) -> Result<(), FhirError> {
+		match self.value {
+			ValueType::Atom(_) => Ok(()),
+			ValueType::List(ref list) => list.iter().enumerate()
+				.map(|(i, v)| v.check_modifiers(understood).map_err(|e| e.at(&format!("[{}]", i))))
+				.collect(),
+			ValueType::Elt(ref elts) => {
+				check_understood(&self.modifier_extension, understood)?;
+				elts.iter().map(|e| e.value.check_modifiers(understood).map_err(|err| err.at(&e.name))).collect()
+			}
+		}
+	}
+
+	fn write_idext<W: Write, O: ElementOrder>(&self, order: &O, w: &mut JsonWriter<W>) -> io::Result<()> {
+		w.begin_object()?;
+		if let Some(ref id) = self.id {
+			w.key("id")?;
+			w.string(id)?;
+		}
+		if self.has_extension() {
+			w.key("extension")?;
+			write_extensions(&self.extension, order, w)?;
+		}
+		w.end_object()
+	}
+
+	// Lists repeat the element; primitives carry a value attribute; extensions come first
+	pub fn write_xml(&self, name: &str, w: &mut XmlWriter) {
+		if let ValueType::List(ref list) = self.value {
+			for v in list.iter() {
+				v.write_xml(name, w);
+			}
+			return;
+		}
+		if let ValueType::Atom(Some(Primitive::Xhtml(ref xhtml))) = self.value {
+			write_div(xhtml, w);
+			return;
+		}
+		let data = match self.value {
+			ValueType::Atom(Some(Primitive::Base64(ref b))) => Some(&b[..]),
+			_ => None
+		};
+		let value_attr = match self.value {
+			ValueType::Atom(Some(Primitive::Base64(_))) => None,
+			ValueType::Atom(Some(ref p)) => Some(format!("{}", p)),
+			_ => None
+		};
+		let mut attrs = Vec::new();
+		if let Some(ref id) = self.id {
+			attrs.push(("id", id.as_ref()));
+		}
+		if let Some(ref v) = value_attr {
+			attrs.push(("value", v.as_ref()));
+		}
+		let elts: &[Element] = match self.value {
+			ValueType::Elt(ref elts) => elts,
+			_ => &[]
+		};
+		if let Some(t) = resource_type(elts) {
+			w.start(name, &[]);
+			self.write_resource_xml(t, elts, w);
+			w.end(name);
+			return;
+		}
+		if !self.has_extension() && self.modifier_extension.is_empty() && elts.is_empty() {
+			match data {
+				Some(data) => w.empty_base64(name, &attrs, data),
+				None => w.empty(name, &attrs)
+			}
+			return;
+		}
+		match data {
+			Some(data) => w.start_base64(name, &attrs, data),
+			None => w.start(name, &attrs)
+		}
+		for e in self.extension.iter() {
+			e.write_xml(w);
+		}
+		for e in self.modifier_extension.iter() {
+			e.write_xml_as("modifierExtension", w);
+		}
+		for e in elts.iter() {
+			e.value.write_xml(&e.name, w);
+		}
+		w.end(name);
+	}
+
+	// A resource held in an element is wrapped in an element named for its
+	// type, and its id is an element, not an attribute. Its leading members
+	// come before its extensions.
+	fn write_resource_xml(&self, t: &str, elts: &[Element], w: &mut XmlWriter) {
+		let header = |e: &&Element| LEADING.contains(&e.name.as_str());
+		w.start(t, &[("xmlns", FHIR_NS)]);
+		if let Some(ref id) = self.id {
+			w.empty("id", &[("value", id)]);
+		}
+		for e in elts.iter().filter(header) {
+			e.value.write_xml(&e.name, w);
+		}
+		for e in self.extension.iter() {
+			e.write_xml(w);
+		}
+		for e in self.modifier_extension.iter() {
+			e.write_xml_as("modifierExtension", w);
+		}
+		for e in elts.iter().filter(|e| !header(e) && e.name != "resourceType") {
+			e.value.write_xml(&e.name, w);
+		}
+		w.end(t);
+	}
+
+	// The narrative div is XHTML and is kept as markup, as in JSON. A resource
+	// held in an element is read as JSON has it, with a resourceType member.
+	pub fn from_xml_node(src: &str, n: &XmlNode) -> Result<Self, FhirError> {
+		if n.name == "div" {
+			let xhtml = Primitive::Xhtml(read_div(&src[n.start..n.end])?);
+			return Ok(Value {value: ValueType::Atom(Some(xhtml)), id: None, extension: Vec::new(), modifier_extension: Vec::new()});
+		}
+		if let (1, Some(r)) = (n.children.len(), n.children.iter().find(|c| c.name.starts_with(char::is_uppercase))) {
+			let mut v = Value::from_xml_node(src, r).map_err(|e| e.at(&r.name))?;
+			if let ValueType::Elt(ref mut elts) = v.value {
+				elts.insert(0, Element {name: "resourceType".to_string(), value: Value::from(r.name.as_str())});
+			}
+			return Ok(v);
+		}
+		let (exts, elts) = elts_from_xml(src, &n.children)?;
+		let mods = modifiers_from_xml(src, &n.children)?;
+		let v = match n.attr("value") {
+			Some(s) if !elts.is_empty() || !mods.is_empty() => return Err(FhirError::unexpected(s, "Primitive element with children")),
+			Some(s) => ValueType::Atom(Some(Primitive::from_xml_value(None, s)?)),
+			None => ValueType::Elt(elts)
+		};
+		Ok(Value {value: v, id: n.attr("id").map(String::from), extension: exts, modifier_extension: mods})
+	}
+
+	fn merge_simple_idext(&mut self, j: &Json) -> Result<(), FhirError> {
+		let o = as_object(j)?;
+		if let Some(id) = o.get("id") {
+			self.id = Some(as_string(id)?.to_string());
+		}
+		if let Some(ext) = o.get("extension") {
+			self.extension = Vec::<Extension>::from_json(ext)?;
+		}
+		Ok(())
+	}
+
+	// Fold a "_name" shadow object (or array of them, for lists) back into id and extension
+	pub fn merge_idext(&mut self, j: &Json) -> Result<(), FhirError> {
+		if let ValueType::List(ref mut list) = self.value {
+			let shadows = j.as_array().ok_or_else(|| FhirError::unexpected(&j.to_string(), "Expected array"))?;
+			if shadows.len() != list.len() {
+				return Err(FhirError::unexpected(&j.to_string(), "Shadow list length mismatch"));
+			}
+			for (i, (v, s)) in list.iter_mut().zip(shadows.iter()).enumerate() {
+				if !s.is_null() {
+					v.merge_simple_idext(s).map_err(|e| e.at(&format!("[{}]", i)))?;
+				}
+			}
+			return Ok(());
+		}
+		self.merge_simple_idext(j)
+	}
+
 }
 
 macro_rules! gen_from {
@@ -109,9 +386,10 @@ macro_rules! gen_from {
 		impl From<$t> for Value {
 			fn from(v: $t) -> Self {
 				Value {
-					value: ValueType::Atom(Primitive::from(v)),
+					value: ValueType::Atom(Some(Primitive::from(v))),
 					id: None,
-					extension: Vec::new()
+					extension: Vec::new(),
+					modifier_extension: Vec::new()
 				}
 			}
 		}
@@ -120,9 +398,17 @@ macro_rules! gen_from {
 
 gen_from!(bool);
 gen_from!(i32);
-gen_from!(u32);
+gen_from!(i64);
+gen_from!(UnsignedInt);
+gen_from!(PositiveInt);
 gen_from!(Dec);
 gen_from!(String);
+gen_from!(Id);
+gen_from!(Code);
+gen_from!(Oid);
+gen_from!(Canonical);
+gen_from!(Uuid);
+gen_from!(Markdown);
 gen_from!(Time);
 gen_from!(Url);
 gen_from!(DateTime<FixedOffset>);
@@ -130,27 +416,88 @@ gen_from!(DateTime<FixedOffset>);
 impl<'a> From<&'a str> for Value {
 	fn from(v: &'a str) -> Self {
 		Value {
-			value: ValueType::Atom(Primitive::from(v)),
+			value: ValueType::Atom(Some(Primitive::from(v))),
 			id: None,
-			extension: Vec::new()
+			extension: Vec::new(),
+			modifier_extension: Vec::new()
 		}
 	}
 }
 
+impl From<ValueType> for Value {
+	fn from(v: ValueType) -> Self {
+		Value {
+			value: v,
+			id: None,
+			extension: Vec::new(),
+			modifier_extension: Vec::new()
+		}
+	}
+}
+
+// Repeated names are folded into one list element, as the formats require
 impl From<Vec<Element>> for Value {
 	fn from(v: Vec<Element>) -> Self {
 		Value {
-			value: ValueType::Elt(v),
+			value: ValueType::Elt(fold(v)),
 			id: None,
-			extension: Vec::new()
+			extension: Vec::new(),
+			modifier_extension: Vec::new()
 		}
 	}
 }
 
 
+impl Extensible for Value {
+	fn exts(&self) -> &[Extension] {
+		&self.extension
+	}
+
+	fn exts_mut(&mut self) -> Result<&mut Vec<Extension>, FhirError> {
+		Ok(&mut self.extension)
+	}
+}
+
+// A composite carries its id and extensions among its members
 impl ToJson for Value {
 	fn to_json(&self) -> Json {
-		self.value.to_json()
+		let mut o = match self.value.to_json() {
+			Json::Object(o) => o,
+			j => return j
+		};
+		if let Some(ref id) = self.id {
+			o.insert(String::from("id"), Json::String(id.clone()));
+		}
+		if self.has_extension() {
+			o.insert(String::from("extension"), self.extension.to_json());
+		}
+		if !self.modifier_extension.is_empty() {
+			o.insert(String::from("modifierExtension"), self.modifier_extension.to_json());
+		}
+		Json::Object(o)
+	}
+}
+
+impl FromJson for Value {
+	fn from_json(j: &Json) -> Result<Self, FhirError> {
+		let o = match *j {
+			Json::Array(_) => return Ok(Value::from(ValueType::List(Vec::<Value>::from_json(j)?))),
+			Json::Object(ref o) => o,
+			Json::Null => return Ok(Value::from(ValueType::Atom(None))),
+			_ => return Ok(Value::from(ValueType::Atom(Some(Primitive::from_json(j)?))))
+		};
+		let mut o = o.clone();
+		let id = match o.remove("id") {
+			Some(id) => Some(as_string(&id).map_err(|e| e.at("id"))?.to_string()),
+			None => None
+		};
+		let mut exts = |key: &str| match o.remove(key) {
+			Some(e) => Vec::<Extension>::from_json(&e).map_err(|e| e.at(key)),
+			None => Ok(Vec::new())
+		};
+		let extension = exts("extension")?;
+		let modifier_extension = exts("modifierExtension")?;
+		Ok(Value {value: ValueType::Elt(elts_from_json(&o)?), id: id, extension: extension, modifier_extension: modifier_extension})
 	}
 }
 
@@ -159,3 +506,21 @@ fn test_bool_value() {
 	let v = Value::from(false);
 	assert_eq!(Json::Boolean(false), v.to_json());
 }
+
+#[test]
+fn test_list_value_from_json() {
+	let mut v = Value::from_json(&Json::from_str("[true, false]").unwrap()).unwrap();
+	v.merge_idext(&Json::from_str(r#"[null, {"id": "abc123"}]"#).unwrap()).unwrap();
+	assert_eq!(Some(Json::from_str(r#"[null, {"id": "abc123"}]"#).unwrap()), v.id_ext_to_json());
+	assert!(v.merge_idext(&Json::from_str("[null]").unwrap()).is_err());
+}
+
+#[test]
+fn test_composite_idext_from_json() {
+	let j = Json::from_str(r#"{"id": "n1", "family": "Smith", "extension": [{"url": "http://example.org/nick", "valueString": "Smithy"}]}"#).unwrap();
+	let v = Value::from_json(&j).unwrap();
+	assert_eq!(Some("n1".to_string()), v.id);
+	assert_eq!(1, v.extension.len());
+	assert!(v.keys("name").len() == 1);
+	assert_eq!(j, v.to_json());
+}
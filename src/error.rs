@@ -0,0 +1,117 @@
+use std::fmt;
+use std::error::Error;
+
+// Paths are dotted element paths ("Patient.name.given"), built up by
+// prefixing segments with `at` as an error travels out of a decoder
+#[derive(Debug, PartialEq, Clone)]
+pub enum FhirError {
+	InvalidValue { path: String, value: String, reason: &'static str },
+	Unexpected { path: String, value: String, reason: &'static str },
+	Missing { path: String, reason: &'static str },
+	Malformed { path: String, reason: &'static str }
+}
+
+impl FhirError {
+	pub fn invalid(value: &str, reason: &'static str) -> Self {
+		FhirError::InvalidValue {path: String::new(), value: value.to_string(), reason: reason}
+	}
+
+	pub fn unexpected(value: &str, reason: &'static str) -> Self {
+		FhirError::Unexpected {path: String::new(), value: value.to_string(), reason: reason}
+	}
+
+	pub fn missing(reason: &'static str) -> Self {
+		FhirError::Missing {path: String::new(), reason: reason}
+	}
+
+	pub fn malformed(reason: &'static str) -> Self {
+		FhirError::Malformed {path: String::new(), reason: reason}
+	}
+
+	pub fn path(&self) -> &str {
+		match *self {
+			FhirError::InvalidValue {ref path, ..} => path,
+			FhirError::Unexpected {ref path, ..} => path,
+			FhirError::Missing {ref path, ..} => path,
+			FhirError::Malformed {ref path, ..} => path
+		}
+	}
+
+	pub fn reason(&self) -> &'static str {
+		match *self {
+			FhirError::InvalidValue {reason, ..} => reason,
+			FhirError::Unexpected {reason, ..} => reason,
+			FhirError::Missing {reason, ..} => reason,
+			FhirError::Malformed {reason, ..} => reason
+		}
+	}
+
+	pub fn value(&self) -> Option<&str> {
+		match *self {
+			FhirError::InvalidValue {ref value, ..} => Some(value),
+			FhirError::Unexpected {ref value, ..} => Some(value),
+			_ => None
+		}
+	}
+
+	// Prefix the path with the segment of the enclosing element
+	pub fn at(mut self, segment: &str) -> Self {
+		{
+			let path = match self {
+				FhirError::InvalidValue {ref mut path, ..} => path,
+				FhirError::Unexpected {ref mut path, ..} => path,
+				FhirError::Missing {ref mut path, ..} => path,
+				FhirError::Malformed {ref mut path, ..} => path
+			};
+			*path = if path.is_empty() {
+				segment.to_string()
+			} else if path.starts_with('[') {
+				format!("{}{}", segment, path)
+			} else {
+				format!("{}.{}", segment, path)
+			};
+		}
+		self
+	}
+}
+
+impl fmt::Display for FhirError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if !self.path().is_empty() {
+			write!(f, "{}: ", self.path())?;
+		}
+		match self.value() {
+			Some(v) => write!(f, "{} '{}'", self.reason(), v),
+			None => write!(f, "{}", self.reason())
+		}
+	}
+}
+
+impl Error for FhirError {}
+
+// The path and reason of an error, which is what tests compare
+#[cfg(test)]
+pub fn issue(e: &FhirError) -> (String, &'static str) {
+	(e.path().to_string(), e.reason())
+}
+
+#[cfg(test)]
+pub fn issues(errors: &[FhirError]) -> Vec<(String, &'static str)> {
+	errors.iter().map(issue).collect()
+}
+
+#[test]
+fn test_error_path() {
+	let e = FhirError::invalid("3.141.23", "Invalid decimal").at("value").at("Observation");
+	assert_eq!("Observation.value", e.path());
+	assert_eq!("Observation.value: Invalid decimal '3.141.23'", e.to_string());
+	let e = FhirError::missing("Missing url").at("[1]").at("extension");
+	assert_eq!("extension[1]", e.path());
+}
+
+#[test]
+fn test_error_without_value() {
+	let e = FhirError::missing("Missing resourceType");
+	assert_eq!(None, e.value());
+	assert_eq!("Missing resourceType", e.to_string());
+}
@@ -1,13 +1,22 @@
 use std::collections::btree_map::BTreeMap;
+use std::io::{self, Write};
 
 use url::Url;
 use rustc_serialize::json::{ToJson, Json};
 
 use primitive::Primitive;
-use element::{Element,NamedFrom};
+use element::{Element,Value,ValueType,elts_from_xml,modifiers_from_xml};
+#[cfg(test)]
+use element::NamedFrom;
+use json::{FromJson, JsonWriter, ElementOrder, as_object, as_string};
+use xml::{ToXml, FromXml, XmlWriter, XmlNode};
+use xml::reader;
+use typed::FromValue;
+use error::FhirError;
 
 
 
+#[derive(Debug,Clone,PartialEq)]
 pub enum ExtensionValue {
 	Atom(Primitive),
 	Composite(Element),
@@ -52,6 +61,7 @@ impl ToJson for ExtensionValue {
 	}
 }
 
+#[derive(Debug,Clone,PartialEq)]
 pub struct Extension {
 	id: Option<String>,
 	uri: Url,
@@ -73,6 +83,307 @@ impl Extension {
 	pub fn builder() -> ExtensionBuilder {
 		ExtensionBuilder::new()
 	}
+
+	pub fn url(&self) -> &Url {
+		&self.uri
+	}
+
+	// The type of the value, as in its name (Boolean, Coding); None for an
+	// extension of sub-extensions
+	pub fn value_type(&self) -> Option<String> {
+		match self.value {
+			ExtensionValue::Extensions(_) => None,
+			ref v => Some(v.value_name()["value".len()..].to_string())
+		}
+	}
+
+	pub fn id(&self) -> Option<&str> {
+		self.id.as_ref().map(|id| id.as_ref())
+	}
+
+	// The value as the Element model holds one
+	pub fn value(&self) -> Option<Value> {
+		match self.value {
+			ExtensionValue::Atom(ref p) => Some(Value::from(ValueType::Atom(Some(p.clone())))),
+			ExtensionValue::Composite(ref e) => Some(e.value.clone()),
+			ExtensionValue::Extensions(_) => None
+		}
+	}
+
+	pub fn get<T: FromValue>(&self) -> Result<T, FhirError> {
+		match self.value() {
+			Some(v) => T::from_value(&v).map_err(|e| e.at(&self.value.value_name())),
+			None => Err(FhirError::missing("Extension without a value"))
+		}
+	}
+
+	// The sub-extensions of a complex extension
+	pub fn extensions(&self) -> &[Extension] {
+		match self.value {
+			ExtensionValue::Extensions(ref v) => v,
+			_ => &[]
+		}
+	}
+
+	pub fn find(&self, url: &str) -> Option<&Extension> {
+		find(self.extensions(), url)
+	}
+
+	// This extension and its sub-extensions, outermost first
+	pub fn all_extensions(&self) -> Vec<&Extension> {
+		let mut out = vec![self];
+		for e in self.extensions().iter() {
+			out.extend(e.all_extensions());
+		}
+		out
+	}
+
+	// The extension as a node of the Element model, its sub-extensions held
+	// as the node's extensions, e.g. for navigation by FHIRPath
+	pub fn to_value(&self) -> Value {
+		let mut elts = vec![Element {name: String::from("url"), value: Value::from(ValueType::Atom(Some(Primitive::Uri(self.uri.clone()))))}];
+		if let Some(v) = self.value() {
+			elts.push(Element {name: self.value.value_name(), value: v});
+		}
+		let mut v = Value::from(elts);
+		v.id = self.id.clone();
+		v.extension = self.extensions().to_vec();
+		v
+	}
+
+	pub fn write_xml(&self, w: &mut XmlWriter) {
+		self.write_xml_as("extension", w)
+	}
+
+	// Modifier extensions have the same content under another name
+	pub fn write_xml_as(&self, tag: &str, w: &mut XmlWriter) {
+		let uri = self.uri.to_string();
+		let mut attrs = Vec::new();
+		if let Some(ref id) = self.id {
+			attrs.push(("id", id.as_ref()));
+		}
+		attrs.push(("url", uri.as_ref()));
+		w.start(tag, &attrs);
+		match self.value {
+			ExtensionValue::Atom(Primitive::Base64(ref b)) => w.empty_base64(&self.value.value_name(), &[], b),
+			ExtensionValue::Atom(ref p) => w.empty(&self.value.value_name(), &[("value", &format!("{}", p))]),
+			ExtensionValue::Composite(ref e) => e.value.write_xml(&self.value.value_name(), w),
+			ExtensionValue::Extensions(ref v) => for e in v.iter() { e.write_xml(w) }
+		}
+		w.end(tag);
+	}
+}
+
+// The url leads, as in the examples of the specification
+impl Extension {
+	pub fn write_json<W: Write, O: ElementOrder>(&self, order: &O, w: &mut JsonWriter<W>) -> io::Result<()> {
+		w.begin_object()?;
+		if let Some(ref id) = self.id {
+			w.key("id")?;
+			w.string(id)?;
+		}
+		w.key("url")?;
+		w.string(&self.uri.to_string())?;
+		match self.value {
+			ExtensionValue::Atom(ref p) => {
+				w.key(&self.value.value_name())?;
+				p.write_json(w)?;
+			},
+			ExtensionValue::Composite(ref e) => e.value.write_json(&self.value.value_name(), &e.name, order, w)?,
+			ExtensionValue::Extensions(ref v) => {
+				w.key("extension")?;
+				write_extensions(v, order, w)?;
+			}
+		}
+		w.end_object()
+	}
+}
+
+pub fn write_extensions<W: Write, O: ElementOrder>(exts: &[Extension], order: &O, w: &mut JsonWriter<W>) -> io::Result<()> {
+	w.begin_array()?;
+	for e in exts.iter() {
+		e.write_json(order, w)?;
+	}
+	w.end_array()
+}
+
+pub fn find<'a>(exts: &'a [Extension], url: &str) -> Option<&'a Extension> {
+	exts.iter().find(|e| e.uri.as_str() == url)
+}
+
+// Lookup, typed reads and changes for whatever holds extensions, by url.
+// A path of urls reaches into complex extensions.
+pub trait Extensible {
+	fn exts(&self) -> &[Extension];
+	fn exts_mut(&mut self) -> Result<&mut Vec<Extension>, FhirError>;
+
+	fn extension(&self, url: &str) -> Option<&Extension> {
+		find(self.exts(), url)
+	}
+
+	fn extensions_by_url(&self, url: &str) -> Vec<&Extension> {
+		self.exts().iter().filter(|e| e.uri.as_str() == url).collect()
+	}
+
+	fn extension_at(&self, urls: &[&str]) -> Option<&Extension> {
+		let (first, rest) = urls.split_first()?;
+		rest.iter().fold(self.extension(first), |e, url| e.and_then(|e| e.find(url)))
+	}
+
+	fn extension_value<T: FromValue>(&self, url: &str) -> Result<Option<T>, FhirError> {
+		match self.extension(url) {
+			Some(e) => e.get().map(Some).map_err(|err| err.at("extension")),
+			None => Ok(None)
+		}
+	}
+
+	// Remove every extension with the url, returning them
+	fn remove_extensions(&mut self, url: &str) -> Result<Vec<Extension>, FhirError> {
+		if self.extension(url).is_none() {
+			return Ok(Vec::new());
+		}
+		let exts = self.exts_mut()?;
+		let (removed, kept): (Vec<Extension>, Vec<Extension>) = exts.drain(..).partition(|e| e.uri.as_str() == url);
+		*exts = kept;
+		Ok(removed)
+	}
+
+	// Put `e` in place of the extensions with its url, or add it
+	fn replace_extension(&mut self, e: Extension) -> Result<(), FhirError> {
+		let url = e.uri.to_string();
+		let exts = self.exts_mut()?;
+		let at = exts.iter().position(|x| x.uri.as_str() == url);
+		exts.retain(|x| x.uri.as_str() != url);
+		match at {
+			Some(i) => exts.insert(i, e),
+			None => exts.push(e)
+		}
+		Ok(())
+	}
+}
+
+// The sub-extensions of a complex extension. An extension has either a value
+// or sub-extensions, so one with a value can't take sub-extensions, and one
+// can't lose all of its sub-extensions.
+impl Extensible for Extension {
+	fn exts(&self) -> &[Extension] {
+		self.extensions()
+	}
+
+	fn exts_mut(&mut self) -> Result<&mut Vec<Extension>, FhirError> {
+		match self.value {
+			ExtensionValue::Extensions(ref mut v) => Ok(v),
+			_ => Err(FhirError::unexpected(self.uri.as_str(), "An extension with a value has no sub-extensions"))
+		}
+	}
+
+	fn remove_extensions(&mut self, url: &str) -> Result<Vec<Extension>, FhirError> {
+		if self.extension(url).is_none() {
+			return Ok(Vec::new());
+		}
+		if self.exts().iter().all(|e| e.uri.as_str() == url) {
+			return Err(FhirError::invalid(url, "An extension needs a value or sub-extensions").at("extension"));
+		}
+		let exts = self.exts_mut()?;
+		let (removed, kept): (Vec<Extension>, Vec<Extension>) = exts.drain(..).partition(|e| e.uri.as_str() == url);
+		*exts = kept;
+		Ok(removed)
+	}
+}
+
+// A modifier extension changes the meaning of what carries it, so one whose
+// url isn't among those `understood` makes the data unsafe to process
+pub fn check_understood(mods: &[Extension], understood: &[&str]) -> Result<(), FhirError> {
+	match mods.iter().position(|m| !understood.contains(&m.uri.as_str())) {
+		Some(i) => Err(FhirError::unexpected(mods[i].uri.as_str(), "Modifier extension not understood")
+			.at(&format!("[{}]", i)).at("modifierExtension")),
+		None => Ok(())
+	}
+}
+
+impl Extension {
+	pub fn from_xml_node(src: &str, n: &XmlNode) -> Result<Self, FhirError> {
+		let mut b = Extension::builder();
+		if let Some(id) = n.attr("id") {
+			b = b.id(id);
+		}
+		if let Some(u) = n.attr("url") {
+			b = b.uri(Url::parse(u).map_err(|_| FhirError::invalid(u, "Invalid URI").at("url"))?);
+		}
+		let mut subs = Vec::new();
+		for c in n.children.iter() {
+			if c.name == "extension" {
+				subs.push(Extension::from_xml_node(src, c).map_err(|e| e.at("extension"))?);
+			} else if c.name.starts_with("value") && c.name.len() > "value".len() {
+				let type_name = &c.name["value".len()..];
+				b = match c.attr("value") {
+					Some(v) => b.atom(Primitive::from_xml_value(Some(type_name), v).map_err(|e| e.at(&c.name))?)?,
+					None => {
+						let (exts, elts) = elts_from_xml(src, &c.children).map_err(|e| e.at(&c.name))?;
+						let mods = modifiers_from_xml(src, &c.children).map_err(|e| e.at(&c.name))?;
+						let value = Value {value: ValueType::Elt(elts), id: c.attr("id").map(String::from), extension: exts, modifier_extension: mods};
+						b.composite(Element {name: type_name.to_string(), value: value})?
+					}
+				};
+			} else {
+				return Err(FhirError::unexpected(&c.name, "Unexpected element in extension"));
+			}
+		}
+		if !subs.is_empty() {
+			b = b.extensions(subs)?;
+		}
+		b.build()
+	}
+}
+
+impl FromXml for Extension {
+	fn from_xml(s: &str) -> Result<Self, FhirError> {
+		Extension::from_xml_node(s, &reader::parse(s)?).map_err(|e| e.at("extension"))
+	}
+}
+
+impl ToXml for Extension {
+	fn to_xml(&self) -> String {
+		let mut w = XmlWriter::new();
+		self.write_xml(&mut w);
+		w.into_string()
+	}
+}
+
+impl ExtensionValue {
+	// The inverse of value_name: the key picks the primitive type or names the composite
+	fn from_json(key: &str, j: &Json) -> Result<Self, FhirError> {
+		if key == "extension" {
+			return Vec::<Extension>::from_json(j).map(ExtensionValue::Extensions).map_err(|e| e.at(key));
+		}
+		let type_name = &key["value".len()..];
+		match *j {
+			Json::Object(_) => Value::from_json(j).map(|v| ExtensionValue::Composite(Element {name: type_name.to_string(), value: v})),
+			_ => Primitive::from_json_typed(type_name, j).map(ExtensionValue::Atom)
+		}.map_err(|e| e.at(key))
+	}
+}
+
+impl FromJson for Extension {
+	fn from_json(j: &Json) -> Result<Self, FhirError> {
+		let o = as_object(j)?;
+		let mut b = Extension::builder();
+		if let Some(id) = o.get("id") {
+			b = b.id(as_string(id)?);
+		}
+		if let Some(u) = o.get("url") {
+			let u = as_string(u).map_err(|e| e.at("url"))?;
+			b = b.uri(Url::parse(u).map_err(|_| FhirError::invalid(u, "Invalid URI").at("url"))?);
+		}
+		for (k, v) in o.iter() {
+			if k == "extension" || (k.starts_with("value") && k.len() > "value".len()) {
+				b = b.value(ExtensionValue::from_json(k, v)?)?;
+			} else if k != "id" && k != "url" {
+				return Err(FhirError::unexpected(k, "Unexpected key in extension"));
+			}
+		}
+		b.build()
+	}
 }
 
 
@@ -102,35 +413,35 @@ impl ExtensionBuilder {
 		self
 	}
 
-	fn value(self, v: ExtensionValue) -> Result<Self, &'static str> {
-		if self.value.is_some() {return Err("Already has value")}
+	fn value(self, v: ExtensionValue) -> Result<Self, FhirError> {
+		if self.value.is_some() {return Err(FhirError::unexpected(&v.value_name(), "Already has value"))}
 		if v.valid_extension() {
 			Ok(self.set_value(v))
 		} else {
-			Err("Invalid atomic value")
+			Err(FhirError::invalid(&v.value_name(), "Invalid extension value type"))
 		}
 
 	}
 
-	pub fn atom(self, p: Primitive) -> Result<Self,&'static str> {
+	pub fn atom(self, p: Primitive) -> Result<Self,FhirError> {
 		self.value(ExtensionValue::Atom(p))
 	}
 
-	pub fn composite(self, e: Element) -> Result<Self,&'static str> {
+	pub fn composite(self, e: Element) -> Result<Self,FhirError> {
 		self.value(ExtensionValue::Composite(e))
 	}
 
-	pub fn extensions(self, e: Vec<Extension>) -> Result<Self, &'static str> {
+	pub fn extensions(self, e: Vec<Extension>) -> Result<Self, FhirError> {
 		self.value(ExtensionValue::Extensions(e))
 	}
 
 
-	pub fn build(self) -> Result<Extension,&'static str> {
+	pub fn build(self) -> Result<Extension,FhirError> {
 		match (self.uri, self.value) {
 			(Some(u), Some(v)) => Ok(Extension{id: self.id, uri: u, value: v}),
-			(None, Some(_)) => Err("URI missing"),
-			(Some(_),None) => Err("Value missing"),
-			(None, None) => Err("Both URI and Value missing")
+			(None, Some(_)) => Err(FhirError::missing("URI missing")),
+			(Some(_),None) => Err(FhirError::missing("Value missing")),
+			(None, None) => Err(FhirError::missing("Both URI and Value missing"))
 		}
 	}
 }
@@ -185,3 +496,138 @@ fn test_extension_with_subextensions() {
 	assert_eq!(j, e.to_json());
 }
 
+
+#[test]
+fn test_extension_round_trip() {
+	let docs = [
+		r#"{"url": "http://example.org/is_happy", "id": "ext_id1", "valueBoolean": false}"#,
+		r#"{"url": "http://example.org/is_happy", "valueCoding": {"system": "http://example.org/mycode", "code": "abc123", "display": "Alpha Bravo Charlie 123"}}"#,
+		r#"{"url": "http://example.org/happy_alpha", "extension": [{"url": "http://example.org/is_happy", "valueBoolean": false},{"url": "http://example.org/myalpha", "valueString": "abc"}]}"#,
+		r#"{"url": "http://example.org/when", "valueDateTime": "2015-05-02T05:34:00-07:00"}"#];
+	for d in docs.iter() {
+		let j = Json::from_str(d).unwrap();
+		assert_eq!(j, Extension::from_json(&j).unwrap().to_json());
+	}
+}
+
+#[test]
+fn test_invalid_extension_from_json() {
+	let missing_url = Json::from_str(r#"{"valueBoolean": false}"#).unwrap();
+	assert!(Extension::from_json(&missing_url).is_err());
+	let two_values = Json::from_str(r#"{"url": "http://example.org/a", "valueBoolean": false, "valueString": "a"}"#).unwrap();
+	assert!(Extension::from_json(&two_values).is_err());
+	let not_a_type = Json::from_str(r#"{"url": "http://example.org/a", "valuePatient": {"active": true}}"#).unwrap();
+	assert!(Extension::from_json(&not_a_type).is_err());
+}
+
+#[test]
+fn test_extension_datatype_values() {
+	let values = [r#""valueAnnotation": {"text": "seen"}"#, r#""valueMoney": {"value": 5, "currency": "EUR"}"#,
+		r#""valueAge": {"value": 40, "unit": "a"}"#, r#""valueDuration": {"value": 2, "unit": "h"}"#,
+		r#""valueMeta": {"versionId": "1"}"#, r#""valueDosage": {"text": "once"}"#, r#""valueContactDetail": {"name": "Jo"}"#,
+		r#""valueUsageContext": {"code": {"code": "focus"}}"#, r#""valueExpression": {"expression": "true"}"#,
+		r#""valueSampledData": {"intervalUnit": "ms", "dimensions": 1}"#];
+	for v in values.iter() {
+		let j = Json::from_str(&format!(r#"{{"url": "http://example.org/a", {}}}"#, v)).unwrap();
+		assert_eq!(j, Extension::from_json(&j).unwrap().to_json(), "{}", v);
+	}
+}
+
+#[test]
+fn test_extension_to_xml() {
+	let e = Extension::builder()
+		.uri(Url::parse("http://example.org/is_happy").ok().unwrap())
+		.id("ext_id1")
+		.atom(Primitive::from(false))
+		.and_then(|e| e.build())
+		.ok().unwrap();
+	assert_eq!(r#"<extension xmlns="http://hl7.org/fhir" id="ext_id1" url="http://example.org/is_happy"><valueBoolean value="false"/></extension>"#, e.to_xml());
+}
+
+#[test]
+fn test_composite_extension_to_xml() {
+	let elt = Element::with("Coding", vec![
+		Element::with("system",Url::parse("http://example.org/mycode").ok().unwrap()),
+		Element::with("code","abc123")]);
+	let e = Extension::builder()
+		.uri(Url::parse("http://example.org/coded").ok().unwrap())
+		.composite(elt)
+		.and_then(|e| e.build())
+		.ok().unwrap();
+	assert_eq!(concat!(r#"<extension xmlns="http://hl7.org/fhir" url="http://example.org/coded">"#,
+		r#"<valueCoding><system value="http://example.org/mycode"/><code value="abc123"/></valueCoding></extension>"#), e.to_xml());
+}
+
+#[test]
+fn test_extension_xml_to_json() {
+	let cases = [
+		(r#"<extension xmlns="http://hl7.org/fhir" id="ext_id1" url="http://example.org/is_happy"><valueBoolean value="false"/></extension>"#,
+		 r#"{"url": "http://example.org/is_happy", "id": "ext_id1", "valueBoolean": false}"#),
+		(r#"<extension url="http://example.org/is_happy"><valueCoding><system value="http://example.org/mycode"/><code value="abc123"/><display value="Alpha Bravo Charlie 123"/></valueCoding></extension>"#,
+		 r#"{"url": "http://example.org/is_happy", "valueCoding": {"system": "http://example.org/mycode", "code": "abc123", "display": "Alpha Bravo Charlie 123"}}"#),
+		(r#"<extension url="http://example.org/happy_alpha"><extension url="http://example.org/is_happy"><valueBoolean value="false"/></extension><extension url="http://example.org/myalpha"><valueString value="abc"/></extension></extension>"#,
+		 r#"{"url": "http://example.org/happy_alpha", "extension": [{"url": "http://example.org/is_happy", "valueBoolean": false},{"url": "http://example.org/myalpha", "valueString": "abc"}]}"#)];
+	for &(x, j) in cases.iter() {
+		let e = Extension::from_xml(x).unwrap();
+		assert_eq!(Json::from_str(j).unwrap(), e.to_json());
+	}
+}
+
+#[test]
+fn test_extension_xml_round_trip() {
+	let x = r#"<extension xmlns="http://hl7.org/fhir" url="http://example.org/myalpha"><valueString value="12"/></extension>"#;
+	assert_eq!(x, Extension::from_xml(x).unwrap().to_xml());
+}
+
+#[test]
+fn test_extension_lookup() {
+	use resource::Resource;
+	let r = Resource::from_json(&Json::from_str(r#"{"resourceType": "Patient",
+		"extension": [{"url": "http://example.org/is_happy", "valueBoolean": true},
+			{"url": "http://example.org/happy_alpha", "extension": [{"url": "http://example.org/myalpha", "valueString": "abc"}]}],
+		"name": [{"given": ["Jo"], "_given": [{"extension": [{"url": "http://example.org/nick", "valueString": "Joey"}]}]}]}"#).unwrap()).unwrap();
+	assert_eq!(Some(true), r.extension_value::<bool>("http://example.org/is_happy").unwrap());
+	assert_eq!(None, r.extension_value::<bool>("http://example.org/none").unwrap());
+	assert!(r.extension_value::<i32>("http://example.org/is_happy").is_err());
+	let alpha = r.extension_at(&["http://example.org/happy_alpha", "http://example.org/myalpha"]).unwrap();
+	assert_eq!("abc", alpha.get::<String>().unwrap());
+	let urls: Vec<&str> = r.all_extensions().iter().map(|e| e.url().as_str()).collect();
+	assert_eq!(vec!["http://example.org/is_happy", "http://example.org/happy_alpha", "http://example.org/myalpha", "http://example.org/nick"], urls);
+}
+
+#[test]
+fn test_extension_mutation() {
+	use element::Value;
+	let ext = |url: &str, v: bool| Extension::builder().uri(Url::parse(url).unwrap()).atom(Primitive::from(v)).and_then(|e| e.build()).unwrap();
+	let mut v = Value::from("Jo");
+	v.extension = vec![ext("http://example.org/a", true), ext("http://example.org/b", true), ext("http://example.org/a", false)];
+	v.replace_extension(ext("http://example.org/a", false)).unwrap();
+	assert_eq!(2, v.extension.len());
+	assert_eq!(Some(false), v.extension_value::<bool>("http://example.org/a").unwrap());
+	assert_eq!(1, v.remove_extensions("http://example.org/b").unwrap().len());
+	assert_eq!(1, v.extensions_by_url("http://example.org/a").len());
+	v.replace_extension(ext("http://example.org/c", true)).unwrap();
+	assert_eq!("http://example.org/c", v.extension[1].url().as_str());
+}
+
+#[test]
+fn test_complex_extension_mutation() {
+	let ext = |url: &str, v: bool| Extension::builder().uri(Url::parse(url).unwrap()).atom(Primitive::from(v)).and_then(|e| e.build()).unwrap();
+	let mut e = Extension::from_json(&Json::from_str(r#"{"url": "http://example.org/pair", "extension": [
+		{"url": "http://example.org/a", "valueBoolean": true}, {"url": "http://example.org/b", "valueBoolean": true}]}"#).unwrap()).unwrap();
+	e.replace_extension(ext("http://example.org/a", false)).unwrap();
+	assert_eq!(Some(false), e.extension_value::<bool>("http://example.org/a").unwrap());
+	assert_eq!(1, e.remove_extensions("http://example.org/b").unwrap().len());
+	assert_eq!(vec!["http://example.org/a"], e.exts().iter().map(|x| x.url().as_str()).collect::<Vec<_>>());
+	// Removing the last sub-extension would leave the extension empty
+	assert!(e.remove_extensions("http://example.org/a").is_err());
+	assert_eq!(1, e.exts().len());
+
+	// An extension with a value keeps it
+	let mut atom = ext("http://example.org/flag", true);
+	assert!(atom.exts().is_empty());
+	assert!(atom.remove_extensions("http://example.org/a").unwrap().is_empty());
+	assert!(atom.replace_extension(ext("http://example.org/a", true)).is_err());
+	assert_eq!(Some(true), atom.get::<bool>().ok());
+	assert!(atom.extensions().is_empty());
+}
@@ -0,0 +1,988 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use chrono::Local;
+use regex::Regex;
+use rustc_serialize::json::ToJson;
+
+use element::{Element, Value, ValueType, NamedFrom};
+use element::choice::{split_choice, is_complex_type};
+use extension::Extension;
+use primitive::{Primitive, Dec, VarDate};
+use resource::Resource;
+use error::FhirError;
+use fhirpath::parser::{Expr, Lit};
+
+// A member of a FHIRPath collection: a node of the resource tree or a
+// System value produced by a literal, operator or function
+#[derive(Clone)]
+pub enum Item<'a> {
+	// A resource and the name it is found by: its type at the root, or contained
+	Resource(&'a str, &'a Resource),
+	Node(&'a str, &'a Value),
+	// A node built from a resource's typed header (id, meta, ...) or an extension
+	Owned(String, Value),
+	Boolean(bool),
+	Integer(i64),
+	Decimal(Dec),
+	Quantity(Dec, String),
+	String(String),
+	Date(String),
+	DateTime(String),
+	Time(String)
+}
+
+impl<'a> fmt::Display for Item<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Item::Resource(_, r) => write!(f, "{}", r.to_json()),
+			Item::Node(..) | Item::Owned(..) => {
+				let v = self.value().unwrap();
+				match v.value {
+					ValueType::Atom(Some(ref p)) => write!(f, "{}", p),
+					_ => write!(f, "{}", v.to_json())
+				}
+			},
+			Item::Boolean(b) => write!(f, "{}", b),
+			Item::Integer(i) => write!(f, "{}", i),
+			Item::Decimal(ref d) => write!(f, "{}", d),
+			Item::Quantity(ref d, ref u) => write!(f, "{} '{}'", d, u),
+			Item::String(ref s) | Item::Date(ref s) | Item::DateTime(ref s) | Item::Time(ref s) => write!(f, "{}", s)
+		}
+	}
+}
+
+impl<'a> Item<'a> {
+	fn value(&self) -> Option<&Value> {
+		match *self {
+			Item::Node(_, v) => Some(v),
+			Item::Owned(_, ref v) => Some(v),
+			_ => None
+		}
+	}
+
+	fn primitive(&self) -> Option<&Primitive> {
+		match self.value() {
+			Some(&Value {value: ValueType::Atom(Some(ref p)), ..}) => Some(p),
+			_ => None
+		}
+	}
+
+	// The System value of a primitive node; other items are returned as they are
+	fn system(&self) -> Item<'a> {
+		let p = match self.primitive() {
+			Some(p) => p,
+			None => return self.clone()
+		};
+		match *p {
+			Primitive::Boolean(b) => Item::Boolean(b),
+			Primitive::Int(i) => Item::Integer(i as i64),
+			Primitive::UInt(i) => Item::Integer(i.value() as i64),
+			Primitive::PInt(i) => Item::Integer(i.value() as i64),
+			Primitive::Integer64(i) => Item::Integer(i),
+			Primitive::Decimal(ref d) => Item::Decimal(d.clone()),
+			Primitive::Date(_) => Item::Date(p.to_string()),
+			Primitive::DateTime(_) | Primitive::Instant(_) => Item::DateTime(p.to_string()),
+			Primitive::Time(_) => Item::Time(p.to_string()),
+			_ => Item::String(p.to_string())
+		}
+	}
+
+	fn type_name(&self) -> &'static str {
+		match self.system() {
+			Item::Resource(..) | Item::Node(..) | Item::Owned(..) => "",
+			Item::Boolean(_) => "Boolean",
+			Item::Integer(_) => "Integer",
+			Item::Decimal(_) => "Decimal",
+			Item::Quantity(..) => "Quantity",
+			Item::String(_) => "String",
+			Item::Date(_) => "Date",
+			Item::DateTime(_) => "DateTime",
+			Item::Time(_) => "Time"
+		}
+	}
+
+	fn name(&self) -> &str {
+		match *self {
+			Item::Resource(name, _) => name,
+			Item::Node(name, _) => name,
+			Item::Owned(ref name, _) => name,
+			_ => ""
+		}
+	}
+
+	// The FHIR type of a node, where the node itself tells it: by its
+	// primitive, as an extension, or by the suffix of a choice element
+	fn fhir_type(&self) -> Option<String> {
+		if let Some(p) = self.primitive() {
+			let t = p.type_suffix();
+			return Some(t[..1].to_lowercase() + &t[1..]);
+		}
+		match *self {
+			Item::Resource(_, r) => Some(r.name.clone()),
+			Item::Node(..) | Item::Owned(..) => match self.name() {
+				"extension" | "modifierExtension" => Some("Extension".to_string()),
+				name => split_choice(name).map(|(_, t)| t).filter(|t| is_complex_type(t)).map(|t| t.to_string())
+			},
+			_ => None
+		}
+	}
+
+	// Child nodes: those of a resource's typed header, its contained
+	// resources and extensions, then the elements
+	fn children(&self) -> Vec<Item<'a>> {
+		let mut out = Vec::new();
+		match *self {
+			Item::Resource(_, r) => {
+				for e in r.header_elts() {
+					push_owned(&mut out, e);
+				}
+				out.extend(r.contained.iter().map(|c| Item::Resource("contained", c)));
+				push_extensions(&mut out, &r.extensions, &r.modifier_extensions);
+				for e in r.elts.iter() {
+					push_value(&mut out, &e.name, &e.value);
+				}
+			},
+			Item::Node(_, v) => {
+				push_extensions(&mut out, &v.extension, &v.modifier_extension);
+				if let ValueType::Elt(ref elts) = v.value {
+					for e in elts.iter() {
+						push_value(&mut out, &e.name, &e.value);
+					}
+				}
+			},
+			Item::Owned(_, ref v) => {
+				push_extensions(&mut out, &v.extension, &v.modifier_extension);
+				if let ValueType::Elt(ref elts) = v.value {
+					for e in elts.iter() {
+						push_owned(&mut out, e.clone());
+					}
+				}
+			},
+			_ => ()
+		}
+		out
+	}
+}
+
+fn push_extensions<'a>(out: &mut Vec<Item<'a>>, exts: &[Extension], mods: &[Extension]) {
+	out.extend(exts.iter().map(|x| Item::Owned("extension".to_string(), x.to_value())));
+	out.extend(mods.iter().map(|x| Item::Owned("modifierExtension".to_string(), x.to_value())));
+}
+
+fn push_value<'a>(out: &mut Vec<Item<'a>>, name: &'a str, v: &'a Value) {
+	match v.value {
+		ValueType::List(ref l) => for x in l.iter() { out.push(Item::Node(name, x)) },
+		_ => out.push(Item::Node(name, v))
+	}
+}
+
+fn push_owned<'a>(out: &mut Vec<Item<'a>>, e: Element) {
+	match e.value.value {
+		ValueType::List(l) => for x in l.into_iter() { out.push(Item::Owned(e.name.clone(), x)) },
+		value => out.push(Item::Owned(e.name, Value {value: value, ..e.value}))
+	}
+}
+
+// Children named `name`; a choice element (value[x]) is found by its base
+// name, when the rest of its name is that of a FHIR type
+fn navigate<'a>(input: &[Item<'a>], name: &str) -> Vec<Item<'a>> {
+	let mut out = Vec::new();
+	for item in input.iter() {
+		if let Item::Resource(n, r) = *item {
+			if n == r.name && r.name == name {
+				out.push(item.clone());
+				continue;
+			}
+		}
+		let children = item.children();
+		let exact = children.iter().any(|c| c.name() == name);
+		out.extend(children.into_iter().filter(|c| if exact {
+			c.name() == name
+		} else {
+			split_choice(c.name()).map_or(false, |(base, _)| base == name)
+		}));
+	}
+	out
+}
+
+// Times without a zone can't be placed on the timeline; compare their local parts
+fn date_key(s: &str) -> &str {
+	match s.find('T') {
+		Some(t) => match s[t..].find(|c| c == 'Z' || c == '+' || c == '-') {
+			Some(z) => &s[..t + z],
+			None => s
+		},
+		None => s
+	}
+}
+
+// Partial dates compare by their shared precision; None when that is indeterminate
+fn compare_dates(a: &str, b: &str) -> Option<Ordering> {
+	if let (Ok(x), Ok(y)) = (VarDate::parse(a), VarDate::parse(b)) {
+		return x.compare(&y);
+	}
+	let (a, b) = (date_key(a), date_key(b));
+	let n = a.len().min(b.len());
+	match a[..n].cmp(&b[..n]) {
+		Ordering::Equal if a.len() == b.len() => Some(Ordering::Equal),
+		Ordering::Equal => None,
+		o => Some(o)
+	}
+}
+
+fn is_temporal(i: &Item) -> bool {
+	match *i {
+		Item::Date(_) | Item::DateTime(_) | Item::Time(_) => true,
+		_ => false
+	}
+}
+
+// UCUM units of time, in seconds
+const TIME_UNITS: &'static [(&'static str, &'static str)] = &[("ms", "0.001"), ("s", "1"), ("min", "60"),
+	("h", "3600"), ("d", "86400"), ("wk", "604800")];
+const METRIC_PREFIXES: &'static [(&'static str, &'static str)] = &[("k", "1000"), ("h", "100"), ("d", "0.1"),
+	("c", "0.01"), ("m", "0.001"), ("u", "0.000001"), ("n", "0.000000001"), ("p", "0.000000000001")];
+const METRIC_UNITS: &'static [&'static str] = &["g", "m", "L", "l", "mol", "s"];
+
+// A quantity in the base unit of its UCUM unit, where that is a unit of
+// time or a metric prefix on a base unit; calendar durations of a second
+// or less are the UCUM ones, longer ones only compare among themselves
+fn canonical(d: &Dec, unit: &str) -> (Dec, String) {
+	let unit = match unit {
+		"second" | "seconds" => "s",
+		"millisecond" | "milliseconds" => "ms",
+		u if u.len() > 1 && u.ends_with('s') && ["year", "month", "week", "day", "hour", "minute"].contains(&&u[..u.len() - 1]) => &u[..u.len() - 1],
+		u => u
+	};
+	let scale = |f: &str, base: &str| (d.clone() * Dec::from_str(f).unwrap(), base.to_string());
+	if let Some(&(_, f)) = TIME_UNITS.iter().find(|&&(u, _)| u == unit) {
+		return scale(f, "s");
+	}
+	if METRIC_UNITS.contains(&unit) {
+		return (d.clone(), unit.to_string());
+	}
+	match METRIC_PREFIXES.iter().find(|&&(p, _)| unit.starts_with(p) && METRIC_UNITS.contains(&&unit[p.len()..])) {
+		Some(&(p, f)) => scale(f, &unit[p.len()..]),
+		None => (d.clone(), unit.to_string())
+	}
+}
+
+fn compare(a: &Item, b: &Item) -> Result<Option<Ordering>, FhirError> {
+	Ok(match (a.system(), b.system()) {
+		// Quantities of units that don't convert to each other are incomparable
+		(Item::Quantity(ref x, ref u), Item::Quantity(ref y, ref v)) => {
+			let (x, u) = canonical(x, u);
+			let (y, v) = canonical(y, v);
+			if u == v { Some(x.cmp(&y)) } else { None }
+		},
+		(Item::Integer(x), Item::Integer(y)) => Some(x.cmp(&y)),
+		(Item::Integer(x), Item::Decimal(ref y)) => Some(Dec::from_i64(x).cmp(y)),
+		(Item::Decimal(ref x), Item::Integer(y)) => Some(x.cmp(&Dec::from_i64(y))),
+		(Item::Decimal(ref x), Item::Decimal(ref y)) => Some(x.cmp(y)),
+		(Item::String(ref x), Item::String(ref y)) => Some(x.cmp(y)),
+		(ref x, ref y) if is_temporal(x) || is_temporal(y) => match (x, y) {
+			(&Item::Date(ref s), &Item::Date(ref t)) | (&Item::DateTime(ref s), &Item::DateTime(ref t))
+				| (&Item::Date(ref s), &Item::DateTime(ref t)) | (&Item::DateTime(ref s), &Item::Date(ref t))
+				| (&Item::Time(ref s), &Item::Time(ref t))
+				| (&Item::String(ref s), &Item::Date(ref t)) | (&Item::String(ref s), &Item::DateTime(ref t))
+				| (&Item::Date(ref s), &Item::String(ref t)) | (&Item::DateTime(ref s), &Item::String(ref t))
+				=> compare_dates(s, t),
+			_ => return Err(FhirError::invalid(&format!("{} {}", x, y), "Values are not comparable"))
+		},
+		(x, y) => return Err(FhirError::invalid(&format!("{} {}", x, y), "Values are not comparable"))
+	})
+}
+
+fn equals(a: &Item, b: &Item) -> Option<bool> {
+	match (a.system(), b.system()) {
+		(Item::Boolean(x), Item::Boolean(y)) => Some(x == y),
+		(Item::Resource(_, x), Item::Resource(_, y)) => Some(x.to_json() == y.to_json()),
+		(ref x, ref y) if x.value().is_some() && y.value().is_some() => Some(x.value().map(|v| v.to_json()) == y.value().map(|v| v.to_json())),
+		(x, y) => match compare(&x, &y) {
+			Ok(o) => o.map(|o| o == Ordering::Equal),
+			Err(_) => Some(false)
+		}
+	}
+}
+
+fn normalize(s: &str) -> String {
+	s.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn equivalent(a: &Item, b: &Item) -> bool {
+	match (a.system(), b.system()) {
+		(Item::String(ref x), Item::String(ref y)) => normalize(x) == normalize(y),
+		(Item::Decimal(ref x), Item::Decimal(ref y)) => x.equivalent(y),
+		(ref x, ref y) => equals(x, y).unwrap_or(false)
+	}
+}
+
+fn contains(col: &[Item], x: &Item) -> bool {
+	col.iter().any(|y| equals(x, y) == Some(true))
+}
+
+fn distinct<'a>(col: Vec<Item<'a>>) -> Vec<Item<'a>> {
+	let mut out: Vec<Item<'a>> = Vec::new();
+	for x in col {
+		if !contains(&out, &x) {
+			out.push(x);
+		}
+	}
+	out
+}
+
+fn singleton<'a>(col: &[Item<'a>], what: &str) -> Result<Option<Item<'a>>, FhirError> {
+	match col.len() {
+		0 => Ok(None),
+		1 => Ok(Some(col[0].system())),
+		n => Err(FhirError::invalid(&n.to_string(), "Expected a single value").at(what))
+	}
+}
+
+// Singleton evaluation of collections as a boolean: None when empty
+fn to_bool(col: &[Item], what: &str) -> Result<Option<bool>, FhirError> {
+	Ok(match singleton(col, what)? {
+		Some(Item::Boolean(b)) => Some(b),
+		Some(_) => Some(true),
+		None => None
+	})
+}
+
+fn boolean<'a>(b: Option<bool>) -> Vec<Item<'a>> {
+	b.map(Item::Boolean).into_iter().collect()
+}
+
+fn string_arg(col: &[Item], what: &str) -> Result<Option<String>, FhirError> {
+	match singleton(col, what)? {
+		Some(Item::String(s)) => Ok(Some(s)),
+		Some(x) => Err(FhirError::invalid(&x.to_string(), "Expected a string").at(what)),
+		None => Ok(None)
+	}
+}
+
+fn integer_arg(col: &[Item], what: &str) -> Result<Option<i64>, FhirError> {
+	match singleton(col, what)? {
+		Some(Item::Integer(i)) => Ok(Some(i)),
+		Some(x) => Err(FhirError::invalid(&x.to_string(), "Expected an integer").at(what)),
+		None => Ok(None)
+	}
+}
+
+fn number(i: &Item) -> Option<Dec> {
+	match *i {
+		Item::Integer(x) => Some(Dec::from_i64(x)),
+		Item::Decimal(ref x) => Some(x.clone()),
+		_ => None
+	}
+}
+
+fn arithmetic<'a>(op: &str, a: Item<'a>, b: Item<'a>) -> Result<Vec<Item<'a>>, FhirError> {
+	let r = match (op, &a, &b) {
+		("+", &Item::String(ref x), &Item::String(ref y)) => Item::String(format!("{}{}", x, y)),
+		// Integer results out of range are empty, as is division by zero
+		("+", &Item::Integer(x), &Item::Integer(y)) => return Ok(x.checked_add(y).map(Item::Integer).into_iter().collect()),
+		("-", &Item::Integer(x), &Item::Integer(y)) => return Ok(x.checked_sub(y).map(Item::Integer).into_iter().collect()),
+		("*", &Item::Integer(x), &Item::Integer(y)) => return Ok(x.checked_mul(y).map(Item::Integer).into_iter().collect()),
+		("div", &Item::Integer(x), &Item::Integer(y)) => return Ok(x.checked_div(y).map(Item::Integer).into_iter().collect()),
+		("mod", &Item::Integer(x), &Item::Integer(y)) => return Ok(x.checked_rem(y).map(Item::Integer).into_iter().collect()),
+		_ => match (number(&a), number(&b)) {
+			(Some(x), Some(y)) => match op {
+				"+" => Item::Decimal(x + y),
+				"-" => Item::Decimal(x - y),
+				"*" => Item::Decimal(x * y),
+				"/" => return Ok(x.checked_div(&y).map(Item::Decimal).into_iter().collect()),
+				"div" => return Ok(x.checked_divmod(&y).and_then(|(q, _)| q.to_i64()).map(Item::Integer).into_iter().collect()),
+				_ => return Ok(x.checked_divmod(&y).map(|(_, r)| Item::Decimal(r)).into_iter().collect())
+			},
+			_ => return Err(FhirError::invalid(&format!("{} {} {}", a, op, b), "Invalid operands"))
+		}
+	};
+	Ok(vec![r])
+}
+
+fn type_matches(i: &Item, t: &str) -> bool {
+	let t = if t.starts_with("System.") || t.starts_with("FHIR.") { &t[t.find('.').unwrap() + 1..] } else { t };
+	match *i {
+		Item::Resource(_, r) => r.name == t || t == "Resource" || t == "DomainResource",
+		Item::Node(..) | Item::Owned(..) => match i.primitive() {
+			Some(p) => p.conforms_to(t) || i.type_name() == t,
+			None => i.fhir_type().map_or(false, |f| f == t)
+		},
+		_ => i.type_name() == t
+	}
+}
+
+// The TypeInfo of type(): the System type of a System value, else the FHIR
+// type where the node tells it
+fn type_info<'a>(i: &Item) -> Option<Item<'a>> {
+	let (ns, name) = match *i {
+		Item::Resource(..) | Item::Node(..) | Item::Owned(..) => ("FHIR", i.fhir_type()?),
+		_ => ("System", i.type_name().to_string())
+	};
+	Some(Item::Owned("TypeInfo".to_string(), Value::from(vec![Element::with("namespace", ns), Element::with("name", name)])))
+}
+
+fn regex(re: &str) -> Result<Regex, FhirError> {
+	// '.' matches any character, newlines included
+	Regex::new(&format!("(?s){}", re)).map_err(|_| FhirError::invalid(re, "Invalid regular expression"))
+}
+
+// The url of an extension node
+fn extension_url(i: &Item) -> Option<String> {
+	i.children().into_iter().find(|c| c.name() == "url").map(|c| c.to_string())
+}
+
+// The type named by the argument of ofType(), is() or as(), e.g. FHIR.Patient
+fn type_arg(e: &Expr) -> Result<&str, FhirError> {
+	match *e {
+		Expr::Ident(ref t) => Ok(t),
+		Expr::Member(ref ns, ref t) => match **ns {
+			Expr::Ident(ref ns) if ns == "FHIR" || ns == "System" => Ok(t),
+			_ => Err(FhirError::invalid(&format!("{:?}", e), "Expected a type name"))
+		},
+		_ => Err(FhirError::invalid(&format!("{:?}", e), "Expected a type name"))
+	}
+}
+
+struct Scope<'a> {
+	this: Vec<Item<'a>>,
+	index: Option<usize>,
+	total: Vec<Item<'a>>
+}
+
+pub struct Evaluator<'a> {
+	resource: &'a Resource
+}
+
+impl<'a> Evaluator<'a> {
+	pub fn new(resource: &'a Resource) -> Self {
+		Evaluator {resource: resource}
+	}
+
+	pub fn evaluate(&self, e: &Expr) -> Result<Vec<Item<'a>>, FhirError> {
+		let root = vec![Item::Resource(&self.resource.name, self.resource)];
+		self.eval(e, &root, &Scope {this: root.clone(), index: None, total: vec![]})
+	}
+
+	fn eval(&self, e: &Expr, input: &[Item<'a>], scope: &Scope<'a>) -> Result<Vec<Item<'a>>, FhirError> {
+		Ok(match *e {
+			Expr::Empty => vec![],
+			Expr::Literal(ref l) => vec![match *l {
+				Lit::Boolean(b) => Item::Boolean(b),
+				Lit::Integer(i) => Item::Integer(i),
+				Lit::Decimal(ref d) => Item::Decimal(d.clone()),
+				Lit::Quantity(ref d, ref u) => Item::Quantity(d.clone(), u.clone()),
+				Lit::String(ref s) => Item::String(s.clone()),
+				Lit::Date(ref s) => Item::Date(s.clone()),
+				Lit::DateTime(ref s) => Item::DateTime(s.clone()),
+				Lit::Time(ref s) => Item::Time(s.clone())
+			}],
+			Expr::Ident(ref name) => navigate(input, name),
+			Expr::This => scope.this.clone(),
+			Expr::Index => scope.index.map(|i| Item::Integer(i as i64)).into_iter().collect(),
+			Expr::Total => scope.total.clone(),
+			Expr::External(ref name) => match name.as_ref() {
+				"resource" | "context" | "rootResource" => vec![Item::Resource(&self.resource.name, self.resource)],
+				"ucum" => vec![Item::String("http://unitsofmeasure.org".to_string())],
+				"sct" => vec![Item::String("http://snomed.info/sct".to_string())],
+				"loinc" => vec![Item::String("http://loinc.org".to_string())],
+				n if n.starts_with("ext-") => vec![Item::String(format!("http://hl7.org/fhir/StructureDefinition/{}", &n[4..]))],
+				n if n.starts_with("vs-") => vec![Item::String(format!("http://hl7.org/fhir/ValueSet/{}", &n[3..]))],
+				_ => return Err(FhirError::unexpected(name, "Unknown external constant"))
+			},
+			Expr::Member(ref t, ref name) => navigate(&self.eval(t, input, scope)?, name),
+			Expr::Call(ref t, ref name, ref args) => {
+				let focus = match *t {
+					Some(ref t) => self.eval(t, input, scope)?,
+					None => input.to_vec()
+				};
+				self.call(name, focus, args, scope).map_err(|e| e.at(name))?
+			},
+			Expr::Indexer(ref t, ref i) => {
+				let col = self.eval(t, input, scope)?;
+				match integer_arg(&self.eval(i, &scope.this, scope)?, "[]")? {
+					Some(i) if i >= 0 && (i as usize) < col.len() => vec![col[i as usize].clone()],
+					_ => vec![]
+				}
+			},
+			Expr::Negate(ref x) => match singleton(&self.eval(x, input, scope)?, "-")? {
+				Some(Item::Integer(i)) => i.checked_neg().map(Item::Integer).into_iter().collect(),
+				Some(Item::Decimal(d)) => vec![Item::Decimal(-d)],
+				Some(Item::Quantity(d, u)) => vec![Item::Quantity(-d, u)],
+				Some(x) => return Err(FhirError::invalid(&x.to_string(), "Cannot negate")),
+				None => vec![]
+			},
+			Expr::Is(ref x, ref t) => {
+				let col = self.eval(x, input, scope)?;
+				match singleton(&col, "is")? {
+					Some(_) => vec![Item::Boolean(type_matches(&col[0], t))],
+					None => vec![]
+				}
+			},
+			Expr::As(ref x, ref t) => self.eval(x, input, scope)?.into_iter().filter(|i| type_matches(i, t)).collect(),
+			Expr::Binary(ref op, ref l, ref r) => {
+				let a = self.eval(l, input, scope)?;
+				let b = self.eval(r, input, scope)?;
+				self.binary(op, a, b).map_err(|e| e.at(op))?
+			}
+		})
+	}
+
+	fn binary(&self, op: &str, a: Vec<Item<'a>>, b: Vec<Item<'a>>) -> Result<Vec<Item<'a>>, FhirError> {
+		Ok(match op {
+			"|" => distinct(a.into_iter().chain(b.into_iter()).collect()),
+			"and" => boolean(match (to_bool(&a, op)?, to_bool(&b, op)?) {
+				(Some(false), _) | (_, Some(false)) => Some(false),
+				(Some(true), Some(true)) => Some(true),
+				_ => None
+			}),
+			"or" => boolean(match (to_bool(&a, op)?, to_bool(&b, op)?) {
+				(Some(true), _) | (_, Some(true)) => Some(true),
+				(Some(false), Some(false)) => Some(false),
+				_ => None
+			}),
+			"xor" => boolean(match (to_bool(&a, op)?, to_bool(&b, op)?) {
+				(Some(x), Some(y)) => Some(x != y),
+				_ => None
+			}),
+			"implies" => boolean(match (to_bool(&a, op)?, to_bool(&b, op)?) {
+				(Some(false), _) | (_, Some(true)) => Some(true),
+				(Some(true), Some(false)) => Some(false),
+				_ => None
+			}),
+			"=" | "!=" => {
+				if a.is_empty() || b.is_empty() {
+					return Ok(vec![]);
+				}
+				let eq = if a.len() != b.len() {
+					Some(false)
+				} else {
+					a.iter().zip(b.iter()).fold(Some(true), |acc, (x, y)| match (acc, equals(x, y)) {
+						(Some(false), _) | (_, Some(false)) => Some(false),
+						(Some(true), Some(true)) => Some(true),
+						_ => None
+					})
+				};
+				boolean(if op == "=" { eq } else { eq.map(|b| !b) })
+			},
+			"~" | "!~" => {
+				let eq = a.len() == b.len() && a.iter().all(|x| b.iter().any(|y| equivalent(x, y)));
+				vec![Item::Boolean(if op == "~" { eq } else { !eq })]
+			},
+			"<" | ">" | "<=" | ">=" => match (singleton(&a, op)?, singleton(&b, op)?) {
+				(Some(x), Some(y)) => boolean(compare(&x, &y)?.map(|o| match op {
+					"<" => o == Ordering::Less,
+					">" => o == Ordering::Greater,
+					"<=" => o != Ordering::Greater,
+					_ => o != Ordering::Less
+				})),
+				_ => vec![]
+			},
+			"in" => match singleton(&a, op)? {
+				Some(x) => vec![Item::Boolean(contains(&b, &x))],
+				None => vec![]
+			},
+			"contains" => match singleton(&b, op)? {
+				Some(x) => vec![Item::Boolean(contains(&a, &x))],
+				None => vec![]
+			},
+			"&" => {
+				let x = singleton(&a, op)?.map_or(String::new(), |x| x.to_string());
+				let y = singleton(&b, op)?.map_or(String::new(), |y| y.to_string());
+				vec![Item::String(x + &y)]
+			},
+			_ => match (singleton(&a, op)?, singleton(&b, op)?) {
+				(Some(x), Some(y)) => arithmetic(op, x, y)?,
+				_ => vec![]
+			}
+		})
+	}
+
+	// Evaluate a lambda argument (where, select, ...) once per item with $this bound
+	fn each(&self, e: &Expr, focus: &[Item<'a>]) -> Result<Vec<Vec<Item<'a>>>, FhirError> {
+		focus.iter().enumerate().map(|(i, item)| {
+			let this = vec![item.clone()];
+			self.eval(e, &this, &Scope {this: this.clone(), index: Some(i), total: vec![]})
+		}).collect()
+	}
+
+	fn call(&self, name: &str, focus: Vec<Item<'a>>, args: &[Expr], scope: &Scope<'a>) -> Result<Vec<Item<'a>>, FhirError> {
+		let arity = |n: usize| if args.len() == n {
+			Ok(())
+		} else {
+			Err(FhirError::invalid(&args.len().to_string(), "Wrong number of arguments"))
+		};
+		let arg = |i: usize| self.eval(&args[i], &scope.this, scope);
+		let str_fn = |f: &dyn Fn(&str) -> Result<Vec<Item<'a>>, FhirError>| -> Result<Vec<Item<'a>>, FhirError> {
+			match singleton(&focus, name)? {
+				Some(Item::String(s)) => f(&s),
+				Some(x) => Err(FhirError::invalid(&x.to_string(), "Expected a string")),
+				None => Ok(vec![])
+			}
+		};
+		Ok(match name {
+			"empty" => vec![Item::Boolean(focus.is_empty())],
+			"exists" => match args.len() {
+				0 => vec![Item::Boolean(!focus.is_empty())],
+				_ => vec![Item::Boolean(self.each(&args[0], &focus)?.iter().any(|r| to_bool(r, name) == Ok(Some(true))))]
+			},
+			"all" => {
+				arity(1)?;
+				vec![Item::Boolean(self.each(&args[0], &focus)?.iter().all(|r| to_bool(r, name) == Ok(Some(true))))]
+			},
+			"allTrue" | "anyTrue" | "allFalse" | "anyFalse" => {
+				let bools = focus.iter().map(|i| match i.system() {
+					Item::Boolean(b) => Ok(b),
+					x => Err(FhirError::invalid(&x.to_string(), "Expected a boolean"))
+				}).collect::<Result<Vec<_>, _>>()?;
+				vec![Item::Boolean(match name {
+					"allTrue" => bools.iter().all(|b| *b),
+					"anyTrue" => bools.iter().any(|b| *b),
+					"allFalse" => bools.iter().all(|b| !*b),
+					_ => bools.iter().any(|b| !*b)
+				})]
+			},
+			"subsetOf" => {
+				arity(1)?;
+				let other = arg(0)?;
+				vec![Item::Boolean(focus.iter().all(|x| contains(&other, x)))]
+			},
+			"supersetOf" => {
+				arity(1)?;
+				vec![Item::Boolean(arg(0)?.iter().all(|x| contains(&focus, x)))]
+			},
+			"count" => vec![Item::Integer(focus.len() as i64)],
+			"distinct" => distinct(focus),
+			"isDistinct" => vec![Item::Boolean(distinct(focus.clone()).len() == focus.len())],
+			"where" => {
+				arity(1)?;
+				let keep = self.each(&args[0], &focus)?;
+				focus.into_iter().zip(keep.iter()).filter(|&(_, k)| to_bool(k, name) == Ok(Some(true))).map(|(i, _)| i).collect()
+			},
+			"select" => {
+				arity(1)?;
+				self.each(&args[0], &focus)?.into_iter().flat_map(|c| c.into_iter()).collect()
+			},
+			"repeat" => {
+				arity(1)?;
+				let mut out: Vec<Item<'a>> = Vec::new();
+				let mut next = focus;
+				while !next.is_empty() {
+					let found = self.each(&args[0], &next)?;
+					next = Vec::new();
+					for x in found.into_iter().flat_map(|c| c.into_iter()) {
+						if !contains(&out, &x) {
+							out.push(x.clone());
+							next.push(x);
+						}
+					}
+				}
+				out
+			},
+			"ofType" => {
+				arity(1)?;
+				let t = type_arg(&args[0])?;
+				focus.into_iter().filter(|i| type_matches(i, t)).collect()
+			},
+			"single" => match focus.len() {
+				0 | 1 => focus,
+				n => return Err(FhirError::invalid(&n.to_string(), "Expected a single value"))
+			},
+			"first" => focus.into_iter().take(1).collect(),
+			"last" => focus.into_iter().last().into_iter().collect(),
+			"tail" => focus.into_iter().skip(1).collect(),
+			"skip" | "take" => {
+				arity(1)?;
+				let n = integer_arg(&arg(0)?, name)?.unwrap_or(0).max(0) as usize;
+				if name == "skip" { focus.into_iter().skip(n).collect() } else { focus.into_iter().take(n).collect() }
+			},
+			"union" => {
+				arity(1)?;
+				distinct(focus.into_iter().chain(arg(0)?.into_iter()).collect())
+			},
+			"combine" => {
+				arity(1)?;
+				focus.into_iter().chain(arg(0)?.into_iter()).collect()
+			},
+			"intersect" => {
+				arity(1)?;
+				let other = arg(0)?;
+				distinct(focus.into_iter().filter(|x| contains(&other, x)).collect())
+			},
+			"exclude" => {
+				arity(1)?;
+				let other = arg(0)?;
+				focus.into_iter().filter(|x| !contains(&other, x)).collect()
+			},
+			"iif" => {
+				if args.len() < 2 || args.len() > 3 {
+					return Err(FhirError::invalid(&args.len().to_string(), "Wrong number of arguments"));
+				}
+				if to_bool(&arg(0)?, name)? == Some(true) {
+					arg(1)?
+				} else if args.len() == 3 {
+					arg(2)?
+				} else {
+					vec![]
+				}
+			},
+			"not" => boolean(to_bool(&focus, name)?.map(|b| !b)),
+			"hasValue" => vec![Item::Boolean(focus.len() == 1 && focus[0].primitive().is_some())],
+			"children" => focus.iter().flat_map(|i| i.children().into_iter()).collect(),
+			"descendants" => {
+				let mut out = Vec::new();
+				let mut next = self.call("children", focus, &[], scope)?;
+				while !next.is_empty() {
+					out.extend(next.iter().cloned());
+					next = self.call("children", next, &[], scope)?;
+				}
+				out
+			},
+			"is" | "as" => {
+				arity(1)?;
+				let t = type_arg(&args[0])?;
+				if name == "is" {
+					match singleton(&focus, name)? {
+						Some(_) => vec![Item::Boolean(type_matches(&focus[0], t))],
+						None => vec![]
+					}
+				} else {
+					focus.into_iter().filter(|i| type_matches(i, t)).collect()
+				}
+			},
+			"extension" => {
+				arity(1)?;
+				match string_arg(&arg(0)?, name)? {
+					Some(url) => navigate(&focus, "extension").into_iter().filter(|x| extension_url(x).as_ref() == Some(&url)).collect(),
+					None => vec![]
+				}
+			},
+			"type" => focus.iter().filter_map(type_info).collect(),
+			"trace" => focus,
+			"today" => vec![Item::Date(Local::now().format("%Y-%m-%d").to_string())],
+			"now" => vec![Item::DateTime(Local::now().to_rfc3339())],
+			"toString" => singleton(&focus, name)?.map(|x| Item::String(x.to_string())).into_iter().collect(),
+			"toInteger" => match singleton(&focus, name)? {
+				Some(Item::Integer(i)) => vec![Item::Integer(i)],
+				Some(Item::Boolean(b)) => vec![Item::Integer(b as i64)],
+				Some(Item::String(s)) => s.parse().map(Item::Integer).into_iter().collect(),
+				_ => vec![]
+			},
+			"toBoolean" => match singleton(&focus, name)? {
+				Some(Item::Boolean(b)) => vec![Item::Boolean(b)],
+				Some(Item::Integer(i)) if i == 0 || i == 1 => vec![Item::Boolean(i == 1)],
+				Some(Item::Decimal(ref d)) if d.is_zero() || *d == Dec::from_i64(1) => vec![Item::Boolean(!d.is_zero())],
+				Some(Item::String(s)) => match s.to_lowercase().as_ref() {
+					"true" | "t" | "yes" | "y" | "1" | "1.0" => vec![Item::Boolean(true)],
+					"false" | "f" | "no" | "n" | "0" | "0.0" => vec![Item::Boolean(false)],
+					_ => vec![]
+				},
+				_ => vec![]
+			},
+			"toDate" | "toDateTime" => match singleton(&focus, name)? {
+				Some(Item::Date(s)) | Some(Item::DateTime(s)) | Some(Item::String(s)) => match VarDate::parse(&s) {
+					Ok(_) if name == "toDate" && s.len() <= 10 => vec![Item::Date(s)],
+					Ok(_) if name == "toDateTime" => vec![Item::DateTime(s)],
+					_ => vec![]
+				},
+				_ => vec![]
+			},
+			"toTime" => match singleton(&focus, name)? {
+				Some(Item::Time(s)) => vec![Item::Time(s)],
+				Some(Item::String(s)) => if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit() || c == ':' || c == '.') {
+					vec![Item::Time(s)]
+				} else {
+					vec![]
+				},
+				_ => vec![]
+			},
+			"convertsToBoolean" | "convertsToInteger" | "convertsToDecimal" | "convertsToString"
+				| "convertsToDate" | "convertsToDateTime" | "convertsToTime" => {
+				if focus.is_empty() {
+					return Ok(vec![]);
+				}
+				let to = format!("to{}", &name["convertsTo".len()..]);
+				vec![Item::Boolean(!self.call(&to, focus, args, scope)?.is_empty())]
+			},
+			"toDecimal" => match singleton(&focus, name)? {
+				Some(Item::Integer(i)) => vec![Item::Decimal(Dec::from_i64(i))],
+				Some(Item::Decimal(d)) => vec![Item::Decimal(d)],
+				Some(Item::Boolean(b)) => vec![Item::Decimal(Dec::from_i64(b as i64))],
+				Some(Item::String(s)) => Dec::from_str(&s).map(Item::Decimal).into_iter().collect(),
+				_ => vec![]
+			},
+			"abs" | "ceiling" | "floor" | "round" | "truncate" => match singleton(&focus, name)? {
+				Some(Item::Integer(i)) if name == "abs" => vec![Item::Integer(i.abs())],
+				Some(Item::Integer(i)) => vec![Item::Integer(i)],
+				Some(Item::Decimal(d)) => match name {
+					"abs" => vec![Item::Decimal(d.abs())],
+					"round" => {
+						let p = match args.len() { 0 => 0, _ => integer_arg(&arg(0)?, name)?.unwrap_or(0) };
+						vec![Item::Decimal(d.round(p as i32))]
+					},
+					_ => {
+						let i = match name { "ceiling" => d.ceil(), "floor" => d.floor(), _ => d.trunc() };
+						i.to_i64().map(Item::Integer).into_iter().collect()
+					}
+				},
+				Some(x) => return Err(FhirError::invalid(&x.to_string(), "Expected a number")),
+				None => vec![]
+			},
+			"length" => str_fn(&|s| Ok(vec![Item::Integer(s.chars().count() as i64)]))?,
+			"upper" => str_fn(&|s| Ok(vec![Item::String(s.to_uppercase())]))?,
+			"lower" => str_fn(&|s| Ok(vec![Item::String(s.to_lowercase())]))?,
+			"trim" => str_fn(&|s| Ok(vec![Item::String(s.trim().to_string())]))?,
+			"toChars" => str_fn(&|s| Ok(s.chars().map(|c| Item::String(c.to_string())).collect()))?,
+			"startsWith" | "endsWith" | "contains" | "indexOf" | "split" => {
+				arity(1)?;
+				let a = match string_arg(&arg(0)?, name)? {
+					Some(a) => a,
+					None => return Ok(vec![])
+				};
+				str_fn(&|s| Ok(match name {
+					"startsWith" => vec![Item::Boolean(s.starts_with(a.as_str()))],
+					"endsWith" => vec![Item::Boolean(s.ends_with(a.as_str()))],
+					"contains" => vec![Item::Boolean(s.contains(a.as_str()))],
+					"indexOf" => vec![Item::Integer(s.find(a.as_str()).map_or(-1, |i| s[..i].chars().count() as i64))],
+					_ => s.split(a.as_str()).map(|p| Item::String(p.to_string())).collect()
+				}))?
+			},
+			"substring" => {
+				if args.is_empty() || args.len() > 2 {
+					return Err(FhirError::invalid(&args.len().to_string(), "Wrong number of arguments"));
+				}
+				let start = integer_arg(&arg(0)?, name)?;
+				let len = match args.len() { 2 => integer_arg(&arg(1)?, name)?, _ => None };
+				str_fn(&|s| Ok(match start {
+					Some(st) if st >= 0 && (st as usize) < s.chars().count() => {
+						let chars = s.chars().skip(st as usize);
+						vec![Item::String(match len {
+							Some(l) => chars.take(l.max(0) as usize).collect(),
+							None => chars.collect()
+						})]
+					},
+					_ => vec![]
+				}))?
+			},
+			"replace" => {
+				arity(2)?;
+				match (string_arg(&arg(0)?, name)?, string_arg(&arg(1)?, name)?) {
+					(Some(a), Some(b)) => str_fn(&|s| Ok(vec![Item::String(s.replace(a.as_str(), &b))]))?,
+					_ => vec![]
+				}
+			},
+			"matches" => {
+				arity(1)?;
+				match string_arg(&arg(0)?, name)? {
+					Some(re) => {
+						let re = regex(&re)?;
+						str_fn(&|s| Ok(vec![Item::Boolean(re.is_match(s))]))?
+					},
+					None => vec![]
+				}
+			},
+			"replaceMatches" => {
+				arity(2)?;
+				match (string_arg(&arg(0)?, name)?, string_arg(&arg(1)?, name)?) {
+					(Some(re), Some(sub)) => {
+						let re = regex(&re)?;
+						str_fn(&|s| Ok(vec![Item::String(re.replace_all(s, sub.as_str()).into_owned())]))?
+					},
+					_ => vec![]
+				}
+			},
+			"join" => {
+				let sep = match args.len() { 0 => String::new(), _ => string_arg(&arg(0)?, name)?.unwrap_or_default() };
+				vec![Item::String(focus.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(&sep))]
+			},
+			_ => return Err(FhirError::unexpected(name, "Unsupported function"))
+		})
+	}
+}
+
+#[cfg(test)]
+fn eval_str(r: &Resource, s: &str) -> Vec<String> {
+	Evaluator::new(r).evaluate(&::fhirpath::parse(s).unwrap()).unwrap().iter().map(|i| i.to_string()).collect()
+}
+
+#[test]
+fn test_compare_partial_dates() {
+	assert_eq!(Some(Ordering::Less), compare_dates("2015-05", "2015-06-01"));
+	assert_eq!(None, compare_dates("2015-05", "2015-05-01"));
+	assert_eq!(Some(Ordering::Equal), compare_dates("2015-05-02T05:34:00-07:00", "2015-05-02T12:34:00Z"));
+	assert_eq!(Some(Ordering::Less), compare_dates("2015-05-02T10:00", "2015-05-02T10:30"));
+	assert!(compare(&Item::Integer(1), &Item::String("1".to_string())).is_err());
+}
+
+#[test]
+fn test_empty_propagation() {
+	let r = Resource::new("Patient").add_elt(Element::with("active", true));
+	assert!(eval_str(&r, "gender = 'male'").is_empty());
+	assert!(eval_str(&r, "gender.upper()").is_empty());
+	assert_eq!(vec!["true"], eval_str(&r, "gender.empty() and active"));
+	assert_eq!(vec!["false"], eval_str(&r, "active.not()"));
+}
+
+#[test]
+fn test_integer_overflow_is_empty() {
+	let r = Resource::new("Patient");
+	assert!(eval_str(&r, "9223372036854775807 + 1").is_empty());
+	assert!(eval_str(&r, "-9223372036854775807 - 2").is_empty());
+	assert!(eval_str(&r, "4611686018427387904 * 2").is_empty());
+	assert!(eval_str(&r, "(-9223372036854775807 - 1) div -1").is_empty());
+	assert!(eval_str(&r, "(-9223372036854775807 - 1) mod -1").is_empty());
+	assert!(eval_str(&r, "-(-9223372036854775807 - 1)").is_empty());
+	assert_eq!(vec!["9223372036854775807"], eval_str(&r, "9223372036854775806 + 1"));
+}
+
+#[test]
+fn test_choice_needs_type_suffix() {
+	let r = Resource::new("Observation")
+		.add_elt(Element::with("valueSetRef", "x"))
+		.add_elt(Element::with("effectiveDateTime", "2015-02-07T13:28:17-05:00"));
+	assert!(eval_str(&r, "value").is_empty());
+	assert_eq!(vec!["2015-02-07T13:28:17-05:00"], eval_str(&r, "effective"));
+}
+
+#[test]
+fn test_extensions_and_contained() {
+	use rustc_serialize::json::Json;
+	use json::FromJson;
+	let j = Json::from_str(r#"{"resourceType": "Patient",
+		"contained": [{"resourceType": "Organization", "id": "o1", "name": "Acme"}],
+		"extension": [{"url": "http://example.org/a", "valueString": "x"}],
+		"birthDate": "1974-12-25", "_birthDate": {"extension": [{"url": "http://example.org/t", "valueDateTime": "1974-12-25T14:35:45-05:00"}]},
+		"name": [{"modifierExtension": [{"url": "http://example.org/m", "valueBoolean": true}], "family": "Doe"}]}"#).unwrap();
+	let r = Resource::from_json(&j).unwrap();
+	assert_eq!(vec!["http://example.org/a"], eval_str(&r, "Patient.extension.url"));
+	assert_eq!(vec!["1974-12-25T14:35:45-05:00"], eval_str(&r, "birthDate.extension('http://example.org/t').value"));
+	assert!(eval_str(&r, "birthDate.extension('http://example.org/a')").is_empty());
+	assert_eq!(vec!["true"], eval_str(&r, "name.modifierExtension.value"));
+	assert_eq!(vec!["Acme"], eval_str(&r, "contained.ofType(Organization).name"));
+	assert_eq!(vec!["true"], eval_str(&r, "(contained is Organization) and (extension is Extension)"));
+	assert!(eval_str(&r, "Organization").is_empty());
+}
+
+#[test]
+fn test_type_and_regex() {
+	let r = Resource::new("Patient").add_elt(Element::with("active", true));
+	assert_eq!(vec!["System", "Integer"], eval_str(&r, "1.type().namespace | 1.type().name"));
+	assert_eq!(vec!["FHIR", "boolean"], eval_str(&r, "active.type().namespace | active.type().name"));
+	assert_eq!(vec!["Patient"], eval_str(&r, "type().name"));
+	assert_eq!(vec!["true"], eval_str(&r, "'N8000123123'.matches('^N[0-9]{10}$')"));
+	assert_eq!(vec!["true"], eval_str(&r, r"'A\n\tB'.matches('A.*B')"));
+	assert_eq!(vec!["11/30/1972"], eval_str(&r, r"'1972-11-30'.replaceMatches('(\\d{4})-(\\d{2})-(\\d{2})', '$2/$3/$1')"));
+	assert!(Evaluator::new(&r).evaluate(&::fhirpath::parse("'a'.matches('(')").unwrap()).is_err());
+}
+
+#[test]
+fn test_quantity_units() {
+	let r = Resource::new("Patient");
+	assert_eq!(vec!["true"], eval_str(&r, "4.0000 'g' = 4000.0 'mg'"));
+	assert_eq!(vec!["true"], eval_str(&r, "1 'h' = 60 'min' and 1 second = 1000 'ms'"));
+	assert_eq!(vec!["true"], eval_str(&r, "2 days > 1 day and 3 'cm' < 1 'dm'"));
+	assert!(eval_str(&r, "1 'g' = 1 'm'").is_empty());
+	assert!(eval_str(&r, "1 year = 1 'a'").is_empty());
+	assert_eq!(vec!["-5 'mg'"], eval_str(&r, "-5 'mg'"));
+}
@@ -0,0 +1,113 @@
+// FHIRPath over the dynamic model. Expressions evaluate to collections of
+// Items borrowed from the resource. Quantities compare across UCUM metric
+// prefixes and units of time, but arithmetic on them is not supported.
+
+pub mod parser;
+pub use fhirpath::parser::parse;
+pub mod eval;
+pub use fhirpath::eval::{Item, Evaluator};
+
+use resource::Resource;
+use error::FhirError;
+
+pub fn evaluate<'a>(r: &'a Resource, expr: &str) -> Result<Vec<Item<'a>>, FhirError> {
+	Evaluator::new(r).evaluate(&parse(expr)?)
+}
+
+#[cfg(test)]
+use std::fs::File;
+#[cfg(test)]
+use std::io::Read;
+#[cfg(test)]
+use xml::{FromXml, XmlNode, reader};
+#[cfg(test)]
+use rustc_serialize::json::Json;
+#[cfg(test)]
+use json::FromJson;
+
+#[cfg(test)]
+fn fixture(name: &str) -> String {
+	read_fixture(name).unwrap()
+}
+
+#[cfg(test)]
+fn read_fixture(name: &str) -> Result<String, String> {
+	let path = format!("{}/fixtures/fhirpath/{}", env!("CARGO_MANIFEST_DIR"), name);
+	let mut s = String::new();
+	File::open(&path).and_then(|mut f| f.read_to_string(&mut s)).map_err(|e| format!("{}: {}", path, e))?;
+	Ok(s)
+}
+
+// Cases of the suite that rely on what the evaluator doesn't support; each
+// must fail, so one that starts passing is noticed
+#[cfg(test)]
+const EXPECTED_FAILURES: &'static [&'static str] = &[];
+
+// An input of the suite, its primitives typed from the definitions of
+// the elements it uses, as XML doesn't carry their types
+#[cfg(test)]
+fn input(name: &str) -> Result<Resource, String> {
+	use validate::Validator;
+	let mut v = Validator::new();
+	v.load(&Json::from_str(&fixture("definitions.json")).unwrap()).unwrap();
+	let mut r = Resource::from_xml(&read_fixture(name)?).map_err(|e| e.to_string())?;
+	v.type_primitives(&mut r);
+	Ok(r)
+}
+
+// Run one <test> of the official suite; a failure is described by the returned string
+#[cfg(test)]
+fn run_case(t: &XmlNode) -> Result<(), String> {
+	let name = t.attr("name").unwrap_or("");
+	let src = t.children.iter().find(|c| c.name == "expression").map(|e| e.text.as_ref()).unwrap_or("");
+	let invalid = t.children.iter().any(|c| c.name == "expression" && c.attr("invalid").is_some());
+	let r = input(t.attr("inputfile").unwrap_or("patient-example.xml")).map_err(|e| format!("{}: {}", name, e))?;
+	let result = evaluate(&r, src);
+	if invalid {
+		return match result {
+			Err(_) => Ok(()),
+			Ok(_) => Err(format!("{}: expected '{}' to fail", name, src))
+		};
+	}
+	let got = result.map_err(|e| format!("{}: {}", name, e))?;
+	let expected: Vec<&XmlNode> = t.children.iter().filter(|c| c.name == "output").collect();
+	let matches = |i: &Item, o: &XmlNode| match o.attr("type") {
+		Some("decimal") => i.to_string().parse::<f64>().ok() == o.text.parse().ok(),
+		_ => i.to_string() == o.text.trim_start_matches('@')
+	};
+	if got.len() != expected.len() || !got.iter().zip(expected.iter()).all(|(i, o)| matches(i, o)) {
+		let got: Vec<String> = got.iter().map(|i| i.to_string()).collect();
+		let want: Vec<&str> = expected.iter().map(|o| o.text.as_ref()).collect();
+		return Err(format!("{}: '{}' gave {:?}, expected {:?}", name, src, got, want));
+	}
+	Ok(())
+}
+
+#[test]
+fn test_official_suite() {
+	let suite = fixture("tests-fhir-r4.xml");
+	let root = reader::parse(&suite).unwrap();
+	let mut failures = Vec::new();
+	for t in root.children.iter().flat_map(|g| g.children.iter()) {
+		let name = t.attr("name").unwrap_or("");
+		match (run_case(t), EXPECTED_FAILURES.contains(&name)) {
+			(Err(e), false) => failures.push(e),
+			(Ok(()), true) => failures.push(format!("{}: passes, but is listed as an expected failure", name)),
+			_ => ()
+		}
+	}
+	assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn test_evaluate_json_resource() {
+	let j = Json::from_str(r#"{"resourceType": "Patient", "name": [{"use": "official", "given": ["Jo", "Al"]}, {"use": "usual", "given": ["J"]}],
+		"birthDate": "1974-12-25", "deceasedBoolean": false}"#).unwrap();
+	let r = Resource::from_json(&j).unwrap();
+	let given = evaluate(&r, "Patient.name.where(use='official').given.first()").unwrap();
+	assert_eq!(vec!["Jo"], given.iter().map(|i| i.to_string()).collect::<Vec<_>>());
+	let flags = evaluate(&r, "deceased is boolean and birthDate > @1970 and birthDate.toDate() < @1975-01").unwrap();
+	assert_eq!(vec!["true"], flags.iter().map(|i| i.to_string()).collect::<Vec<_>>());
+	assert!(evaluate(&r, "name.given.single()").is_err());
+	assert!(evaluate(&r, "name.nope()").is_err());
+}
@@ -0,0 +1,421 @@
+use primitive::Dec;
+use error::FhirError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lit {
+	Boolean(bool),
+	Integer(i64),
+	Decimal(Dec),
+	// A number and its UCUM unit or calendar duration keyword
+	Quantity(Dec, String),
+	String(String),
+	Date(String),
+	DateTime(String),
+	Time(String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+	Empty,
+	Literal(Lit),
+	Ident(String),
+	This,
+	Index,
+	Total,
+	External(String),
+	Member(Box<Expr>, String),
+	Call(Option<Box<Expr>>, String, Vec<Expr>),
+	Indexer(Box<Expr>, Box<Expr>),
+	Negate(Box<Expr>),
+	Binary(String, Box<Expr>, Box<Expr>),
+	Is(Box<Expr>, String),
+	As(Box<Expr>, String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Ident(String),
+	Delimited(String),
+	Str(String),
+	Number(String),
+	Date(String),
+	DateTime(String),
+	Time(String),
+	Var(String),
+	Ext(String),
+	Sym(&'static str)
+}
+
+const SYMBOLS: &'static [&'static str] = &["<=", ">=", "!=", "!~", ".", "(", ")", "[", "]", "{", "}", ",",
+	"+", "-", "*", "/", "&", "|", "=", "<", ">", "~"];
+
+// The calendar duration keywords that may follow a number as its unit
+const CALENDAR_UNITS: &'static [&'static str] = &["year", "years", "month", "months", "week", "weeks", "day", "days",
+	"hour", "hours", "minute", "minutes", "second", "seconds", "millisecond", "milliseconds"];
+
+fn syntax(s: &str, reason: &'static str) -> FhirError {
+	FhirError::invalid(s, reason)
+}
+
+fn take_while<F: Fn(char) -> bool>(s: &str, f: F) -> &str {
+	let n = s.find(|c: char| !f(c)).unwrap_or(s.len());
+	&s[..n]
+}
+
+// The time of a date/time literal; a trailing '.' starts an invocation, as in @T14.is(Time)
+fn time_part(s: &str) -> &str {
+	take_while(s, |c| c.is_ascii_digit() || c == ':' || c == '.').trim_end_matches('.')
+}
+
+fn quoted(s: &str, q: char) -> Result<(String, usize), FhirError> {
+	let mut out = String::new();
+	let mut chars = s.char_indices().skip(1);
+	while let Some((i, c)) = chars.next() {
+		if c == q {
+			return Ok((out, i + 1));
+		}
+		if c != '\\' {
+			out.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some((_, 'n')) => out.push('\n'),
+			Some((_, 'r')) => out.push('\r'),
+			Some((_, 't')) => out.push('\t'),
+			Some((_, 'f')) => out.push('\u{c}'),
+			Some((j, 'u')) => {
+				let hex = s.get(j + 1..j + 5).ok_or_else(|| syntax(s, "Invalid unicode escape"))?;
+				let c = u32::from_str_radix(hex, 16).ok().and_then(::std::char::from_u32)
+					.ok_or_else(|| syntax(hex, "Invalid unicode escape"))?;
+				out.push(c);
+				for _ in 0..4 {
+					chars.next();
+				}
+			},
+			Some((_, c)) => out.push(c),
+			None => break
+		}
+	}
+	Err(syntax(s, "Unterminated string"))
+}
+
+fn lex(s: &str) -> Result<Vec<Token>, FhirError> {
+	let mut toks = Vec::new();
+	let mut rest = s;
+	loop {
+		rest = rest.trim_start();
+		if rest.starts_with("//") {
+			rest = rest.find('\n').map_or("", |i| &rest[i..]);
+			continue;
+		}
+		if rest.starts_with("/*") {
+			rest = rest.find("*/").map_or("", |i| &rest[i + 2..]);
+			continue;
+		}
+		let c = match rest.chars().next() {
+			Some(c) => c,
+			None => return Ok(toks)
+		};
+		let (tok, len) = if c == '\'' || c == '`' {
+			let (v, n) = quoted(rest, c)?;
+			(if c == '\'' { Token::Str(v) } else { Token::Delimited(v) }, n)
+		} else if c.is_ascii_digit() {
+			let int = take_while(rest, |c| c.is_ascii_digit());
+			let frac = &rest[int.len()..];
+			let n = if frac.starts_with('.') && frac[1..].starts_with(|c: char| c.is_ascii_digit()) {
+				int.len() + 1 + take_while(&frac[1..], |c| c.is_ascii_digit()).len()
+			} else {
+				int.len()
+			};
+			(Token::Number(rest[..n].to_string()), n)
+		} else if c == '@' {
+			let body = &rest[1..];
+			if body.starts_with('T') {
+				let t = time_part(&body[1..]);
+				(Token::Time(t.to_string()), t.len() + 2)
+			} else {
+				let d = take_while(body, |c| c.is_ascii_digit() || c == '-');
+				let after = &body[d.len()..];
+				if after.starts_with('T') {
+					let t = time_part(&after[1..]);
+					let zone = &after[1 + t.len()..];
+					let z = if zone.starts_with('Z') {
+						1
+					} else if !t.is_empty() && (zone.starts_with('+') || zone.starts_with('-')) {
+						1 + take_while(&zone[1..], |c| c.is_ascii_digit() || c == ':').len()
+					} else {
+						0
+					};
+					let n = d.len() + 1 + t.len() + z;
+					(Token::DateTime(body[..n].to_string()), n + 1)
+				} else {
+					(Token::Date(d.to_string()), d.len() + 1)
+				}
+			}
+		} else if c == '$' || c == '%' {
+			let body = &rest[1..];
+			let (name, n) = if body.starts_with('`') || body.starts_with('\'') {
+				quoted(body, body.chars().next().unwrap())?
+			} else {
+				let n = take_while(body, |c| c.is_alphanumeric() || c == '_');
+				(n.to_string(), n.len())
+			};
+			(if c == '$' { Token::Var(name) } else { Token::Ext(name) }, n + 1)
+		} else if c.is_alphabetic() || c == '_' {
+			let n = take_while(rest, |c| c.is_alphanumeric() || c == '_');
+			(Token::Ident(n.to_string()), n.len())
+		} else {
+			match SYMBOLS.iter().find(|sym| rest.starts_with(*sym)) {
+				Some(sym) => (Token::Sym(sym), sym.len()),
+				None => return Err(syntax(rest, "Unexpected character"))
+			}
+		};
+		toks.push(tok);
+		rest = &rest[len..];
+	}
+}
+
+// Binding powers, lowest first: implies, or/xor, and, membership, equality,
+// comparison, union, type, additive, multiplicative
+fn infix_power(t: &Token) -> Option<(u8, String)> {
+	let op = match *t {
+		Token::Sym(s) => s,
+		Token::Ident(ref s) => s.as_ref(),
+		_ => return None
+	};
+	let bp = match op {
+		"implies" => 1,
+		"or" | "xor" => 2,
+		"and" => 3,
+		"in" | "contains" => 4,
+		"=" | "~" | "!=" | "!~" => 5,
+		"<" | ">" | "<=" | ">=" => 6,
+		"|" => 7,
+		"is" | "as" => 8,
+		"+" | "-" | "&" => 9,
+		"*" | "/" | "div" | "mod" => 10,
+		_ => return None
+	};
+	Some((bp, op.to_string()))
+}
+
+const UNARY_POWER: u8 = 11;
+
+struct Parser {
+	toks: Vec<Token>,
+	pos: usize
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.toks.get(self.pos)
+	}
+
+	fn next(&mut self) -> Result<Token, FhirError> {
+		let t = self.toks.get(self.pos).cloned().ok_or_else(|| FhirError::malformed("Unexpected end of expression"))?;
+		self.pos += 1;
+		Ok(t)
+	}
+
+	fn eat(&mut self, sym: &str) -> bool {
+		if self.peek() == Some(&Token::Sym(Self::intern(sym))) {
+			self.pos += 1;
+			true
+		} else {
+			false
+		}
+	}
+
+	fn intern(sym: &str) -> &'static str {
+		SYMBOLS.iter().find(|s| **s == sym).cloned().unwrap_or("")
+	}
+
+	fn expect(&mut self, sym: &str) -> Result<(), FhirError> {
+		if self.eat(sym) {
+			Ok(())
+		} else {
+			Err(syntax(sym, "Expected symbol"))
+		}
+	}
+
+	fn identifier(&mut self) -> Result<String, FhirError> {
+		match self.next()? {
+			Token::Ident(s) | Token::Delimited(s) => Ok(s),
+			t => Err(syntax(&format!("{:?}", t), "Expected identifier"))
+		}
+	}
+
+	fn args(&mut self) -> Result<Vec<Expr>, FhirError> {
+		let mut args = Vec::new();
+		if self.eat(")") {
+			return Ok(args);
+		}
+		loop {
+			args.push(self.expr(0)?);
+			if self.eat(")") {
+				return Ok(args);
+			}
+			self.expect(",")?;
+		}
+	}
+
+	// A possibly qualified type name, e.g. FHIR.Patient or System.String
+	fn type_name(&mut self) -> Result<String, FhirError> {
+		let mut name = self.identifier()?;
+		while self.eat(".") {
+			name = format!("{}.{}", name, self.identifier()?);
+		}
+		Ok(name)
+	}
+
+	fn prefix(&mut self) -> Result<Expr, FhirError> {
+		Ok(match self.next()? {
+			// A signed number is a literal, so -1.abs() applies to -1
+			Token::Sym("-") => match self.peek() {
+				Some(&Token::Number(_)) => match self.prefix()? {
+					Expr::Literal(Lit::Integer(i)) => Expr::Literal(Lit::Integer(-i)),
+					Expr::Literal(Lit::Decimal(d)) => Expr::Literal(Lit::Decimal(-d)),
+					Expr::Literal(Lit::Quantity(d, u)) => Expr::Literal(Lit::Quantity(-d, u)),
+					e => Expr::Negate(Box::new(e))
+				},
+				_ => Expr::Negate(Box::new(self.expr(UNARY_POWER)?))
+			},
+			Token::Sym("+") => self.expr(UNARY_POWER)?,
+			Token::Sym("(") => {
+				let e = self.expr(0)?;
+				self.expect(")")?;
+				e
+			},
+			Token::Sym("{") => {
+				self.expect("}")?;
+				Expr::Empty
+			},
+			Token::Str(s) => Expr::Literal(Lit::String(s)),
+			Token::Number(n) => {
+				let unit = match self.peek() {
+					Some(&Token::Str(ref u)) => Some(u.clone()),
+					Some(&Token::Ident(ref u)) if CALENDAR_UNITS.contains(&u.as_str()) => Some(u.clone()),
+					_ => None
+				};
+				match unit {
+					Some(u) => {
+						self.next()?;
+						Expr::Literal(Lit::Quantity(Dec::from_str(&n)?, u))
+					},
+					None if n.contains('.') => Expr::Literal(Lit::Decimal(Dec::from_str(&n)?)),
+					None => Expr::Literal(Lit::Integer(n.parse().map_err(|_| syntax(&n, "Invalid number"))?))
+				}
+			},
+			Token::Date(d) => Expr::Literal(Lit::Date(d)),
+			Token::DateTime(d) => Expr::Literal(Lit::DateTime(d)),
+			Token::Time(t) => Expr::Literal(Lit::Time(t)),
+			Token::Var(ref v) if v == "this" => Expr::This,
+			Token::Var(ref v) if v == "index" => Expr::Index,
+			Token::Var(ref v) if v == "total" => Expr::Total,
+			Token::Var(v) => return Err(syntax(&v, "Unknown variable")),
+			Token::Ext(e) => Expr::External(e),
+			Token::Ident(ref s) if s == "true" => Expr::Literal(Lit::Boolean(true)),
+			Token::Ident(ref s) if s == "false" => Expr::Literal(Lit::Boolean(false)),
+			Token::Ident(s) | Token::Delimited(s) => {
+				if self.eat("(") {
+					Expr::Call(None, s, self.args()?)
+				} else {
+					Expr::Ident(s)
+				}
+			},
+			t => return Err(syntax(&format!("{:?}", t), "Unexpected token"))
+		})
+	}
+
+	fn expr(&mut self, min_bp: u8) -> Result<Expr, FhirError> {
+		let mut lhs = self.prefix()?;
+		loop {
+			if self.eat(".") {
+				let name = self.identifier()?;
+				lhs = if self.eat("(") {
+					Expr::Call(Some(Box::new(lhs)), name, self.args()?)
+				} else {
+					Expr::Member(Box::new(lhs), name)
+				};
+				continue;
+			}
+			if self.eat("[") {
+				let i = self.expr(0)?;
+				self.expect("]")?;
+				lhs = Expr::Indexer(Box::new(lhs), Box::new(i));
+				continue;
+			}
+			let (bp, op) = match self.peek().and_then(infix_power) {
+				Some(p) => p,
+				None => break
+			};
+			if bp <= min_bp {
+				break;
+			}
+			self.pos += 1;
+			lhs = match op.as_ref() {
+				"is" => Expr::Is(Box::new(lhs), self.type_name()?),
+				"as" => Expr::As(Box::new(lhs), self.type_name()?),
+				_ => Expr::Binary(op.clone(), Box::new(lhs), Box::new(self.expr(bp)?))
+			};
+		}
+		Ok(lhs)
+	}
+}
+
+pub fn parse(s: &str) -> Result<Expr, FhirError> {
+	let mut p = Parser {toks: lex(s)?, pos: 0};
+	let e = p.expr(0)?;
+	match p.peek() {
+		None => Ok(e),
+		Some(t) => Err(syntax(&format!("{:?}", t), "Unexpected token after expression"))
+	}
+}
+
+#[cfg(test)]
+fn ident(s: &str) -> Box<Expr> {
+	Box::new(Expr::Ident(s.to_string()))
+}
+
+#[test]
+fn test_parse_path_and_call() {
+	let e = parse("Patient.name.where(use = 'official').given.first()").unwrap();
+	let name = Expr::Member(ident("Patient"), "name".to_string());
+	let cond = Expr::Binary("=".to_string(), ident("use"), Box::new(Expr::Literal(Lit::String("official".to_string()))));
+	let wher = Expr::Call(Some(Box::new(name)), "where".to_string(), vec![cond]);
+	let given = Expr::Member(Box::new(wher), "given".to_string());
+	assert_eq!(Expr::Call(Some(Box::new(given)), "first".to_string(), vec![]), e);
+}
+
+#[test]
+fn test_parse_precedence() {
+	let e = parse("1 + 2 * 3 = 7 and true").unwrap();
+	let mul = Expr::Binary("*".to_string(), Box::new(Expr::Literal(Lit::Integer(2))), Box::new(Expr::Literal(Lit::Integer(3))));
+	let add = Expr::Binary("+".to_string(), Box::new(Expr::Literal(Lit::Integer(1))), Box::new(mul));
+	let eq = Expr::Binary("=".to_string(), Box::new(add), Box::new(Expr::Literal(Lit::Integer(7))));
+	assert_eq!(Expr::Binary("and".to_string(), Box::new(eq), Box::new(Expr::Literal(Lit::Boolean(true)))), e);
+}
+
+#[test]
+fn test_parse_literals() {
+	assert_eq!(Expr::Literal(Lit::Date("2015-02-04".to_string())), parse("@2015-02-04").unwrap());
+	assert_eq!(Expr::Literal(Lit::DateTime("2015-02-04T14:34:28+09:00".to_string())), parse("@2015-02-04T14:34:28+09:00").unwrap());
+	assert_eq!(Expr::Literal(Lit::Time("14:34".to_string())), parse("@T14:34").unwrap());
+	assert_eq!(Expr::Literal(Lit::String("it's\n".to_string())), parse(r"'it\'s\n'").unwrap());
+	assert_eq!(Expr::Literal(Lit::Decimal(Dec::from_str("3.5").unwrap())), parse("3.5").unwrap());
+	assert_eq!(Expr::Literal(Lit::Integer(-1)), parse("-1").unwrap());
+	assert_eq!(Expr::Literal(Lit::Quantity(Dec::from_str("4.5").unwrap(), "mg".to_string())), parse("4.5 'mg'").unwrap());
+	assert_eq!(Expr::Literal(Lit::Quantity(Dec::from_str("-2").unwrap(), "days".to_string())), parse("-2 days").unwrap());
+	assert_eq!(Expr::Negate(ident("a")), parse("-a").unwrap());
+	assert_eq!(Expr::Is(ident("value"), "FHIR.Quantity".to_string()), parse("value is FHIR.Quantity").unwrap());
+	assert_eq!(Expr::Member(ident("a"), "div".to_string()), parse("a.`div`").unwrap());
+}
+
+#[test]
+fn test_parse_errors() {
+	assert!(parse("name.").is_err());
+	assert!(parse("name name").is_err());
+	assert!(parse("where(").is_err());
+	assert!(parse("'abc").is_err());
+	assert!(parse("4 mg").is_err());
+}
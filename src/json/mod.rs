@@ -0,0 +1,66 @@
+use std::collections::btree_map::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+use rustc_serialize::json::Json;
+
+use error::FhirError;
+
+pub mod writer;
+pub use json::writer::{JsonWriter, ElementOrder};
+pub mod reader;
+pub use json::reader::parse;
+
+// The Json tree holds a number that isn't an integer as a binary float, in
+// which 1.50 is 1.5. A decimal keeps its written precision in the model, and
+// only JsonWriter, which writes primitives from the model, writes it exactly.
+
+// The text of a number, in the shortest form that reads back as its value
+pub fn number_text(j: &Json) -> Option<String> {
+	match *j {
+		Json::I64(i) => Some(i.to_string()),
+		Json::U64(u) => Some(u.to_string()),
+		Json::F64(f) => Some(format!("{:?}", f)),
+		_ => None
+	}
+}
+
+// The inverse of rustc_serialize's ToJson for the FHIR model
+pub trait FromJson: Sized {
+	fn from_json(j: &Json) -> Result<Self, FhirError>;
+}
+
+impl<T: FromJson> FromJson for Vec<T> {
+	fn from_json(j: &Json) -> Result<Self, FhirError> {
+		match *j {
+			Json::Array(ref a) => a.iter().enumerate()
+				.map(|(i, v)| T::from_json(v).map_err(|e| e.at(&format!("[{}]", i))))
+				.collect(),
+			_ => Err(FhirError::unexpected(&j.to_string(), "Expected array"))
+		}
+	}
+}
+
+pub fn as_object(j: &Json) -> Result<&BTreeMap<String,Json>, FhirError> {
+	j.as_object().ok_or_else(|| FhirError::unexpected(&j.to_string(), "Expected object"))
+}
+
+pub fn as_string(j: &Json) -> Result<&str, FhirError> {
+	j.as_string().ok_or_else(|| FhirError::unexpected(&j.to_string(), "Expected string"))
+}
+
+pub fn read_file(path: &str) -> Result<Json, FhirError> {
+	let mut s = String::new();
+	File::open(path).and_then(|mut f| f.read_to_string(&mut s))
+		.map_err(|_| FhirError::invalid(path, "Unable to read file"))?;
+	parse(&s).map_err(|e| e.at(path))
+}
+
+// The resources of a type in a Bundle, or the document itself if it is one
+pub fn resources_of_type<'a>(doc: &'a Json, resource_type: &str) -> Vec<&'a Json> {
+	let is_type = |r: &&Json| r.find("resourceType").and_then(|t| t.as_string()) == Some(resource_type);
+	match doc.find("entry").and_then(|e| e.as_array()) {
+		Some(entries) => entries.iter().filter_map(|e| e.find("resource")).filter(is_type).collect(),
+		None => Some(doc).into_iter().filter(is_type).collect()
+	}
+}
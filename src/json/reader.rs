@@ -0,0 +1,224 @@
+use std::collections::btree_map::BTreeMap;
+
+use rustc_serialize::json::Json;
+
+use error::FhirError;
+
+// A JSON reader that, unlike rustc_serialize's, refuses duplicate keys and
+// numbers beyond the range of a double. Integers are U64 or, when negative,
+// I64, and other numbers F64, as rustc_serialize reads them.
+pub fn parse(s: &str) -> Result<Json, FhirError> {
+	let mut p = Parser {s: s, pos: 0};
+	let j = p.value()?;
+	p.skip_ws();
+	if p.pos != s.len() {
+		return Err(FhirError::malformed("Content after JSON value"));
+	}
+	Ok(j)
+}
+
+struct Parser<'a> {
+	s: &'a str,
+	pos: usize
+}
+
+impl<'a> Parser<'a> {
+	fn rest(&self) -> &'a str {
+		&self.s[self.pos..]
+	}
+
+	fn skip_ws(&mut self) {
+		let r = self.rest();
+		self.pos += r.len() - r.trim_start_matches(|c| c == ' ' || c == '\t' || c == '\n' || c == '\r').len();
+	}
+
+	fn expect(&mut self, pat: &str) -> Result<(), FhirError> {
+		if self.rest().starts_with(pat) {
+			self.pos += pat.len();
+			Ok(())
+		} else {
+			Err(FhirError::malformed("Malformed JSON"))
+		}
+	}
+
+	fn value(&mut self) -> Result<Json, FhirError> {
+		self.skip_ws();
+		match self.rest().chars().next() {
+			Some('{') => self.object(),
+			Some('[') => self.array(),
+			Some('"') => self.string().map(Json::String),
+			Some('t') => self.expect("true").map(|_| Json::Boolean(true)),
+			Some('f') => self.expect("false").map(|_| Json::Boolean(false)),
+			Some('n') => self.expect("null").map(|_| Json::Null),
+			Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+			Some(_) => Err(FhirError::malformed("Malformed JSON")),
+			None => Err(FhirError::malformed("Unexpected end of JSON"))
+		}
+	}
+
+	fn object(&mut self) -> Result<Json, FhirError> {
+		self.expect("{")?;
+		let mut o = BTreeMap::new();
+		self.skip_ws();
+		if self.rest().starts_with('}') {
+			self.pos += 1;
+			return Ok(Json::Object(o));
+		}
+		loop {
+			self.skip_ws();
+			let k = self.string()?;
+			self.skip_ws();
+			self.expect(":")?;
+			let v = self.value()?;
+			if o.insert(k.clone(), v).is_some() {
+				return Err(FhirError::unexpected(&k, "Duplicate key"));
+			}
+			self.skip_ws();
+			if self.rest().starts_with('}') {
+				self.pos += 1;
+				return Ok(Json::Object(o));
+			}
+			self.expect(",")?;
+		}
+	}
+
+	fn array(&mut self) -> Result<Json, FhirError> {
+		self.expect("[")?;
+		let mut a = Vec::new();
+		self.skip_ws();
+		if self.rest().starts_with(']') {
+			self.pos += 1;
+			return Ok(Json::Array(a));
+		}
+		loop {
+			a.push(self.value()?);
+			self.skip_ws();
+			if self.rest().starts_with(']') {
+				self.pos += 1;
+				return Ok(Json::Array(a));
+			}
+			self.expect(",")?;
+		}
+	}
+
+	fn hex4(&mut self) -> Result<u32, FhirError> {
+		let r = self.rest();
+		if r.len() < 4 || !r.is_char_boundary(4) {
+			return Err(FhirError::malformed("Invalid \\u escape"));
+		}
+		let n = u32::from_str_radix(&r[..4], 16).map_err(|_| FhirError::malformed("Invalid \\u escape"))?;
+		self.pos += 4;
+		Ok(n)
+	}
+
+	fn string(&mut self) -> Result<String, FhirError> {
+		self.expect("\"")?;
+		let mut out = String::new();
+		loop {
+			let c = self.rest().chars().next().ok_or(FhirError::malformed("Unterminated string"))?;
+			self.pos += c.len_utf8();
+			let c = match c {
+				'"' => return Ok(out),
+				'\\' => {
+					let e = self.rest().chars().next().ok_or(FhirError::malformed("Unterminated string"))?;
+					self.pos += e.len_utf8();
+					match e {
+						'"' | '\\' | '/' => e,
+						'b' => '\u{8}',
+						'f' => '\u{c}',
+						'n' => '\n',
+						'r' => '\r',
+						't' => '\t',
+						'u' => {
+							let hi = self.hex4()?;
+							let code = if hi >= 0xD800 && hi < 0xDC00 {
+								self.expect("\\u")?;
+								let lo = self.hex4()?;
+								if lo < 0xDC00 || lo >= 0xE000 {
+									return Err(FhirError::malformed("Invalid surrogate pair"));
+								}
+								0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00)
+							} else {
+								hi
+							};
+							::std::char::from_u32(code).ok_or(FhirError::malformed("Invalid \\u escape"))?
+						},
+						_ => return Err(FhirError::malformed("Invalid escape"))
+					}
+				},
+				c if (c as u32) < 0x20 => return Err(FhirError::malformed("Control character in string")),
+				c => c
+			};
+			out.push(c);
+		}
+	}
+
+	// -?(0|[1-9][0-9]*)(.[0-9]+)?([eE][+-]?[0-9]+)?
+	fn number(&mut self) -> Result<Json, FhirError> {
+		let r = self.rest();
+		let b = r.as_bytes();
+		let digits = |mut i: usize| {
+			let start = i;
+			while i < b.len() && b[i].is_ascii_digit() {
+				i += 1;
+			}
+			if i == start { Err(FhirError::malformed("Invalid number")) } else { Ok(i) }
+		};
+		let mut i = if b[0] == b'-' { 1 } else { 0 };
+		let int = i;
+		i = digits(i)?;
+		if b[int] == b'0' && i > int + 1 {
+			return Err(FhirError::malformed("Invalid number"));
+		}
+		let integral = i;
+		if i < b.len() && b[i] == b'.' {
+			i = digits(i + 1)?;
+		}
+		if i < b.len() && (b[i] == b'e' || b[i] == b'E') {
+			i += 1;
+			if i < b.len() && (b[i] == b'+' || b[i] == b'-') {
+				i += 1;
+			}
+			i = digits(i)?;
+		}
+		self.pos += i;
+		let text = &r[..i];
+		if i == integral {
+			if let Ok(u) = text.parse::<u64>() {
+				return Ok(Json::U64(u));
+			}
+			if let Ok(n) = text.parse::<i64>() {
+				return Ok(Json::I64(n));
+			}
+		}
+		match text.parse::<f64>() {
+			Ok(f) if f.is_finite() => Ok(Json::F64(f)),
+			_ => Err(FhirError::invalid(text, "Number out of range"))
+		}
+	}
+}
+
+#[test]
+fn test_parse_like_rustc_serialize() {
+	let s = r#" {"a": [1, -2, true, null, "x\"\u00e9\ud83d\ude00"], "b": {}, "c": []} "#;
+	assert_eq!(Json::from_str(s).unwrap(), parse(s).unwrap());
+}
+
+#[test]
+fn test_parse_numbers() {
+	let j = parse(r#"{"a": 1.50, "b": -0.0, "c": 1e300, "d": 18446744073709551616, "e": "\u0000"}"#).unwrap();
+	let text = |k: &str| ::json::number_text(j.find(k).unwrap());
+	assert_eq!(Some("1.5".to_string()), text("a"));
+	assert_eq!(Some("-0.0".to_string()), text("b"));
+	assert_eq!(Some("1e300".to_string()), text("c"));
+	assert_eq!(Some("1.8446744073709552e19".to_string()), text("d"));
+	assert_eq!(Some("\u{0}"), j.find("e").unwrap().as_string());
+	assert!(parse("1e999999999").is_err());
+}
+
+#[test]
+fn test_parse_errors() {
+	for bad in &["", "{", "[1,]", "{\"a\" 1}", "01", "1.", "-", "1e", "\"\\x\"", "\"a\nb\"", "{\"a\": 1, \"a\": 2}", "1 2"] {
+		assert!(parse(bad).is_err(), "{}", bad);
+	}
+}
@@ -0,0 +1,173 @@
+use std::io::{self, Write};
+
+use primitive::base64::Encoder;
+
+// A streaming JSON writer, compact like XmlWriter. The model types write
+// themselves through it (see Resource::write_json) in an order given by an
+// ElementOrder rather than the alphabetical order of a Json object.
+
+// Where a child element belongs in output: its position among the children
+// defined at `path` ("Patient", "Patient.contact", "HumanName"), and the
+// path its own children are defined under
+pub trait ElementOrder {
+	fn place(&self, path: &str, name: &str) -> Option<(usize, String)>;
+}
+
+// Keeps elements in the order the model holds them
+pub struct ModelOrder;
+
+impl ElementOrder for ModelOrder {
+	fn place(&self, _path: &str, _name: &str) -> Option<(usize, String)> {
+		None
+	}
+}
+
+// resourceType, id and meta lead; then the defined order; then members the
+// definition doesn't know, in the order they are held (the sort is stable)
+pub fn sort_key<O: ElementOrder>(order: &O, path: &str, name: &str) -> ((usize, usize), String) {
+	let placed = order.place(path, name);
+	let key = match (name, &placed) {
+		("resourceType", _) => (0, 0),
+		("id", _) => (0, 1),
+		("meta", _) => (0, 2),
+		(_, &Some((i, _))) => (1, i),
+		(_, &None) => (2, 0)
+	};
+	(key, placed.map_or_else(|| format!("{}.{}", path, name), |(_, p)| p))
+}
+
+pub struct JsonWriter<W: Write> {
+	out: W,
+	// for each open object or array, whether it has no members yet
+	first: Vec<bool>,
+	keyed: bool
+}
+
+impl<W: Write> JsonWriter<W> {
+	pub fn new(out: W) -> Self {
+		JsonWriter {out: out, first: Vec::new(), keyed: false}
+	}
+
+	fn separate(&mut self) -> io::Result<()> {
+		if self.keyed {
+			self.keyed = false;
+			return Ok(());
+		}
+		if let Some(first) = self.first.last_mut() {
+			if !*first {
+				self.out.write_all(b",")?;
+			}
+			*first = false;
+		}
+		Ok(())
+	}
+
+	fn quoted(&mut self, s: &str) -> io::Result<()> {
+		let mut buf = String::with_capacity(s.len() + 2);
+		buf.push('"');
+		for c in s.chars() {
+			match c {
+				'"' => buf.push_str("\\\""),
+				'\\' => buf.push_str("\\\\"),
+				'\n' => buf.push_str("\\n"),
+				'\r' => buf.push_str("\\r"),
+				'\t' => buf.push_str("\\t"),
+				c if (c as u32) < 0x20 => buf.push_str(&format!("\\u{:04x}", c as u32)),
+				c => buf.push(c)
+			}
+		}
+		buf.push('"');
+		self.out.write_all(buf.as_bytes())
+	}
+
+	pub fn begin_object(&mut self) -> io::Result<()> {
+		self.separate()?;
+		self.first.push(true);
+		self.out.write_all(b"{")
+	}
+
+	pub fn end_object(&mut self) -> io::Result<()> {
+		self.first.pop();
+		self.out.write_all(b"}")
+	}
+
+	pub fn begin_array(&mut self) -> io::Result<()> {
+		self.separate()?;
+		self.first.push(true);
+		self.out.write_all(b"[")
+	}
+
+	pub fn end_array(&mut self) -> io::Result<()> {
+		self.first.pop();
+		self.out.write_all(b"]")
+	}
+
+	// The key of the next member of the open object
+	pub fn key(&mut self, k: &str) -> io::Result<()> {
+		self.separate()?;
+		self.quoted(k)?;
+		self.out.write_all(b":")?;
+		self.keyed = true;
+		Ok(())
+	}
+
+	pub fn string(&mut self, s: &str) -> io::Result<()> {
+		self.separate()?;
+		self.quoted(s)
+	}
+
+	// Bytes as a base64 string, encoded straight into the output
+	pub fn base64(&mut self, bytes: &[u8]) -> io::Result<()> {
+		self.separate()?;
+		self.out.write_all(b"\"")?;
+		let mut e = Encoder::new(&mut self.out);
+		e.write_all(bytes)?;
+		e.finish()?;
+		self.out.write_all(b"\"")
+	}
+
+	// A number, boolean or null, written as given
+	pub fn raw(&mut self, s: &str) -> io::Result<()> {
+		self.separate()?;
+		self.out.write_all(s.as_bytes())
+	}
+
+	pub fn into_inner(self) -> W {
+		self.out
+	}
+}
+
+
+#[test]
+fn test_writer() {
+	let mut w = JsonWriter::new(Vec::new());
+	w.begin_object().unwrap();
+	w.key("b").unwrap();
+	w.raw("1.50").unwrap();
+	w.key("a").unwrap();
+	w.begin_array().unwrap();
+	w.string("x\"y\n").unwrap();
+	w.raw("null").unwrap();
+	w.begin_object().unwrap();
+	w.end_object().unwrap();
+	w.end_array().unwrap();
+	w.end_object().unwrap();
+	assert_eq!(r#"{"b":1.50,"a":["x\"y\n",null,{}]}"#, String::from_utf8(w.into_inner()).unwrap());
+}
+
+#[test]
+fn test_writer_base64() {
+	let mut w = JsonWriter::new(Vec::new());
+	w.begin_array().unwrap();
+	w.base64(b"hello, world").unwrap();
+	w.base64(b"f").unwrap();
+	w.end_array().unwrap();
+	assert_eq!(r#"["aGVsbG8sIHdvcmxk","Zg=="]"#, String::from_utf8(w.into_inner()).unwrap());
+}
+
+#[test]
+fn test_sort_key() {
+	assert_eq!(((0, 0), "Patient.resourceType".to_string()), sort_key(&ModelOrder, "Patient", "resourceType"));
+	assert!(sort_key(&ModelOrder, "Patient", "meta") < sort_key(&ModelOrder, "Patient", "active"));
+	assert_eq!(((2, 0), "Patient.contact".to_string()), sort_key(&ModelOrder, "Patient", "contact"));
+}
@@ -1,10 +1,29 @@
 extern crate chrono;
 extern crate url;
 extern crate rustc_serialize;
+extern crate regex;
 mod primitive;
 mod element;
 mod resource;
 mod extension;
+mod error;
+mod json;
+mod xml;
+mod typed;
+mod codegen;
+mod validate;
+mod fhirpath;
+#[macro_use]
+mod datatypes;
+mod bundle;
+mod narrative;
+mod resolve;
+mod registry;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::process;
 
 use rustc_serialize::json::{ToJson};
 use url::Url;
@@ -16,8 +35,28 @@ use primitive::Primitive;
 
 
 
+// fhir codegen <out.rs> <structure definitions>...
+fn run_codegen(out: &str, inputs: &[String]) -> Result<(), String> {
+	let docs = inputs.iter().map(|p| json::read_file(p)).collect::<Result<Vec<_>,_>>().map_err(|e| e.to_string())?;
+	let src = codegen::generate(&docs).map_err(|e| e.to_string())?;
+	File::create(out).and_then(|mut f| f.write_all(src.as_bytes())).map_err(|e| format!("{}: {}", out, e))
+}
+
 #[allow(dead_code)]
 fn main() {
+	let args: Vec<String> = env::args().collect();
+	if args.len() > 1 && args[1] == "codegen" {
+		if args.len() < 4 {
+			println!("usage: {} codegen <out.rs> <profiles.json>...", args[0]);
+			process::exit(2);
+		}
+		if let Err(e) = run_codegen(&args[2], &args[3..]) {
+			println!("{}", e);
+			process::exit(1);
+		}
+		return;
+	}
+
 	let ext = Extension::builder()
 		.uri(Url::parse("http://example.org/is_happy").ok().unwrap())
 		.atom(Primitive::from(false))
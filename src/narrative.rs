@@ -0,0 +1,357 @@
+use std::fmt;
+
+use element::{Element, Value, ValueType};
+use extension::{Extension, check_understood};
+use primitive::{Primitive, Code};
+use resource::Resource;
+use datatypes::Validate;
+use typed::{Composite, ToValue, FromValue, elts_of, put_opt, get_opt, split_extras, graft_extras};
+use xml::{escape, XmlNode, XmlWriter};
+use xml::reader;
+use error::FhirError;
+#[cfg(test)]
+use error::{issue, issues};
+
+// The human-readable part of a resource. Its div is held as an XHTML tree,
+// which is parsed only for well-formedness; `check` applies the subset of
+// XHTML that FHIR allows, so that a narrative can be shown without running
+// anything in it.
+
+pub const XHTML_NS: &'static str = "http://www.w3.org/1999/xhtml";
+
+const ALLOWED_ELEMENTS: &'static [&'static str] = &["a", "abbr", "acronym", "b", "big", "blockquote", "br", "caption",
+	"cite", "code", "col", "colgroup", "dd", "dfn", "div", "dl", "dt", "em", "h1", "h2", "h3", "h4", "h5", "h6", "hr",
+	"i", "img", "li", "ol", "p", "pre", "q", "samp", "small", "span", "strong", "sub", "sup", "table", "tbody", "td",
+	"tfoot", "th", "thead", "tr", "tt", "ul", "var"];
+
+const ALLOWED_ATTRS: &'static [&'static str] = &["abbr", "accesskey", "align", "alt", "axis", "bgcolor", "border",
+	"cellhalign", "cellpadding", "cellspacing", "cellvalign", "char", "charoff", "charset", "cite", "class", "colspan",
+	"compact", "coords", "dir", "frame", "headers", "height", "href", "hreflang", "hspace", "id", "lang", "longdesc",
+	"name", "nowrap", "rel", "rev", "rowspan", "rules", "scope", "shape", "span", "src", "start", "style", "summary",
+	"tabindex", "title", "type", "valign", "value", "vspace", "width", "xml:lang"];
+
+const STATUSES: &'static [&'static str] = &["generated", "extensions", "additional", "empty"];
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum XhtmlNode {
+	Element(XhtmlElement),
+	Text(String)
+}
+
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct XhtmlElement {
+	pub name: String,
+	pub attrs: Vec<(String, String)>,
+	pub children: Vec<XhtmlNode>
+}
+
+impl XhtmlElement {
+	pub fn new(name: &str) -> Self {
+		XhtmlElement {name: name.to_string(), ..XhtmlElement::default()}
+	}
+
+	pub fn attr(mut self, name: &str, value: &str) -> Self {
+		self.attrs.push((name.to_string(), value.to_string()));
+		self
+	}
+
+	pub fn child(mut self, e: XhtmlElement) -> Self {
+		self.children.push(XhtmlNode::Element(e));
+		self
+	}
+
+	pub fn text(mut self, t: &str) -> Self {
+		self.children.push(XhtmlNode::Text(t.to_string()));
+		self
+	}
+
+	// An empty narrative div
+	pub fn div() -> Self {
+		XhtmlElement::new("div").attr("xmlns", XHTML_NS)
+	}
+
+	pub fn parse(s: &str) -> Result<Self, FhirError> {
+		Ok(XhtmlElement::from_node(&reader::parse(s)?))
+	}
+
+	fn from_node(n: &XmlNode) -> Self {
+		let mut children = Vec::new();
+		let mut runs = n.runs.iter().peekable();
+		for (i, c) in n.children.iter().enumerate() {
+			while runs.peek().map_or(false, |r| r.0 == i) {
+				if let Some(&(_, ref t)) = runs.next() {
+					children.push(XhtmlNode::Text(t.clone()));
+				}
+			}
+			children.push(XhtmlNode::Element(XhtmlElement::from_node(c)));
+		}
+		children.extend(runs.map(|&(_, ref t)| XhtmlNode::Text(t.clone())));
+		XhtmlElement {name: n.name.clone(), attrs: n.attrs.clone(), children: children}
+	}
+
+	fn attr_value(&self, name: &str) -> Option<&str> {
+		self.attrs.iter().find(|&&(ref k, _)| k == name).map(|&(_, ref v)| v.as_ref())
+	}
+
+	// The text, without markup
+	pub fn plain_text(&self) -> String {
+		self.children.iter().map(|c| match *c {
+			XhtmlNode::Text(ref t) => t.clone(),
+			XhtmlNode::Element(ref e) => e.plain_text()
+		}).collect()
+	}
+
+	fn has_content(&self) -> bool {
+		self.name == "img" || self.children.iter().any(|c| match *c {
+			XhtmlNode::Text(ref t) => !t.trim().is_empty(),
+			XhtmlNode::Element(ref e) => e.has_content()
+		})
+	}
+
+	// The narrative rules: a div in the XHTML namespace with some content,
+	// and only the allowed elements and attributes, so no scripts, forms,
+	// style sheets or event handlers. Links may not run script either.
+	pub fn check(&self) -> Vec<FhirError> {
+		self.violations().into_iter().map(|e| e.at("div")).collect()
+	}
+
+	// The same, with paths relative to the element holding the div
+	pub fn violations(&self) -> Vec<FhirError> {
+		let mut issues = Vec::new();
+		if self.name != "div" {
+			issues.push(FhirError::invalid(&self.name, "A narrative must be a div"));
+		}
+		if self.attr_value("xmlns") != Some(XHTML_NS) {
+			issues.push(FhirError::invalid(self.attr_value("xmlns").unwrap_or(""), "A narrative must be in the XHTML namespace"));
+		}
+		if !self.has_content() {
+			issues.push(FhirError::missing("A narrative must have some text"));
+		}
+		self.check_tree(true, &mut issues);
+		issues
+	}
+
+	fn check_tree(&self, root: bool, issues: &mut Vec<FhirError>) {
+		for &(ref k, ref v) in self.attrs.iter() {
+			if root && k == "xmlns" {
+				continue;
+			}
+			if !ALLOWED_ATTRS.contains(&k.as_str()) {
+				issues.push(FhirError::unexpected(k, "Attribute not allowed in a narrative"));
+			} else if (k == "href" || k == "src") && v.trim_start().to_lowercase().starts_with("javascript:") {
+				issues.push(FhirError::invalid(v, "Script in a narrative link"));
+			}
+		}
+		for c in self.children.iter() {
+			if let XhtmlNode::Element(ref e) = *c {
+				let mut inner = Vec::new();
+				if !ALLOWED_ELEMENTS.contains(&e.name.as_str()) {
+					inner.push(FhirError::unexpected(&e.name, "Element not allowed in a narrative"));
+				} else {
+					e.check_tree(false, &mut inner);
+				}
+				issues.extend(inner.into_iter().map(|err| err.at(&e.name)));
+			}
+		}
+	}
+}
+
+// A div as read from JSON or XML. It must keep to the narrative rules, and
+// is kept re-serialized from its tree, so no markup is passed on that hasn't
+// been parsed and checked.
+pub fn read_div(s: &str) -> Result<String, FhirError> {
+	let div = XhtmlElement::parse(s)?;
+	match div.violations().into_iter().next() {
+		Some(e) => Err(e),
+		None => Ok(div.to_string())
+	}
+}
+
+// A div as written to JSON or XML. Markup goes out only from a checked div;
+// anything else goes out as text.
+pub fn safe_div(s: &str) -> String {
+	read_div(s).unwrap_or_else(|_| format!("<div xmlns=\"{}\">{}</div>", XHTML_NS, escape(s)))
+}
+
+pub fn write_div(s: &str, w: &mut XmlWriter) {
+	w.raw(&safe_div(s));
+}
+
+impl fmt::Display for XhtmlElement {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "<{}", self.name)?;
+		for &(ref k, ref v) in self.attrs.iter() {
+			write!(f, " {}=\"{}\"", k, escape(v))?;
+		}
+		if self.children.is_empty() {
+			return write!(f, "/>");
+		}
+		write!(f, ">")?;
+		for c in self.children.iter() {
+			match *c {
+				XhtmlNode::Text(ref t) => write!(f, "{}", escape(t))?,
+				XhtmlNode::Element(ref e) => write!(f, "{}", e)?
+			}
+		}
+		write!(f, "</{}>", self.name)
+	}
+}
+
+impl ToValue for XhtmlElement {
+	fn to_value(&self) -> Value {
+		Value::from(ValueType::Atom(Some(Primitive::Xhtml(self.to_string()))))
+	}
+}
+
+impl FromValue for XhtmlElement {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		match v.value {
+			ValueType::Atom(Some(Primitive::Xhtml(ref s))) | ValueType::Atom(Some(Primitive::String(ref s))) => XhtmlElement::parse(s),
+			_ => Err(FhirError::missing("Expected XHTML"))
+		}
+	}
+}
+
+datatype!(Narrative {
+	status: opt Code = "status",
+	div: opt XhtmlElement = "div"
+});
+
+impl Narrative {
+	pub fn new(status: Code, div: XhtmlElement) -> Self {
+		Narrative {status: Some(status), div: Some(div), ..Narrative::default()}
+	}
+
+	// A generated narrative listing the resource's elements and their values
+	pub fn generate(r: &Resource) -> Self {
+		let mut title = XhtmlElement::new("p").child(XhtmlElement::new("b").text(&r.name));
+		if let Some(ref id) = r.id {
+			title = title.text(&format!(" {}", id.as_str()));
+		}
+		let mut rows = XhtmlElement::new("tbody");
+		for e in r.elts.iter().filter(|e| e.name != "text") {
+			let shown = summary(&e.value);
+			if !shown.is_empty() {
+				rows = rows.child(XhtmlElement::new("tr")
+					.child(XhtmlElement::new("th").text(&e.name))
+					.child(XhtmlElement::new("td").text(&shown)));
+			}
+		}
+		let mut div = XhtmlElement::div().child(title);
+		if !rows.children.is_empty() {
+			div = div.child(XhtmlElement::new("table").child(rows));
+		}
+		Narrative {status: Code::parse("generated").ok(), div: Some(div), ..Narrative::default()}
+	}
+}
+
+// A value in a line: the items of a list by commas, the parts of a composite
+// by spaces, and extensions left out
+fn summary(v: &Value) -> String {
+	let parts: Vec<String> = match v.value {
+		ValueType::Atom(ref p) => return p.as_ref().map(|p| p.to_string()).unwrap_or_default(),
+		ValueType::List(ref list) => return list.iter().map(summary).filter(|s| !s.is_empty()).collect::<Vec<String>>().join(", "),
+		ValueType::Elt(ref elts) => elts.iter().map(|e| summary(&e.value)).collect()
+	};
+	parts.into_iter().filter(|s| !s.is_empty()).collect::<Vec<String>>().join(" ")
+}
+
+impl Validate for Narrative {
+	fn validate(&self) -> Result<(), FhirError> {
+		match self.status {
+			Some(ref s) if !STATUSES.contains(&s.as_str()) => return Err(FhirError::invalid(s.as_str(), "Invalid narrative status").at("status")),
+			None => return Err(FhirError::missing("Missing required element").at("status")),
+			_ => ()
+		}
+		match self.div {
+			Some(ref div) => div.check().into_iter().next().map_or(Ok(()), Err),
+			None => Err(FhirError::missing("Missing required element").at("div"))
+		}
+	}
+}
+
+
+#[test]
+fn test_xhtml_round_trip() {
+	let s = r#"<div xmlns="http://www.w3.org/1999/xhtml"><p>Jo &amp; <b>Al</b> Smith</p><br/>tail</div>"#;
+	let div = XhtmlElement::parse(s).unwrap();
+	assert_eq!(3, div.children.len());
+	match div.children[0] {
+		XhtmlNode::Element(ref p) => assert_eq!(3, p.children.len()),
+		_ => panic!("expected p")
+	}
+	assert_eq!("Jo & Al Smithtail", div.plain_text());
+	assert_eq!(s, div.to_string());
+	assert!(div.check().is_empty());
+}
+
+#[test]
+fn test_narrative_safety() {
+	let check = |s: &str| issues(&XhtmlElement::parse(s).unwrap().check());
+	assert_eq!(vec![("div.p.script".to_string(), "Element not allowed in a narrative")],
+		check(r#"<div xmlns="http://www.w3.org/1999/xhtml"><p><script>alert(1)</script>Hi</p></div>"#));
+	assert_eq!(vec![("div.p".to_string(), "Attribute not allowed in a narrative")],
+		check(r#"<div xmlns="http://www.w3.org/1999/xhtml"><p onclick="x()">Hi</p></div>"#));
+	assert_eq!(vec![("div.a".to_string(), "Script in a narrative link")],
+		check(r#"<div xmlns="http://www.w3.org/1999/xhtml"><a href=" JavaScript:x()">Hi</a></div>"#));
+	assert_eq!(vec![("div".to_string(), "A narrative must be in the XHTML namespace"), ("div".to_string(), "A narrative must have some text")],
+		check(r#"<div><p> </p></div>"#));
+	assert!(XhtmlElement::parse("<div><p>unclosed</div>").is_err());
+}
+
+#[test]
+fn test_narrative_in_resource() {
+	use rustc_serialize::json::{Json, ToJson};
+	use json::FromJson;
+	use xml::{ToXml, FromXml};
+	let mut r = Resource::from_json(&Json::from_str(r#"{"resourceType": "Patient", "id": "p1", "active": true,
+		"name": [{"family": "Smith", "given": ["Jo", "Al"]}], "_active": {"extension": [{"url": "http://example.org/x", "valueBoolean": true}]}}"#).unwrap()).unwrap();
+	assert_eq!(None, r.narrative().unwrap());
+	let n = Narrative::generate(&r);
+	assert!(n.validate().is_ok());
+	assert_eq!(concat!(r#"<div xmlns="http://www.w3.org/1999/xhtml"><p><b>Patient</b> p1</p><table><tbody>"#,
+		r#"<tr><th>active</th><td>true</td></tr><tr><th>name</th><td>Smith Jo, Al</td></tr></tbody></table></div>"#),
+		n.div.as_ref().unwrap().to_string());
+	r.set_narrative(n.clone());
+	assert_eq!(Some(n.clone()), r.narrative().unwrap());
+	let j = r.to_json();
+	assert_eq!(Some("generated"), j.find_path(&["text", "status"]).and_then(|s| s.as_string()));
+	assert_eq!(Some(n.clone()), Resource::from_json(&j).unwrap().narrative().unwrap());
+	assert_eq!(Some(n), Resource::from_xml(&r.to_xml()).unwrap().narrative().unwrap());
+	assert!(Narrative {status: Some(Code::parse("draft").unwrap()), ..Narrative::generate(&r)}.validate().is_err());
+}
+
+#[test]
+fn test_div_reserialized() {
+	use rustc_serialize::json::Json;
+	use json::FromJson;
+	use xml::ToXml;
+	let read = |div: &str| Resource::from_json(&Json::from_str(&format!(r#"{{"resourceType": "Patient", "text": {{"status": "generated", "div": {}}}}}"#,
+		Json::String(div.to_string()))).unwrap());
+	assert!(read(r#"<div xmlns="http://www.w3.org/1999/xhtml">Hi</div><script>alert(1)</script>"#).is_err());
+	assert_eq!("Patient.text.div", read(r#"<p xmlns="http://www.w3.org/1999/xhtml">Hi</p>"#).unwrap_err().path());
+	let r = read("<div xmlns='http://www.w3.org/1999/xhtml'>Hi<br></br></div>").unwrap();
+	assert!(r.to_xml().contains(r#"<div xmlns="http://www.w3.org/1999/xhtml">Hi<br/></div>"#));
+	let mut w = XmlWriter::new();
+	Value::from(ValueType::Atom(Some(Primitive::Xhtml("</div><script>x()</script>".to_string())))).write_xml("div", &mut w);
+	assert_eq!(r#"<div xmlns="http://www.w3.org/1999/xhtml">&lt;/div&gt;&lt;script&gt;x()&lt;/script&gt;</div>"#, w.into_string());
+}
+
+#[test]
+fn test_unsafe_div_refused() {
+	use rustc_serialize::json::{Json, ToJson};
+	use json::FromJson;
+	use xml::{ToXml, FromXml};
+	let json = |div: &str| Resource::from_json(&Json::from_str(&format!(r#"{{"resourceType": "Patient", "text": {{"status": "generated", "div": {}}}}}"#,
+		Json::String(div.to_string()))).unwrap()).map_err(|e| issue(&e));
+	assert_eq!(Err(("Patient.text.div.p.script".to_string(), "Element not allowed in a narrative")),
+		json(r#"<div xmlns="http://www.w3.org/1999/xhtml"><p><script>alert(1)</script>Hi</p></div>"#));
+	assert_eq!(Err(("Patient.text.div.a".to_string(), "Script in a narrative link")),
+		json(r#"<div xmlns="http://www.w3.org/1999/xhtml"><a href="javascript:x()">Hi</a></div>"#));
+	assert!(Resource::from_xml(r#"<Patient xmlns="http://hl7.org/fhir"><text><status value="generated"/><div xmlns="http://www.w3.org/1999/xhtml"><p onclick="x()">Hi</p></div></text></Patient>"#).is_err());
+	let mut r = Resource::new("Patient");
+	r.set_narrative(Narrative::new(Code::parse("generated").unwrap(), XhtmlElement::div().child(XhtmlElement::new("script").text("x()"))));
+	let shown = r#"<div xmlns="http://www.w3.org/1999/xhtml">&lt;div xmlns=&quot;http://www.w3.org/1999/xhtml&quot;&gt;&lt;script&gt;x()&lt;/script&gt;&lt;/div&gt;</div>"#;
+	assert_eq!(Some(shown), r.to_json().find_path(&["text", "div"]).and_then(|d| d.as_string()));
+	assert!(r.to_xml().contains(shown));
+}
@@ -0,0 +1,203 @@
+use std::io::{self, Read, Write};
+
+use error::FhirError;
+
+// Standard base64 (RFC 4648) with padding. Decoding skips whitespace, which
+// XML documents may wrap long values with. Encoder and Decoder adapt a
+// writer or reader so large attachments can be streamed a chunk at a time.
+
+const ALPHABET: &'static [u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Groups the Encoder encodes at a time
+const BLOCK: usize = 1024;
+
+fn encode_chunk(chunk: &[u8], out: &mut String) {
+	let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+	let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+	for i in 0..4 {
+		if i <= chunk.len() {
+			out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+		} else {
+			out.push('=');
+		}
+	}
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+	let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+	for chunk in bytes.chunks(3) {
+		encode_chunk(chunk, &mut out);
+	}
+	out
+}
+
+fn sextet(c: u8) -> Option<u32> {
+	ALPHABET.iter().position(|a| *a == c).map(|p| p as u32)
+}
+
+// Decode one group of four symbols, of which the last one or two may be padding
+fn decode_quad(q: &[u8], out: &mut Vec<u8>) -> Result<(), FhirError> {
+	let invalid = || FhirError::invalid(&String::from_utf8_lossy(q), "Invalid base64");
+	let pad = q.iter().rev().take_while(|c| **c == b'=').count();
+	if q.len() != 4 || pad > 2 {
+		return Err(invalid());
+	}
+	let mut n = 0u32;
+	for c in q[..4 - pad].iter() {
+		n = n << 6 | sextet(*c).ok_or_else(invalid)?;
+	}
+	n <<= 6 * pad as u32;
+	let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+	out.extend_from_slice(&bytes[..3 - pad]);
+	Ok(())
+}
+
+pub fn decode(s: &str) -> Result<Vec<u8>, FhirError> {
+	let symbols: Vec<u8> = s.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+	if symbols.len() % 4 != 0 {
+		return Err(FhirError::invalid(s, "Invalid base64 length"));
+	}
+	let mut out = Vec::with_capacity(symbols.len() / 4 * 3);
+	let quads = symbols.chunks(4).count();
+	for (i, q) in symbols.chunks(4).enumerate() {
+		if i + 1 < quads && q.contains(&b'=') {
+			return Err(FhirError::invalid(s, "Invalid base64 padding"));
+		}
+		decode_quad(q, &mut out).map_err(|e| e.at(&format!("[{}]", i * 4)))?;
+	}
+	Ok(out)
+}
+
+// Writes base64 text for the bytes written to it; call finish to flush
+// the final partial group and its padding
+pub struct Encoder<W: Write> {
+	inner: W,
+	pending: Vec<u8>
+}
+
+impl<W: Write> Encoder<W> {
+	pub fn new(inner: W) -> Self {
+		Encoder {inner: inner, pending: Vec::with_capacity(3)}
+	}
+
+	pub fn finish(mut self) -> io::Result<W> {
+		if !self.pending.is_empty() {
+			let mut out = String::with_capacity(4);
+			encode_chunk(&self.pending, &mut out);
+			self.inner.write_all(out.as_bytes())?;
+		}
+		Ok(self.inner)
+	}
+}
+
+impl<W: Write> Write for Encoder<W> {
+	// Complete the pending group, then encode whole groups a block at a time
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let mut rest = buf;
+		while !self.pending.is_empty() && self.pending.len() < 3 && !rest.is_empty() {
+			self.pending.push(rest[0]);
+			rest = &rest[1..];
+		}
+		if self.pending.len() == 3 {
+			let mut out = String::with_capacity(4);
+			encode_chunk(&self.pending, &mut out);
+			self.inner.write_all(out.as_bytes())?;
+			self.pending.clear();
+		}
+		let whole = rest.len() / 3 * 3;
+		for block in rest[..whole].chunks(3 * BLOCK) {
+			self.inner.write_all(encode(block).as_bytes())?;
+		}
+		self.pending.extend_from_slice(&rest[whole..]);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+// Reads the bytes encoded by the base64 text of an inner reader
+pub struct Decoder<R: Read> {
+	inner: R,
+	symbols: Vec<u8>,
+	decoded: Vec<u8>,
+	padded: bool,
+	done: bool
+}
+
+impl<R: Read> Decoder<R> {
+	pub fn new(inner: R) -> Self {
+		Decoder {inner: inner, symbols: Vec::new(), decoded: Vec::new(), padded: false, done: false}
+	}
+
+	fn fill(&mut self) -> io::Result<()> {
+		let mut buf = [0u8; 4096];
+		while self.decoded.is_empty() && !self.done {
+			let n = self.inner.read(&mut buf)?;
+			self.done = n == 0;
+			self.symbols.extend(buf[..n].iter().filter(|c| !c.is_ascii_whitespace()));
+			let whole = self.symbols.len() / 4 * 4;
+			if self.done && whole != self.symbols.len() {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid base64 length"));
+			}
+			for q in self.symbols[..whole].chunks(4) {
+				if self.padded {
+					return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid base64 padding"));
+				}
+				decode_quad(q, &mut self.decoded).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+				self.padded = q.contains(&b'=');
+			}
+			self.symbols.drain(..whole);
+		}
+		Ok(())
+	}
+}
+
+impl<R: Read> Read for Decoder<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.fill()?;
+		let n = buf.len().min(self.decoded.len());
+		buf[..n].copy_from_slice(&self.decoded[..n]);
+		self.decoded.drain(..n);
+		Ok(n)
+	}
+}
+
+#[test]
+fn test_encode() {
+	assert_eq!("", encode(b""));
+	assert_eq!("Zg==", encode(b"f"));
+	assert_eq!("Zm8=", encode(b"fo"));
+	assert_eq!("Zm9v", encode(b"foo"));
+	assert_eq!("aGVsbG8sIHdvcmxk", encode(b"hello, world"));
+}
+
+#[test]
+fn test_decode() {
+	assert_eq!(b"foob".to_vec(), decode("Zm9vYg==").unwrap());
+	assert_eq!(b"hello, world".to_vec(), decode("aGVs bG8s\nIHdv cmxk").unwrap());
+	assert!(decode("Zm9vYg=").is_err());
+	assert!(decode("Zg==Zm9v").is_err());
+	assert!(decode("Zm9*").is_err());
+}
+
+#[test]
+fn test_streaming() {
+	let data: Vec<u8> = (0..10000u32).map(|i| (i * 7) as u8).collect();
+	let mut e = Encoder::new(Vec::new());
+	for chunk in data.chunks(1000) {
+		e.write_all(chunk).unwrap();
+	}
+	let text = e.finish().unwrap();
+	assert_eq!(encode(&data).into_bytes(), text);
+	let mut e = Encoder::new(Vec::new());
+	for b in data[..7].iter() {
+		e.write_all(&[*b]).unwrap();
+	}
+	assert_eq!(encode(&data[..7]).into_bytes(), e.finish().unwrap());
+	let mut back = Vec::new();
+	Decoder::new(&text[..]).read_to_end(&mut back).unwrap();
+	assert_eq!(data, back);
+	assert!(Decoder::new(&b"Zm9vY"[..]).read_to_end(&mut Vec::new()).is_err());
+}
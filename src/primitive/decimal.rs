@@ -1,88 +1,410 @@
 use std::fmt;
+use std::cmp::Ordering;
+use std::ops::{Add, Sub, Mul, Neg};
 
-#[derive(Debug)]
+use error::FhirError;
+
+// An exact decimal: the value is digits × 10^-scale. Digits are kept least
+// significant first, without high zeros, so zero has no digits. The scale is
+// the precision written (0.10 has scale 2); exponent forms can make it negative.
+#[derive(Debug,Clone)]
 pub struct Dec {
-	pub val: f64,
-	pub precision: usize 
+	neg: bool,
+	digits: Vec<u8>,
+	scale: i32,
+	text: Option<String>
+}
+
+// Digits kept after the point when a quotient does not terminate
+const DIV_SCALE: i32 = 8;
+
+// Exponents beyond this would ask for more zeros than any value needs;
+// 1e999999999 would otherwise be padded out to a billion digits
+const MAX_EXPONENT: i64 = 1000;
+
+fn trim(mut d: Vec<u8>) -> Vec<u8> {
+	while d.last() == Some(&0) {
+		d.pop();
+	}
+	d
 }
 
+fn cmp_mag(a: &[u8], b: &[u8]) -> Ordering {
+	a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_mag(a: &[u8], b: &[u8]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+	let mut carry = 0;
+	for i in 0..a.len().max(b.len()) {
+		let s = a.get(i).cloned().unwrap_or(0) + b.get(i).cloned().unwrap_or(0) + carry;
+		out.push(s % 10);
+		carry = s / 10;
+	}
+	out.push(carry);
+	trim(out)
+}
+
+// a - b, where a >= b
+fn sub_mag(a: &[u8], b: &[u8]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(a.len());
+	let mut borrow = 0;
+	for i in 0..a.len() {
+		let mut d = a[i] as i8 - b.get(i).cloned().unwrap_or(0) as i8 - borrow;
+		borrow = if d < 0 { d += 10; 1 } else { 0 };
+		out.push(d as u8);
+	}
+	trim(out)
+}
+
+fn mul_mag(a: &[u8], b: &[u8]) -> Vec<u8> {
+	let mut out = vec![0u32; a.len() + b.len()];
+	for (i, x) in a.iter().enumerate() {
+		for (j, y) in b.iter().enumerate() {
+			out[i + j] += (*x as u32) * (*y as u32);
+		}
+	}
+	let mut carry = 0;
+	let digits = out.iter().map(|d| {
+		let s = d + carry;
+		carry = s / 10;
+		(s % 10) as u8
+	}).collect::<Vec<_>>();
+	trim(digits)
+}
+
+// Long division of magnitudes: (quotient, remainder)
+fn divmod_mag(a: &[u8], b: &[u8]) -> (Vec<u8>, Vec<u8>) {
+	let mut q = vec![0u8; a.len()];
+	let mut r: Vec<u8> = Vec::new();
+	for i in (0..a.len()).rev() {
+		r.insert(0, a[i]);
+		r = trim(r);
+		while cmp_mag(&r, b) != Ordering::Less {
+			r = sub_mag(&r, b);
+			q[i] += 1;
+		}
+	}
+	(trim(q), r)
+}
+
+fn shift(d: &[u8], n: i32) -> Vec<u8> {
+	if d.is_empty() || n <= 0 {
+		return d.to_vec();
+	}
+	let mut out = vec![0u8; n as usize];
+	out.extend_from_slice(d);
+	out
+}
 
 impl Dec {
-	fn find_precision(s: &str) -> usize {
-		s.rfind('.').map(|i| s.len() - i - 1 ).unwrap_or(0)
+	fn new(neg: bool, digits: Vec<u8>, scale: i32) -> Self {
+		let digits = trim(digits);
+		Dec {neg: neg && !digits.is_empty(), digits: digits, scale: scale, text: None}
+	}
+
+	// Decimals as FHIR writes them: -?digits(.digits)?([eE][+-]?digits)?
+	pub fn from_str(s: &str) -> Result<Self,FhirError> {
+		let invalid = || FhirError::invalid(s, "Invalid decimal");
+		let (neg, body) = match s.chars().next() {
+			Some('-') => (true, &s[1..]),
+			_ => (false, s)
+		};
+		let (mantissa, exp) = match body.find(|c| c == 'e' || c == 'E') {
+			Some(i) => (&body[..i], Some(body[i + 1..].parse::<i64>().map_err(|_| invalid())?)),
+			None => (body, None)
+		};
+		if exp.map_or(false, |e| e.abs() > MAX_EXPONENT) {
+			return Err(FhirError::invalid(s, "Decimal exponent out of range"));
+		}
+		let (int, frac) = match mantissa.find('.') {
+			Some(i) if i + 1 < mantissa.len() => (&mantissa[..i], &mantissa[i + 1..]),
+			Some(_) => return Err(invalid()),
+			None => (mantissa, "")
+		};
+		if int.is_empty() || !int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit()) {
+			return Err(invalid());
+		}
+		let scale = frac.len() as i64 - exp.unwrap_or(0);
+		if scale > i32::MAX as i64 {
+			return Err(FhirError::invalid(s, "Decimal too long"));
+		}
+		let digits = int.bytes().chain(frac.bytes()).rev().map(|b| b - b'0').collect();
+		let mut d = Dec::new(neg, digits, scale as i32);
+		if exp.is_some() {
+			d.text = Some(s.to_string());
+		}
+		Ok(d)
+	}
+
+	pub fn from_i64(i: i64) -> Self {
+		let mut digits = Vec::new();
+		let mut n = i.unsigned_abs();
+		while n > 0 {
+			digits.push((n % 10) as u8);
+			n /= 10;
+		}
+		Dec::new(i < 0, digits, 0)
+	}
+
+	// Digits after the point; an exponent form such as 1e3 has none
+	pub fn precision(&self) -> usize {
+		self.scale.max(0) as usize
+	}
+
+	pub fn is_zero(&self) -> bool {
+		self.digits.is_empty()
+	}
+
+	pub fn to_f64(&self) -> f64 {
+		self.to_string().parse().unwrap_or(0f64)
 	}
 
-	pub fn from_str(s: &str) -> Result<Self,()> {
-		let f :f64  = match s.parse() {
-			Ok(f) => f,
-			_ => return Err(()) // can't handle error due to Bug #24748
+	// The integral value, if it has no fractional part and fits
+	pub fn to_i64(&self) -> Option<i64> {
+		let d = self.rescale(0);
+		if d.cmp(self) != Ordering::Equal || d.digits.len() > 19 {
+			return None;
+		}
+		let n = d.digits.iter().rev().fold(0i128, |n, x| n * 10 + *x as i128);
+		let n = if d.neg { -n } else { n };
+		if n >= i64::MIN as i128 && n <= i64::MAX as i128 { Some(n as i64) } else { None }
+	}
+
+	// The coefficient at another scale; digits past a smaller scale are truncated
+	fn rescale(&self, scale: i32) -> Dec {
+		let digits = if scale >= self.scale {
+			shift(&self.digits, scale - self.scale)
+		} else {
+			let n = (self.scale - scale) as usize;
+			if n >= self.digits.len() { Vec::new() } else { self.digits[n..].to_vec() }
 		};
-		Ok(Dec {
-			val: f,
-			precision: Dec::find_precision(s)
-		})
+		Dec::new(self.neg, digits, scale)
+	}
+
+	pub fn abs(&self) -> Dec {
+		Dec::new(false, self.digits.clone(), self.scale)
+	}
+
+	pub fn trunc(&self) -> Dec {
+		self.rescale(self.scale.min(0))
+	}
+
+	pub fn floor(&self) -> Dec {
+		let t = self.trunc();
+		if self.neg && t != *self { t - Dec::from_i64(1) } else { t }
+	}
+
+	pub fn ceil(&self) -> Dec {
+		let t = self.trunc();
+		if !self.neg && t != *self { t + Dec::from_i64(1) } else { t }
+	}
+
+	// Round half away from zero to the given number of places
+	pub fn round(&self, places: i32) -> Dec {
+		if places >= self.scale {
+			return self.clone();
+		}
+		let half = Dec::new(self.neg, vec![5], places + 1);
+		(self.clone() + half).rescale(places)
+	}
+
+	// None for division by zero; quotients that do not terminate are cut at DIV_SCALE places
+	pub fn checked_div(&self, other: &Dec) -> Option<Dec> {
+		if other.is_zero() {
+			return None;
+		}
+		let scale = (self.scale.max(other.scale).max(0) + DIV_SCALE).max(self.scale - other.scale);
+		let a = shift(&self.digits, scale + other.scale - self.scale);
+		let (q, _) = divmod_mag(&a, &other.digits);
+		let mut d = Dec::new(self.neg != other.neg, q, scale);
+		while d.scale > 0 && d.digits.first() == Some(&0) {
+			d.digits.remove(0);
+			d.scale -= 1;
+		}
+		if d.digits.is_empty() {
+			d.scale = 0;
+		}
+		Some(d)
+	}
+
+	// Truncated division and its remainder, the sign following the dividend
+	pub fn checked_divmod(&self, other: &Dec) -> Option<(Dec, Dec)> {
+		if other.is_zero() {
+			return None;
+		}
+		let scale = self.scale.max(other.scale);
+		let (a, b) = (self.rescale(scale), other.rescale(scale));
+		let (q, r) = divmod_mag(&a.digits, &b.digits);
+		Some((Dec::new(self.neg != other.neg, q, 0), Dec::new(self.neg, r, scale)))
+	}
+
+	// The interval implied by the written precision: 0.10 stands for [0.095, 0.105)
+	pub fn range(&self) -> (Dec, Dec) {
+		let half = Dec::new(false, vec![5], self.scale + 1);
+		(self.clone() - half.clone(), self.clone() + half)
+	}
+
+	// Whether a value lies within the implicit range of this one, as in search
+	pub fn contains(&self, v: &Dec) -> bool {
+		let (lo, hi) = self.range();
+		lo <= *v && *v < hi
+	}
+
+	// Equal when rounded to the precision of the less precise operand
+	pub fn equivalent(&self, other: &Dec) -> bool {
+		let places = self.scale.min(other.scale);
+		self.round(places) == other.round(places)
+	}
+}
+
+impl PartialEq for Dec {
+	fn eq(&self, other: &Dec) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for Dec {}
+
+impl PartialOrd for Dec {
+	fn partial_cmp(&self, other: &Dec) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Dec {
+	fn cmp(&self, other: &Dec) -> Ordering {
+		let scale = self.scale.max(other.scale);
+		let mag = cmp_mag(&shift(&self.digits, scale - self.scale), &shift(&other.digits, scale - other.scale));
+		match (self.neg, other.neg) {
+			(false, false) => mag,
+			(true, true) => mag.reverse(),
+			(true, false) => Ordering::Less,
+			(false, true) => Ordering::Greater
+		}
+	}
+}
+
+impl Neg for Dec {
+	type Output = Dec;
+	fn neg(self) -> Dec {
+		Dec::new(!self.neg, self.digits, self.scale)
+	}
+}
+
+impl Add for Dec {
+	type Output = Dec;
+	fn add(self, other: Dec) -> Dec {
+		let scale = self.scale.max(other.scale);
+		let (a, b) = (shift(&self.digits, scale - self.scale), shift(&other.digits, scale - other.scale));
+		if self.neg == other.neg {
+			return Dec::new(self.neg, add_mag(&a, &b), scale);
+		}
+		match cmp_mag(&a, &b) {
+			Ordering::Less => Dec::new(other.neg, sub_mag(&b, &a), scale),
+			_ => Dec::new(self.neg, sub_mag(&a, &b), scale)
+		}
+	}
+}
+
+impl Sub for Dec {
+	type Output = Dec;
+	fn sub(self, other: Dec) -> Dec {
+		self + -other
+	}
+}
+
+impl Mul for Dec {
+	type Output = Dec;
+	fn mul(self, other: Dec) -> Dec {
+		Dec::new(self.neg != other.neg, mul_mag(&self.digits, &other.digits), self.scale + other.scale)
 	}
 }
 
+impl From<i32> for Dec {
+	fn from(i: i32) -> Self {
+		Dec::from_i64(i as i64)
+	}
+}
+
+// As written when parsed from an exponent form; otherwise plain, with trailing zeros kept
 impl fmt::Display for Dec {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f,"{:.*}",self.precision,self.val)
+		if let Some(ref t) = self.text {
+			return write!(f, "{}", t);
+		}
+		let mut s: String = self.digits.iter().rev().map(|d| (b'0' + d) as char).collect();
+		if self.scale < 0 {
+			let zeros = if s.is_empty() { 1 } else { -self.scale as usize };
+			s.extend(::std::iter::repeat('0').take(zeros));
+			return write!(f, "{}{}", if self.neg { "-" } else { "" }, s);
+		}
+		let scale = self.scale as usize;
+		while s.len() <= scale {
+			s.insert(0, '0');
+		}
+		if scale > 0 {
+			s.insert(s.len() - scale, '.');
+		}
+		write!(f, "{}{}", if self.neg { "-" } else { "" }, s)
 	}
 }
 
 #[test]
 fn test_decimal_from_string() {
 	let d = Dec::from_str("3.14").ok().unwrap();
-	assert_eq!(d.val, 3.14f64);
-	assert_eq!(d.precision, 2);
+	assert_eq!(d.to_f64(), 3.14f64);
+	assert_eq!(d.precision(), 2);
 }
 
 #[test]
 fn test_decimal_representation() {
 	let d = Dec::from_str("0.1").ok().unwrap(); //force rounding
 	assert_eq!("0.1", d.to_string());
-	assert_eq!(0.1f64, d.val);
-	assert_eq!(1, d.precision);
+	assert_eq!(0.1f64, d.to_f64());
+	assert_eq!(1, d.precision());
 }
 
 #[test]
 fn test_decimal_representation_with_precision() {
 	let d = Dec::from_str("0.10").ok().unwrap(); //force rounding
 	assert_eq!("0.10", d.to_string());
-	assert_eq!(0.1f64, d.val);
-	assert_eq!(2, d.precision);
+	assert_eq!(0.1f64, d.to_f64());
+	assert_eq!(2, d.precision());
 }
 
 #[test]
 fn test_long_zero_decimal_from_string() {
 	let d = Dec::from_str("0.0000000").ok().unwrap();
-	assert_eq!(0f64, d.val);
+	assert_eq!(0f64, d.to_f64());
 	assert_eq!("0.0000000", d.to_string());
-	assert_eq!(7, d.precision);
+	assert_eq!(7, d.precision());
 }
 
 
 #[test]
 fn test_long_decimal_from_string() {
 	let d = Dec::from_str("3.1415926").ok().unwrap();
-	assert_eq!(3.1415926f64, d.val);
-	assert_eq!(7, d.precision);
+	assert_eq!(3.1415926f64, d.to_f64());
+	assert_eq!(7, d.precision());
 }
 
 #[test]
 fn test_integer_with_point_from_string() {
-	let d = Dec::from_str("3.").ok().unwrap();
-	assert_eq!(3f64, d.val);
-	assert_eq!("3", d.to_string());
-	assert_eq!(0, d.precision);
+	// FHIR's decimal needs a digit after the point
+	assert!(Dec::from_str("3.").is_err());
+	let d = Dec::from_str("3.0").ok().unwrap();
+	assert_eq!(3f64, d.to_f64());
+	assert_eq!("3.0", d.to_string());
+	assert_eq!(1, d.precision());
 }
 
 #[test]
 fn test_integer_from_string() {
 	let d = Dec::from_str("3").ok().unwrap();
-	assert_eq!(3f64, d.val);
+	assert_eq!(3f64, d.to_f64());
 	assert_eq!("3", d.to_string());
-	assert_eq!(0, d.precision);
+	assert_eq!(0, d.precision());
 }
 
 #[test]
@@ -96,4 +418,53 @@ fn test_invalid_decimal_from_string() {
 fn test_invalid_numberlike_decimal_from_string() {
 	let d = Dec::from_str("3.141.23");
 	assert!(d.is_err());
+	assert!(Dec::from_str("+5").is_err());
+	assert!(Dec::from_str(".5").is_err());
+}
+
+#[test]
+fn test_large_and_exponent_decimals() {
+	let d = Dec::from_str("123456789012345678901234567890.000000001").unwrap();
+	assert_eq!("123456789012345678901234567890.000000001", d.to_string());
+	let e = Dec::from_str("1.50e2").unwrap();
+	assert_eq!("1.50e2", e.to_string());
+	assert_eq!(Dec::from_i64(150), e);
+	assert_eq!(0, e.precision());
+	assert_eq!(Some(-1500), Dec::from_str("-1.5E3").unwrap().to_i64());
+	assert!(Dec::from_str("1e").is_err());
+	assert_eq!("1e-1000", Dec::from_str("1e-1000").unwrap().to_string());
+	assert!(Dec::from_str("1e999999999").is_err());
+	assert!(Dec::from_str("1e-2147483648").is_err());
+	assert!(Dec::from_str("1e99999999999999999999").is_err());
+}
+
+#[test]
+fn test_decimal_arithmetic() {
+	let d = |s| Dec::from_str(s).unwrap();
+	assert_eq!("0.6", (d("1.8") - d("1.2")).to_string());
+	assert_eq!("2.16", (d("1.2") * d("1.8")).to_string());
+	assert_eq!("-0.25", (d("0.5") + d("-0.75")).to_string());
+	assert_eq!("0.33333333", d("1").checked_div(&d("3")).unwrap().to_string());
+	assert_eq!("2", d("4").checked_div(&d("2")).unwrap().to_string());
+	assert!(d("1").checked_div(&d("0.0")).is_none());
+	let (q, r) = d("5.5").checked_divmod(&d("2")).unwrap();
+	assert_eq!(("2".to_string(), "1.5".to_string()), (q.to_string(), r.to_string()));
+	assert_eq!("3.142", d("3.14159").round(3).to_string());
+	assert_eq!("-3", d("-2.1").floor().to_string());
+	assert_eq!("2", d("1.1").ceil().to_string());
+}
+
+#[test]
+fn test_decimal_comparison_and_precision() {
+	let d = |s| Dec::from_str(s).unwrap();
+	assert_eq!(d("1.0"), d("1.00"));
+	assert!(d("-2") < d("-1.5"));
+	assert!(d("0.10").contains(&d("0.104")));
+	assert!(!d("0.10").contains(&d("0.105")));
+	assert_eq!(("50".to_string(), "150".to_string()), {
+		let (lo, hi) = d("1e2").range();
+		(lo.to_string(), hi.to_string())
+	});
+	assert!(d("1.01").equivalent(&d("1.0")));
+	assert!(!d("1.06").equivalent(&d("1.0")));
 }
@@ -0,0 +1,114 @@
+use std::fmt;
+
+use error::FhirError;
+
+// The non-negative integer types share the 32 bit signed range of integer,
+// so a u32 alone would admit values no other implementation can read back.
+
+const MAX: i64 = 2147483647;
+
+// Digits with no sign or leading zeros, as all the integer types are written
+fn plain_digits(s: &str) -> bool {
+	!s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) && (s == "0" || !s.starts_with('0'))
+}
+
+fn parse_digits(s: &str, reason: &'static str) -> Result<i64,FhirError> {
+	if plain_digits(s) {
+		s.parse().map_err(|_| FhirError::invalid(s, reason))
+	} else {
+		Err(FhirError::invalid(s, reason))
+	}
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub struct UnsignedInt(u32);
+
+impl UnsignedInt {
+	pub fn new(v: i64) -> Result<Self,FhirError> {
+		if v >= 0 && v <= MAX {
+			Ok(UnsignedInt(v as u32))
+		} else {
+			Err(FhirError::invalid(&v.to_string(), "Invalid unsignedInt"))
+		}
+	}
+
+	pub fn parse(s: &str) -> Result<Self,FhirError> {
+		parse_digits(s, "Invalid unsignedInt").and_then(UnsignedInt::new)
+	}
+
+	pub fn value(&self) -> u32 {
+		self.0
+	}
+}
+
+impl fmt::Display for UnsignedInt {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f,"{}",self.0)
+	}
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub struct PositiveInt(u32);
+
+impl PositiveInt {
+	pub fn new(v: i64) -> Result<Self,FhirError> {
+		if v >= 1 && v <= MAX {
+			Ok(PositiveInt(v as u32))
+		} else {
+			Err(FhirError::invalid(&v.to_string(), "Invalid positiveInt"))
+		}
+	}
+
+	// The specification also allows a leading '+'
+	pub fn parse(s: &str) -> Result<Self,FhirError> {
+		parse_digits(s.strip_prefix('+').unwrap_or(s), "Invalid positiveInt").and_then(PositiveInt::new)
+	}
+
+	pub fn value(&self) -> u32 {
+		self.0
+	}
+}
+
+impl fmt::Display for PositiveInt {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f,"{}",self.0)
+	}
+}
+
+// integer64 travels as a JSON string, so its range is checked when the text is read
+pub fn parse_integer64(s: &str) -> Result<i64,FhirError> {
+	let invalid = || FhirError::invalid(s, "Invalid integer64");
+	if s == "-0" || !plain_digits(s.strip_prefix('-').unwrap_or(s)) {
+		return Err(invalid());
+	}
+	s.parse().map_err(|_| invalid())
+}
+
+
+#[test]
+fn test_unsigned_int() {
+	assert_eq!(0, UnsignedInt::new(0).unwrap().value());
+	assert_eq!(2147483647, UnsignedInt::parse("2147483647").unwrap().value());
+	assert!(UnsignedInt::new(-1).is_err());
+	assert!(UnsignedInt::new(2147483648).is_err());
+	for s in ["", "-1", "+1", "01", "1.0", "2147483648"].iter() {
+		assert!(UnsignedInt::parse(s).is_err(), "{}", s);
+	}
+}
+
+#[test]
+fn test_positive_int() {
+	assert_eq!("5", PositiveInt::parse("+5").unwrap().to_string());
+	assert!(PositiveInt::new(0).is_err());
+	assert!(PositiveInt::parse("0").is_err());
+	assert!(PositiveInt::new(2147483648).is_err());
+}
+
+#[test]
+fn test_integer64() {
+	assert_eq!(9223372036854775807, parse_integer64("9223372036854775807").unwrap());
+	assert_eq!(-9223372036854775808, parse_integer64("-9223372036854775808").unwrap());
+	for s in ["9223372036854775808", "-9223372036854775809", "", "-", "007", "1e3", "+1"].iter() {
+		assert!(parse_integer64(s).is_err(), "{}", s);
+	}
+}
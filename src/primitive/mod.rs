@@ -1,34 +1,50 @@
 use std::fmt;
+use std::io::{self, Write};
 use url::{Url};
 use std::convert::{From};
 use chrono::{DateTime,FixedOffset};
 use rustc_serialize::json::{ToJson, Json};
 
+use json::{FromJson, JsonWriter, as_string, number_text};
+use narrative::{read_div, safe_div};
+use error::FhirError;
+
 pub mod decimal;
 pub use primitive::decimal::{Dec};
 pub mod time;
 pub use primitive::time::{Time};
-mod vardate;
-use primitive::vardate::{VarDate};
+pub mod vardate;
+pub use primitive::vardate::{VarDate, Precision};
+pub mod base64;
+pub mod text;
+pub use primitive::text::{Id, Code, Oid, Uuid, Canonical, Markdown};
+pub mod integer;
+pub use primitive::integer::{UnsignedInt, PositiveInt};
 
 
-#[derive(Debug)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum Primitive {
 	Boolean(bool),
 	Int(i32),
-	UInt(u32),
+	UInt(UnsignedInt),
 	Decimal(Dec),
 	String(String),
-	Id(String),
-	Code(String),
+	Id(Id),
+	Code(Code),
 	Uri(Url),
-	Oid(Url),
-	Base64(String),
+	Oid(Oid),
+	Base64(Vec<u8>),
 	Instant(DateTime<FixedOffset>),
 	Date(VarDate),
 	DateTime(VarDate),
 	Time(Time),
-	PInt(u32),
+	PInt(PositiveInt),
+	Canonical(Canonical),
+	Url(Url),
+	Uuid(Uuid),
+	Markdown(Markdown),
+	Integer64(i64),
+	Xhtml(String),
 }
 
 // create a From defintion for each type for Primitive
@@ -44,9 +60,17 @@ macro_rules! gen_from {
 
 gen_from!(bool,Boolean);
 gen_from!(i32,Int);
-gen_from!(u32,UInt);
+gen_from!(i64,Integer64);
+gen_from!(UnsignedInt,UInt);
+gen_from!(PositiveInt,PInt);
 gen_from!(Dec,Decimal);
 gen_from!(String,String);
+gen_from!(Id,Id);
+gen_from!(Code,Code);
+gen_from!(Oid,Oid);
+gen_from!(Canonical,Canonical);
+gen_from!(Uuid,Uuid);
+gen_from!(Markdown,Markdown);
 gen_from!(Time,Time);
 gen_from!(Url,Uri);
 gen_from!(DateTime<FixedOffset>,Instant);
@@ -58,32 +82,175 @@ impl<'a> From<&'a str> for Primitive {
 }
 
 impl Primitive {
+	// Every primitive but the narrative's xhtml may be an extension value
 	pub fn valid_extension(&self) -> bool {
 		match *self {
-			Primitive::Time(_) => false,
-			Primitive::UInt(_) => false,
-			Primitive::Oid(_) => false,
-			Primitive::PInt(_) => false,
-			Primitive::Id(_) => false,
+			Primitive::Xhtml(_) => false,
 			_ => true
 		}
 	}
 
-	pub fn extension_name(&self) -> String {
-		let s = match *self {
+	// The FHIR type name, as used for the suffix of a value[x] element
+	pub fn type_suffix(&self) -> &'static str {
+		match *self {
 			Primitive::Boolean(_) => "Boolean",
 	 		Primitive::Int(_) => "Integer",
+	 		Primitive::UInt(_) => "UnsignedInt",
 	 		Primitive::Decimal(_) => "Decimal",
 	 		Primitive::String(_) => "String",
+	 		Primitive::Id(_) => "Id",
 	 		Primitive::Code(_) => "Code",
 	 		Primitive::Uri(_) => "Uri",
+	 		Primitive::Oid(_) => "Oid",
 	 		Primitive::Base64(_) => "Base64Binary",
 	 		Primitive::Instant(_) => "Instant",
 	 		Primitive::Date(_) => "Date",
 	 		Primitive::DateTime(_) => "DateTime",
-	 		_  => "Error"
+	 		Primitive::Time(_) => "Time",
+	 		Primitive::PInt(_) => "PositiveInt",
+	 		Primitive::Canonical(_) => "Canonical",
+	 		Primitive::Url(_) => "Url",
+	 		Primitive::Uuid(_) => "Uuid",
+	 		Primitive::Markdown(_) => "Markdown",
+	 		Primitive::Integer64(_) => "Integer64",
+	 		Primitive::Xhtml(_) => "Xhtml",
+		}
+	}
+
+	pub fn extension_name(&self) -> String {
+		format!("value{}",self.type_suffix())
+	}
+
+	// XML carries every primitive as a string attribute. Its type comes from
+	// a value[x] suffix or a definition; without one it stays a string, as
+	// text that looks like a number or a boolean may be neither.
+	pub fn from_xml_value(type_name: Option<&str>, s: &str) -> Result<Self, FhirError> {
+		match type_name {
+			Some("Boolean") => s.parse().map(Primitive::Boolean).map_err(|_| FhirError::invalid(s, "Invalid boolean")),
+			Some("Integer") => s.parse().map(Primitive::Int).map_err(|_| FhirError::invalid(s, "Invalid integer")),
+			Some("Decimal") => Dec::from_str(s).map(Primitive::Decimal),
+			Some("UnsignedInt") => UnsignedInt::parse(s).map(Primitive::UInt),
+			Some("PositiveInt") => PositiveInt::parse(s).map(Primitive::PInt),
+			Some(t) => Primitive::from_json_typed(t, &Json::String(s.to_string())),
+			None => Ok(Primitive::from(s))
+		}
+	}
+
+	// Decode a primitive whose FHIR type is known from a value[x] suffix, e.g. "DateTime"
+	pub fn from_json_typed(type_name: &str, j: &Json) -> Result<Self, FhirError> {
+		let invalid = |reason| FhirError::invalid(&j.to_string(), reason);
+		match type_name {
+			"Boolean" => j.as_boolean().map(Primitive::Boolean).ok_or_else(|| invalid("Invalid boolean")),
+			"Integer" => j.as_i64()
+				.and_then(|i| if i >= i32::MIN as i64 && i <= i32::MAX as i64 { Some(i as i32) } else { None })
+				.map(Primitive::Int).ok_or_else(|| invalid("Invalid integer")),
+			"UnsignedInt" => j.as_i64().ok_or_else(|| invalid("Invalid unsignedInt"))
+				.and_then(UnsignedInt::new).map(Primitive::UInt),
+			"PositiveInt" => j.as_i64().ok_or_else(|| invalid("Invalid positiveInt"))
+				.and_then(PositiveInt::new).map(Primitive::PInt),
+			"Integer64" => as_string(j).and_then(integer::parse_integer64).map(Primitive::Integer64),
+			"Decimal" => number_text(j)
+				.ok_or_else(|| invalid("Invalid decimal"))
+				.and_then(|t| Dec::from_str(&t))
+				.map(Primitive::Decimal),
+			"String" => as_string(j).map(Primitive::from),
+			"Id" => as_string(j).and_then(Id::parse).map(Primitive::Id),
+			"Code" => as_string(j).and_then(Code::parse).map(Primitive::Code),
+			"Uri" => as_string(j).and_then(text::parse_uri).map(Primitive::Uri),
+			"Oid" => as_string(j).and_then(Oid::parse).map(Primitive::Oid),
+			"Canonical" => as_string(j).and_then(Canonical::parse).map(Primitive::Canonical),
+			"Url" => as_string(j).and_then(text::parse_url).map(Primitive::Url),
+			"Uuid" => as_string(j).and_then(Uuid::parse).map(Primitive::Uuid),
+			"Markdown" => as_string(j).and_then(Markdown::parse).map(Primitive::Markdown),
+			"Xhtml" => as_string(j).and_then(read_div).map(Primitive::Xhtml),
+			"Base64Binary" => as_string(j).and_then(base64::decode).map(Primitive::Base64),
+			"Instant" => as_string(j)
+				.and_then(|s| s.parse::<DateTime<FixedOffset>>().map_err(|_| FhirError::invalid(s, "Invalid instant")))
+				.map(Primitive::Instant),
+			"Date" => as_string(j).and_then(VarDate::parse)
+				.and_then(|d| if d.precision() == Precision::Second { Err(invalid("Invalid date")) } else { Ok(d) })
+				.map(Primitive::Date),
+			"DateTime" => as_string(j).and_then(VarDate::parse).map(Primitive::DateTime),
+			"Time" => as_string(j).and_then(Time::parse).map(Primitive::Time),
+			_ => Err(FhirError::unexpected(type_name, "Unknown primitive type"))
+		}
+	}
+
+	// Numbers are written as their text, so a decimal keeps its precision
+	pub fn write_json<W: Write>(&self, w: &mut JsonWriter<W>) -> io::Result<()> {
+		if let Primitive::Xhtml(ref x) = *self {
+			w.string(&safe_div(x))
+		} else if let Primitive::Base64(ref b) = *self {
+			w.base64(b)
+		} else if self.is_textual() {
+			w.string(&self.to_string())
+		} else {
+			w.raw(&self.to_string())
+		}
+	}
+
+	fn is_textual(&self) -> bool {
+		match *self {
+			Primitive::Boolean(_) | Primitive::Int(_) | Primitive::UInt(_) | Primitive::PInt(_) | Primitive::Decimal(_) => false,
+			_ => true
+		}
+	}
+
+	// Whether this value can stand for a primitive of the given FHIR type code.
+	// Decoded documents carry no types, so strings are checked by their content.
+	pub fn conforms_to(&self, code: &str) -> bool {
+		let code = match code {
+			"http://hl7.org/fhirpath/System.Boolean" => "boolean",
+			"http://hl7.org/fhirpath/System.Integer" => "integer",
+			"http://hl7.org/fhirpath/System.Decimal" => "decimal",
+			c if c.starts_with("http://hl7.org/fhirpath/System.") => "string",
+			c => c
 		};
-		format!("value{}",s)
+		match (code, self) {
+			("boolean", &Primitive::Boolean(_)) => true,
+			("integer", &Primitive::Int(_)) => true,
+			("integer", &Primitive::UInt(_)) | ("integer", &Primitive::PInt(_)) => true,
+			("unsignedInt", &Primitive::UInt(_)) | ("unsignedInt", &Primitive::PInt(_)) => true,
+			("unsignedInt", &Primitive::Int(i)) => i >= 0,
+			("positiveInt", &Primitive::PInt(_)) => true,
+			("positiveInt", &Primitive::UInt(i)) => i.value() > 0,
+			("positiveInt", &Primitive::Int(i)) => i > 0,
+			("decimal", p) => !p.is_textual() && !p.conforms_to("boolean"),
+			("date", &Primitive::Date(_)) => true,
+			("date", &Primitive::String(ref s)) => s.len() <= 10 && VarDate::parse(s).is_ok(),
+			("dateTime", &Primitive::Date(_)) | ("dateTime", &Primitive::DateTime(_)) | ("dateTime", &Primitive::Instant(_)) => true,
+			("dateTime", &Primitive::String(ref s)) => VarDate::parse(s).is_ok(),
+			("instant", &Primitive::Instant(_)) => true,
+			("instant", &Primitive::String(ref s)) => s.parse::<DateTime<FixedOffset>>().is_ok(),
+			("time", &Primitive::Time(_)) => true,
+			("time", &Primitive::String(ref s)) => Time::parse(s).is_ok(),
+			("base64Binary", &Primitive::Base64(_)) => true,
+			("base64Binary", &Primitive::String(ref s)) => base64::decode(s).is_ok(),
+			("integer64", &Primitive::Integer64(_)) | ("integer64", &Primitive::Int(_))
+				| ("integer64", &Primitive::UInt(_)) | ("integer64", &Primitive::PInt(_)) => true,
+			("integer64", &Primitive::String(ref s)) => integer::parse_integer64(s).is_ok(),
+			("id", &Primitive::Id(_)) | ("code", &Primitive::Code(_)) | ("oid", &Primitive::Oid(_)) => true,
+			("uuid", &Primitive::Uuid(_)) | ("markdown", &Primitive::Markdown(_)) => true,
+			("canonical", &Primitive::Canonical(_)) | ("url", &Primitive::Url(_)) => true,
+			("xhtml", &Primitive::Xhtml(_)) | ("xhtml", &Primitive::String(_)) => true,
+			("id", &Primitive::String(ref s)) => Id::parse(s).is_ok(),
+			("code", &Primitive::String(ref s)) => Code::parse(s).is_ok(),
+			("oid", &Primitive::String(ref s)) => Oid::parse(s).is_ok(),
+			("uuid", &Primitive::String(ref s)) => Uuid::parse(s).is_ok(),
+			("markdown", &Primitive::String(ref s)) => Markdown::parse(s).is_ok(),
+			("canonical", &Primitive::String(ref s)) => Canonical::parse(s).is_ok(),
+			("canonical", &Primitive::Uri(_)) => true,
+			("url", &Primitive::String(ref s)) => text::parse_url(s).is_ok(),
+			("url", &Primitive::Uri(ref u)) => u.scheme() != "urn",
+			("uri", &Primitive::String(ref s)) => !s.is_empty() && !s.chars().any(char::is_whitespace),
+			("uri", &Primitive::Uri(_)) | ("uri", &Primitive::Oid(_)) | ("uri", &Primitive::Canonical(_))
+				| ("uri", &Primitive::Url(_)) | ("uri", &Primitive::Uuid(_)) => true,
+			("boolean", _) | ("integer", _) | ("unsignedInt", _) | ("positiveInt", _)
+				| ("date", _) | ("dateTime", _) | ("instant", _) | ("time", _) | ("base64Binary", _)
+				| ("id", _) | ("code", _) | ("oid", _) | ("uuid", _) | ("markdown", _)
+				| ("canonical", _) | ("url", _) | ("uri", _) | ("integer64", _) | ("xhtml", _) => false,
+			(_, p) => p.is_textual()
+		}
 	}
 
 	fn to_string(&self) -> String {
@@ -98,11 +265,17 @@ impl Primitive {
 	 		Primitive::Code(ref s) => format!("{}",s),
 	 		Primitive::Uri(ref v) => format!("{}",v),
 	 		Primitive::Oid(ref v) => format!("{}",v),
-	 		Primitive::Base64(ref s) => format!("{}",s),
+	 		Primitive::Base64(ref b) => base64::encode(b),
 	 		Primitive::Instant(ref x) => x.to_rfc3339(),
 	 		Primitive::Date(ref x) => format!("{}",x),
 	 		Primitive::DateTime(ref x) => format!("{}",x),
 	 		Primitive::Time(ref x) => format!("{}",x),
+	 		Primitive::Canonical(ref x) => format!("{}",x),
+	 		Primitive::Url(ref x) => format!("{}",x),
+	 		Primitive::Uuid(ref x) => format!("{}",x),
+	 		Primitive::Markdown(ref x) => format!("{}",x),
+	 		Primitive::Integer64(i) => format!("{}",i),
+	 		Primitive::Xhtml(ref x) => format!("{}",x),
 		}
 	}
 }
@@ -114,28 +287,58 @@ impl fmt::Display for Primitive {
 
 }
 
+// A decimal is a JSON number, so in the Json tree it has a double's
+// precision; write_json writes it as written. integer64 is written as a
+// string, as it doesn't fit a double.
 impl ToJson for Primitive {
 	fn to_json(&self) -> Json {
 		match *self {
 			Primitive::Boolean(v) => Json::Boolean(v),
 	 		Primitive::Int(i) => Json::I64(i as i64),
-	 		Primitive::UInt(i) => Json::U64(i as u64),
-	 		Primitive::PInt(i) => Json::U64(i as u64),
-	 		Primitive::Decimal(ref d) => Json::F64(d.val),
+	 		Primitive::UInt(i) => Json::U64(i.value() as u64),
+	 		Primitive::PInt(i) => Json::U64(i.value() as u64),
+	 		Primitive::Decimal(ref d) => decimal_json(d),
 	 		Primitive::String(ref s) => Json::String(s.clone()),
 	 		Primitive::Id(ref v) => Json::String(v.to_string()),
 	 		Primitive::Code(ref v) => Json::String(v.to_string()),
 	 		Primitive::Uri(ref v) => Json::String(v.to_string()),
 	 		Primitive::Oid(ref v) => Json::String(v.to_string()),
-	 		Primitive::Base64(ref v) => Json::String(v.to_string()),
+	 		Primitive::Base64(ref v) => Json::String(base64::encode(v)),
 	 		Primitive::Instant(ref v) => Json::String(v.to_rfc3339()),
 	 		Primitive::Date(ref v) => Json::String(v.to_string()),
 	 		Primitive::DateTime(ref v) => Json::String(v.to_string()),
 	 		Primitive::Time(ref v) => Json::String(v.to_string()),
+	 		Primitive::Canonical(ref v) => Json::String(v.to_string()),
+	 		Primitive::Url(ref v) => Json::String(v.to_string()),
+	 		Primitive::Uuid(ref v) => Json::String(v.to_string()),
+	 		Primitive::Markdown(ref v) => Json::String(v.to_string()),
+	 		Primitive::Integer64(i) => Json::String(i.to_string()),
+	 		Primitive::Xhtml(ref v) => Json::String(safe_div(v)),
 	 	}
 	}
 }
 
+// A decimal written as an integer, such as one read from a JSON integer
+// beyond the range of integer, is a JSON integer, as json::parse reads it
+fn decimal_json(d: &Dec) -> Json {
+	let t = d.to_string();
+	t.parse().map(Json::U64).or_else(|_| t.parse().map(Json::I64)).unwrap_or_else(|_| Json::F64(t.parse().unwrap()))
+}
+
+// Without a type hint only the JSON representation is known, so strings stay strings
+impl FromJson for Primitive {
+	fn from_json(j: &Json) -> Result<Self, FhirError> {
+		match *j {
+			Json::Boolean(v) => Ok(Primitive::Boolean(v)),
+			Json::I64(i) if i >= i32::MIN as i64 && i <= i32::MAX as i64 => Ok(Primitive::Int(i as i32)),
+			Json::U64(i) if i <= i32::MAX as u64 => UnsignedInt::new(i as i64).map(Primitive::UInt),
+			Json::String(ref s) => Ok(Primitive::String(s.clone())),
+			Json::I64(_) | Json::U64(_) | Json::F64(_) => Primitive::from_json_typed("Decimal", j),
+			_ => Err(FhirError::unexpected(&j.to_string(), "Not a primitive value"))
+		}
+	}
+}
+
 #[test]
 fn test_bool() {
 	let p = Primitive::from(true);
@@ -152,14 +355,14 @@ fn test_i32() {
 
 #[test]
 fn test_u32() {
-	let p = Primitive::from(5u32);
+	let p = Primitive::from(UnsignedInt::new(5).unwrap());
 	assert_eq!("5",p.to_string());
 	assert_eq!(Json::U64(5),p.to_json());
 }
 
 #[test]
 fn test_positive() {
-	let p = Primitive::PInt(5);
+	let p = Primitive::PInt(PositiveInt::new(5).unwrap());
 	assert_eq!("5",p.to_string());
 	assert_eq!(Json::U64(5),p.to_json());
 }
@@ -198,31 +401,34 @@ fn test_urn() {
 
 #[test]
 fn test_oid() {
-	let u = Url::parse("urn:oid:0.0.0.0").ok().unwrap();
-	let p = Primitive::Oid(u);
+	let p = Primitive::Oid(Oid::parse("urn:oid:0.0.0.0").unwrap());
 	assert_eq!("urn:oid:0.0.0.0", p.to_string());
 	assert_eq!(Json::String("urn:oid:0.0.0.0".to_string()),p.to_json());
 }
 
 #[test]
 fn test_id() {
-	let p = Primitive::Id("hello, world".to_string());
-	assert_eq!("hello, world",p.to_string());
-	assert_eq!(Json::String("hello, world".to_string()),p.to_json());
+	let p = Primitive::Id(Id::parse("hello-world.1").unwrap());
+	assert_eq!("hello-world.1",p.to_string());
+	assert_eq!(Json::String("hello-world.1".to_string()),p.to_json());
 }
 
 #[test]
 fn test_base64() {
-	let p = Primitive::Base64("hello, world".to_string()); //TODO: Actual base64
-	assert_eq!("hello, world",p.to_string());
-	assert_eq!(Json::String("hello, world".to_string()),p.to_json());
+	let p = Primitive::Base64(b"hello, world".to_vec());
+	assert_eq!("aGVsbG8sIHdvcmxk",p.to_string());
+	assert_eq!(Json::String("aGVsbG8sIHdvcmxk".to_string()),p.to_json());
+	let p = Primitive::from_json_typed("Base64Binary", &Json::String("aGVsbG8sIHdvcmxk".to_string())).unwrap();
+	assert_eq!("valueBase64Binary", p.extension_name());
+	assert!(Primitive::from_json_typed("Base64Binary", &Json::String("hello, world".to_string())).is_err());
 }
 
 #[test]
 fn test_code() {
-	let p = Primitive::Code("hello, world".to_string()); 
+	let p = Primitive::Code(Code::parse("hello, world").unwrap());
 	assert_eq!("hello, world",p.to_string());
 	assert_eq!(Json::String("hello, world".to_string()),p.to_json());
+	assert!(Primitive::from_json_typed("Code", &Json::String(" hello".to_string())).is_err());
 }
 
 
@@ -248,4 +454,117 @@ fn test_datetime() {
 	let p = Primitive::DateTime(dt);
 	assert_eq!("2015",p.to_string());
 	assert_eq!(Json::String("2015".to_string()),p.to_json());
-}	
\ No newline at end of file
+}	
+#[test]
+fn test_from_json() {
+	assert_eq!(Json::Boolean(true), Primitive::from_json(&Json::Boolean(true)).unwrap().to_json());
+	assert_eq!(Json::I64(-5), Primitive::from_json(&Json::I64(-5)).unwrap().to_json());
+	assert_eq!(Json::U64(5), Primitive::from_json(&Json::U64(5)).unwrap().to_json());
+	assert_eq!(Json::U64(3000000000), Primitive::from_json(&Json::U64(3000000000)).unwrap().to_json());
+	assert_eq!(Json::I64(-3000000000), Primitive::from_json(&Json::I64(-3000000000)).unwrap().to_json());
+	let big = Primitive::from_json_typed("Decimal", &Json::U64(u64::MAX)).unwrap();
+	assert_eq!(Json::U64(u64::MAX), big.to_json());
+	let mut w = JsonWriter::new(Vec::new());
+	Primitive::from_json(&Json::I64(-3000000000)).unwrap().write_json(&mut w).unwrap();
+	assert_eq!(b"-3000000000".to_vec(), w.into_inner());
+	assert_eq!(Json::F64(3.14), Primitive::from_json(&Json::F64(3.14)).unwrap().to_json());
+	assert_eq!(Json::F64(1e300), Primitive::from_json(&Json::F64(1e300)).unwrap().to_json());
+	assert!(Primitive::from_json(&Json::Null).is_err());
+}
+
+#[test]
+fn test_from_json_typed() {
+	let p = Primitive::from_json_typed("Date", &Json::String("2015-05".to_string())).unwrap();
+	assert_eq!("valueDate", p.extension_name());
+	assert_eq!("2015-05", p.to_string());
+	let p = Primitive::from_json_typed("Integer", &Json::U64(5)).unwrap();
+	assert_eq!(Json::I64(5), p.to_json());
+	assert!(Primitive::from_json_typed("Uri", &Json::String("not a uri".to_string())).is_err());
+	assert!(Primitive::from_json_typed("Boolean", &Json::U64(1)).is_err());
+	let p = Primitive::from_json_typed("Time", &Json::String("05:34:00.5".to_string())).unwrap();
+	assert_eq!(Json::String("05:34:00.5".to_string()), p.to_json());
+	assert!(Primitive::from_json_typed("Time", &Json::String("5:34".to_string())).is_err());
+	assert!(Primitive::from_json_typed("Date", &Json::String("2015-05-02T05:34:00-07:00".to_string())).is_err());
+}
+
+#[test]
+fn test_from_xml_value() {
+	assert_eq!(Json::String("false".to_string()), Primitive::from_xml_value(None, "false").unwrap().to_json());
+	assert_eq!(Json::String("0023".to_string()), Primitive::from_xml_value(None, "0023").unwrap().to_json());
+	assert_eq!(Json::Boolean(false), Primitive::from_xml_value(Some("Boolean"), "false").unwrap().to_json());
+	assert_eq!(Json::I64(-23), Primitive::from_xml_value(Some("Integer"), "-23").unwrap().to_json());
+	assert_eq!("0.10", Primitive::from_xml_value(Some("Decimal"), "0.10").unwrap().to_string());
+	assert_eq!(Json::String("abc".to_string()), Primitive::from_xml_value(None, "abc").unwrap().to_json());
+	assert_eq!(Json::String("23".to_string()), Primitive::from_xml_value(Some("String"), "23").unwrap().to_json());
+	assert!(Primitive::from_xml_value(Some("Boolean"), "yes").is_err());
+}
+
+#[test]
+fn test_write_json() {
+	let mut w = JsonWriter::new(Vec::new());
+	w.begin_array().unwrap();
+	for p in [Primitive::from(Dec::from_str("1.50").unwrap()), Primitive::from(true), Primitive::from(-3),
+		Primitive::from(9007199254740993i64), Primitive::from("a\"b")].iter() {
+		p.write_json(&mut w).unwrap();
+	}
+	w.end_array().unwrap();
+	assert_eq!(r#"[1.50,true,-3,"9007199254740993","a\"b"]"#, String::from_utf8(w.into_inner()).unwrap());
+}
+
+#[test]
+fn test_extension_names() {
+	let cases = [
+		(Primitive::from(UnsignedInt::new(5).unwrap()), "valueUnsignedInt"),
+		(Primitive::from(PositiveInt::new(5).unwrap()), "valuePositiveInt"),
+		(Primitive::from(Id::parse("a1").unwrap()), "valueId"),
+		(Primitive::from(Oid::parse("urn:oid:1.2.3").unwrap()), "valueOid"),
+		(Primitive::from(Time::from_hm(12,30).unwrap()), "valueTime"),
+		(Primitive::from(Canonical::parse("http://example.org/sd|1").unwrap()), "valueCanonical"),
+		(Primitive::Url(Url::parse("http://example.org").unwrap()), "valueUrl"),
+		(Primitive::from(Uuid::parse("urn:uuid:c757873d-ec9a-4326-a141-556f43239520").unwrap()), "valueUuid"),
+		(Primitive::from(Markdown::parse("*hi*").unwrap()), "valueMarkdown"),
+		(Primitive::from(5i64), "valueInteger64")];
+	for &(ref p, name) in cases.iter() {
+		assert_eq!(name, p.extension_name());
+		assert!(p.valid_extension());
+		let back = Primitive::from_json_typed(&name["value".len()..], &p.to_json()).unwrap();
+		assert_eq!(name, back.extension_name());
+		assert_eq!(p.to_string(), back.to_string());
+	}
+	assert!(!Primitive::Xhtml("<div/>".to_string()).valid_extension());
+}
+
+#[test]
+fn test_integer64() {
+	let p = Primitive::from(9007199254740993i64);
+	assert_eq!(Json::String("9007199254740993".to_string()), p.to_json());
+	assert!(Primitive::from_json_typed("Integer64", &Json::I64(5)).is_err());
+	assert_eq!("-42", Primitive::from_xml_value(Some("Integer64"), "-42").unwrap().to_string());
+	assert!(Primitive::from("-42").conforms_to("integer64"));
+	assert!(Primitive::from_xml_value(Some("PositiveInt"), "0").is_err());
+}
+
+#[test]
+fn test_conforms_to() {
+	assert!(Primitive::from(true).conforms_to("boolean"));
+	assert!(!Primitive::from("true").conforms_to("boolean"));
+	assert!(Primitive::from(UnsignedInt::new(5).unwrap()).conforms_to("positiveInt"));
+	assert!(!Primitive::from(UnsignedInt::new(0).unwrap()).conforms_to("positiveInt"));
+	assert!(!Primitive::from(-1).conforms_to("unsignedInt"));
+	assert!(Primitive::from(UnsignedInt::new(5).unwrap()).conforms_to("decimal"));
+	assert!(Primitive::from("2015-05-02").conforms_to("date"));
+	assert!(!Primitive::from("2015-05-02T05:34:00-07:00").conforms_to("date"));
+	assert!(Primitive::from("2015-05-02T05:34:00-07:00").conforms_to("instant"));
+	assert!(!Primitive::from("May 2nd").conforms_to("dateTime"));
+	assert!(Primitive::from("05:34:00").conforms_to("time"));
+	assert!(!Primitive::from("05:34").conforms_to("time"));
+	assert!(Primitive::from("aGVsbG8=").conforms_to("base64Binary"));
+	assert!(!Primitive::from("hello").conforms_to("base64Binary"));
+	assert!(Primitive::from("abc").conforms_to("code"));
+	assert!(!Primitive::from("a  b").conforms_to("code"));
+	assert!(!Primitive::from("a_b").conforms_to("id"));
+	assert!(Primitive::from("urn:oid:1.2.3").conforms_to("oid"));
+	assert!(!Primitive::from("urn:oid:1.2.3").conforms_to("url"));
+	assert!(Primitive::from("abc").conforms_to("http://hl7.org/fhirpath/System.String"));
+	assert!(!Primitive::from(5).conforms_to("string"));
+}
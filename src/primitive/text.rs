@@ -0,0 +1,162 @@
+use std::fmt;
+use url::Url;
+
+use error::FhirError;
+
+// String primitives whose content the specification constrains by a regex.
+// Each is only built through `parse`, so holding one means the text is valid.
+
+macro_rules! checked_string {
+	($name:ident, $check:ident, $reason:expr) => {
+		#[derive(Debug,Clone,PartialEq,Eq,Hash,PartialOrd,Ord)]
+		pub struct $name(String);
+
+		impl $name {
+			pub fn parse(s: &str) -> Result<Self,FhirError> {
+				if $check(s) {
+					Ok($name(s.to_string()))
+				} else {
+					Err(FhirError::invalid(s, $reason))
+				}
+			}
+
+			pub fn as_str(&self) -> &str {
+				&self.0
+			}
+		}
+
+		impl fmt::Display for $name {
+			fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+				write!(f,"{}",self.0)
+			}
+		}
+	}
+}
+
+checked_string!(Id, is_id, "Invalid id");
+checked_string!(Code, is_code, "Invalid code");
+checked_string!(Oid, is_oid, "Invalid oid");
+checked_string!(Uuid, is_uuid, "Invalid uuid");
+checked_string!(Canonical, is_canonical, "Invalid canonical");
+checked_string!(Markdown, is_markdown, "Invalid markdown");
+
+// Strings of every kind are limited to 1MB
+const MAX_STRING: usize = 1024 * 1024;
+
+// [A-Za-z0-9\-\.]{1,64}
+fn is_id(s: &str) -> bool {
+	!s.is_empty() && s.len() <= 64 && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+// [^\s]+( [^\s]+)*
+fn is_code(s: &str) -> bool {
+	!s.is_empty() && s.split(' ').all(|w| !w.is_empty() && !w.chars().any(char::is_whitespace))
+}
+
+// urn:oid:[0-2](\.(0|[1-9][0-9]*))+
+fn is_oid(s: &str) -> bool {
+	let arcs: Vec<&str> = match s.strip_prefix("urn:oid:") {
+		Some(rest) => rest.split('.').collect(),
+		None => return false
+	};
+	let number = |a: &&str| !a.is_empty() && a.chars().all(|c| c.is_ascii_digit()) && (*a == "0" || !a.starts_with('0'));
+	arcs.len() > 1 && (arcs[0] == "0" || arcs[0] == "1" || arcs[0] == "2") && arcs.iter().all(number)
+}
+
+// urn:uuid:[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}
+fn is_uuid(s: &str) -> bool {
+	let groups: Vec<&str> = match s.strip_prefix("urn:uuid:") {
+		Some(rest) => rest.split('-').collect(),
+		None => return false
+	};
+	let hex = |g: &str| g.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+	groups.len() == 5 && groups.iter().zip([8, 4, 4, 4, 12].iter()).all(|(g, n)| g.len() == *n && hex(g))
+}
+
+// An absolute uri, optionally followed by |version
+fn is_canonical(s: &str) -> bool {
+	let mut parts = s.splitn(2, '|');
+	let uri = parts.next().unwrap_or("");
+	let version_ok = match parts.next() {
+		Some(v) => !v.is_empty() && !v.chars().any(char::is_whitespace),
+		None => true
+	};
+	version_ok && parse_uri(uri).is_ok()
+}
+
+// Any non-empty text without control characters other than line breaks and tabs
+fn is_markdown(s: &str) -> bool {
+	!s.is_empty() && s.len() <= MAX_STRING && !s.chars().any(|c| c.is_control() && c != '\t' && c != '\r' && c != '\n')
+}
+
+// The url crate silently trims surrounding whitespace, which a uri may not contain
+pub fn parse_uri(s: &str) -> Result<Url,FhirError> {
+	if s.is_empty() || s.chars().any(char::is_whitespace) {
+		return Err(FhirError::invalid(s, "Invalid URI"));
+	}
+	Url::parse(s).map_err(|_| FhirError::invalid(s, "Invalid URI"))
+}
+
+// A url is a uri that can be dereferenced, so names such as urn: are not urls
+pub fn parse_url(s: &str) -> Result<Url,FhirError> {
+	match parse_uri(s) {
+		Ok(ref u) if u.scheme() == "urn" => Err(FhirError::invalid(s, "Invalid URL")),
+		Ok(u) => Ok(u),
+		Err(_) => Err(FhirError::invalid(s, "Invalid URL"))
+	}
+}
+
+
+#[test]
+fn test_id() {
+	assert_eq!("example-1.a", Id::parse("example-1.a").unwrap().as_str());
+	assert!(Id::parse(&"a".repeat(64)).is_ok());
+	for s in ["", "a b", "a_b", "ä"].iter() {
+		assert!(Id::parse(s).is_err(), "{}", s);
+	}
+	assert!(Id::parse(&"a".repeat(65)).is_err());
+}
+
+#[test]
+fn test_code() {
+	assert!(Code::parse("final").is_ok());
+	assert!(Code::parse("Home phone").is_ok());
+	for s in ["", " final", "final ", "a  b", "a\tb", "a\nb"].iter() {
+		assert!(Code::parse(s).is_err(), "{:?}", s);
+	}
+}
+
+#[test]
+fn test_oid() {
+	assert_eq!("urn:oid:2.16.840.1.113883", Oid::parse("urn:oid:2.16.840.1.113883").unwrap().to_string());
+	for s in ["2.16.840", "urn:oid:3.1", "urn:oid:1", "urn:oid:1.02", "urn:oid:1..2", "urn:oid:1.2."].iter() {
+		assert!(Oid::parse(s).is_err(), "{}", s);
+	}
+}
+
+#[test]
+fn test_uuid() {
+	assert!(Uuid::parse("urn:uuid:c757873d-ec9a-4326-a141-556f43239520").is_ok());
+	assert!(Uuid::parse("urn:uuid:C757873D-EC9A-4326-A141-556F43239520").is_err());
+	assert!(Uuid::parse("c757873d-ec9a-4326-a141-556f43239520").is_err());
+	assert!(Uuid::parse("urn:uuid:c757873d-ec9a-4326-a141-556f4323952").is_err());
+}
+
+#[test]
+fn test_canonical_and_urls() {
+	assert!(Canonical::parse("http://hl7.org/fhir/StructureDefinition/Patient").is_ok());
+	assert!(Canonical::parse("http://example.org/ValueSet/x|1.0").is_ok());
+	assert!(Canonical::parse("http://example.org/ValueSet/x|").is_err());
+	assert!(Canonical::parse("not a uri").is_err());
+	assert!(parse_uri("urn:oid:1.2.3").is_ok());
+	assert!(parse_uri(" http://example.org").is_err());
+	assert!(parse_url("http://example.org/fhir").is_ok());
+	assert!(parse_url("urn:uuid:c757873d-ec9a-4326-a141-556f43239520").is_err());
+}
+
+#[test]
+fn test_markdown() {
+	assert!(Markdown::parse("# Title\n\n*emphasis*\ttab").is_ok());
+	assert!(Markdown::parse("").is_err());
+	assert!(Markdown::parse("bell\u{7}").is_err());
+}
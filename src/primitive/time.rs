@@ -1,26 +1,110 @@
 use std::fmt;
+use std::cmp::Ordering;
+use chrono::{NaiveTime, Timelike};
 
+use error::FhirError;
 
-#[derive(Debug)]
+
+// A time of day, hh:mm:ss with up to nine fractional digits; the number of
+// digits written is kept for output. A leap second is carried as 60 seconds.
+#[derive(Debug,Clone)]
 pub struct Time {
 	h: u8,
 	m: u8,
-	s: Option<f64>
+	s: u8,
+	nanos: u32,
+	frac: u8
+}
+
+fn two_digits(s: &str, max: u8) -> Option<u8> {
+	if s.len() != 2 || !s.chars().all(|c| c.is_ascii_digit()) {
+		return None;
+	}
+	s.parse().ok().and_then(|v| if v <= max { Some(v) } else { None })
 }
 
 impl Time {
-	pub fn from_hm(h: u8, m: u8) -> Option<Self> {
+	pub fn from_hm(h: u8, m: u8) -> Result<Self,FhirError> {
 		match (h,m) {
-			(0...23,0...59) => Some(Time {h: h, m: m, s: None}),
-			_ => None
+			(0..=23,0..=59) => Ok(Time {h: h, m: m, s: 0, nanos: 0, frac: 0}),
+			_ => Err(FhirError::invalid(&format!("{}:{}", h, m), "Invalid time"))
+		}
+	}
+
+	pub fn from_hms(h: u8, m: u8, s: u8) -> Result<Self,FhirError> {
+		match (h,m,s) {
+			(0..=23,0..=59,0..=60) => Ok(Time {h: h, m: m, s: s, nanos: 0, frac: 0}),
+			_ => Err(FhirError::invalid(&format!("{}:{}:{}", h, m, s), "Invalid time"))
+		}
+	}
+
+	pub fn parse(s: &str) -> Result<Self,FhirError> {
+		let invalid = || FhirError::invalid(s, "Invalid time");
+		let (hms, frac) = match s.find('.') {
+			Some(i) => (&s[..i], Some(&s[i + 1..])),
+			None => (s, None)
+		};
+		let parts: Vec<&str> = hms.split(':').collect();
+		if parts.len() != 3 {
+			return Err(invalid());
 		}
+		let (h, m, sec) = match (two_digits(parts[0], 23), two_digits(parts[1], 59), two_digits(parts[2], 60)) {
+			(Some(h), Some(m), Some(sec)) => (h, m, sec),
+			_ => return Err(invalid())
+		};
+		let (nanos, digits) = match frac {
+			Some(f) if f.is_empty() || f.len() > 9 || !f.chars().all(|c| c.is_ascii_digit()) => return Err(invalid()),
+			Some(f) => (f.parse::<u32>().map_err(|_| invalid())? * 10u32.pow(9 - f.len() as u32), f.len() as u8),
+			None => (0, 0)
+		};
+		Ok(Time {h: h, m: m, s: sec, nanos: nanos, frac: digits})
+	}
+
+	pub fn to_naive(&self) -> NaiveTime {
+		let (s, nanos) = if self.s == 60 { (59, self.nanos + 1_000_000_000) } else { (self.s as u32, self.nanos) };
+		NaiveTime::from_hms_nano(self.h as u32, self.m as u32, s, nanos)
+	}
+}
+
+// Written with millisecond digits when there is a fraction of a second
+impl From<NaiveTime> for Time {
+	fn from(t: NaiveTime) -> Self {
+		let (s, nanos) = if t.nanosecond() >= 1_000_000_000 {
+			(60, t.nanosecond() - 1_000_000_000)
+		} else {
+			(t.second() as u8, t.nanosecond())
+		};
+		Time {h: t.hour() as u8, m: t.minute() as u8, s: s, nanos: nanos, frac: if nanos == 0 { 0 } else { 3 }}
+	}
+}
+
+impl PartialEq for Time {
+	fn eq(&self, other: &Time) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for Time {}
+
+impl PartialOrd for Time {
+	fn partial_cmp(&self, other: &Time) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Time {
+	fn cmp(&self, other: &Time) -> Ordering {
+		(self.h, self.m, self.s, self.nanos).cmp(&(other.h, other.m, other.s, other.nanos))
 	}
 }
 
 impl fmt::Display for Time {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		let s_msg = (self.s).map_or(String::from(""),|s| format!("{}",s));
-		write!(f,"{}:{}{}",self.h,self.m,s_msg)
+		write!(f,"{:02}:{:02}:{:02}",self.h,self.m,self.s)?;
+		if self.frac > 0 {
+			write!(f,".{:0w$}",self.nanos / 10u32.pow(9 - self.frac as u32),w = self.frac as usize)?;
+		}
+		Ok(())
 	}
 }
 
@@ -28,11 +112,37 @@ impl fmt::Display for Time {
 #[test]
 fn test_time_repr() {
 	let t = Time::from_hm(12,30).unwrap();
-	assert_eq!("12:30",format!("{}",t));
+	assert_eq!("12:30:00",format!("{}",t));
+	assert_eq!("12:05:00",format!("{}",Time::from_hm(12,5).unwrap()));
 }
 
 #[test]
 fn test_time_invalid() {
 	let t = Time::from_hm(53,30);
-	assert!(t.is_none());
+	assert!(t.is_err());
+}
+
+#[test]
+fn test_time_parse() {
+	assert_eq!("05:34:00", Time::parse("05:34:00").unwrap().to_string());
+	assert_eq!("05:34:00.250", Time::parse("05:34:00.250").unwrap().to_string());
+	assert_eq!("23:59:60", Time::parse("23:59:60").unwrap().to_string());
+	for s in ["5:34:00", "05:34", "24:00:00", "05:60:00", "05:34:00.", "05:34:00.1234567890", "05:34:0a"].iter() {
+		assert!(Time::parse(s).is_err(), "{}", s);
+	}
+}
+
+#[test]
+fn test_time_ordering() {
+	assert!(Time::parse("05:34:00.1").unwrap() < Time::parse("05:34:00.25").unwrap());
+	assert_eq!(Time::parse("05:34:00.5").unwrap(), Time::parse("05:34:00.500").unwrap());
+	assert!(Time::from_hm(12,30).unwrap() > Time::parse("09:59:59").unwrap());
+}
+
+#[test]
+fn test_time_naive() {
+	let t = Time::parse("14:35:45.125").unwrap();
+	assert_eq!(NaiveTime::from_hms_milli(14, 35, 45, 125), t.to_naive());
+	assert_eq!(t, Time::from(t.to_naive()));
+	assert_eq!("23:59:60", Time::from(Time::parse("23:59:60").unwrap().to_naive()).to_string());
 }
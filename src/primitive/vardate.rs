@@ -1,63 +1,229 @@
 use std::fmt;
-use chrono::{DateTime,FixedOffset};
-use chrono::format::{Item,Fixed,Parsed,ParseError,self};
+use std::cmp::Ordering;
+use chrono::{DateTime,FixedOffset,NaiveDate,Datelike,TimeZone,Duration};
+use chrono::format::{Item,Fixed,Parsed,self};
 
+use error::FhirError;
 
 
-#[derive(Debug)]
-pub struct VarDate {
-	y: Option<i32>,
-	m: Option<u32>,
-	d: Option<u32>,
-	dt: Option<DateTime<FixedOffset>>
+// A FHIR date or dateTime at the precision it was written: a year, a month,
+// a day, or a full date and time with a timezone
+#[derive(Debug,Clone)]
+pub enum VarDate {
+	Year(i32),
+	Month(i32, u32),
+	Day(NaiveDate),
+	Full(DateTime<FixedOffset>)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Precision {
+	Year,
+	Month,
+	Day,
+	Second
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Unit {
+	Year,
+	Month,
+	Week,
+	Day,
+	Hour,
+	Minute,
+	Second
+}
+
+const DAY_SECS: i64 = 86400;
+
+impl Unit {
+	// Calendar years and months have no fixed length; these are only used
+	// to truncate a finer amount to a coarse precision
+	fn seconds(self) -> i64 {
+		match self {
+			Unit::Year => 365 * DAY_SECS,
+			Unit::Month => 30 * DAY_SECS,
+			Unit::Week => 7 * DAY_SECS,
+			Unit::Day => DAY_SECS,
+			Unit::Hour => 3600,
+			Unit::Minute => 60,
+			Unit::Second => 1
+		}
+	}
+
+	fn is_calendar(self) -> bool {
+		self == Unit::Year || self == Unit::Month
+	}
 }
 
 impl From<DateTime<FixedOffset>> for VarDate {
 	fn from(dt: DateTime<FixedOffset>) -> Self {
-		VarDate{
-			y: None,
-			m: None,
-			d: None,
-			dt: Some(dt)
-		}
+		VarDate::Full(dt)
 	}
 }
 
+fn digits(s: &str, n: usize) -> Option<u32> {
+	if s.len() == n && s.chars().all(|c| c.is_ascii_digit()) { s.parse().ok() } else { None }
+}
+
+fn add_months(y: i32, m: u32, n: i64) -> Option<(i32, u32)> {
+	let total = (y as i64 * 12 + m as i64 - 1).checked_add(n)?;
+	let (y, m) = (total.div_euclid(12), total.rem_euclid(12) + 1);
+	if y < 1 || y > 9999 { None } else { Some((y as i32, m as u32)) }
+}
+
+// The same day in another month, clamped to its end: Jan 31 + 1 month is Feb 28
+fn with_month(d: NaiveDate, n: i64) -> Option<NaiveDate> {
+	let (y, m) = add_months(d.year(), d.month(), n)?;
+	(28..d.day() + 1).rev().filter_map(|day| NaiveDate::from_ymd_opt(y, m, day)).next()
+		.or_else(|| NaiveDate::from_ymd_opt(y, m, d.day()))
+}
+
 impl VarDate {
 
-	fn _from_parsed_result(r: Result<(),ParseError>, p: Parsed) -> Result<Self,ParseError> {
-		match r {
-        	Ok(_) => match p.to_datetime() {
-        		Ok(dt) => Ok(VarDate {y: None, m: None, d: None, dt: Some(dt)}),
-        		Err(e) => Err(e)
-        	},
-        	Err(e) => match (p.year, p.month, p.day) {
-        		(None, None, None) => Err(e),
-        		_ => Ok(VarDate {y: p.year, m: p.month, d: p.day, dt: None})
-        	}
-        }
+	pub fn parse(s: &str) -> Result<Self,FhirError> {
+		let invalid = || FhirError::invalid(s, "Invalid date");
+		if s.contains('T') {
+			const ITEMS: &'static [Item<'static>] = &[Item::Fixed(Fixed::RFC3339)];
+			let mut parsed = Parsed::new();
+			format::parse(&mut parsed, s, ITEMS.iter().cloned()).map_err(|_| invalid())?;
+			return parsed.to_datetime().map(VarDate::Full).map_err(|_| invalid());
+		}
+		let parts: Vec<&str> = s.split('-').collect();
+		let y = parts.get(0).and_then(|y| digits(y, 4)).ok_or_else(invalid)? as i32;
+		let m = match parts.get(1) {
+			Some(m) => Some(digits(m, 2).filter(|m| *m >= 1 && *m <= 12).ok_or_else(invalid)?),
+			None => None
+		};
+		match (m, parts.get(2), parts.len()) {
+			(None, _, 1) => Ok(VarDate::Year(y)),
+			(Some(m), None, 2) => Ok(VarDate::Month(y, m)),
+			(Some(m), Some(d), 3) => digits(d, 2).and_then(|d| NaiveDate::from_ymd_opt(y, m, d)).map(VarDate::Day).ok_or_else(invalid),
+			_ => Err(invalid())
+		}
+	}
+
+	pub fn precision(&self) -> Precision {
+		match *self {
+			VarDate::Year(_) => Precision::Year,
+			VarDate::Month(..) => Precision::Month,
+			VarDate::Day(_) => Precision::Day,
+			VarDate::Full(_) => Precision::Second
+		}
+	}
+
+	// The same instant in another timezone; dates without a time are unchanged
+	pub fn with_offset(&self, offset: FixedOffset) -> VarDate {
+		match *self {
+			VarDate::Full(dt) => VarDate::Full(dt.with_timezone(&offset)),
+			ref d => d.clone()
+		}
+	}
+
+	fn first_day(&self) -> NaiveDate {
+		match *self {
+			VarDate::Year(y) => NaiveDate::from_ymd(y, 1, 1),
+			VarDate::Month(y, m) => NaiveDate::from_ymd(y, m, 1),
+			VarDate::Day(d) => d,
+			VarDate::Full(dt) => dt.naive_local().date()
+		}
+	}
+
+	// The instants covered, as [start, end). A date without a time is read in
+	// the given timezone; a full dateTime covers its second. A range can't
+	// run past 9999, so one that would ends at the last instant of that year.
+	pub fn range(&self, offset: FixedOffset) -> (DateTime<FixedOffset>, DateTime<FixedOffset>) {
+		let last = NaiveDate::from_ymd(9999, 12, 31).and_hms_nano(23, 59, 59, 999_999_999);
+		let (start, end) = match *self {
+			VarDate::Full(dt) => (dt, Some((dt + Duration::seconds(1)).naive_local())),
+			_ => {
+				let first = self.first_day();
+				let end = match *self {
+					VarDate::Year(_) => with_month(first, 12),
+					VarDate::Month(..) => with_month(first, 1),
+					_ => first.succ_opt()
+				};
+				(offset.from_local_datetime(&first.and_hms(0, 0, 0)).unwrap(), end.map(|d| d.and_hms(0, 0, 0)))
+			}
+		};
+		let end = end.map_or(last, |e| e.min(last));
+		(start, start.offset().from_local_datetime(&end).unwrap())
+	}
+
+	// Compare precision by precision; None when one value stops before the
+	// other and they agree up to there, so whether they are equal is unknown
+	pub fn compare(&self, other: &VarDate) -> Option<Ordering> {
+		if let (&VarDate::Full(a), &VarDate::Full(b)) = (self, other) {
+			return Some(a.cmp(&b));
+		}
+		let (a, b) = (self.first_day(), other.first_day());
+		let common = self.precision().min(other.precision());
+		let o = a.year().cmp(&b.year())
+			.then_with(|| if common >= Precision::Month { a.month().cmp(&b.month()) } else { Ordering::Equal })
+			.then_with(|| if common >= Precision::Day { a.day().cmp(&b.day()) } else { Ordering::Equal });
+		match o {
+			Ordering::Equal if self.precision() != other.precision() => None,
+			o => Some(o)
+		}
 	}
 
-	pub fn parse(s: &str) -> Result<Self,ParseError> {
-        const ITEMS: &'static [Item<'static>] = &[Item::Fixed(Fixed::RFC3339)];
-        let mut parsed = Parsed::new();
+	// Add a duration at this value's precision. Years and months follow the
+	// calendar; an amount finer than the precision is truncated to it, so a
+	// year plus 400 days is the next year. None when the result is out of range.
+	pub fn add(&self, n: i64, unit: Unit) -> Option<VarDate> {
+		let secs = n.checked_mul(unit.seconds())?;
+		let months = match unit {
+			Unit::Year => n.checked_mul(12)?,
+			Unit::Month => n,
+			_ => 0
+		};
+		Some(match *self {
+			VarDate::Year(y) => {
+				let years = if unit.is_calendar() { months / 12 } else { secs / Unit::Year.seconds() };
+				VarDate::Year(add_months(y, 1, years.checked_mul(12)?)?.0)
+			},
+			VarDate::Month(y, m) => {
+				let months = if unit.is_calendar() { months } else { secs / Unit::Month.seconds() };
+				let (y, m) = add_months(y, m, months)?;
+				VarDate::Month(y, m)
+			},
+			VarDate::Day(d) if unit.is_calendar() => VarDate::Day(with_month(d, months)?),
+			VarDate::Day(d) => VarDate::Day(d.checked_add(Duration::days(secs / DAY_SECS))?),
+			VarDate::Full(dt) if unit.is_calendar() => {
+				let day = with_month(dt.naive_local().date(), months)?;
+				let local = day.and_time(dt.naive_local().time());
+				VarDate::Full(dt.offset().from_local_datetime(&local).single()?)
+			},
+			VarDate::Full(dt) => VarDate::Full(dt.checked_add(Duration::seconds(secs))?)
+		})
+	}
 
-        let r = format::parse(&mut parsed, s, ITEMS.iter().cloned());
-        VarDate::_from_parsed_result(r, parsed)
+	pub fn sub(&self, n: i64, unit: Unit) -> Option<VarDate> {
+		self.add(n.checked_neg()?, unit)
 	}
+}
 
+impl PartialEq for VarDate {
+	fn eq(&self, other: &VarDate) -> bool {
+		self.compare(other) == Some(Ordering::Equal)
+	}
+}
 
+impl PartialOrd for VarDate {
+	fn partial_cmp(&self, other: &VarDate) -> Option<Ordering> {
+		self.compare(other)
+	}
 }
 
 
 impl fmt::Display for VarDate {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		match (self.dt, self.y, self.m, self.d) {
-			(Some(dt),_,_,_) => write!(f,"{}",dt.to_rfc3339()),
-			(_,Some(y),None,None) => write!(f,"{}",y),
-			(_,Some(y),Some(m),None) => write!(f,"{}-{:02}",y,m), // TODO: pad month
-			(_,Some(y),Some(m),Some(d)) => write!(f,"{}-{:02}-{:02}",y,m,d), // TODO: pad day
-			_ => panic!("Invalid date")
+		match *self {
+			VarDate::Year(y) => write!(f,"{:04}",y),
+			VarDate::Month(y,m) => write!(f,"{:04}-{:02}",y,m),
+			VarDate::Day(d) => write!(f,"{}",d.format("%Y-%m-%d")),
+			VarDate::Full(dt) => write!(f,"{}",dt.to_rfc3339())
 		}
 	}
 }
@@ -66,34 +232,84 @@ impl fmt::Display for VarDate {
 #[test]
 fn test_parse_y() {
 	let d = VarDate::parse("2015").unwrap();
-	assert_eq!(d.y, Some(2015));
-	assert_eq!((d.m,d.d,d.dt), (None, None, None));
+	assert_eq!(Precision::Year, d.precision());
 	assert_eq!("2015", d.to_string());
 }
 
 #[test]
 fn test_parse_ym() {
 	let d = VarDate::parse("2015-05").unwrap();
-	assert_eq!(d.y, Some(2015));
-	assert_eq!(d.m, Some(5));
-	assert_eq!((d.d,d.dt), (None, None));
+	assert_eq!(Precision::Month, d.precision());
 	assert_eq!("2015-05", d.to_string());
 }
 
 #[test]
 fn test_parse_ymd() {
 	let d = VarDate::parse("2015-05-02").unwrap();
-	assert_eq!(d.y, Some(2015));
-	assert_eq!(d.m, Some(5));
-	assert_eq!(d.d, Some(2));
-	assert_eq!(d.dt, None);
+	assert_eq!(Precision::Day, d.precision());
 	assert_eq!("2015-05-02", d.to_string());
 }
 
 #[test]
 fn test_parse_complete_date() {
 	let d = VarDate::parse("2015-05-02T05:34:00-07:00").unwrap();
-	assert!(d.dt.is_some());
+	assert_eq!(Precision::Second, d.precision());
 	assert_eq!("2015-05-02T05:34:00-07:00", d.to_string());
 }
 
+#[test]
+fn test_parse_invalid() {
+	for s in ["15", "2015-5", "2015-13", "2015-02-30", "2015-05-02T05", "2015-05-02T05:34:00", "May"].iter() {
+		assert!(VarDate::parse(s).is_err(), "{}", s);
+	}
+}
+
+#[test]
+fn test_range() {
+	let utc = FixedOffset::east(0);
+	let (lo, hi) = VarDate::parse("2015-12").unwrap().range(utc);
+	assert_eq!(("2015-12-01T00:00:00+00:00".to_string(), "2016-01-01T00:00:00+00:00".to_string()), (lo.to_rfc3339(), hi.to_rfc3339()));
+	let (lo, hi) = VarDate::parse("2016").unwrap().range(FixedOffset::east(-5 * 3600));
+	assert_eq!(("2016-01-01T00:00:00-05:00".to_string(), "2017-01-01T00:00:00-05:00".to_string()), (lo.to_rfc3339(), hi.to_rfc3339()));
+	let (lo, hi) = VarDate::parse("2015-05-02T05:34:00-07:00").unwrap().range(utc);
+	assert_eq!(Duration::seconds(1), hi - lo);
+	for s in ["9999", "9999-12", "9999-12-31", "9999-12-31T23:59:59+00:00"].iter() {
+		let (lo, hi) = VarDate::parse(s).unwrap().range(utc);
+		assert!(lo < hi);
+		assert_eq!("9999-12-31T23:59:59.999999999+00:00", hi.to_rfc3339());
+	}
+	let (_, hi) = VarDate::parse("9998").unwrap().range(utc);
+	assert_eq!("9999-01-01T00:00:00+00:00", hi.to_rfc3339());
+}
+
+#[test]
+fn test_compare() {
+	let d = |s| VarDate::parse(s).unwrap();
+	assert_eq!(Some(Ordering::Less), d("2015-05").compare(&d("2015-06-01")));
+	assert_eq!(None, d("2015-05").compare(&d("2015-05-01")));
+	assert_eq!(Some(Ordering::Equal), d("2015-05-02").compare(&d("2015-05-02")));
+	assert_eq!(Some(Ordering::Greater), d("2016").compare(&d("2015-05-02T05:34:00-07:00")));
+	assert!(d("2015-05-02T05:34:00-07:00") == d("2015-05-02T12:34:00Z"));
+	assert!(d("2015-05-02T05:34:00-07:00") < d("2015-05-02T12:35:00Z"));
+	assert!(!(d("2015") < d("2015-01")) && !(d("2015") >= d("2015-01")));
+}
+
+#[test]
+fn test_add() {
+	let d = |s| VarDate::parse(s).unwrap();
+	assert_eq!("2016-02-29", d("2016-01-31").add(1, Unit::Month).unwrap().to_string());
+	assert_eq!("2017-02-28", d("2016-02-29").add(1, Unit::Year).unwrap().to_string());
+	assert_eq!("2014-11", d("2015-01").sub(2, Unit::Month).unwrap().to_string());
+	assert_eq!("2016", d("2015").add(400, Unit::Day).unwrap().to_string());
+	assert_eq!("2015-05-03", d("2015-05-02").add(36, Unit::Hour).unwrap().to_string());
+	assert_eq!("2015-05-03T05:34:00-07:00", d("2015-05-02T05:34:00-07:00").add(1, Unit::Day).unwrap().to_string());
+	assert_eq!("2015-06-02T05:34:00-07:00", d("2015-05-02T05:34:00-07:00").add(1, Unit::Month).unwrap().to_string());
+	assert!(d("9999").add(1, Unit::Year).is_none());
+}
+
+#[test]
+fn test_with_offset() {
+	let d = VarDate::parse("2015-05-02T05:34:00-07:00").unwrap().with_offset(FixedOffset::east(0));
+	assert_eq!("2015-05-02T12:34:00+00:00", d.to_string());
+	assert_eq!("2015", VarDate::parse("2015").unwrap().with_offset(FixedOffset::east(0)).to_string());
+}
@@ -0,0 +1,310 @@
+use std::collections::btree_map::BTreeMap;
+
+use rustc_serialize::json::Json;
+
+use element::{Value, ValueType};
+use extension::Extension;
+use primitive::Primitive;
+use resource::Resource;
+use typed::FromValue;
+use json::{read_file, resources_of_type};
+use validate::{Validator, str_at};
+use codegen::capitalize;
+use error::FhirError;
+#[cfg(test)]
+use error::issues;
+
+// Extension definitions by url, from StructureDefinitions or declared in
+// code, so that the value, repetitions and placement of an extension can be
+// checked and its value read typed. Contexts naming a datatype are matched
+// through the other definitions loaded. The sub-extensions of a complex
+// extension are not checked.
+
+pub struct ExtensionDef {
+	url: String,
+	// type codes of value[x]; none for an extension of sub-extensions
+	types: Vec<String>,
+	min: u64,
+	max: Option<u64>,
+	// element paths (Patient.contact) or type names (HumanName, Element) it may be used on
+	contexts: Vec<String>
+}
+
+impl ExtensionDef {
+	pub fn new(url: &str, types: &[&str]) -> Self {
+		ExtensionDef {url: url.to_string(), types: types.iter().map(|t| t.to_string()).collect(), min: 0, max: None, contexts: Vec::new()}
+	}
+
+	pub fn min(mut self, min: u64) -> Self {
+		self.min = min;
+		self
+	}
+
+	pub fn max(mut self, max: u64) -> Self {
+		self.max = Some(max);
+		self
+	}
+
+	pub fn context(mut self, context: &str) -> Self {
+		self.contexts.push(context.to_string());
+		self
+	}
+
+	// Contexts are objects with an expression (R4) or plain strings (STU3)
+	fn from_json(sd: &Json) -> Result<Self, FhirError> {
+		let url = str_at(sd, "url").ok_or_else(|| FhirError::missing("StructureDefinition without url"))?;
+		let elements = sd.find_path(&["snapshot", "element"]).and_then(|e| e.as_array())
+			.ok_or_else(|| FhirError::missing("Missing snapshot").at(url))?;
+		let element = |path: &str| elements.iter().find(|e| str_at(e, "path") == Some(path) && e.find("sliceName").is_none());
+		let mut def = ExtensionDef::new(url, &[]);
+		if let Some(root) = element("Extension") {
+			def.min = root.find("min").and_then(|m| m.as_u64()).unwrap_or(0);
+			def.max = match str_at(root, "max") {
+				Some("*") | None => None,
+				Some(m) => Some(m.parse().map_err(|_| FhirError::invalid(m, "Invalid max cardinality").at(url))?)
+			};
+		}
+		if let Some(value) = element("Extension.value[x]").filter(|v| str_at(v, "max") != Some("0")) {
+			def.types = value.find("type").and_then(|t| t.as_array())
+				.map(|ts| ts.iter().filter_map(|t| str_at(t, "code")).map(String::from).collect())
+				.unwrap_or_else(Vec::new);
+		}
+		def.contexts = sd.find("context").and_then(|c| c.as_array())
+			.map(|cs| cs.iter().filter_map(|c| c.as_string().or_else(|| str_at(c, "expression"))).map(String::from).collect())
+			.unwrap_or_else(Vec::new);
+		Ok(def)
+	}
+
+	// `t` is the type as an extension's value names it (Boolean, Coding)
+	fn allows_type(&self, t: Option<&str>) -> bool {
+		match t {
+			Some(t) => self.types.iter().any(|c| capitalize(c) == t),
+			None => self.types.is_empty()
+		}
+	}
+
+	// `path` has no indexes; `resolved` is the definition path and types of
+	// the element there, without which only a context naming the path matches
+	fn allows_context(&self, path: &str, resolved: Option<&(&str, Vec<&str>)>) -> bool {
+		let root = !path.contains('.');
+		self.contexts.is_empty() || self.contexts.iter().any(|c| c == path || c == "Element"
+			|| (root && (c == "Resource" || c == "DomainResource"))
+			|| resolved.map_or(false, |&(p, ref types)| c == p || types.contains(&c.as_str())))
+	}
+}
+
+pub struct ExtensionRegistry {
+	by_url: BTreeMap<String, ExtensionDef>,
+	elements: Validator
+}
+
+impl ExtensionRegistry {
+	pub fn new() -> Self {
+		ExtensionRegistry {by_url: BTreeMap::new(), elements: Validator::new()}
+	}
+
+	pub fn declare(&mut self, def: ExtensionDef) {
+		self.by_url.insert(def.url.clone(), def);
+	}
+
+	// Load the definitions in a Bundle (or a single StructureDefinition):
+	// those of extensions, and those of the elements they may be used on
+	pub fn load(&mut self, doc: &Json) -> Result<(), FhirError> {
+		self.elements.load(doc)?;
+		for sd in resources_of_type(doc, "StructureDefinition").into_iter().filter(|sd| str_at(sd, "type") == Some("Extension")) {
+			self.declare(ExtensionDef::from_json(sd)?);
+		}
+		Ok(())
+	}
+
+	pub fn load_file(&mut self, path: &str) -> Result<(), FhirError> {
+		self.load(&read_file(path)?)
+	}
+
+	fn definition(&self, url: &str) -> Result<&ExtensionDef, FhirError> {
+		self.by_url.get(url).ok_or_else(|| FhirError::unexpected(url, "Unknown extension"))
+	}
+
+	// An extension of a known url holding a value of an allowed type
+	pub fn extension<T: Into<Primitive>>(&self, url: &str, v: T) -> Result<Extension, FhirError> {
+		let p = v.into();
+		if !self.definition(url)?.allows_type(Some(p.type_suffix())) {
+			return Err(FhirError::invalid(p.type_suffix(), "Wrong type for extension").at(url));
+		}
+		let u = ::url::Url::parse(url).map_err(|_| FhirError::invalid(url, "Invalid URI"))?;
+		Extension::builder().uri(u).atom(p).and_then(|b| b.build())
+	}
+
+	// The values of the resource's extensions with this url
+	pub fn get_all<T: FromValue>(&self, r: &Resource, url: &str) -> Result<Vec<T>, FhirError> {
+		self.definition(url)?;
+		r.extensions.iter().enumerate()
+			.filter(|&(_, e)| e.url().as_str() == url)
+			.map(|(i, e)| e.get().map_err(|err| err.at(&format!("[{}]", i)).at("extension").at(&r.name)))
+			.collect()
+	}
+
+	pub fn get<T: FromValue>(&self, r: &Resource, url: &str) -> Result<Option<T>, FhirError> {
+		self.get_all(r, url).map(|vs| vs.into_iter().next())
+	}
+
+	// Check every extension in the resource against its definition
+	pub fn check(&self, r: &Resource) -> Vec<FhirError> {
+		let mut issues = Vec::new();
+		self.check_resource(r, &r.name, &mut issues);
+		issues
+	}
+
+	// A contained resource is its own context, found at `at` in its container
+	fn check_resource(&self, r: &Resource, at: &str, issues: &mut Vec<FhirError>) {
+		self.check_exts(&r.extensions, "extension", &r.name, at, issues);
+		self.check_exts(&r.modifier_extensions, "modifierExtension", &r.name, at, issues);
+		for e in r.all_elts().iter().filter(|e| e.name != "contained") {
+			self.check_value(&e.value, &format!("{}.{}", r.name, e.name), &format!("{}.{}", at, e.name), issues);
+		}
+		for (i, c) in r.contained.iter().enumerate() {
+			self.check_resource(c, &format!("{}.contained[{}]", at, i), issues);
+		}
+	}
+
+	// `ctx` is the path of the holder for its context, `at` the same with indexes
+	fn check_value(&self, v: &Value, ctx: &str, at: &str, issues: &mut Vec<FhirError>) {
+		match v.value {
+			ValueType::List(ref list) => for (i, x) in list.iter().enumerate() {
+				self.check_value(x, ctx, &format!("{}[{}]", at, i), issues);
+			},
+			ValueType::Atom(_) => self.check_exts(&v.extension, "extension", ctx, at, issues),
+			ValueType::Elt(ref elts) => {
+				self.check_exts(&v.extension, "extension", ctx, at, issues);
+				self.check_exts(&v.modifier_extension, "modifierExtension", ctx, at, issues);
+				for e in elts.iter() {
+					self.check_value(&e.value, &format!("{}.{}", ctx, e.name), &format!("{}.{}", at, e.name), issues);
+				}
+			}
+		}
+	}
+
+	fn check_exts(&self, exts: &[Extension], key: &str, ctx: &str, at: &str, issues: &mut Vec<FhirError>) {
+		let list = format!("{}.{}", at, key);
+		let resolved = if exts.is_empty() { None } else { self.elements.resolve_path(ctx) };
+		let mut counts: BTreeMap<&str, u64> = BTreeMap::new();
+		for (i, e) in exts.iter().enumerate() {
+			let url = e.url().as_str();
+			let here = format!("{}[{}]", list, i);
+			let def = match self.definition(url) {
+				Ok(def) => def,
+				Err(err) => {
+					issues.push(err.at(&here));
+					continue;
+				}
+			};
+			*counts.entry(url).or_insert(0) += 1;
+			let t = e.value_type();
+			if !def.allows_type(t.as_ref().map(|t| t.as_ref())) {
+				issues.push(FhirError::invalid(&t.unwrap_or_else(|| key.to_string()), "Wrong type for extension").at(&here));
+			}
+			if !def.allows_context(ctx, resolved.as_ref()) {
+				issues.push(FhirError::unexpected(url, "Extension not allowed here").at(&here));
+			}
+		}
+		for (url, n) in counts.iter() {
+			if self.by_url[*url].max.map_or(false, |m| *n > m) {
+				issues.push(FhirError::unexpected(url, "Too many repetitions of extension").at(&list));
+			}
+		}
+		// Required only where a context names this element exactly
+		if key == "extension" {
+			for def in self.by_url.values().filter(|d| d.min > 0 && d.contexts.iter().any(|c| c == ctx)) {
+				if counts.get(def.url.as_str()).map_or(true, |n| *n < def.min) {
+					issues.push(FhirError::invalid(&def.url, "Required extension missing").at(&list));
+				}
+			}
+		}
+	}
+}
+
+
+#[cfg(test)]
+const TEST_EXTENSIONS: &'static str = r##"{"resourceType": "Bundle", "entry": [
+	{"resource": {"resourceType": "StructureDefinition", "url": "http://example.org/is_happy", "type": "Extension",
+		"derivation": "constraint", "context": [{"type": "element", "expression": "Patient"}], "snapshot": {"element": [
+			{"path": "Extension", "min": 0, "max": "1"},
+			{"path": "Extension.url", "min": 1, "max": "1", "type": [{"code": "uri"}]},
+			{"path": "Extension.value[x]", "min": 1, "max": "1", "type": [{"code": "boolean"}]}]}}},
+	{"resource": {"resourceType": "StructureDefinition", "url": "http://example.org/nickname", "type": "Extension",
+		"derivation": "constraint", "context": ["HumanName"], "snapshot": {"element": [
+			{"path": "Extension", "min": 0, "max": "*"},
+			{"path": "Extension.value[x]", "min": 1, "max": "1", "type": [{"code": "string"}]}]}}},
+	{"resource": {"resourceType": "StructureDefinition", "url": "http://hl7.org/fhir/StructureDefinition/Patient", "type": "Patient",
+		"derivation": "specialization", "snapshot": {"element": [
+			{"path": "Patient", "min": 0, "max": "*"},
+			{"path": "Patient.active", "min": 0, "max": "1", "type": [{"code": "boolean"}]},
+			{"path": "Patient.name", "min": 0, "max": "*", "type": [{"code": "HumanName"}]},
+			{"path": "Patient.contact", "min": 0, "max": "*", "type": [{"code": "BackboneElement"}]},
+			{"path": "Patient.contact.name", "min": 0, "max": "1", "type": [{"code": "HumanName"}]}]}}}]}"##;
+
+#[cfg(test)]
+fn test_registry() -> ExtensionRegistry {
+	let mut reg = ExtensionRegistry::new();
+	reg.load(&Json::from_str(TEST_EXTENSIONS).unwrap()).unwrap();
+	reg.declare(ExtensionDef::new("http://example.org/proxy", &["boolean"]).context("Patient.contact").min(1));
+	reg
+}
+
+#[test]
+fn test_registry_get() {
+	use json::FromJson;
+	let reg = test_registry();
+	let r = Resource::from_json(&Json::from_str(r#"{"resourceType": "Patient",
+		"extension": [{"url": "http://example.org/is_happy", "valueBoolean": true}]}"#).unwrap()).unwrap();
+	assert_eq!(Some(true), reg.get::<bool>(&r, "http://example.org/is_happy").unwrap());
+	assert_eq!(None, reg.get::<String>(&r, "http://example.org/nickname").unwrap());
+	assert!(reg.get::<i32>(&r, "http://example.org/is_happy").is_err());
+	assert!(reg.get::<bool>(&r, "http://example.org/unknown").is_err());
+	assert!(reg.extension("http://example.org/is_happy", false).is_ok());
+	assert!(reg.extension("http://example.org/is_happy", "yes").is_err());
+}
+
+#[test]
+fn test_registry_check() {
+	use json::FromJson;
+	let reg = test_registry();
+	let r = Resource::from_json(&Json::from_str(r#"{"resourceType": "Patient",
+		"extension": [{"url": "http://example.org/is_happy", "valueBoolean": true}, {"url": "http://example.org/is_happy", "valueString": "yes"}],
+		"name": [{"family": "Smith", "extension": [{"url": "http://example.org/nickname", "valueString": "Smithy"}]}],
+		"contact": [{"extension": [{"url": "http://example.org/nickname", "valueString": "Jo"}],
+			"name": {"extension": [{"url": "http://example.org/nickname", "valueString": "Jo"}]}}],
+		"active": true, "_active": {"extension": [{"url": "http://example.org/unknown", "valueBoolean": true}]}}"#).unwrap()).unwrap();
+	assert_eq!(vec![
+		("Patient.extension[1]".to_string(), "Wrong type for extension"),
+		("Patient.extension".to_string(), "Too many repetitions of extension"),
+		("Patient.active.extension[0]".to_string(), "Unknown extension"),
+		("Patient.contact[0].extension[0]".to_string(), "Extension not allowed here"),
+		("Patient.contact[0].extension".to_string(), "Required extension missing")], issues(&reg.check(&r)));
+	let mut bare = ExtensionRegistry::new();
+	bare.declare(ExtensionDef::new("http://example.org/nickname", &["string"]).context("HumanName"));
+	assert_eq!(vec![
+		("Patient.extension[0]".to_string(), "Unknown extension"),
+		("Patient.extension[1]".to_string(), "Unknown extension"),
+		("Patient.active.extension[0]".to_string(), "Unknown extension"),
+		("Patient.contact[0].extension[0]".to_string(), "Extension not allowed here"),
+		("Patient.contact[0].name.extension[0]".to_string(), "Extension not allowed here"),
+		("Patient.name[0].extension[0]".to_string(), "Extension not allowed here")], issues(&bare.check(&r)));
+}
+
+#[test]
+fn test_registry_check_header_and_contained() {
+	use json::FromJson;
+	let reg = test_registry();
+	let r = Resource::from_json(&Json::from_str(r##"{"resourceType": "Patient",
+		"meta": {"versionId": "1", "_versionId": {"extension": [{"url": "http://example.org/unknown", "valueBoolean": true}]}},
+		"text": {"status": "generated", "extension": [{"url": "http://example.org/is_happy", "valueBoolean": true}],
+			"div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">Jo</div>"},
+		"contained": [{"resourceType": "Patient", "id": "p2", "extension": [{"url": "http://example.org/is_happy", "valueString": "yes"}],
+			"name": [{"extension": [{"url": "http://example.org/nickname", "valueString": "Jo"}]}]}],
+		"link": [{"other": {"reference": "#p2"}}]}"##).unwrap()).unwrap();
+	assert_eq!(vec![
+		("Patient.meta.versionId.extension[0]".to_string(), "Unknown extension"),
+		("Patient.text.extension[0]".to_string(), "Extension not allowed here"),
+		("Patient.contained[0].extension[0]".to_string(), "Wrong type for extension")], issues(&reg.check(&r)));
+}
@@ -0,0 +1,174 @@
+use std::borrow::Cow;
+use std::collections::btree_map::BTreeMap;
+
+use url::Url;
+
+use resource::Resource;
+use bundle::Bundle;
+use datatypes::{Identifier, Reference, ReferenceTarget};
+use typed::get_vec;
+use error::FhirError;
+
+// Looking references up. Each place a reference can point into is a
+// Resolver; `resolve` asks them in turn, usually the resources contained in
+// the one holding the reference, then the enclosing Bundle, then a store.
+// Places that hold resources give them borrowed, stores may give them owned.
+
+pub trait Resolver {
+	fn lookup<'a>(&'a self, target: &ReferenceTarget) -> Option<Cow<'a, Resource>>;
+}
+
+pub fn resolve<'a>(reference: &Reference, places: &[&'a dyn Resolver]) -> Result<Option<Cow<'a, Resource>>, FhirError> {
+	let target = match reference.target()? {
+		Some(t) => t,
+		None => return Ok(None)
+	};
+	let found = places.iter().filter_map(|p| p.lookup(&target)).next();
+	if let (Some(r), Some(t)) = (found.as_ref(), target.resource_type()) {
+		if r.name != t {
+			return Err(FhirError::invalid(&r.name, "Reference resolves to the wrong type").at("reference"));
+		}
+	}
+	Ok(found)
+}
+
+pub fn has_identifier(r: &Resource, identifier: &Identifier) -> bool {
+	get_vec::<Identifier>(&r.elts, "identifier").map(|ids| ids.iter().any(|i| i.matches(identifier))).unwrap_or(false)
+}
+
+fn is_version(r: &Resource, version: &Option<String>) -> bool {
+	version.as_ref().map_or(true, |v| r.meta.as_ref().and_then(|m| m.version_id.as_ref()).map(|i| i.as_str()) == Some(v))
+}
+
+// The resources contained in a resource; "#" is the resource itself
+pub struct Contained<'r>(pub &'r Resource);
+
+impl<'r> Resolver for Contained<'r> {
+	fn lookup<'a>(&'a self, target: &ReferenceTarget) -> Option<Cow<'a, Resource>> {
+		match *target {
+			ReferenceTarget::Contained(_) => target.reference().and_then(|r| self.0.resolve_local(&r)).map(Cow::Borrowed),
+			_ => None
+		}
+	}
+}
+
+// An enclosing Bundle, seen from the entry holding the reference
+pub struct InBundle<'b> {
+	pub bundle: &'b Bundle,
+	pub from: Option<&'b Url>
+}
+
+impl<'b> Resolver for InBundle<'b> {
+	fn lookup<'a>(&'a self, target: &ReferenceTarget) -> Option<Cow<'a, Resource>> {
+		let found = match *target {
+			ReferenceTarget::Contained(_) => None,
+			ReferenceTarget::Logical {ref resource_type, ref identifier} => self.bundle.resources().into_iter()
+				.find(|r| resource_type.as_ref().map_or(true, |t| *t == r.name) && has_identifier(r, identifier)),
+			_ => self.bundle.resolve(target, self.from).and_then(|e| e.resource.as_ref())
+		};
+		found.map(Cow::Borrowed)
+	}
+}
+
+// Resources kept in memory by type and id, with their earlier versions.
+// Absolute references resolve only under the store's base url.
+pub struct MemoryStore {
+	base: Option<String>,
+	by_key: BTreeMap<(String, String), Vec<Resource>>
+}
+
+impl MemoryStore {
+	pub fn new() -> Self {
+		MemoryStore {base: None, by_key: BTreeMap::new()}
+	}
+
+	pub fn base(mut self, base: &Url) -> Self {
+		let b = base.as_str();
+		self.base = Some(if b.ends_with('/') { b.to_string() } else { format!("{}/", b) });
+		self
+	}
+
+	// The latest version is the last put
+	pub fn put(&mut self, r: Resource) -> Result<(), FhirError> {
+		let id = r.id.as_ref().map(|i| i.as_str().to_string())
+			.ok_or_else(|| FhirError::missing("A stored resource needs an id").at(&r.name))?;
+		self.by_key.entry((r.name.clone(), id)).or_insert_with(Vec::new).push(r);
+		Ok(())
+	}
+
+	pub fn read(&self, resource_type: &str, id: &str, version: &Option<String>) -> Option<&Resource> {
+		self.by_key.get(&(resource_type.to_string(), id.to_string()))
+			.and_then(|vs| vs.iter().rev().find(|r| is_version(r, version)))
+	}
+}
+
+impl Resolver for MemoryStore {
+	fn lookup<'a>(&'a self, target: &ReferenceTarget) -> Option<Cow<'a, Resource>> {
+		let found = match *target {
+			ReferenceTarget::Relative {ref resource_type, ref id, ref version} => self.read(resource_type, id, version),
+			ReferenceTarget::Absolute {ref url, resource_type: Some(ref t), id: Some(ref id), ref version} => {
+				let relative = ReferenceTarget::Relative {resource_type: t.clone(), id: id.clone(), version: version.clone()};
+				match (&self.base, relative.reference()) {
+					(&Some(ref b), Some(ref rel)) if url.as_str() == format!("{}{}", b, rel) => self.read(t, id, version),
+					_ => None
+				}
+			},
+			ReferenceTarget::Logical {ref resource_type, ref identifier} => self.by_key.iter()
+				.filter(|&(k, _)| resource_type.as_ref().map_or(true, |t| *t == k.0))
+				.filter_map(|(_, vs)| vs.last())
+				.find(|r| has_identifier(r, identifier)),
+			_ => None
+		};
+		found.map(Cow::Borrowed)
+	}
+}
+
+
+#[cfg(test)]
+fn test_patient() -> Resource {
+	use rustc_serialize::json::Json;
+	use json::FromJson;
+	Resource::from_json(&Json::from_str(r##"{"resourceType": "Patient", "id": "1",
+		"contained": [{"resourceType": "Practitioner", "id": "pr1", "name": [{"family": "Jones"}]}],
+		"generalPractitioner": [{"reference": "#pr1"}], "managingOrganization": {"reference": "Organization/o1"},
+		"identifier": [{"system": "http://example.org/mrn", "value": "12345"}]}"##).unwrap()).unwrap()
+}
+
+#[test]
+fn test_resolve_contained_and_store() {
+	let p = test_patient();
+	let contained = Contained(&p);
+	let mut store = MemoryStore::new().base(&Url::parse("http://example.org/fhir").unwrap());
+	store.put(Resource::new("Organization").id(::primitive::Id::parse("o1").unwrap())).unwrap();
+	store.put(p.clone()).unwrap();
+	let places: Vec<&dyn Resolver> = vec![&contained, &store];
+	assert_eq!("Practitioner", resolve(&Reference::new("#pr1"), &places).unwrap().unwrap().name);
+	assert_eq!("Patient", resolve(&Reference::new("#"), &places).unwrap().unwrap().name);
+	assert!(resolve(&Reference::new("#pr2"), &places).unwrap().is_none());
+	assert_eq!("Organization", resolve(&Reference::new("Organization/o1"), &places).unwrap().unwrap().name);
+	assert!(resolve(&Reference::new("http://example.org/fhir/Organization/o1"), &places).unwrap().is_some());
+	assert!(resolve(&Reference::new("http://elsewhere.org/fhir/Organization/o1"), &places).unwrap().is_none());
+	let mrn = Identifier::new(Url::parse("http://example.org/mrn").unwrap(), "12345");
+	assert_eq!(Some("1"), resolve(&Reference::logical("Patient", mrn), &places).unwrap().unwrap().id.as_ref().map(|i| i.as_str()));
+	assert!(resolve(&Reference::new("Practitioner/o1"), &places).unwrap().is_none());
+	assert!(resolve(&Reference::new("Patient"), &places).is_err());
+}
+
+#[test]
+fn test_resolve_in_bundle() {
+	use bundle::{BundleType, BundleEntry};
+	let mut b = Bundle::new(BundleType::Collection);
+	let full = Url::parse("http://example.org/fhir/Patient/1").unwrap();
+	b.add(BundleEntry::new(test_patient()).full_url(full.clone()));
+	b.add(BundleEntry::new(Resource::new("Organization").id(::primitive::Id::parse("o1").unwrap()))
+		.full_url(Url::parse("http://example.org/fhir/Organization/o1").unwrap()));
+	let in_bundle = InBundle {bundle: &b, from: Some(&full)};
+	let places: Vec<&dyn Resolver> = vec![&in_bundle];
+	match resolve(&Reference::new("Organization/o1"), &places).unwrap() {
+		Some(Cow::Borrowed(r)) => assert_eq!("Organization", r.name),
+		r => panic!("{:?}", r)
+	}
+	let mrn = Identifier::new(Url::parse("http://example.org/mrn").unwrap(), "12345");
+	assert!(resolve(&Reference::logical("Patient", mrn), &places).unwrap().is_some());
+	assert!(resolve(&Reference::new("#pr1"), &places).unwrap().is_none());
+}
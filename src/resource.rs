@@ -1,58 +1,503 @@
 use std::collections::btree_map::BTreeMap;
+use std::io::{self, Write};
 use rustc_serialize::json::{ToJson, Json};
 use url::Url;
+use chrono::{UTC, FixedOffset};
 
 
-use element::{Element,NamedFrom};
-use extension::Extension;
+use element::{Element,Value,ValueType,NamedFrom,elts_from_json,elts_from_xml,modifiers_from_xml,write_members,append,fold,resource_type,get_choice,set_choice,check_choice};
+use extension::{Extension, Extensible, check_understood};
+use primitive::{Id, Code, Canonical};
+use datatypes::{Meta, Coding};
+use narrative::Narrative;
+use typed::{ToValue, FromValue, elts_of, get_opt};
+use json::{FromJson, JsonWriter, ElementOrder, as_object, as_string};
+use json::writer::sort_key;
+use xml::{ToXml, FromXml, XmlWriter, FHIR_NS};
+use xml::reader;
+use error::FhirError;
+#[cfg(test)]
+use error::issues;
+#[cfg(test)]
 use primitive::Primitive;
 
+// The elements every resource has are held typed; the rest stay in elts
+#[derive(Debug,Clone,PartialEq)]
 pub struct Resource {
 	pub name: String,
+	pub id: Option<Id>,
+	pub meta: Option<Meta>,
+	pub implicit_rules: Option<Url>,
+	pub language: Option<Code>,
+	pub contained: Vec<Resource>,
 	pub extensions: Vec<Extension>,
+	pub modifier_extensions: Vec<Extension>,
 	pub elts: Vec<Element>
 }
 
+// Whether the typed form writes back as the element it was read from, with
+// nothing of it dropped or rewritten
+fn holds<T: ToValue>(t: &T, v: &Value) -> bool {
+	t.to_value().to_json() == v.to_json()
+}
+
+// Converts one header element, leaving it in elts if its type can't hold it
+fn lift<T: FromValue + ToValue>(elts: &mut Vec<Element>, name: &str) -> Option<T> {
+	let i = elts.iter().position(|e| e.name == name && !e.value.has_idext())?;
+	let v = T::from_value(&elts[i].value).ok()?;
+	if !holds(&v, &elts[i].value) {
+		return None;
+	}
+	elts.remove(i);
+	Some(v)
+}
+
 impl Resource {
 	pub fn new(name: &str) -> Self {
-		Resource {name:String::from(name), elts: Vec::new(), extensions: Vec::new()}
+		Resource {name:String::from(name), id: None, meta: None, implicit_rules: None, language: None, contained: Vec::new(),
+			elts: Vec::new(), extensions: Vec::new(), modifier_extensions: Vec::new()}
 	}
 
 	pub fn new_with_elts(name: &str, elts: Vec<Element>) -> Self {
 		let mut r = Self::new(name);
-		r.elts = elts;
+		r.elts = fold(elts);
+		r.lift_header();
 		r
 	}
 
+	fn lift_header(&mut self) {
+		if let Some(id) = lift(&mut self.elts, "id") { self.id = Some(id); }
+		if let Some(meta) = lift(&mut self.elts, "meta") { self.meta = Some(meta); }
+		if let Some(u) = lift(&mut self.elts, "implicitRules") { self.implicit_rules = Some(u); }
+		if let Some(l) = lift(&mut self.elts, "language") { self.language = Some(l); }
+		self.lift_contained();
+	}
+
+	// Contained resources are lifted only if all of them read as resources
+	fn lift_contained(&mut self) {
+		let i = match self.elts.iter().position(|e| e.name == "contained" && !e.value.has_idext()) {
+			Some(i) => i,
+			None => return
+		};
+		let lifted = |v: &Value| Resource::from_value(v).ok().and_then(|r| if holds(&r, v) { Some(r) } else { None });
+		let read: Option<Vec<Resource>> = match self.elts[i].value.value {
+			ValueType::List(ref list) => list.iter().map(lifted).collect(),
+			_ => lifted(&self.elts[i].value).map(|r| vec![r])
+		};
+		if let Some(rs) = read {
+			self.contained.extend(rs);
+			self.elts.remove(i);
+		}
+	}
+
+	// The typed header as elements, in the order they are serialized
+	pub fn header_elts(&self) -> Vec<Element> {
+		let mut elts = Vec::new();
+		if let Some(ref id) = self.id {
+			elts.push(Element {name: "id".to_string(), value: id.to_value()});
+		}
+		if let Some(ref meta) = self.meta {
+			elts.push(Element {name: "meta".to_string(), value: meta.to_value()});
+		}
+		if let Some(ref u) = self.implicit_rules {
+			elts.push(Element {name: "implicitRules".to_string(), value: u.to_value()});
+		}
+		if let Some(ref l) = self.language {
+			elts.push(Element {name: "language".to_string(), value: l.to_value()});
+		}
+		elts
+	}
+
+	pub fn contained_elt(&self) -> Option<Element> {
+		match self.contained.len() {
+			0 => None,
+			_ => Some(Element::with("contained", self.contained.iter().map(|r| r.to_value()).collect::<Vec<Value>>()))
+		}
+	}
+
+	// Every member as elements, the typed ones where they are serialized
+	pub fn all_elts(&self) -> Vec<Element> {
+		let mut elts = self.header_elts();
+		let (text, rest): (Vec<&Element>, Vec<&Element>) = self.elts.iter().partition(|e| e.name == "text");
+		elts.extend(text.into_iter().cloned());
+		elts.extend(self.contained_elt());
+		elts.extend(rest.into_iter().cloned());
+		elts
+	}
+
+	pub fn id(mut self, id: Id) -> Self {
+		self.id = Some(id);
+		self
+	}
+
+	pub fn meta(mut self, meta: Meta) -> Self {
+		self.meta = Some(meta);
+		self
+	}
+
+	pub fn implicit_rules(mut self, rules: Url) -> Self {
+		self.implicit_rules = Some(rules);
+		self
+	}
+
+	pub fn language(mut self, language: Code) -> Self {
+		self.language = Some(language);
+		self
+	}
+
+	// A meta that Meta can't hold is kept as an element, which isn't edited here
+	fn meta_mut(&mut self) -> Result<&mut Meta, FhirError> {
+		if self.meta.is_none() && self.elts.iter().any(|e| e.name == "meta") {
+			return Err(FhirError::malformed("Meta isn't held as its type").at("meta").at(&self.name));
+		}
+		Ok(self.meta.get_or_insert_with(Meta::new))
+	}
+
+	// Claim conformance to a profile; a profile already listed isn't repeated
+	pub fn add_profile(&mut self, profile: Canonical) -> Result<(), FhirError> {
+		let meta = self.meta_mut()?;
+		if !meta.profile.contains(&profile) {
+			meta.profile.push(profile);
+		}
+		Ok(())
+	}
+
+	pub fn add_tag(&mut self, tag: Coding) -> Result<(), FhirError> {
+		let meta = self.meta_mut()?;
+		if !meta.tag.contains(&tag) {
+			meta.tag.push(tag);
+		}
+		Ok(())
+	}
+
+	// Set meta.lastUpdated to now, as a server does on each write
+	pub fn touch_last_updated(&mut self) -> Result<(), FhirError> {
+		self.meta_mut()?.last_updated = Some(UTC::now().with_timezone(&FixedOffset::east(0)));
+		Ok(())
+	}
+
+	// An element whose name is already present is added to its values
 	pub fn add_elt(mut self, e: Element) -> Self {
-		self.elts.push(e);
+		append(&mut self.elts, e);
+		self.lift_header();
 		self
 	}
 
+	pub fn narrative(&self) -> Result<Option<Narrative>, FhirError> {
+		get_opt(&self.elts, "text").map_err(|e| e.at(&self.name))
+	}
+
+	// Replaces any narrative the resource has
+	pub fn set_narrative(&mut self, n: Narrative) {
+		self.elts.retain(|e| e.name != "text");
+		self.elts.insert(0, Element {name: "text".to_string(), value: n.to_value()});
+	}
+
+	// The variant the choice `base` (deceased for deceased[x]) holds
+	pub fn choice(&self, base: &str) -> Result<Option<&Element>, FhirError> {
+		check_choice(&self.elts, base).map_err(|e| e.at(&self.name))?;
+		Ok(get_choice(&self.elts, base))
+	}
+
+	// Replaces whichever variant the choice held before
+	pub fn set_choice(&mut self, base: &str, e: Element) -> Result<(), FhirError> {
+		set_choice(&mut self.elts, base, e).map_err(|e| e.at(&self.name))
+	}
+
+	pub fn add_contained(mut self, r: Resource) -> Self {
+		self.contained.push(r);
+		self
+	}
+
+	pub fn find_contained(&self, id: &str) -> Option<&Resource> {
+		self.contained.iter().find(|r| r.id.as_ref().map(|i| i.as_str()) == Some(id))
+	}
+
+	// The resource a local reference points to: "#" is this one
+	pub fn resolve_local(&self, reference: &str) -> Option<&Resource> {
+		match reference {
+			"#" => Some(self),
+			r if r.starts_with('#') => self.find_contained(&r[1..]),
+			_ => None
+		}
+	}
+
+	// The rules of contained resources: each has a unique id, has no
+	// contained resources of its own, no narrative (dom-1), nor the meta a
+	// server keeps for a resource stored by itself (versionId, lastUpdated,
+	// security), and is referred to from elsewhere in the resource or refers
+	// to it. Every local reference must name a contained resource.
+	pub fn check_contained(&self) -> Vec<FhirError> {
+		let mut issues = Vec::new();
+		let mut refs = Vec::new();
+		resource_refs(self, &mut refs);
+		let inner: Vec<Vec<String>> = self.contained.iter().map(|c| {
+			let mut refs = Vec::new();
+			resource_refs(c, &mut refs);
+			refs
+		}).collect();
+		for (i, c) in self.contained.iter().enumerate() {
+			let mut issue = |err: FhirError| issues.push(err.at(&format!("[{}]", i)).at("contained"));
+			if !c.contained.is_empty() {
+				issue(FhirError::unexpected("contained", "A contained resource can't contain resources").at("contained"));
+			}
+			if c.elts.iter().any(|e| e.name == "text") {
+				issue(FhirError::unexpected("text", "A contained resource can't have a narrative").at("text"));
+			}
+			if let Some(ref m) = c.meta {
+				if m.version_id.is_some() || m.last_updated.is_some() || !m.security.is_empty() {
+					issue(FhirError::unexpected("meta", "A contained resource can't have a version, update time or security labels").at("meta"));
+				}
+			}
+			let id = match c.id {
+				Some(ref id) => format!("#{}", id.as_str()),
+				None => {
+					issue(FhirError::missing("A contained resource needs an id").at("id"));
+					continue;
+				}
+			};
+			if self.contained[..i].iter().any(|o| o.id == c.id) {
+				issue(FhirError::invalid(&id, "Duplicate contained id").at("id"));
+			}
+			let referenced = refs.contains(&id) || inner.iter().enumerate().any(|(j, rs)| j != i && rs.contains(&id));
+			if !referenced && !inner[i].iter().any(|r| r == "#") {
+				issue(FhirError::unexpected(&id, "Contained resource isn't referenced"));
+			}
+		}
+		for r in refs.iter().chain(inner.iter().flat_map(|rs| rs.iter())) {
+			if self.resolve_local(r).is_none() {
+				issues.push(FhirError::invalid(r, "Local reference to no contained resource"));
+			}
+		}
+		issues.into_iter().map(|e| e.at(&self.name)).collect()
+	}
+
 	pub fn add_ext(mut self, e: Extension) -> Self {
 		self.extensions.push(e);
 		self
 	}
 
+	pub fn add_modifier_ext(mut self, e: Extension) -> Self {
+		self.modifier_extensions.push(e);
+		self
+	}
+
 	pub fn has_extensions(&self) -> bool {
 		self.extensions.len() > 0
 	}
+
+	// Every extension in the resource, with the sub-extensions of complex ones
+	pub fn all_extensions(&self) -> Vec<&Extension> {
+		let mut out: Vec<&Extension> = self.extensions.iter().chain(self.modifier_extensions.iter())
+			.flat_map(|e| e.all_extensions())
+			.collect();
+		for c in self.contained.iter() {
+			out.extend(c.all_extensions());
+		}
+		for e in self.elts.iter() {
+			out.extend(e.value.all_extensions());
+		}
+		out
+	}
+
+	// Application code must not see a resource whose meaning is changed by a
+	// modifier extension it doesn't know; the error gives the extension's path
+	pub fn check_modifiers(&self, understood: &[&str]) -> Result<(), FhirError> {
+		check_understood(&self.modifier_extensions, understood).map_err(|e| e.at(&self.name))?;
+		for (i, c) in self.contained.iter().enumerate() {
+			c.check_modifiers(understood).map_err(|e| e.at(&format!("[{}]", i)).at("contained").at(&self.name))?;
+		}
+		for e in self.elts.iter() {
+			e.value.check_modifiers(understood).map_err(|err| err.at(&e.name).at(&self.name))?;
+		}
+		Ok(())
+	}
+
+	// The resource, if every modifier extension in it is understood
+	pub fn accept(self, understood: &[&str]) -> Result<Self, FhirError> {
+		self.check_modifiers(understood)?;
+		Ok(self)
+	}
+
+	pub fn write_json<W: Write, O: ElementOrder>(&self, order: &O, w: &mut JsonWriter<W>) -> io::Result<()> {
+		w.begin_object()?;
+		w.key("resourceType")?;
+		w.string(&self.name)?;
+		let mut elts = self.all_elts();
+		let rest = elts.split_off(self.header_elts().len());
+		for e in elts {
+			let (_, child) = sort_key(order, &self.name, &e.name);
+			e.value.write_json(&e.name, &child, order, w)?;
+		}
+		write_members(&rest, &self.extensions, &self.modifier_extensions, &self.name, order, w)?;
+		w.end_object()
+	}
+
+	// JSON text in the order given: a Validator's definitions, or ModelOrder
+	pub fn to_json_ordered<O: ElementOrder>(&self, order: &O) -> String {
+		let mut w = JsonWriter::new(Vec::new());
+		// Writing to memory can't fail, and every byte written came from a str
+		let _ = self.write_json(order, &mut w);
+		String::from_utf8(w.into_inner()).unwrap_or_default()
+	}
+}
+
+// The members of a resource that come before its extensions in XML
+pub const LEADING: &'static [&'static str] = &["id", "meta", "implicitRules", "language", "text", "contained"];
+
+// The local references ("#id") anywhere in a resource but its contained
+// resources, extensions included
+fn resource_refs(r: &Resource, out: &mut Vec<String>) {
+	local_refs(&r.elts, out);
+	extension_refs(&r.extensions, out);
+	extension_refs(&r.modifier_extensions, out);
+}
+
+fn local_refs(elts: &[Element], out: &mut Vec<String>) {
+	for e in elts.iter() {
+		match e.value.value {
+			ValueType::List(ref list) => for v in list.iter() {
+				value_refs(&e.name, v, out);
+			},
+			_ => value_refs(&e.name, &e.value, out)
+		}
+	}
+}
+
+fn value_refs(name: &str, v: &Value, out: &mut Vec<String>) {
+	extension_refs(&v.extension, out);
+	extension_refs(&v.modifier_extension, out);
+	match v.value {
+		ValueType::Atom(Some(ref p)) if name == "reference" => {
+			let s = p.to_string();
+			if s.starts_with('#') && !out.contains(&s) {
+				out.push(s);
+			}
+		},
+		ValueType::Elt(ref elts) => local_refs(elts, out),
+		_ => ()
+	}
+}
+
+fn extension_refs(exts: &[Extension], out: &mut Vec<String>) {
+	for x in exts.iter() {
+		match x.value() {
+			Some(v) => value_refs("", &v, out),
+			None => extension_refs(x.extensions(), out)
+		}
+	}
+}
+
+impl Extensible for Resource {
+	fn exts(&self) -> &[Extension] {
+		&self.extensions
+	}
+
+	fn exts_mut(&mut self) -> Result<&mut Vec<Extension>, FhirError> {
+		Ok(&mut self.extensions)
+	}
+}
+
+// A resource held in an element, as a Bundle entry or contained resource is,
+// names its type in a resourceType member
+impl ToValue for Resource {
+	fn to_value(&self) -> Value {
+		let mut elts = vec![Element::with("resourceType", self.name.as_str())];
+		elts.extend(self.all_elts());
+		Value {value: ValueType::Elt(elts), id: None, extension: self.extensions.clone(), modifier_extension: self.modifier_extensions.clone()}
+	}
+}
+
+impl FromValue for Resource {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		let elts = elts_of(v)?;
+		let name = resource_type(elts).ok_or_else(|| FhirError::missing("Missing resourceType"))?;
+		let mut rest: Vec<Element> = v.id.iter().map(|id| Element::with("id", id.as_str())).collect();
+		rest.extend(elts.iter().filter(|e| e.name != "resourceType").cloned());
+		let mut r = Resource::new_with_elts(name, rest);
+		r.extensions = v.extension.clone();
+		r.modifier_extensions = v.modifier_extension.clone();
+		Ok(r)
+	}
 }
 
 impl ToJson for Resource {
 	fn to_json(&self) -> Json {
 		let mut o: BTreeMap<String,Json> = BTreeMap::new();
 		o.insert("resourceType".to_string(),Json::String(self.name.clone()));
-		for e in self.elts.iter() {
-			o.insert(e.name.clone(), e.value.to_json());
+		for e in self.all_elts().iter() {
+			for (name, json) in e.value.keys(&e.name) {
+				o.insert(name, json);
+			}
 		}
 		if self.has_extensions() {
 			o.insert(String::from("extension"), self.extensions.to_json());
 		}
+		if !self.modifier_extensions.is_empty() {
+			o.insert(String::from("modifierExtension"), self.modifier_extensions.to_json());
+		}
 		Json::Object(o)
 	}
 }
 
+impl ToXml for Resource {
+	fn to_xml(&self) -> String {
+		let mut w = XmlWriter::new();
+		w.start(&self.name, &[]);
+		let (lead, rest): (Vec<Element>, Vec<Element>) = self.all_elts().into_iter().partition(|e| LEADING.contains(&e.name.as_str()));
+		for e in lead.iter() {
+			e.value.write_xml(&e.name, &mut w);
+		}
+		for e in self.extensions.iter() {
+			e.write_xml(&mut w);
+		}
+		for e in self.modifier_extensions.iter() {
+			e.write_xml_as("modifierExtension", &mut w);
+		}
+		for e in rest.iter() {
+			e.value.write_xml(&e.name, &mut w);
+		}
+		w.end(&self.name);
+		w.into_string()
+	}
+}
+
+impl FromXml for Resource {
+	fn from_xml(s: &str) -> Result<Self, FhirError> {
+		let root = reader::parse(s)?;
+		if root.attr("xmlns") != Some(FHIR_NS) {
+			return Err(FhirError::invalid(root.attr("xmlns").unwrap_or(""), "Not in the FHIR namespace").at(&root.name));
+		}
+		let (exts, elts) = elts_from_xml(s, &root.children).map_err(|e| e.at(&root.name))?;
+		let mut r = Resource::new_with_elts(&root.name, elts);
+		r.extensions = exts;
+		r.modifier_extensions = modifiers_from_xml(s, &root.children).map_err(|e| e.at(&root.name))?;
+		Ok(r)
+	}
+}
+
+impl FromJson for Resource {
+	fn from_json(j: &Json) -> Result<Self, FhirError> {
+		let mut o = as_object(j)?.clone();
+		let name = match o.remove("resourceType") {
+			Some(n) => as_string(&n).map_err(|e| e.at("resourceType"))?.to_string(),
+			None => return Err(FhirError::missing("Missing resourceType"))
+		};
+		let extensions = match o.remove("extension") {
+			Some(e) => Vec::<Extension>::from_json(&e).map_err(|e| e.at("extension").at(&name))?,
+			None => Vec::new()
+		};
+		let modifier_extensions = match o.remove("modifierExtension") {
+			Some(e) => Vec::<Extension>::from_json(&e).map_err(|e| e.at("modifierExtension").at(&name))?,
+			None => Vec::new()
+		};
+		let mut r = Resource::new_with_elts(&name, elts_from_json(&o).map_err(|e| e.at(&name))?);
+		r.extensions = extensions;
+		r.modifier_extensions = modifier_extensions;
+		Ok(r)
+	}
+}
+
 #[test]
 fn test_resource_to_json () {
 	let r = Resource::new("foo")
@@ -83,4 +528,284 @@ fn test_resource_with_ext () {
 	let j = Json::from_str(r#"{"resourceType": "foo", "extension": [{"url": "http://example.org/is_happy", "valueBoolean": false}], "bar": false, "baz": true}"#).unwrap();
 
 	assert_eq!(j, r.to_json());
-}
\ No newline at end of file
+}
+#[test]
+fn test_resource_round_trip() {
+	let j = Json::from_str(r#"{"resourceType": "Patient", "extension": [{"url": "http://example.org/is_happy", "valueBoolean": false}],
+		"active": true, "_active": {"id": "a1"}, "name": [{"family": ["Smith"], "given": ["Jo", "Al"], "_given": [null, {"extension": [{"url": "http://example.org/nick", "valueString": "Al"}]}]}],
+		"multipleBirthInteger": 2, "deceasedBoolean": false}"#).unwrap();
+	let r = Resource::from_json(&j).unwrap();
+	assert_eq!("Patient", r.name);
+	assert!(r.has_extensions());
+	assert_eq!(j, r.to_json());
+}
+
+#[test]
+fn test_resource_to_json_ordered() {
+	use json::writer::ModelOrder;
+	use primitive::Dec;
+	let e = Extension::builder()
+		.uri(Url::parse("http://example.org/is_happy").ok().unwrap())
+		.atom(Primitive::from(false))
+		.and_then(|e| e.build())
+		.ok().unwrap();
+	let r = Resource::new("Observation")
+		.add_elt(Element::with("valueDecimal", Dec::from_str("0.10").unwrap()))
+		.add_elt(Element::with("meta", vec![Element::with("versionId", "2")]))
+		.add_elt(Element::with("status", "final").id("s1"))
+		.add_elt(Element::with("id", "o1"))
+		.add_ext(e);
+	let expected = concat!(r#"{"resourceType":"Observation","id":"o1","meta":{"versionId":"2"},"valueDecimal":0.10,"#,
+		r#""status":"final","_status":{"id":"s1"},"extension":[{"url":"http://example.org/is_happy","valueBoolean":false}]}"#);
+	assert_eq!(expected, r.to_json_ordered(&ModelOrder));
+	assert_eq!(::json::parse(expected).unwrap(), r.to_json());
+}
+
+#[test]
+fn test_resource_decimal_json() {
+	use json::writer::ModelOrder;
+	use primitive::Dec;
+	let r = Resource::new("Observation")
+		.add_elt(Element::with("component", vec![Element::with("valueDecimal", Dec::from_str("1.0e2").unwrap())]))
+		.add_elt(Element::with("valueDecimal", Dec::from_str("1.50").unwrap()));
+	assert_eq!(r#"{"resourceType":"Observation","component":{"valueDecimal":1.0e2},"valueDecimal":1.50}"#, r.to_json_ordered(&ModelOrder));
+	assert_eq!(r#"{"component":{"valueDecimal":100.0},"resourceType":"Observation","valueDecimal":1.5}"#, r.to_json().to_string());
+	assert!(::json::parse(r#"{"resourceType":"Observation","valueDecimal":1e999999999}"#).is_err());
+}
+
+#[test]
+fn test_resource_from_json_requires_type() {
+	let j = Json::from_str(r#"{"bar": false}"#).unwrap();
+	assert!(Resource::from_json(&j).is_err());
+}
+
+#[test]
+fn test_resource_to_xml() {
+	let e = Extension::builder()
+		.uri(Url::parse("http://example.org/is_happy").ok().unwrap())
+		.atom(Primitive::from(false))
+		.and_then(|e| e.build())
+		.ok().unwrap();
+	let r = Resource::new("Patient")
+		.add_elt(Element::with("active",true).id("a1"))
+		.add_elt(Element::with("name", vec![Element::with("given", vec![Value::from("Jo"), Value::from("Al & Co")])]))
+		.add_ext(e);
+	let expected = concat!(r#"<Patient xmlns="http://hl7.org/fhir">"#,
+		r#"<extension url="http://example.org/is_happy"><valueBoolean value="false"/></extension>"#,
+		r#"<active id="a1" value="true"/>"#,
+		r#"<name><given value="Jo"/><given value="Al &amp; Co"/></name>"#,
+		r#"</Patient>"#);
+	assert_eq!(expected, r.to_xml());
+}
+
+#[test]
+fn test_resource_xml_to_json() {
+	// Without a definition an XML value keeps its text; a value[x] suffix gives its type
+	let x = r#"<foo xmlns="http://hl7.org/fhir"><bar value="false"/><baz value="0.10"/></foo>"#;
+	let j = Json::from_str("{\"resourceType\": \"foo\",\"bar\": \"false\",\"baz\": \"0.10\"}").unwrap();
+	assert_eq!(j, Resource::from_xml(x).unwrap().to_json());
+
+	let x = r#"<foo xmlns="http://hl7.org/fhir"><extension url="http://example.org/is_happy"><valueBoolean value="false"/></extension><bar value="false"/><baz value="true"/></foo>"#;
+	let j = Json::from_str(r#"{"resourceType": "foo", "extension": [{"url": "http://example.org/is_happy", "valueBoolean": false}], "bar": "false", "baz": "true"}"#).unwrap();
+	assert_eq!(j, Resource::from_xml(x).unwrap().to_json());
+}
+
+#[test]
+fn test_resource_xml_lists_and_narrative() {
+	let x = concat!(r#"<Patient xmlns="http://hl7.org/fhir">"#,
+		r#"<text><status value="generated"/><div xmlns="http://www.w3.org/1999/xhtml"><p>Jo &amp; Al</p></div></text>"#,
+		r#"<active id="a1" value="true"/>"#,
+		r#"<name><given value="Jo"/><given value="Al"><extension url="http://example.org/nick"><valueString value="Al"/></extension></given></name>"#,
+		r#"</Patient>"#);
+	let r = Resource::from_xml(x).unwrap();
+	let j = Json::from_str(r#"{"resourceType": "Patient",
+		"text": {"status": "generated", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\"><p>Jo &amp; Al</p></div>"},
+		"active": "true", "_active": {"id": "a1"},
+		"name": {"given": ["Jo", "Al"], "_given": [null, {"extension": [{"url": "http://example.org/nick", "valueString": "Al"}]}]}}"#).unwrap();
+	assert_eq!(j, r.to_json());
+	assert_eq!(x, r.to_xml());
+}
+
+#[test]
+fn test_resource_xml_requires_namespace() {
+	assert!(Resource::from_xml("<foo><bar value=\"false\"/></foo>").is_err());
+}
+
+#[test]
+fn test_resource_from_json_error_path() {
+	let j = Json::from_str(r#"{"resourceType": "Patient", "name": [{"given": ["Jo"], "_given": [null, null]}]}"#).unwrap();
+	let e = Resource::from_json(&j).err().unwrap();
+	assert_eq!("Patient.name[0]._given", e.path());
+	let j = Json::from_str(r#"{"resourceType": "Patient", "extension": [{"url": "http://example.org/a", "valueDate": "May"}]}"#).unwrap();
+	match Resource::from_json(&j) {
+		Err(FhirError::InvalidValue {ref path, ref value, ..}) => {
+			assert_eq!("Patient.extension[0].valueDate", path);
+			assert_eq!("May", value);
+		},
+		_ => panic!("expected an invalid value")
+	}
+}
+
+#[test]
+fn test_resource_header() {
+	use json::writer::ModelOrder;
+	let mut r = Resource::new("Patient")
+		.add_elt(Element::with("active", true))
+		.language(Code::parse("en-AU").unwrap())
+		.id(Id::parse("p1").unwrap());
+	r.add_profile(Canonical::parse("http://example.org/StructureDefinition/p").unwrap()).unwrap();
+	r.add_profile(Canonical::parse("http://example.org/StructureDefinition/p").unwrap()).unwrap();
+	r.add_tag(Coding::new(Url::parse("http://example.org/tags").unwrap(), Code::parse("urgent").unwrap())).unwrap();
+	assert_eq!(1, r.meta.as_ref().unwrap().profile.len());
+	let expected = concat!(r#"{"resourceType":"Patient","id":"p1","meta":{"profile":["http://example.org/StructureDefinition/p"],"#,
+		r#""tag":[{"system":"http://example.org/tags","code":"urgent"}]},"language":"en-AU","active":true}"#);
+	assert_eq!(expected, r.to_json_ordered(&ModelOrder));
+	assert!(r.to_xml().starts_with(r#"<Patient xmlns="http://hl7.org/fhir"><id value="p1"/><meta>"#));
+
+	let back = Resource::from_json(&Json::from_str(expected).unwrap()).unwrap();
+	assert_eq!("p1", back.id.as_ref().unwrap().as_str());
+	assert_eq!(r.meta, back.meta);
+	assert_eq!(1, back.elts.len());
+
+	r.touch_last_updated().unwrap();
+	assert!(r.meta.as_ref().unwrap().last_updated.is_some());
+}
+
+#[test]
+fn test_resource_header_kept_when_invalid() {
+	let j = Json::from_str(r#"{"resourceType": "Patient", "id": "not an id", "language": "en", "_language": {"id": "l1"}}"#).unwrap();
+	let r = Resource::from_json(&j).unwrap();
+	assert!(r.id.is_none() && r.language.is_none());
+	assert_eq!(j, r.to_json());
+}
+
+#[test]
+fn test_resource_header_lifted_only_without_loss() {
+	let lossy = [
+		r#"{"resourceType": "Patient", "meta": {"lastUpdated": "2015-05-02T12:34:00Z"}}"#];
+	for s in lossy.iter() {
+		let j = Json::from_str(s).unwrap();
+		let mut r = Resource::from_json(&j).unwrap();
+		assert!(r.meta.is_none(), "{}", s);
+		assert_eq!(j, r.to_json());
+		assert_eq!(Some("Patient.meta"), r.add_tag(Coding::new(Url::parse("http://example.org/tags").unwrap(), Code::parse("x").unwrap())).err().as_ref().map(|e| e.path()));
+	}
+	let j = Json::from_str(r#"{"resourceType": "Patient", "meta": {"versionId": "2", "lastUpdated": "2015-05-02T05:34:00-07:00"}}"#).unwrap();
+	let mut r = Resource::from_json(&j).unwrap();
+	assert!(r.meta.is_some());
+	assert_eq!(j, r.to_json());
+	assert!(r.touch_last_updated().is_ok());
+	// The ids and extensions of primitive members are kept
+	for s in [r#"{"resourceType": "Patient", "meta": {"versionId": "2", "_versionId": {"extension": [{"url": "http://example.org/x", "valueBoolean": true}]}}}"#,
+		r#"{"resourceType": "Patient", "meta": {"lastUpdated": "2015-05-02T05:34:00-07:00", "_lastUpdated": {"id": "u1"}}}"#,
+		r#"{"resourceType": "Patient", "meta": {"tag": [{"code": "urgent", "_code": {"id": "c1"}}]}}"#].iter() {
+		let j = Json::from_str(s).unwrap();
+		let r = Resource::from_json(&j).unwrap();
+		assert!(r.meta.is_some(), "{}", s);
+		assert_eq!(j, r.to_json());
+	}
+}
+
+#[test]
+fn test_modifier_extensions() {
+	use xml::ToXml;
+	let j = Json::from_str(r#"{"resourceType": "Patient",
+		"modifierExtension": [{"url": "http://example.org/not-real", "valueBoolean": true}],
+		"contact": [{"name": {"text": "Jo"}}, {"modifierExtension": [{"url": "http://example.org/proxy", "valueBoolean": true}], "name": {"text": "Al"}}]}"#).unwrap();
+	let r = Resource::from_json(&j).unwrap();
+	assert_eq!(1, r.modifier_extensions.len());
+	assert_eq!(j, r.to_json());
+	let x = r.to_xml();
+	assert!(x.contains(r#"<modifierExtension url="http://example.org/not-real"><valueBoolean value="true"/></modifierExtension>"#));
+	assert!(x.contains(r#"<contact><modifierExtension url="http://example.org/proxy">"#));
+	assert_eq!(j, Resource::from_xml(&x).unwrap().to_json());
+	assert!(r.to_json_ordered(&::json::writer::ModelOrder).contains(r#"{"name":{"text":"Al"},"modifierExtension":[{"url":"http://example.org/proxy","valueBoolean":true}]}"#));
+
+	let e = r.check_modifiers(&["http://example.org/not-real"]).err().unwrap();
+	assert_eq!("Patient.contact[1].modifierExtension[0]", e.path());
+	assert!(r.check_modifiers(&[]).is_err());
+	assert!(r.accept(&["http://example.org/not-real", "http://example.org/proxy"]).is_ok());
+}
+
+#[test]
+fn test_contained() {
+	use xml::ToXml;
+	let j = Json::from_str(r##"{"resourceType": "Patient", "id": "p1",
+		"text": {"status": "generated", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">Jo</div>"},
+		"contained": [{"resourceType": "Practitioner", "id": "pr1", "name": [{"family": "Jones"}]},
+			{"resourceType": "Organization", "id": "o1", "extension": [{"url": "http://example.org/x", "valueBoolean": true}]}],
+		"generalPractitioner": [{"reference": "#pr1"}], "managingOrganization": {"reference": "#o1"}}"##).unwrap();
+	let r = Resource::from_json(&j).unwrap();
+	assert_eq!(2, r.contained.len());
+	assert!(r.elts.iter().all(|e| e.name != "contained"));
+	assert_eq!("Practitioner", r.resolve_local("#pr1").unwrap().name);
+	assert_eq!("Patient", r.resolve_local("#").unwrap().name);
+	assert!(r.resolve_local("#pr2").is_none());
+	assert_eq!(j, r.to_json());
+	let x = r.to_xml();
+	assert!(x.contains(r#"</text><contained><Practitioner xmlns="http://hl7.org/fhir"><id value="pr1"/>"#));
+	assert!(x.contains(r#"<Organization xmlns="http://hl7.org/fhir"><id value="o1"/><extension url="http://example.org/x">"#));
+	let back = Resource::from_xml(&x).unwrap();
+	assert_eq!(2, back.contained.len());
+	assert_eq!(x, back.to_xml());
+	assert!(r.to_json_ordered(&::json::writer::ModelOrder).starts_with(r#"{"resourceType":"Patient","id":"p1","text":"#));
+	assert!(r.check_contained().is_empty());
+}
+
+#[test]
+fn test_contained_rules() {
+	let j = Json::from_str(r##"{"resourceType": "Patient",
+		"contained": [{"resourceType": "Practitioner", "id": "pr1", "meta": {"versionId": "2"}},
+			{"resourceType": "Organization", "id": "o1", "contained": [{"resourceType": "Basic", "id": "b1"}]},
+			{"resourceType": "Basic", "id": "pr1"},
+			{"resourceType": "Basic", "extension": [{"url": "http://example.org/x", "valueBoolean": true}]},
+			{"resourceType": "Provenance", "id": "pv1", "target": [{"reference": "#"}]},
+			{"resourceType": "Basic", "id": "b2", "text": {"status": "generated", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">B</div>"},
+				"extension": [{"url": "http://example.org/r", "extension": [{"url": "http://example.org/to", "valueReference": {"reference": "#b3"}}]}]},
+			{"resourceType": "Basic", "id": "b3"},
+			{"resourceType": "Basic", "id": "b4"}],
+		"extension": [{"url": "http://example.org/r", "valueReference": {"reference": "#b2"}}],
+		"modifierExtension": [{"url": "http://example.org/m", "valueReference": {"reference": "#b4"}}],
+		"generalPractitioner": [{"reference": "#pr1"}, {"reference": "#nope", "extension": [{"url": "http://example.org/r", "valueReference": {"reference": "#gone"}}]}]}"##).unwrap();
+	assert_eq!(vec![
+		("Patient.contained[0].meta".to_string(), "A contained resource can't have a version, update time or security labels"),
+		("Patient.contained[1].contained".to_string(), "A contained resource can't contain resources"),
+		("Patient.contained[1]".to_string(), "Contained resource isn't referenced"),
+		("Patient.contained[2].id".to_string(), "Duplicate contained id"),
+		("Patient.contained[3].id".to_string(), "A contained resource needs an id"),
+		("Patient.contained[5].text".to_string(), "A contained resource can't have a narrative"),
+		("Patient".to_string(), "Local reference to no contained resource"),
+		("Patient".to_string(), "Local reference to no contained resource")], issues(&Resource::from_json(&j).unwrap().check_contained()));
+}
+
+#[test]
+fn test_resource_base64_round_trip() {
+	use json::writer::ModelOrder;
+	let r = Resource::new("Binary")
+		.add_elt(Element::with("contentType", "text/plain"))
+		.add_elt(Element {name: "data".to_string(), value: Value::from(ValueType::Atom(Some(Primitive::Base64(b"hello, world".to_vec())))).id("d1")})
+		.add_ext(Extension::builder()
+			.uri(Url::parse("http://example.org/sig").ok().unwrap())
+			.atom(Primitive::Base64(b"f".to_vec()))
+			.and_then(|e| e.build())
+			.ok().unwrap());
+	let x = r.to_xml();
+	assert!(x.contains(r#"<valueBase64Binary value="Zg=="/>"#));
+	assert!(x.contains(r#"<data id="d1" value="aGVsbG8sIHdvcmxk"/>"#));
+	let j = r.to_json_ordered(&ModelOrder);
+	assert!(j.contains(r#""valueBase64Binary":"Zg==""#));
+	assert!(j.contains(r#""data":"aGVsbG8sIHdvcmxk","_data":{"id":"d1"}"#));
+}
+
+#[test]
+fn test_resource_choice() {
+	let mut r = Resource::new("Patient")
+		.add_elt(Element::with("birthDate", "1970-01-01"))
+		.add_elt(Element::choice("deceased", false));
+	r.set_choice("deceased", Element::choice("deceased", Primitive::String("2015-02-07".to_string()))).unwrap();
+	assert_eq!("deceasedString", r.choice("deceased").unwrap().unwrap().name);
+	assert_eq!(vec!["birthDate", "deceasedString"], r.elts.iter().map(|e| e.name.as_ref()).collect::<Vec<&str>>());
+	assert!(r.set_choice("deceased", Element::with("birthDate", "1970")).is_err());
+	r.elts.push(Element::choice("deceased", true));
+	assert_eq!(vec![("Patient.deceased[x]".to_string(), "Two values for one choice")], issues(&[r.choice("deceased").unwrap_err()]));
+}
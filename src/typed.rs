@@ -0,0 +1,459 @@
+use std::borrow::Cow;
+
+use url::Url;
+use chrono::{DateTime, FixedOffset};
+
+use element::{Element, Value, ValueType};
+use primitive::{Primitive, Dec, UnsignedInt, PositiveInt, Id, Code, Canonical, Markdown, Time, VarDate};
+use primitive::text::parse_uri;
+use primitive::base64;
+use resource::Resource;
+use extension::{Extension, check_understood};
+use error::FhirError;
+
+// Conversions between typed structs (see codegen) and the dynamic Element model
+
+pub trait ToValue {
+	fn to_value(&self) -> Value;
+}
+
+pub trait FromValue: Sized {
+	fn from_value(v: &Value) -> Result<Self, FhirError>;
+}
+
+pub trait Composite: Sized {
+	fn to_elts(&self) -> Vec<Element>;
+	fn from_elts(elts: &[Element]) -> Result<Self, FhirError>;
+}
+
+pub trait TypedResource: Composite {
+	fn resource_type() -> &'static str;
+
+	// The resource's own extensions and modifier extensions, which aren't
+	// among its elements
+	fn extensions(&self) -> (Vec<Extension>, Vec<Extension>) {
+		(Vec::new(), Vec::new())
+	}
+
+	// A struct with nowhere to keep them refuses them rather than losing them
+	fn set_extensions(&mut self, exts: &[Extension], mods: &[Extension]) -> Result<(), FhirError> {
+		check_understood(mods, &[])?;
+		refuse_extensions(exts)
+	}
+
+	fn to_resource(&self) -> Resource {
+		let mut r = Resource::new_with_elts(Self::resource_type(), self.to_elts());
+		let (exts, mods) = self.extensions();
+		r.extensions = exts;
+		r.modifier_extensions = mods;
+		r
+	}
+
+	fn from_resource(r: &Resource) -> Result<Self, FhirError> {
+		if r.name != Self::resource_type() {
+			return Err(FhirError::unexpected(&r.name, "Wrong resource type"));
+		}
+		let mut x = Self::from_elts(&r.all_elts()).map_err(|e| e.at(&r.name))?;
+		x.set_extensions(&r.extensions, &r.modifier_extensions).map_err(|e| e.at(&r.name))?;
+		Ok(x)
+	}
+}
+
+pub fn refuse_extensions(exts: &[Extension]) -> Result<(), FhirError> {
+	match exts.first() {
+		Some(e) => Err(FhirError::unexpected(e.url().as_str(), "Extension can't be kept").at("[0]").at("extension")),
+		None => Ok(())
+	}
+}
+
+// Generated structs refuse the elements they have no field for
+pub fn check_known(elts: &[Element], known: &[&str]) -> Result<(), FhirError> {
+	match elts.iter().find(|e| !known.contains(&e.name.as_str())) {
+		Some(e) => Err(FhirError::unexpected(&e.name, "Element can't be kept")),
+		None => Ok(())
+	}
+}
+
+// A typed primitive has nowhere to keep an id or extensions, so a value
+// with any is refused rather than losing them
+fn atom(v: &Value) -> Result<&Primitive, FhirError> {
+	if let Some(ref id) = v.id {
+		return Err(FhirError::unexpected(id, "Primitive id can't be kept"));
+	}
+	if let Some(e) = v.extension.first() {
+		return Err(FhirError::unexpected(e.url().as_str(), "Primitive extension can't be kept").at("extension"));
+	}
+	match v.value {
+		ValueType::Atom(Some(ref p)) => Ok(p),
+		_ => Err(FhirError::missing("Expected a primitive value"))
+	}
+}
+
+// XML leaves primitives as text until something knows their type; the
+// typed struct does, so text is read as the type it expects
+fn typed_atom<'a>(v: &'a Value, type_name: &str) -> Result<Cow<'a, Primitive>, FhirError> {
+	match *atom(v)? {
+		Primitive::String(ref s) => Primitive::from_xml_value(Some(type_name), s).map(Cow::Owned),
+		ref p => Ok(Cow::Borrowed(p))
+	}
+}
+
+pub fn elts_of(v: &Value) -> Result<&[Element], FhirError> {
+	match v.value {
+		ValueType::Elt(ref elts) => Ok(elts),
+		_ => Err(FhirError::missing("Expected a composite value"))
+	}
+}
+
+impl ToValue for bool {
+	fn to_value(&self) -> Value {
+		Value::from(*self)
+	}
+}
+
+impl FromValue for bool {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		match *typed_atom(v, "Boolean")? {
+			Primitive::Boolean(b) => Ok(b),
+			ref p => Err(FhirError::invalid(&p.to_string(), "Expected boolean"))
+		}
+	}
+}
+
+impl ToValue for i32 {
+	fn to_value(&self) -> Value {
+		Value::from(*self)
+	}
+}
+
+impl FromValue for i32 {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		match *typed_atom(v, "Integer")? {
+			Primitive::Int(i) => Ok(i),
+			Primitive::UInt(i) => Ok(i.value() as i32),
+			Primitive::PInt(i) => Ok(i.value() as i32),
+			ref p => Err(FhirError::invalid(&p.to_string(), "Expected integer"))
+		}
+	}
+}
+
+impl ToValue for i64 {
+	fn to_value(&self) -> Value {
+		Value::from(*self)
+	}
+}
+
+impl FromValue for i64 {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		match *typed_atom(v, "Integer64")? {
+			Primitive::Integer64(i) => Ok(i),
+			Primitive::Int(i) => Ok(i as i64),
+			Primitive::UInt(i) => Ok(i.value() as i64),
+			Primitive::PInt(i) => Ok(i.value() as i64),
+			ref p => Err(FhirError::invalid(&p.to_string(), "Expected integer64"))
+		}
+	}
+}
+
+impl ToValue for UnsignedInt {
+	fn to_value(&self) -> Value {
+		Value::from(*self)
+	}
+}
+
+impl FromValue for UnsignedInt {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		match *typed_atom(v, "UnsignedInt")? {
+			Primitive::UInt(i) => Ok(i),
+			Primitive::PInt(i) => UnsignedInt::new(i.value() as i64),
+			Primitive::Int(i) => UnsignedInt::new(i as i64),
+			ref p => Err(FhirError::invalid(&p.to_string(), "Expected unsigned integer"))
+		}
+	}
+}
+
+impl ToValue for PositiveInt {
+	fn to_value(&self) -> Value {
+		Value::from(*self)
+	}
+}
+
+impl FromValue for PositiveInt {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		match *typed_atom(v, "PositiveInt")? {
+			Primitive::PInt(i) => Ok(i),
+			Primitive::UInt(i) => PositiveInt::new(i.value() as i64),
+			Primitive::Int(i) => PositiveInt::new(i as i64),
+			ref p => Err(FhirError::invalid(&p.to_string(), "Expected positive integer"))
+		}
+	}
+}
+
+impl ToValue for Dec {
+	fn to_value(&self) -> Value {
+		Value::from(self.clone())
+	}
+}
+
+impl FromValue for Dec {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		match *typed_atom(v, "Decimal")? {
+			Primitive::Decimal(ref d) => Ok(d.clone()),
+			Primitive::Int(_) | Primitive::UInt(_) | Primitive::PInt(_) => Dec::from_str(&atom(v)?.to_string()),
+			ref p => Err(FhirError::invalid(&p.to_string(), "Expected decimal"))
+		}
+	}
+}
+
+// Every string-like FHIR primitive (code, uri, dateTime, ...) is carried as text
+impl ToValue for String {
+	fn to_value(&self) -> Value {
+		Value::from(self.clone())
+	}
+}
+
+impl FromValue for String {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		atom(v).map(|p| p.to_string())
+	}
+}
+
+// Constrained strings are read from whichever primitive holds their text,
+// as JSON leaves them all as plain strings
+macro_rules! parsed_value {
+	($t:ty, $parse:expr) => {
+		impl ToValue for $t {
+			fn to_value(&self) -> Value {
+				Value::from(self.clone())
+			}
+		}
+
+		impl FromValue for $t {
+			fn from_value(v: &Value) -> Result<Self, FhirError> {
+				atom(v).and_then(|p| $parse(&p.to_string()))
+			}
+		}
+	}
+}
+
+parsed_value!(Id, Id::parse);
+parsed_value!(Code, Code::parse);
+parsed_value!(Canonical, Canonical::parse);
+parsed_value!(Markdown, Markdown::parse);
+parsed_value!(Time, Time::parse);
+parsed_value!(Url, parse_uri);
+parsed_value!(DateTime<FixedOffset>, |s: &str| s.parse::<DateTime<FixedOffset>>().map_err(|_| FhirError::invalid(s, "Invalid instant")));
+
+// A date, dateTime or instant; written back as a dateTime
+impl ToValue for VarDate {
+	fn to_value(&self) -> Value {
+		Value::from(ValueType::Atom(Some(Primitive::DateTime(self.clone()))))
+	}
+}
+
+impl FromValue for VarDate {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		match *atom(v)? {
+			Primitive::Date(ref d) | Primitive::DateTime(ref d) => Ok(d.clone()),
+			ref p => VarDate::parse(&p.to_string())
+		}
+	}
+}
+
+// base64Binary
+impl ToValue for Vec<u8> {
+	fn to_value(&self) -> Value {
+		Value::from(ValueType::Atom(Some(Primitive::Base64(self.clone()))))
+	}
+}
+
+impl FromValue for Vec<u8> {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		match *atom(v)? {
+			Primitive::Base64(ref b) => Ok(b.clone()),
+			ref p => base64::decode(&p.to_string())
+		}
+	}
+}
+
+impl<T: ToValue> ToValue for Box<T> {
+	fn to_value(&self) -> Value {
+		(**self).to_value()
+	}
+}
+
+impl<T: FromValue> FromValue for Box<T> {
+	fn from_value(v: &Value) -> Result<Self, FhirError> {
+		T::from_value(v).map(Box::new)
+	}
+}
+
+pub fn put<T: ToValue>(elts: &mut Vec<Element>, name: &str, v: &T) {
+	elts.push(Element {name: name.to_string(), value: v.to_value()});
+}
+
+pub fn put_opt<T: ToValue>(elts: &mut Vec<Element>, name: &str, v: &Option<T>) {
+	if let Some(ref v) = *v {
+		put(elts, name, v);
+	}
+}
+
+pub fn put_vec<T: ToValue>(elts: &mut Vec<Element>, name: &str, v: &[T]) {
+	if !v.is_empty() {
+		let list = v.iter().map(|x| x.to_value()).collect();
+		elts.push(Element {name: name.to_string(), value: Value {value: ValueType::List(list), id: None, extension: Vec::new(), modifier_extension: Vec::new()}});
+	}
+}
+
+fn find<'a>(elts: &'a [Element], name: &str) -> Option<&'a Value> {
+	elts.iter().find(|e| e.name == name).map(|e| &e.value)
+}
+
+pub fn get<T: FromValue>(elts: &[Element], name: &str) -> Result<T, FhirError> {
+	get_opt(elts, name)?.ok_or_else(|| FhirError::missing("Missing required element").at(name))
+}
+
+pub fn get_opt<T: FromValue>(elts: &[Element], name: &str) -> Result<Option<T>, FhirError> {
+	match find(elts, name) {
+		Some(v) => T::from_value(v).map(Some).map_err(|e| e.at(name)),
+		None => Ok(None)
+	}
+}
+
+// A repeating element read from XML may have come back as a single value
+pub fn get_vec<T: FromValue>(elts: &[Element], name: &str) -> Result<Vec<T>, FhirError> {
+	match find(elts, name) {
+		Some(&Value {value: ValueType::List(ref l), ..}) => l.iter().enumerate()
+			.map(|(i, v)| T::from_value(v).map_err(|e| e.at(&format!("[{}]", i)).at(name)))
+			.collect(),
+		Some(v) => T::from_value(v).map(|x| vec![x]).map_err(|e| e.at(name)),
+		None => Ok(Vec::new())
+	}
+}
+
+// Whether a value is a primitive with an id or extensions, which a plain
+// Rust value can't hold
+fn has_extras(v: &Value) -> bool {
+	match v.value {
+		ValueType::Atom(_) => v.has_idext(),
+		_ => false
+	}
+}
+
+// A primitive value without its id and extensions, and those without the value
+fn split_atom(v: &Value) -> (Value, Value) {
+	let shell = Value {value: ValueType::Atom(None), ..v.clone()};
+	(Value::from(v.value.clone()), shell)
+}
+
+// The known members with the ids and extensions of their primitives taken
+// out, and those ids and extensions, each as its member without a value. A
+// primitive without a value is only its extras; one in a list is left for
+// the typed read to refuse, as the list would lose its place.
+pub fn split_extras(elts: &[Element], known: &[&str]) -> (Vec<Element>, Vec<Element>) {
+	let mut values = Vec::new();
+	let mut extras = Vec::new();
+	for e in elts.iter().filter(|e| known.contains(&e.name.as_str())) {
+		match e.value.value {
+			ValueType::Atom(ref p) if has_extras(&e.value) => {
+				let (value, shell) = split_atom(&e.value);
+				if p.is_some() {
+					values.push(Element {name: e.name.clone(), value: value});
+				}
+				extras.push(Element {name: e.name.clone(), value: shell});
+			},
+			ValueType::List(ref l) if l.iter().any(has_extras) => {
+				let (list, shells): (Vec<Value>, Vec<Value>) = l.iter().map(|v| match v.value {
+					ValueType::Atom(Some(_)) => split_atom(v),
+					_ => (v.clone(), Value::from(ValueType::Atom(None)))
+				}).unzip();
+				values.push(Element {name: e.name.clone(), value: Value::from(ValueType::List(list))});
+				extras.push(Element {name: e.name.clone(), value: Value::from(ValueType::List(shells))});
+			},
+			_ => values.push(e.clone())
+		}
+	}
+	(values, extras)
+}
+
+fn graft(v: &mut Value, extra: &Value) {
+	match (&mut v.value, &extra.value) {
+		(&mut ValueType::List(ref mut l), &ValueType::List(ref xs)) => {
+			for (v, x) in l.iter_mut().zip(xs.iter()) {
+				graft(v, x);
+			}
+			return;
+		},
+		_ => ()
+	}
+	v.id = extra.id.clone();
+	v.extension = extra.extension.clone();
+}
+
+// Put the ids and extensions back on the primitives they came from
+pub fn graft_extras(elts: &mut Vec<Element>, extras: &[Element]) {
+	for x in extras.iter() {
+		match elts.iter_mut().find(|e| e.name == x.name) {
+			Some(e) => graft(&mut e.value, &x.value),
+			None => elts.push(x.clone())
+		}
+	}
+}
+
+#[cfg(test)]
+struct Coding {
+	system: Option<String>,
+	code: String,
+	version: Vec<String>
+}
+
+#[cfg(test)]
+impl Composite for Coding {
+	fn to_elts(&self) -> Vec<Element> {
+		let mut elts = Vec::new();
+		put_opt(&mut elts, "system", &self.system);
+		put(&mut elts, "code", &self.code);
+		put_vec(&mut elts, "version", &self.version);
+		elts
+	}
+
+	fn from_elts(elts: &[Element]) -> Result<Self, FhirError> {
+		Ok(Coding {
+			system: get_opt(elts, "system")?,
+			code: get(elts, "code")?,
+			version: get_vec(elts, "version")?
+		})
+	}
+}
+
+#[cfg(test)]
+impl TypedResource for Coding {
+	fn resource_type() -> &'static str {
+		"Coding"
+	}
+}
+
+#[test]
+fn test_composite_round_trip() {
+	use rustc_serialize::json::{Json, ToJson};
+	use json::FromJson;
+	let j = Json::from_str(r#"{"resourceType": "Coding", "code": "abc", "version": ["1", "2"]}"#).unwrap();
+	let c = Coding::from_resource(&Resource::from_json(&j).unwrap()).unwrap();
+	assert_eq!(None, c.system);
+	assert_eq!("abc", c.code);
+	assert_eq!(j, c.to_resource().to_json());
+}
+
+#[test]
+fn test_missing_required_element() {
+	let r = Resource::new_with_elts("Coding", vec![]);
+	let e = Coding::from_resource(&r).err().unwrap();
+	assert_eq!("Coding.code", e.path());
+}
+
+#[test]
+fn test_single_value_for_repeating_element() {
+	use element::NamedFrom;
+	let elts = vec![Element::with("version", "1")];
+	assert_eq!(vec!["1".to_string()], get_vec::<String>(&elts, "version").unwrap());
+}
@@ -0,0 +1,505 @@
+use std::collections::btree_map::BTreeMap;
+use std::mem;
+
+use rustc_serialize::json::Json;
+
+use element::{Element, Value, ValueType};
+use primitive::Primitive;
+use narrative::XhtmlElement;
+use resource::Resource;
+use error::FhirError;
+use json::{ElementOrder, read_file, resources_of_type};
+use codegen::capitalize;
+
+// Structural validation of the dynamic model against StructureDefinition
+// snapshots: cardinality, element types, required and unknown elements.
+// Slicing, invariants and terminology bindings are not checked.
+
+struct ElementDef {
+	path: String,
+	min: u64,
+	max: Option<u64>,
+	types: Vec<String>,
+	content_ref: Option<String>
+}
+
+struct Definition {
+	type_name: String,
+	elements: Vec<ElementDef>
+}
+
+pub struct Validator {
+	by_url: BTreeMap<String, Definition>,
+	base: BTreeMap<String, String>
+}
+
+pub fn str_at<'a>(j: &'a Json, key: &str) -> Option<&'a str> {
+	j.find(key).and_then(|v| v.as_string())
+}
+
+fn is_primitive(code: &str) -> bool {
+	code.starts_with("http://hl7.org/fhirpath/System.") || code.starts_with(char::is_lowercase)
+}
+
+impl ElementDef {
+	fn from_json(e: &Json) -> Result<Self, FhirError> {
+		let path = str_at(e, "path").ok_or_else(|| FhirError::missing("Element without path"))?;
+		let max = match str_at(e, "max") {
+			Some("*") | None => None,
+			Some(m) => Some(m.parse().map_err(|_| FhirError::invalid(m, "Invalid max cardinality").at(path))?)
+		};
+		Ok(ElementDef {
+			path: path.to_string(),
+			min: e.find("min").and_then(|m| m.as_u64()).unwrap_or(0),
+			max: max,
+			types: e.find("type").and_then(|t| t.as_array())
+				.map(|ts| ts.iter().filter_map(|t| str_at(t, "code")).map(String::from).collect())
+				.unwrap_or_else(Vec::new),
+			content_ref: str_at(e, "contentReference").map(|r| r.rsplit('#').next().unwrap_or(r).to_string())
+		})
+	}
+
+	fn name(&self, prefix: &str) -> &str {
+		&self.path[prefix.len()..]
+	}
+
+	// The type named by an instance element if it matches this definition;
+	// choice elements (value[x]) match valueQuantity etc.
+	fn matches<'a>(&'a self, prefix: &str, name: &str) -> Option<Option<&'a str>> {
+		let seg = self.name(prefix);
+		if seg.ends_with("[x]") {
+			let base = &seg[..seg.len() - 3];
+			if !name.starts_with(base) {
+				return None;
+			}
+			let suffix = &name[base.len()..];
+			self.types.iter().find(|t| capitalize(t) == suffix).map(|t| Some(t.as_ref()))
+		} else if seg == name {
+			Some(None)
+		} else {
+			None
+		}
+	}
+
+	// The path a backbone element's children are defined under, if it is one
+	fn nested(&self) -> Option<&str> {
+		match self.content_ref {
+			Some(ref r) => Some(r.as_ref()),
+			None if self.types.iter().all(|t| t == "BackboneElement" || t == "Element") => Some(self.path.as_ref()),
+			None => None
+		}
+	}
+
+	// The types an instance element may have; a choice element has the one its name gives
+	fn types_for<'a>(&'a self, choice: Option<&'a str>) -> Vec<&'a str> {
+		match choice {
+			Some(t) => vec![t],
+			None => self.types.iter().map(|t| t.as_ref()).collect()
+		}
+	}
+
+	// The path the children of an instance are defined under: a referenced
+	// or nested backbone element, or the definition of its one type
+	fn child_path(&self, choice: Option<&str>) -> String {
+		match (self.content_ref.as_ref(), choice, self.types.len()) {
+			(Some(r), _, _) => r.clone(),
+			(None, Some(t), _) => t.to_string(),
+			(None, None, 1) if self.types[0] != "BackboneElement" && self.types[0] != "Element" => self.types[0].clone(),
+			_ => self.path.clone()
+		}
+	}
+}
+
+impl Definition {
+	// The definitions of the elements directly under a path
+	fn children(&self, prefix: &str) -> Vec<&ElementDef> {
+		self.elements.iter()
+			.filter(|d| d.path.starts_with(prefix) && !d.name(prefix).contains('.'))
+			.collect()
+	}
+}
+
+impl Validator {
+	pub fn new() -> Self {
+		Validator {by_url: BTreeMap::new(), base: BTreeMap::new()}
+	}
+
+	// Load every StructureDefinition in a Bundle (or a single definition)
+	pub fn load(&mut self, doc: &Json) -> Result<(), FhirError> {
+		for sd in resources_of_type(doc, "StructureDefinition") {
+			let url = str_at(sd, "url").ok_or_else(|| FhirError::missing("StructureDefinition without url"))?;
+			let type_name = str_at(sd, "type").ok_or_else(|| FhirError::missing("StructureDefinition without type").at(url))?;
+			let elements = sd.find_path(&["snapshot", "element"]).and_then(|e| e.as_array())
+				.ok_or_else(|| FhirError::missing("Missing snapshot").at(url))?;
+			let mut defs = Vec::new();
+			for e in elements.iter().filter(|e| e.find("sliceName").is_none()) {
+				defs.push(ElementDef::from_json(e).map_err(|e| e.at(url))?);
+			}
+			if str_at(sd, "derivation") != Some("constraint") {
+				self.base.insert(type_name.to_string(), url.to_string());
+			}
+			self.by_url.insert(url.to_string(), Definition {type_name: type_name.to_string(), elements: defs});
+		}
+		Ok(())
+	}
+
+	pub fn load_file(&mut self, path: &str) -> Result<(), FhirError> {
+		self.load(&read_file(path)?)
+	}
+
+	fn base_definition(&self, type_name: &str) -> Option<&Definition> {
+		self.base.get(type_name).and_then(|u| self.by_url.get(u))
+	}
+
+	// The definition path and types of the element at an instance path
+	// without indexes: Patient.name.given is HumanName.given, a string
+	pub fn resolve_path(&self, path: &str) -> Option<(&str, Vec<&str>)> {
+		let mut segs = path.split('.');
+		let mut def = self.base_definition(segs.next()?)?;
+		let mut def_path = def.type_name.as_str();
+		let mut found: Option<(&ElementDef, Option<&str>)> = None;
+		for seg in segs {
+			// Below an element: its backbone, or the definition of its one type
+			if let Some((d, choice)) = found {
+				let types = d.types_for(choice);
+				match (d.nested(), types.len(), types.first().and_then(|t| self.base_definition(t))) {
+					(Some(n), _, _) => def_path = n,
+					(None, 1, Some(td)) => {
+						def = td;
+						def_path = td.type_name.as_str();
+					},
+					_ => return None
+				}
+			}
+			let prefix = format!("{}.", def_path);
+			found = Some(def.children(&prefix).into_iter().filter_map(|d| d.matches(&prefix, seg).map(|t| (d, t))).next()?);
+		}
+		found.map(|(d, choice)| (d.path.as_str(), d.types_for(choice)))
+	}
+
+	// Validate against the base definition of the resource's type
+	pub fn validate(&self, r: &Resource) -> Vec<FhirError> {
+		match self.base_definition(&r.name) {
+			Some(def) => self.check(def, r),
+			None => vec![FhirError::unexpected(&r.name, "No definition for resource type").at(&r.name)]
+		}
+	}
+
+	// Validate against a profile, identified by its canonical url
+	pub fn validate_profile(&self, r: &Resource, url: &str) -> Vec<FhirError> {
+		match self.by_url.get(url) {
+			Some(def) if def.type_name == r.name => self.check(def, r),
+			Some(ref def) => vec![FhirError::unexpected(&def.type_name, "Profile is for a different resource type").at(&r.name)],
+			None => vec![FhirError::unexpected(url, "Unknown profile").at(&r.name)]
+		}
+	}
+
+	// XML leaves primitives as text, and a repeating element that occurs once
+	// as a single value; give them the types and the lists their definitions
+	// declare. Text that isn't valid for its type is left for validation to report.
+	pub fn type_primitives(&self, r: &mut Resource) {
+		if let Some(def) = self.base_definition(&r.name) {
+			self.type_elts(def, &def.type_name, &mut r.elts);
+		}
+		for c in r.contained.iter_mut() {
+			self.type_primitives(c);
+		}
+	}
+
+	fn type_elts(&self, def: &Definition, def_path: &str, elts: &mut [Element]) {
+		let prefix = format!("{}.", def_path);
+		let children = def.children(&prefix);
+		for e in elts.iter_mut() {
+			if let Some((d, choice)) = children.iter().filter_map(|d| d.matches(&prefix, &e.name).map(|t| (d, t))).next() {
+				self.type_value(def, d, choice, &mut e.value);
+				match e.value.value {
+					ValueType::List(_) => (),
+					_ if d.max.map_or(true, |m| m > 1) => {
+						let single = mem::replace(&mut e.value, Value::from(ValueType::List(Vec::new())));
+						e.value = Value::from(ValueType::List(vec![single]));
+					},
+					_ => ()
+				}
+			}
+		}
+	}
+
+	fn type_value(&self, def: &Definition, d: &ElementDef, choice: Option<&str>, v: &mut Value) {
+		let types = d.types_for(choice);
+		let typed = match v.value {
+			ValueType::List(ref mut list) => {
+				for x in list.iter_mut() {
+					self.type_value(def, d, choice, x);
+				}
+				None
+			},
+			ValueType::Elt(ref mut elts) => {
+				match (d.nested(), types.len(), types.first().and_then(|t| self.base_definition(t))) {
+					(Some(n), _, _) => self.type_elts(def, n, elts),
+					(None, 1, Some(td)) if types[0] != "Extension" && types[0] != "Resource" => self.type_elts(td, &td.type_name, elts),
+					_ => ()
+				}
+				None
+			},
+			ValueType::Atom(Some(Primitive::String(ref s))) if types.len() == 1 && is_primitive(types[0]) =>
+				Primitive::from_xml_value(Some(&capitalize(types[0])), s).ok(),
+			_ => None
+		};
+		if let Some(p) = typed {
+			v.value = ValueType::Atom(Some(p));
+		}
+	}
+
+	// Contained resources are checked against the base definitions of their
+	// own types
+	fn check(&self, def: &Definition, r: &Resource) -> Vec<FhirError> {
+		let mut issues = r.check_contained();
+		self.check_elts(def, &def.type_name, &r.name, &r.all_elts(), &mut issues);
+		for (i, c) in r.contained.iter().enumerate() {
+			let path = format!("{}.contained[{}]", r.name, i);
+			match self.base_definition(&c.name) {
+				Some(cdef) => self.check_elts(cdef, &cdef.type_name, &path, &c.all_elts(), &mut issues),
+				None => issues.push(FhirError::unexpected(&c.name, "No definition for resource type").at(&path))
+			}
+		}
+		issues
+	}
+
+	fn check_elts(&self, def: &Definition, def_path: &str, path: &str, elts: &[Element], issues: &mut Vec<FhirError>) {
+		let prefix = format!("{}.", def_path);
+		let children = def.children(&prefix);
+		for e in elts.iter() {
+			let here = format!("{}.{}", path, e.name);
+			let (d, choice) = match children.iter().filter_map(|d| d.matches(&prefix, &e.name).map(|t| (d, t))).next() {
+				Some(m) => m,
+				None => {
+					issues.push(FhirError::unexpected(&e.name, "Unknown element").at(&here));
+					continue;
+				}
+			};
+			let values: Vec<&Value> = match e.value.value {
+				ValueType::List(ref l) => l.iter().collect(),
+				_ => vec![&e.value]
+			};
+			if d.max.map_or(false, |m| values.len() as u64 > m) {
+				issues.push(FhirError::unexpected(&values.len().to_string(), "Too many values").at(&here));
+			}
+			for (i, v) in values.iter().enumerate() {
+				let vpath = match e.value.value {
+					ValueType::List(_) => format!("{}[{}]", here, i),
+					_ => here.clone()
+				};
+				self.check_value(def, d, choice, v, &vpath, issues);
+			}
+		}
+		for d in children.iter().filter(|d| d.name(&prefix).ends_with("[x]")) {
+			let name = d.name(&prefix);
+			let variants: Vec<&Element> = elts.iter().filter(|e| d.matches(&prefix, &e.name).is_some()).collect();
+			if variants.len() > 1 {
+				let names: Vec<&str> = variants.iter().map(|e| e.name.as_ref()).collect();
+				issues.push(FhirError::unexpected(&names.join(" and "), "Two values for one choice").at(&format!("{}.{}", path, name)));
+			}
+		}
+		for d in children.iter().filter(|d| d.min > 0) {
+			let count: usize = elts.iter().filter(|e| d.matches(&prefix, &e.name).is_some())
+				.map(|e| match e.value.value {
+					ValueType::List(ref l) => l.len(),
+					_ => 1
+				})
+				.sum();
+			let here = format!("{}.{}", path, d.name(&prefix));
+			if count == 0 {
+				issues.push(FhirError::missing("Missing required element").at(&here));
+			} else if (count as u64) < d.min {
+				issues.push(FhirError::invalid(&count.to_string(), "Too few values").at(&here));
+			}
+		}
+	}
+
+	fn check_value(&self, def: &Definition, d: &ElementDef, choice: Option<&str>, v: &Value, path: &str, issues: &mut Vec<FhirError>) {
+		let nested = d.nested();
+		let types = d.types_for(choice);
+		match v.value {
+			ValueType::Elt(ref elts) if nested.is_some() => self.check_elts(def, nested.unwrap(), path, elts, issues),
+			ValueType::Atom(Some(Primitive::Xhtml(ref x))) if types.contains(&"xhtml") => match XhtmlElement::parse(x) {
+				Ok(div) => issues.extend(div.violations().into_iter().map(|e| e.at(path))),
+				Err(e) => issues.push(e.at(path))
+			},
+			ValueType::Atom(Some(ref p)) if types.iter().any(|t| is_primitive(t) && p.conforms_to(t)) => {},
+			ValueType::Atom(None) if types.iter().any(|t| is_primitive(t)) => {},
+			ValueType::Elt(ref elts) if types.iter().any(|t| !is_primitive(t)) => {
+				// Only a single complex type can be followed into its own definition
+				if let (1, Some(td)) = (types.len(), self.base_definition(types[0])) {
+					if types[0] != "Extension" && types[0] != "Resource" {
+						self.check_elts(td, &td.type_name, path, elts, issues);
+					}
+				}
+			},
+			ValueType::Atom(Some(ref p)) => issues.push(FhirError::invalid(&p.to_string(), "Wrong type for element").at(path)),
+			_ => issues.push(FhirError::invalid("{...}", "Wrong type for element").at(path))
+		}
+	}
+}
+
+// JSON output in the element order of the base definitions
+impl ElementOrder for Validator {
+	fn place(&self, path: &str, name: &str) -> Option<(usize, String)> {
+		let def = self.base_definition(path.split('.').next().unwrap_or(path))?;
+		let prefix = format!("{}.", path);
+		def.elements.iter()
+			.filter(|d| d.path.starts_with(&prefix) && !d.name(&prefix).contains('.'))
+			.enumerate()
+			.filter_map(|(i, d)| d.matches(&prefix, name).map(|choice| (i, d.child_path(choice))))
+			.next()
+	}
+}
+
+#[cfg(test)]
+const TEST_DEFINITIONS: &'static str = r##"{"resourceType": "Bundle", "entry": [
+	{"resource": {"resourceType": "StructureDefinition", "url": "http://hl7.org/fhir/StructureDefinition/HumanName", "type": "HumanName",
+		"derivation": "specialization", "snapshot": {"element": [
+			{"path": "HumanName", "min": 0, "max": "*"},
+			{"path": "HumanName.family", "min": 0, "max": "1", "type": [{"code": "string"}]},
+			{"path": "HumanName.given", "min": 0, "max": "*", "type": [{"code": "string"}]}]}}},
+	{"resource": {"resourceType": "StructureDefinition", "url": "http://hl7.org/fhir/StructureDefinition/Patient", "type": "Patient",
+		"derivation": "specialization", "snapshot": {"element": [
+			{"path": "Patient", "min": 0, "max": "*"},
+			{"path": "Patient.id", "min": 0, "max": "1", "type": [{"code": "id"}]},
+			{"path": "Patient.contained", "min": 0, "max": "*", "type": [{"code": "Resource"}]},
+			{"path": "Patient.active", "min": 0, "max": "1", "type": [{"code": "boolean"}]},
+			{"path": "Patient.name", "min": 0, "max": "*", "type": [{"code": "HumanName"}]},
+			{"path": "Patient.birthDate", "min": 0, "max": "1", "type": [{"code": "date"}]},
+			{"path": "Patient.deceased[x]", "min": 0, "max": "1", "type": [{"code": "boolean"}, {"code": "dateTime"}]},
+			{"path": "Patient.contact", "min": 0, "max": "*", "type": [{"code": "BackboneElement"}]},
+			{"path": "Patient.contact.gender", "min": 1, "max": "1", "type": [{"code": "code"}]},
+			{"path": "Patient.text", "min": 0, "max": "1", "type": [{"code": "Narrative"}]}]}}},
+	{"resource": {"resourceType": "StructureDefinition", "url": "http://hl7.org/fhir/StructureDefinition/Narrative", "type": "Narrative",
+		"derivation": "specialization", "snapshot": {"element": [
+			{"path": "Narrative", "min": 0, "max": "*"},
+			{"path": "Narrative.status", "min": 1, "max": "1", "type": [{"code": "code"}]},
+			{"path": "Narrative.div", "min": 1, "max": "1", "type": [{"code": "xhtml"}]}]}}},
+	{"resource": {"resourceType": "StructureDefinition", "url": "http://example.org/StructureDefinition/named-patient", "type": "Patient",
+		"derivation": "constraint", "snapshot": {"element": [
+			{"path": "Patient", "min": 0, "max": "*"},
+			{"path": "Patient.active", "min": 0, "max": "0", "type": [{"code": "boolean"}]},
+			{"path": "Patient.name", "min": 1, "max": "2", "type": [{"code": "HumanName"}]},
+			{"path": "Patient.contact", "min": 0, "max": "*", "type": [{"code": "BackboneElement"}]},
+			{"path": "Patient.contact.gender", "min": 2, "max": "*", "type": [{"code": "code"}]}]}}}]}"##;
+
+#[cfg(test)]
+fn test_validator() -> Validator {
+	let mut v = Validator::new();
+	v.load(&Json::from_str(TEST_DEFINITIONS).unwrap()).unwrap();
+	v
+}
+
+#[cfg(test)]
+fn issue_paths(r: &str, profile: Option<&str>) -> Vec<String> {
+	use json::FromJson;
+	let r = Resource::from_json(&Json::from_str(r).unwrap()).unwrap();
+	let v = test_validator();
+	let issues = match profile {
+		Some(p) => v.validate_profile(&r, p),
+		None => v.validate(&r)
+	};
+	issues.iter().map(|i| format!("{} {}", i.path(), i.reason())).collect()
+}
+
+#[test]
+fn test_valid_resource() {
+	let r = r#"{"resourceType": "Patient", "active": true, "name": [{"family": "Smith", "given": ["Jo"]}],
+		"deceasedDateTime": "2015-05-02", "contact": [{"gender": "female"}]}"#;
+	assert!(issue_paths(r, None).is_empty());
+}
+
+#[test]
+fn test_invalid_resource() {
+	let r = r#"{"resourceType": "Patient", "active": "yes", "name": [{"family": ["Smith", "Jones"]}],
+		"birthDate": "2015-05-02T05:34:00-07:00", "deceasedString": "no", "contact": [{}], "nickname": "JJ"}"#;
+	assert_eq!(vec!["Patient.active Wrong type for element",
+		"Patient.birthDate Wrong type for element",
+		"Patient.contact[0].gender Missing required element",
+		"Patient.deceasedString Unknown element",
+		"Patient.name[0].family Too many values",
+		"Patient.nickname Unknown element"], issue_paths(r, None));
+}
+
+#[test]
+fn test_two_choice_values() {
+	let r = r#"{"resourceType": "Patient", "deceasedBoolean": true, "deceasedDateTime": "2015-05-02", "contact": [{"gender": "female"}]}"#;
+	assert_eq!(vec!["Patient.deceased[x] Two values for one choice"], issue_paths(r, None));
+}
+
+#[test]
+fn test_contained_validated() {
+	let r = r##"{"resourceType": "Patient", "contained": [{"resourceType": "Patient", "id": "p2", "active": "yes"},
+		{"resourceType": "Basic", "id": "b1"}], "contact": [{"gender": "male"}], "link": [{"other": {"reference": "#p2"}}, {"other": {"reference": "#b1"}}]}"##;
+	assert_eq!(vec!["Patient.link Unknown element", "Patient.contained[0].active Wrong type for element",
+		"Patient.contained[1] No definition for resource type"], issue_paths(r, None));
+}
+
+#[test]
+fn test_resolve_path() {
+	let v = test_validator();
+	assert_eq!(Some(("HumanName.given", vec!["string"])), v.resolve_path("Patient.name.given"));
+	assert_eq!(Some(("Patient.name", vec!["HumanName"])), v.resolve_path("Patient.name"));
+	assert_eq!(Some(("Patient.contact.gender", vec!["code"])), v.resolve_path("Patient.contact.gender"));
+	assert_eq!(Some(("Patient.deceased[x]", vec!["dateTime"])), v.resolve_path("Patient.deceasedDateTime"));
+	assert_eq!(None, v.resolve_path("Patient.active.value"));
+	assert_eq!(None, v.resolve_path("Patient.nickname"));
+	assert_eq!(None, v.resolve_path("Basic.code"));
+}
+
+#[test]
+fn test_profile_cardinality() {
+	let url = Some("http://example.org/StructureDefinition/named-patient");
+	assert_eq!(vec!["Patient.active Too many values", "Patient.name Missing required element"],
+		issue_paths(r#"{"resourceType": "Patient", "active": true}"#, url));
+	assert_eq!(vec!["Patient.contact[0].gender Too few values", "Patient.name Too many values"],
+		issue_paths(r#"{"resourceType": "Patient", "name": [{}, {}, {}], "contact": [{"gender": "male"}]}"#, url));
+	assert_eq!(vec!["Patient Unknown profile"],
+		issue_paths(r#"{"resourceType": "Patient"}"#, Some("http://example.org/nope")));
+}
+
+#[test]
+fn test_narrative_rules() {
+	use narrative::Narrative;
+	use primitive::Code;
+	let r = r#"{"resourceType": "Patient", "text": {"status": "generated", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">Jo</div>"}}"#;
+	assert!(issue_paths(r, None).is_empty());
+	let div = XhtmlElement::div().child(XhtmlElement::new("p").attr("onclick", "x()").text("Jo")).child(XhtmlElement::new("script"));
+	let mut r = Resource::new("Patient");
+	r.set_narrative(Narrative::new(Code::parse("generated").unwrap(), div));
+	assert_eq!(vec!["Patient.text.div.p Attribute not allowed in a narrative", "Patient.text.div.script Element not allowed in a narrative"],
+		test_validator().validate(&r).iter().map(|i| format!("{} {}", i.path(), i.reason())).collect::<Vec<String>>());
+}
+
+#[test]
+fn test_type_primitives() {
+	use rustc_serialize::json::ToJson;
+	use xml::FromXml;
+	let mut r = Resource::from_xml(concat!(r#"<Patient xmlns="http://hl7.org/fhir"><active value="true"/><name><family value="true"/></name>"#,
+		r#"<deceasedBoolean value="false"/><contact><gender value="female"/></contact></Patient>"#)).unwrap();
+	assert_eq!(vec!["Patient.active Wrong type for element", "Patient.deceasedBoolean Wrong type for element"], test_validator().validate(&r).iter()
+		.map(|i| format!("{} {}", i.path(), i.reason())).collect::<Vec<String>>());
+	test_validator().type_primitives(&mut r);
+	assert!(test_validator().validate(&r).is_empty());
+	assert_eq!(Json::from_str(r#"{"resourceType": "Patient", "active": true, "name": [{"family": "true"}], "deceasedBoolean": false,
+		"contact": [{"gender": "female"}]}"#).unwrap(), r.to_json());
+}
+
+#[test]
+fn test_unknown_resource_type() {
+	assert_eq!(vec!["Basic No definition for resource type"], issue_paths(r#"{"resourceType": "Basic"}"#, None));
+}
+
+#[test]
+fn test_element_order() {
+	use json::FromJson;
+	let j = Json::from_str(r#"{"resourceType": "Patient", "contact": [{"gender": "female"}], "deceasedBoolean": false, "zzz": 1.5,
+		"name": [{"given": ["Jo"], "family": "Smith"}], "active": true, "_active": {"id": "a1"}, "id": "p1"}"#).unwrap();
+	let r = Resource::from_json(&j).unwrap();
+	assert_eq!(concat!(r#"{"resourceType":"Patient","id":"p1","active":true,"_active":{"id":"a1"},"#,
+		r#""name":[{"family":"Smith","given":["Jo"]}],"deceasedBoolean":false,"contact":[{"gender":"female"}],"zzz":1.5}"#),
+		r.to_json_ordered(&test_validator()));
+}
@@ -0,0 +1,126 @@
+pub mod reader;
+pub use xml::reader::{XmlNode};
+
+use std::io::Write;
+
+use primitive::base64::Encoder;
+use error::FhirError;
+
+pub const FHIR_NS: &'static str = "http://hl7.org/fhir";
+
+pub trait ToXml {
+	fn to_xml(&self) -> String;
+}
+
+pub trait FromXml: Sized {
+	fn from_xml(s: &str) -> Result<Self, FhirError>;
+}
+
+pub fn escape(s: &str) -> String {
+	let mut out = String::with_capacity(s.len());
+	for c in s.chars() {
+		match c {
+			'&' => out.push_str("&amp;"),
+			'<' => out.push_str("&lt;"),
+			'>' => out.push_str("&gt;"),
+			'"' => out.push_str("&quot;"),
+			'\'' => out.push_str("&apos;"),
+			_ => out.push(c)
+		}
+	}
+	out
+}
+
+// Minimal compact XML writer; the outermost element carries the FHIR namespace
+pub struct XmlWriter {
+	out: Vec<u8>,
+	depth: usize
+}
+
+impl XmlWriter {
+	pub fn new() -> Self {
+		XmlWriter {out: Vec::new(), depth: 0}
+	}
+
+	fn push(&mut self, s: &str) {
+		self.out.extend_from_slice(s.as_bytes());
+	}
+
+	fn open(&mut self, name: &str, attrs: &[(&str, &str)], data: Option<&[u8]>) {
+		self.push("<");
+		self.push(name);
+		if self.depth == 0 {
+			self.push(&format!(" xmlns=\"{}\"", FHIR_NS));
+		}
+		for &(k, v) in attrs {
+			self.push(&format!(" {}=\"{}\"", k, escape(v)));
+		}
+		// base64 needs no escaping, so it is encoded straight into the output
+		if let Some(data) = data {
+			self.push(" value=\"");
+			let mut e = Encoder::new(&mut self.out);
+			let _ = e.write_all(data).and_then(|_| e.finish());
+			self.push("\"");
+		}
+	}
+
+	pub fn start(&mut self, name: &str, attrs: &[(&str, &str)]) {
+		self.open(name, attrs, None);
+		self.push(">");
+		self.depth += 1;
+	}
+
+	pub fn empty(&mut self, name: &str, attrs: &[(&str, &str)]) {
+		self.open(name, attrs, None);
+		self.push("/>");
+	}
+
+	// As start and empty, with bytes as a base64 value attribute after attrs
+	pub fn start_base64(&mut self, name: &str, attrs: &[(&str, &str)], data: &[u8]) {
+		self.open(name, attrs, Some(data));
+		self.push(">");
+		self.depth += 1;
+	}
+
+	pub fn empty_base64(&mut self, name: &str, attrs: &[(&str, &str)], data: &[u8]) {
+		self.open(name, attrs, Some(data));
+		self.push("/>");
+	}
+
+	// Pre-serialized markup, e.g. the XHTML of a narrative div
+	pub fn raw(&mut self, s: &str) {
+		self.push(s);
+	}
+
+	pub fn end(&mut self, name: &str) {
+		self.depth -= 1;
+		self.push(&format!("</{}>", name));
+	}
+
+	pub fn into_string(self) -> String {
+		String::from_utf8(self.out).unwrap()
+	}
+}
+
+#[test]
+fn test_escape() {
+	assert_eq!("a &lt;b&gt; &amp; &quot;c&quot; &apos;d&apos;", escape("a <b> & \"c\" 'd'"));
+}
+
+#[test]
+fn test_writer_namespace_on_root_only() {
+	let mut w = XmlWriter::new();
+	w.start("Patient", &[]);
+	w.empty("active", &[("value", "true")]);
+	w.end("Patient");
+	assert_eq!(r#"<Patient xmlns="http://hl7.org/fhir"><active value="true"/></Patient>"#, w.into_string());
+}
+
+#[test]
+fn test_writer_base64_value() {
+	let mut w = XmlWriter::new();
+	w.start("Binary", &[]);
+	w.empty_base64("data", &[("id", "d1")], b"hello, world");
+	w.end("Binary");
+	assert_eq!(r#"<Binary xmlns="http://hl7.org/fhir"><data id="d1" value="aGVsbG8sIHdvcmxk"/></Binary>"#, w.into_string());
+}
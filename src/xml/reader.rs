@@ -0,0 +1,220 @@
+// A small non-validating XML reader, sufficient for FHIR documents: elements,
+// attributes, text, comments, CDATA and the predefined/numeric entities.
+// Each node records its byte span so XHTML can be kept verbatim, and where
+// its text falls among its children, for mixed content.
+
+use error::FhirError;
+
+pub struct XmlNode {
+	pub name: String,
+	pub attrs: Vec<(String,String)>,
+	pub children: Vec<XmlNode>,
+	pub text: String,
+	// runs of text, each before the child at its index
+	pub runs: Vec<(usize, String)>,
+	pub start: usize,
+	pub end: usize
+}
+
+impl XmlNode {
+	pub fn attr(&self, name: &str) -> Option<&str> {
+		self.attrs.iter().find(|&&(ref k, _)| k == name).map(|&(_, ref v)| v.as_ref())
+	}
+
+	fn push_text(&mut self, t: &str) {
+		self.text.push_str(t);
+		let at = self.children.len();
+		match self.runs.last_mut() {
+			Some(&mut (i, ref mut run)) if i == at => run.push_str(t),
+			_ => self.runs.push((at, t.to_string()))
+		}
+	}
+}
+
+pub fn unescape(s: &str) -> Result<String, FhirError> {
+	let mut out = String::with_capacity(s.len());
+	let mut rest = s;
+	while let Some(i) = rest.find('&') {
+		out.push_str(&rest[..i]);
+		rest = &rest[i + 1..];
+		let end = rest.find(';').ok_or(FhirError::malformed("Unterminated entity"))?;
+		let c = match &rest[..end] {
+			"amp" => '&',
+			"lt" => '<',
+			"gt" => '>',
+			"quot" => '"',
+			"apos" => '\'',
+			e if e.starts_with("#x") => u32::from_str_radix(&e[2..], 16).ok()
+				.and_then(::std::char::from_u32).ok_or(FhirError::malformed("Invalid character reference"))?,
+			e if e.starts_with('#') => e[1..].parse::<u32>().ok()
+				.and_then(::std::char::from_u32).ok_or(FhirError::malformed("Invalid character reference"))?,
+			_ => return Err(FhirError::malformed("Unknown entity"))
+		};
+		out.push(c);
+		rest = &rest[end + 1..];
+	}
+	out.push_str(rest);
+	Ok(out)
+}
+
+pub fn parse(s: &str) -> Result<XmlNode, FhirError> {
+	let mut p = Parser {s: s, pos: 0};
+	p.skip_misc()?;
+	let root = p.element()?;
+	p.skip_misc()?;
+	if p.pos != s.len() {
+		return Err(FhirError::malformed("Content after root element"));
+	}
+	Ok(root)
+}
+
+struct Parser<'a> {
+	s: &'a str,
+	pos: usize
+}
+
+impl<'a> Parser<'a> {
+	fn rest(&self) -> &'a str {
+		&self.s[self.pos..]
+	}
+
+	fn skip_ws(&mut self) {
+		let r = self.rest();
+		self.pos += r.len() - r.trim_start().len();
+	}
+
+	fn skip_past(&mut self, pat: &str) -> Result<(), FhirError> {
+		match self.rest().find(pat) {
+			Some(i) => {
+				self.pos += i + pat.len();
+				Ok(())
+			},
+			None => Err(FhirError::malformed("Unterminated markup"))
+		}
+	}
+
+	fn expect(&mut self, pat: &str) -> Result<(), FhirError> {
+		if self.rest().starts_with(pat) {
+			self.pos += pat.len();
+			Ok(())
+		} else {
+			Err(FhirError::malformed("Malformed XML"))
+		}
+	}
+
+	// XML declaration, processing instructions, comments and DOCTYPE outside the root
+	fn skip_misc(&mut self) -> Result<(), FhirError> {
+		loop {
+			self.skip_ws();
+			if self.rest().starts_with("<?") {
+				self.skip_past("?>")?;
+			} else if self.rest().starts_with("<!--") {
+				self.skip_past("-->")?;
+			} else if self.rest().starts_with("<!") {
+				self.skip_past(">")?;
+			} else {
+				return Ok(());
+			}
+		}
+	}
+
+	fn name(&mut self) -> Result<String, FhirError> {
+		let r = self.rest();
+		let n = r.find(|c: char| c.is_whitespace() || c == '>' || c == '/' || c == '=').unwrap_or(r.len());
+		if n == 0 {
+			return Err(FhirError::malformed("Expected name"));
+		}
+		self.pos += n;
+		Ok(r[..n].to_string())
+	}
+
+	fn attr_value(&mut self) -> Result<String, FhirError> {
+		let q = match self.rest().chars().next() {
+			Some(c) if c == '"' || c == '\'' => c,
+			_ => return Err(FhirError::malformed("Expected quoted attribute value"))
+		};
+		self.pos += 1;
+		let len = self.rest().find(q).ok_or(FhirError::malformed("Unterminated attribute value"))?;
+		let v = unescape(&self.rest()[..len])?;
+		self.pos += len + 1;
+		Ok(v)
+	}
+
+	fn element(&mut self) -> Result<XmlNode, FhirError> {
+		let start = self.pos;
+		self.expect("<")?;
+		let mut node = XmlNode {name: self.name()?, attrs: Vec::new(), children: Vec::new(), text: String::new(), runs: Vec::new(), start: start, end: start};
+		loop {
+			self.skip_ws();
+			if self.rest().starts_with("/>") {
+				self.pos += 2;
+				node.end = self.pos;
+				return Ok(node);
+			}
+			if self.rest().starts_with('>') {
+				self.pos += 1;
+				break;
+			}
+			let k = self.name()?;
+			self.skip_ws();
+			self.expect("=")?;
+			self.skip_ws();
+			let v = self.attr_value()?;
+			node.attrs.push((k, v));
+		}
+		loop {
+			let r = self.rest();
+			if r.starts_with("</") {
+				self.pos += 2;
+				if self.name()? != node.name {
+					return Err(FhirError::malformed("Mismatched closing tag"));
+				}
+				self.skip_ws();
+				self.expect(">")?;
+				node.end = self.pos;
+				return Ok(node);
+			} else if r.starts_with("<!--") {
+				self.skip_past("-->")?;
+			} else if r.starts_with("<![CDATA[") {
+				let i = r.find("]]>").ok_or(FhirError::malformed("Unterminated CDATA"))?;
+				node.push_text(&r[9..i]);
+				self.pos += i + 3;
+			} else if r.starts_with('<') {
+				node.children.push(self.element()?);
+			} else if r.is_empty() {
+				return Err(FhirError::malformed("Unexpected end of document"));
+			} else {
+				let n = r.find('<').unwrap_or(r.len());
+				node.push_text(&unescape(&r[..n])?);
+				self.pos += n;
+			}
+		}
+	}
+}
+
+#[test]
+fn test_parse_nested() {
+	let s = r#"<?xml version="1.0"?><!-- c --><a x='1' y="&lt;2&gt;"><b/><c>t&amp;u<![CDATA[<v>]]></c></a>"#;
+	let n = parse(s).unwrap();
+	assert_eq!("a", n.name);
+	assert_eq!(Some("1"), n.attr("x"));
+	assert_eq!(Some("<2>"), n.attr("y"));
+	assert_eq!(2, n.children.len());
+	assert_eq!("t&u<v>", n.children[1].text);
+	assert_eq!(vec![(0, "t&u<v>".to_string())], n.children[1].runs);
+	assert_eq!("<b/>", &s[n.children[0].start..n.children[0].end]);
+}
+
+#[test]
+fn test_parse_errors() {
+	assert!(parse("<a><b></a>").is_err());
+	assert!(parse("<a>").is_err());
+	assert!(parse("<a x=1/>").is_err());
+	assert!(parse("<a/><b/>").is_err());
+	assert!(unescape("&bogus;").is_err());
+}
+
+#[test]
+fn test_unescape_char_refs() {
+	assert_eq!("A B", unescape("&#65;&#x20;B").unwrap());
+}